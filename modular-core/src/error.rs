@@ -3,6 +3,31 @@ pub enum ModuleError {
     UnknownMethod,
     Custom(CustomModuleError),
     Destroyed,
+    /// The caller isn't allowed to invoke the module.
+    Denied,
+    /// The call was rejected by a rate limit.
+    Overloaded,
+    /// The request or response exceeded the payload limit.
+    PayloadTooLarge(PayloadTooLarge),
+    /// There is no module with the requested name.
+    NotFound,
+}
+
+#[derive(thiserror::Error, Debug, Copy, Clone, PartialEq, Eq)]
+#[error("payload of {size} bytes exceeds the limit of {limit} bytes")]
+pub struct PayloadTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl PayloadTooLarge {
+    /// Checks `size` against `limit`, if there is one.
+    pub fn check(size: usize, limit: Option<usize>) -> Result<(), Self> {
+        match limit {
+            Some(limit) if size > limit => Err(Self { size, limit }),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -12,12 +37,86 @@ pub struct CustomModuleError {
     pub message: Option<String>,
 }
 
+#[derive(thiserror::Error, Debug)]
 pub enum SubscribeError {
-    InvalidPattern(anyhow::Error),
+    #[error("invalid pattern: {0}")]
+    InvalidPattern(#[from] PatternError),
+    #[error("invalid filter: {0}")]
+    InvalidFilter(#[from] FilterError),
+    #[error("modular instance is shutting down")]
+    ShuttingDown,
+    #[error("permission denied")]
+    Denied,
+    /// The key of a queue group member isn't an argument of its pattern or differs from
+    /// the key of the group.
+    #[error("invalid group key `{0}`")]
+    InvalidGroupKey(String),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("expected {expected} at {position}, found `{segment}`")]
+pub struct PatternError {
+    /// Byte offset in the pattern where parsing failed.
+    pub position: usize,
+    pub expected: Expected,
+    /// The `.`-separated segment containing `position`.
+    pub segment: String,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Segment,
+    ClosingBrace,
+    EscapedChar,
+    End,
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Segment => write!(f, "segment"),
+            Expected::ClosingBrace => write!(f, "identifier or `}}`"),
+            Expected::EscapedChar => write!(f, "one of `{{`, `}}`, `.`, `\\`, `>` after `\\`"),
+            Expected::End => write!(f, "`.` or end of pattern"),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum RegistryError {
     #[error("module already exists")]
     AlreadyExists,
+    #[error("modular instance is shutting down")]
+    ShuttingDown,
+    #[error("permission denied")]
+    Denied,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RequestError {
+    #[error("no responders for the request")]
+    NoResponders,
+    #[error("no reply before the timeout")]
+    Timeout,
+    #[error("permission denied")]
+    Denied,
+    /// The request was dropped by an interceptor, a payload limit or a rate limit.
+    #[error("request dropped")]
+    Dropped,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CronError {
+    #[error("expected 5 fields, found {0}")]
+    FieldCount(usize),
+    #[error("invalid field `{0}`")]
+    InvalidField(String),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{message} at {position}")]
+pub struct FilterError {
+    /// Byte offset in the filter where parsing failed.
+    pub position: usize,
+    pub message: String,
 }
@@ -0,0 +1,49 @@
+use crate::request::ModuleRequest;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+
+/// Conventional header names, none of them are required.
+pub mod headers {
+    /// Milliseconds since the Unix epoch when the event was created.
+    pub const TIMESTAMP: &str = "timestamp";
+    /// Name of the module that published the event.
+    pub const SOURCE: &str = "source";
+    pub const CORRELATION_ID: &str = "correlation-id";
+    pub const CONTENT_TYPE: &str = "content-type";
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    pub topic: String,
+    pub data: Bytes,
+    pub headers: BTreeMap<String, String>,
+    /// Assigned when the event is published, increases with every event published on
+    /// the same instance.
+    pub seq: u64,
+}
+
+impl Event {
+    pub fn new(topic: &str, data: Bytes) -> Self {
+        Self {
+            topic: topic.to_owned(),
+            data,
+            headers: Default::default(),
+            seq: 0,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+impl From<ModuleRequest<Bytes>> for Event {
+    fn from(value: ModuleRequest<Bytes>) -> Self {
+        Self::new(&value.action, value.body)
+    }
+}
@@ -0,0 +1,490 @@
+//! Declarative event filters, e.g. `headers.severity >= 3 && !(topic == "logs.debug")`.
+//!
+//! * Fields are `topic`, `data` (the payload as UTF-8), `seq` and `headers.<name>`. A
+//!   field on its own checks that it's present, which only makes a difference for headers.
+//! * Comparisons are `==`, `!=`, `<`, `<=`, `>`, `>=` and `contains` between a field and
+//!   a value. Values are numbers, quoted strings or bare words. Both sides are compared
+//!   as numbers when they are numbers, as strings otherwise. Comparisons with a missing
+//!   header are false.
+//! * `<`, `<=`, `>` and `>=` need a number or a log level (`trace`, `debug`, `info`,
+//!   `warn`, `warning` or `error`, in that order) as value, so `headers.severity >= warn`
+//!   matches `warn` and `error`. Fields that aren't of the same kind don't match.
+//! * Expressions are combined with `&&`, `||`, `!` and parentheses.
+
+use crate::error::FilterError;
+use crate::event::Event;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            end: source.len(),
+            depth: 0,
+        };
+
+        let expr = parser.or()?;
+        if let Some((position, _)) = parser.tokens.get(parser.pos) {
+            return Err(error(*position, "expected `&&`, `||` or end of filter"));
+        }
+
+        Ok(Self {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    /// The string this filter was parsed from.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.expr.eval(event)
+    }
+}
+
+/// How deeply `!` and parentheses can nest, evaluating and dropping recurse that deep.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+enum Expr {
+    /// Chains are kept flat so long ones don't nest.
+    Or(Vec<Expr>),
+    And(Vec<Expr>),
+    Not(Box<Expr>),
+    Exists(Field),
+    Compare(Field, Op, String),
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Topic,
+    Data,
+    Seq,
+    Header(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl Expr {
+    fn eval(&self, event: &Event) -> bool {
+        match self {
+            Expr::Or(v) => v.iter().any(|v| v.eval(event)),
+            Expr::And(v) => v.iter().all(|v| v.eval(event)),
+            Expr::Not(v) => !v.eval(event),
+            Expr::Exists(field) => field.get(event).is_some(),
+            Expr::Compare(field, op, value) => match field.get(event) {
+                Some(v) => compare(&v, *op, value),
+                None => false,
+            },
+        }
+    }
+}
+
+impl Field {
+    fn get<'a>(&self, event: &'a Event) -> Option<std::borrow::Cow<'a, str>> {
+        match self {
+            Field::Topic => Some(event.topic.as_str().into()),
+            Field::Data => Some(String::from_utf8_lossy(&event.data)),
+            Field::Seq => Some(event.seq.to_string().into()),
+            Field::Header(name) => event.header(name).map(Into::into),
+        }
+    }
+}
+
+/// Rank of a log level name, for ordering comparisons.
+fn level(name: &str) -> Option<u8> {
+    ["trace", "debug", "info", "warn", "error"]
+        .iter()
+        .position(|v| name.eq_ignore_ascii_case(v))
+        .or_else(|| name.eq_ignore_ascii_case("warning").then_some(3))
+        .map(|v| v as u8)
+}
+
+fn compare(left: &str, op: Op, right: &str) -> bool {
+    if op == Op::Contains {
+        return left.contains(right);
+    }
+
+    let ordering = match (left.parse::<f64>(), right.parse::<f64>()) {
+        (Ok(l), Ok(r)) => match l.partial_cmp(&r) {
+            Some(v) => v,
+            None => return false,
+        },
+        _ if matches!(op, Op::Eq | Op::Ne) => left.cmp(right),
+        _ => match (level(left), level(right)) {
+            (Some(l), Some(r)) => l.cmp(&r),
+            _ => return false,
+        },
+    };
+
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+        Op::Contains => unreachable!(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn error(position: usize, message: &str) -> FilterError {
+    FilterError {
+        position,
+        message: message.to_owned(),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let next = chars.peek().map(|(_, c)| *c);
+        let token = match (c, next) {
+            (c, _) if c.is_whitespace() => continue,
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            ('=', Some('=')) => Token::Op(Op::Eq),
+            ('!', Some('=')) => Token::Op(Op::Ne),
+            ('<', Some('=')) => Token::Op(Op::Le),
+            ('>', Some('=')) => Token::Op(Op::Ge),
+            ('!', _) => Token::Not,
+            ('<', _) => Token::Op(Op::Lt),
+            ('>', _) => Token::Op(Op::Gt),
+            ('"' | '\'', _) => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, v)) => value.push(v),
+                            None => return Err(error(source.len(), "unterminated string")),
+                        },
+                        Some((_, v)) if v == c => break,
+                        Some((_, v)) => value.push(v),
+                        None => return Err(error(source.len(), "unterminated string")),
+                    }
+                }
+
+                tokens.push((start, Token::Str(value)));
+                continue;
+            }
+            (c, _) if is_word_char(c) => {
+                let mut value = c.to_string();
+                while let Some((_, v)) = chars.next_if(|(_, v)| is_word_char(*v)) {
+                    value.push(v);
+                }
+
+                let token = match value.as_str() {
+                    "contains" => Token::Op(Op::Contains),
+                    _ => Token::Word(value),
+                };
+                tokens.push((start, token));
+                continue;
+            }
+            _ => return Err(error(start, "unexpected character")),
+        };
+
+        // the two-character tokens
+        if matches!(
+            token,
+            Token::And | Token::Or | Token::Op(Op::Eq | Op::Ne | Op::Le | Op::Ge)
+        ) {
+            chars.next();
+        }
+
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '$')
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Length of the source, reported when it ends too early.
+    end: usize,
+    /// Enclosing `!` and parentheses.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, v)| v)
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), FilterError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| error(self.end, "unexpected end of filter"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Parses operands with `operand` for as long as they are separated by `separator`.
+    fn chain(
+        &mut self,
+        separator: Token,
+        operand: fn(&mut Self) -> Result<Expr, FilterError>,
+        combine: fn(Vec<Expr>) -> Expr,
+    ) -> Result<Expr, FilterError> {
+        let mut operands = vec![operand(self)?];
+        while self.peek() == Some(&separator) {
+            self.pos += 1;
+            operands.push(operand(self)?);
+        }
+
+        Ok(match operands.len() {
+            1 => operands.remove(0),
+            _ => combine(operands),
+        })
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        self.chain(Token::Or, Self::and, Expr::Or)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        self.chain(Token::And, Self::not, Expr::And)
+    }
+
+    /// Parses `inner` one level deeper, fails at `position` past [`MAX_DEPTH`].
+    fn nested(
+        &mut self,
+        position: usize,
+        inner: impl FnOnce(&mut Self) -> Result<Expr, FilterError>,
+    ) -> Result<Expr, FilterError> {
+        if self.depth == MAX_DEPTH {
+            return Err(error(position, "filter is nested too deeply"));
+        }
+
+        self.depth += 1;
+        let expr = inner(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn not(&mut self) -> Result<Expr, FilterError> {
+        if self.peek() == Some(&Token::Not) {
+            let (position, _) = self.next()?;
+            return self.nested(position, |v| Ok(Expr::Not(Box::new(v.not()?))));
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, FilterError> {
+        let (position, token) = self.next()?;
+        let field = match token {
+            Token::Open => {
+                return self.nested(position, |v| {
+                    let expr = v.or()?;
+                    match v.next()? {
+                        (_, Token::Close) => Ok(expr),
+                        (position, _) => Err(error(position, "expected `)`")),
+                    }
+                });
+            }
+            Token::Word(name) => match name.as_str() {
+                "topic" => Field::Topic,
+                "data" => Field::Data,
+                "seq" => Field::Seq,
+                _ => match name.strip_prefix("headers.") {
+                    Some(v) if !v.is_empty() => Field::Header(v.to_owned()),
+                    _ => return Err(error(position, "unknown field")),
+                },
+            },
+            _ => return Err(error(position, "expected field or `(`")),
+        };
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            _ => return Ok(Expr::Exists(field)),
+        };
+        self.pos += 1;
+
+        match self.next()? {
+            (position, Token::Word(value) | Token::Str(value))
+                if matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge)
+                    && value.parse::<f64>().is_err()
+                    && level(&value).is_none() =>
+            {
+                Err(error(position, "expected a number or log level"))
+            }
+            (_, Token::Word(value) | Token::Str(value)) => Ok(Expr::Compare(field, op, value)),
+            (position, _) => Err(error(position, "expected value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn event() -> Event {
+        Event::new("logs.app", Bytes::from("disk full"))
+            .with_header("severity", "3")
+            .with_header("level", "warn")
+    }
+
+    fn matches(filter: &str) -> bool {
+        Filter::parse(filter).unwrap().matches(&event())
+    }
+
+    fn error_at(filter: &str) -> usize {
+        Filter::parse(filter).unwrap_err().position
+    }
+
+    #[test]
+    fn fields_and_comparisons() {
+        assert!(matches("topic == logs.app"));
+        assert!(matches("topic != 'logs.debug'"));
+        assert!(matches("data contains \"disk\""));
+        assert!(matches("seq == 0"));
+        assert!(matches("headers.severity"));
+        assert!(!matches("headers.missing"));
+        assert!(!matches("headers.missing != x"));
+        assert!(matches("!headers.missing"));
+    }
+
+    #[test]
+    fn numbers_compare_as_numbers() {
+        assert!(matches("headers.severity >= 3"));
+        assert!(matches("headers.severity < 10"));
+        assert!(matches("headers.severity == 3.0"));
+        // as strings "3" > "10"
+        assert!(!matches("headers.severity > 10"));
+    }
+
+    #[test]
+    fn log_levels_compare_by_severity() {
+        assert!(matches("headers.level >= warn"));
+        assert!(matches("headers.level >= WARNING"));
+        assert!(matches("headers.level > info"));
+        assert!(!matches("headers.level > warn"));
+        assert!(!matches("headers.level >= error"));
+        assert!(matches("headers.level < error"));
+
+        // fields of a different kind don't match
+        assert!(!matches("headers.severity >= trace"));
+        assert!(!matches("headers.level > 1"));
+        assert!(!matches("topic < error"));
+    }
+
+    #[test]
+    fn other_values_only_compare_for_equality() {
+        assert!(matches("headers.level == warn"));
+        assert!(matches("headers.level != 'error'"));
+        assert!(matches("headers.severity != x"));
+
+        assert_eq!(error_at("headers.level < zzz"), 16);
+        assert_eq!(error_at("topic >= 'logs.app'"), 9);
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(matches("topic == x && seq == 1 || seq == 0"));
+        assert!(matches("seq == 0 || topic == x && seq == 1"));
+        assert!(!matches("(seq == 0 || topic == x) && seq == 1"));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert!(matches("!headers.missing && seq == 0"));
+        assert!(!matches("!(headers.missing || seq == 0)"));
+        assert!(matches("!!headers.severity"));
+    }
+
+    #[test]
+    fn errors_report_the_position() {
+        assert_eq!(error_at("topic == x &&"), 13);
+        assert_eq!(error_at("topic =="), 8);
+        assert_eq!(error_at("topic == x y"), 11);
+        assert_eq!(error_at("(topic == x"), 11);
+        assert_eq!(error_at("payload == x"), 0);
+        assert_eq!(error_at("seq == 1 && headers. == x"), 12);
+        assert_eq!(error_at("topic == 'x"), 11);
+        assert_eq!(error_at("topic # x"), 6);
+        assert_eq!(error_at("topic == &&"), 9);
+        assert_eq!(error_at(""), 0);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |open: &str, close: &str, depth: usize| {
+            format!("{}seq == 0{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert!(matches(&nested("!!", "", MAX_DEPTH / 2)));
+        assert!(matches(&nested("(", ")", MAX_DEPTH)));
+        assert!(matches(&nested("!(", ")", MAX_DEPTH / 2)));
+
+        assert_eq!(error_at(&nested("!", "", 5000)), MAX_DEPTH);
+        assert_eq!(error_at(&nested("(", ")", MAX_DEPTH + 1)), MAX_DEPTH);
+        assert_eq!(error_at(&nested("!(", ")", MAX_DEPTH)), MAX_DEPTH);
+
+        // long chains don't nest
+        let chain = vec!["seq == 1"; 5000].join(" || ") + " || seq == 0";
+        assert!(matches(&chain));
+    }
+
+    #[test]
+    fn round_trips_the_source() {
+        let filter: Filter = "seq > 1".parse().unwrap();
+        assert_eq!(filter.as_str(), "seq > 1");
+        assert_eq!(filter.to_string(), "seq > 1");
+    }
+}
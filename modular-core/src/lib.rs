@@ -1,4 +1,6 @@
 pub mod error;
+pub mod event;
+pub mod filter;
 pub mod modular;
 pub mod module;
 pub mod request;
@@ -6,6 +8,7 @@ pub mod response;
 
 pub mod modules {
     pub use super::error::*;
+    pub use super::event::*;
     pub use super::request::*;
     pub use super::response::*;
 }
@@ -1,13 +1,13 @@
+use crate::event::Event;
 use crate::module::Module;
 use crate::modules::*;
-use bytes::Bytes;
 use futures::future::BoxFuture;
 use futures::Sink;
 use std::future::Future;
 use tower::Service;
 
 pub type BoxModule =
-    Box<dyn Module<Future = BoxFuture<'static, Result<ModuleResponse, ModuleError>>>>;
+    Box<dyn Module<Future = BoxFuture<'static, Result<ModuleResponse, ModuleError>>> + Send + Sync>;
 
 pub trait Modular: Send + Sync {
     type Stream: Send + 'static;
@@ -26,11 +26,11 @@ pub trait Modular: Send + Sync {
         sink: Option<S>,
     ) -> Result<Self::Stream, SubscribeError>
     where
-        S: Sink<(String, Bytes), Error = Err> + Send + Sync + 'static;
+        S: Sink<Event, Error = Err> + Send + Sync + 'static;
 
-    fn publish<Request>(&self, event: Request)
+    fn publish<E>(&self, event: E)
     where
-        Request: Into<ModuleRequest<Bytes>>;
+        E: Into<Event>;
 
     fn get_module(&self, name: &str) -> Option<Self::Module>;
 
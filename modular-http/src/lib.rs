@@ -0,0 +1,464 @@
+//! HTTP gateway for `modular-rs`.
+//!
+//! * `POST /modules/{name}/{action}` invokes `action` of module `name` with the request
+//!   body and responds with the module's response. System modules are not found unless
+//!   [`Gateway::allow_system_modules`] was called.
+//! * `GET /events?pattern=...` streams events matching the pattern as Server-Sent Events,
+//!   `&filter=...` only streams the events accepted by a filter expression. Events that
+//!   don't fit into the stream's buffer are dropped and reported with a `lagged` event
+//!   whose data is `{"dropped": 10}`.
+//! * `GET /ws` upgrades to a WebSocket where clients subscribe, unsubscribe and publish,
+//!   see [`ws`] for the messages.
+
+use axum::extract::{Path, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::Engine;
+use bytes::Bytes;
+use futures::{Sink, Stream, StreamExt};
+use modular_core::error::{ModuleError, PatternError, SubscribeError};
+use modular_core::filter::Filter;
+use modular_core::modular::Modular as _;
+use modular_core::module::Module as _;
+use modular_core::modules::{Event as ModularEvent, ModuleRequest};
+use modular_rs::core::modules::is_system_module;
+use modular_rs::core::pattern::Pattern;
+use modular_rs::core::Modular;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+pub mod ws;
+
+/// Default number of messages buffered for each WebSocket connection.
+pub const DEFAULT_WS_BUFFER: usize = 256;
+
+/// Default number of events buffered for each Server-Sent Events stream.
+pub const DEFAULT_SSE_BUFFER: usize = 256;
+
+pub struct Gateway {
+    modular: Arc<Modular>,
+    ws_buffer: usize,
+    sse_buffer: usize,
+    publish: Vec<Pattern>,
+    system_modules: bool,
+}
+
+impl Gateway {
+    pub fn new(modular: Arc<Modular>) -> Self {
+        Self {
+            modular,
+            ws_buffer: DEFAULT_WS_BUFFER,
+            sse_buffer: DEFAULT_SSE_BUFFER,
+            publish: vec![],
+            system_modules: false,
+        }
+    }
+
+    /// Sets how many events are buffered for a Server-Sent Events stream before events
+    /// are dropped.
+    pub fn set_sse_buffer(&mut self, size: usize) {
+        self.sse_buffer = size.max(1);
+    }
+
+    /// Sets how many messages are buffered for a WebSocket client before events are
+    /// dropped.
+    pub fn set_ws_buffer(&mut self, size: usize) {
+        self.ws_buffer = size.max(1);
+    }
+
+    /// Allows WebSocket clients to publish to topics matching `pattern`. Publishing is
+    /// denied for all topics by default.
+    pub fn allow_publish(&mut self, pattern: &str) -> Result<(), PatternError> {
+        self.publish.push(Pattern::parse(pattern)?);
+        Ok(())
+    }
+
+    /// Allows invoking system modules like [`METRICS_MODULE`](modular_rs::core::METRICS_MODULE), they are hidden by default.
+    pub fn allow_system_modules(&mut self) {
+        self.system_modules = true;
+    }
+
+    fn can_publish(&self, topic: &str) -> bool {
+        self.publish.iter().any(|i| i.matches_strict(topic))
+    }
+
+    pub fn into_router(self) -> Router {
+        Router::new()
+            .route("/modules/{name}/{action}", post(invoke))
+            .route("/events", get(events))
+            .route("/ws", get(ws::upgrade))
+            .with_state(Arc::new(self))
+    }
+}
+
+/// Builds the gateway routes for `modular` with the default settings.
+pub fn router(modular: Arc<Modular>) -> Router {
+    Gateway::new(modular).into_router()
+}
+
+/// Status code an invoke failing with `err` responds with.
+///
+/// Codes of custom errors are used as is when they are valid HTTP error statuses,
+/// everything else maps to `500 Internal Server Error`.
+pub fn status_for(err: &ModuleError) -> StatusCode {
+    match err {
+        ModuleError::UnknownMethod => StatusCode::NOT_FOUND,
+        ModuleError::Custom(err) => u16::try_from(err.code)
+            .ok()
+            .filter(|v| (400..600).contains(v))
+            .and_then(|v| StatusCode::from_u16(v).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        ModuleError::Destroyed => StatusCode::SERVICE_UNAVAILABLE,
+        ModuleError::Denied => StatusCode::FORBIDDEN,
+        ModuleError::Overloaded => StatusCode::TOO_MANY_REQUESTS,
+        ModuleError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        ModuleError::NotFound => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn invoke(
+    State(gateway): State<Arc<Gateway>>,
+    Path((name, action)): Path<(String, String)>,
+    body: Bytes,
+) -> Response {
+    if is_system_module(&name) && !gateway.system_modules {
+        return error(StatusCode::NOT_FOUND, "module not found");
+    }
+
+    let Some(module) = gateway.modular.get_module(&name) else {
+        return error(StatusCode::NOT_FOUND, "module not found");
+    };
+
+    let result = match module.invoke(ModuleRequest::new(&action, body)).await {
+        Ok(fut) => fut.await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(response) => {
+            ([(CONTENT_TYPE, "application/octet-stream")], response.data).into_response()
+        }
+        Err(err) => {
+            let status = status_for(&err);
+            match err {
+                ModuleError::Custom(err) => {
+                    let body = json!({
+                        "code": err.code,
+                        "name": err.name,
+                        "message": err.message,
+                    });
+
+                    (status, Json(body)).into_response()
+                }
+                ModuleError::UnknownMethod => error(status, "unknown method"),
+                ModuleError::Destroyed => error(status, "module destroyed"),
+                ModuleError::Denied => error(status, "permission denied"),
+                ModuleError::Overloaded => error(status, "overloaded"),
+                ModuleError::PayloadTooLarge(e) => error(status, &e.to_string()),
+                ModuleError::NotFound => error(status, "module not found"),
+            }
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "message": message }))).into_response()
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    pattern: String,
+    filter: Option<String>,
+}
+
+async fn events(
+    State(gateway): State<Arc<Gateway>>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
+    let stream = EventStream::subscribe(
+        gateway.modular.clone(),
+        &query.pattern,
+        query.filter.as_deref(),
+        gateway.sse_buffer,
+    )
+    .map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+
+    let stream = stream.map(|message| {
+        let event = match message {
+            StreamMessage::Event(event) => Event::default().data(event.to_string()),
+            StreamMessage::Lagged(dropped) => Event::default()
+                .event("lagged")
+                .data(json!({ "dropped": dropped }).to_string()),
+        };
+
+        Ok(event)
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Subscribes `sink` to `pattern`, only passing events accepted by `filter` if set.
+/// Returns the id of the subscription.
+fn subscribe<S, Err>(
+    modular: &Modular,
+    pattern: &str,
+    filter: Option<&str>,
+    sink: S,
+) -> Result<u64, SubscribeError>
+where
+    S: Sink<ModularEvent, Error = Err> + Send + Sync + 'static,
+{
+    let filter = filter.map(Filter::parse).transpose()?;
+    modular.subscribe_filtered(
+        pattern,
+        move |event| filter.as_ref().is_none_or(|v| v.matches(event)),
+        sink,
+    )
+}
+
+/// Queues events as JSON messages without waiting, events that don't fit into the
+/// buffer are counted in `dropped` and dropped.
+pub(crate) struct LaggingSink<F> {
+    pub(crate) tx: mpsc::Sender<Value>,
+    pub(crate) dropped: Arc<AtomicU64>,
+    pub(crate) message: F,
+}
+
+impl<F> Sink<ModularEvent> for LaggingSink<F>
+where
+    F: Fn(&ModularEvent) -> Value + Unpin,
+{
+    type Error = ();
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.tx.is_closed() {
+            true => Poll::Ready(Err(())),
+            false => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, event: ModularEvent) -> Result<(), Self::Error> {
+        match self.tx.try_send((self.message)(&event)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+enum StreamMessage {
+    Event(Value),
+    /// Number of events dropped before the next one.
+    Lagged(u64),
+}
+
+/// Events of a Server-Sent Events subscription, the subscription is removed when the
+/// stream is dropped because the client disconnected.
+struct EventStream {
+    modular: Arc<Modular>,
+    id: u64,
+    rx: mpsc::Receiver<Value>,
+    dropped: Arc<AtomicU64>,
+    /// Event received while reporting dropped events.
+    pending: Option<Value>,
+}
+
+impl EventStream {
+    fn subscribe(
+        modular: Arc<Modular>,
+        pattern: &str,
+        filter: Option<&str>,
+        buffer: usize,
+    ) -> Result<Self, SubscribeError> {
+        let (tx, rx) = mpsc::channel(buffer);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let sink = LaggingSink {
+            tx,
+            dropped: dropped.clone(),
+            message: |event: &ModularEvent| {
+                let mut message = json!({ "topic": event.topic });
+                put_event(&mut message, event);
+                message
+            },
+        };
+
+        let id = subscribe(&modular, pattern, filter, sink)?;
+        Ok(Self {
+            modular,
+            id,
+            rx,
+            dropped,
+            pending: None,
+        })
+    }
+}
+
+impl Stream for EventStream {
+    type Item = StreamMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.pending.take() {
+            return Poll::Ready(Some(StreamMessage::Event(event)));
+        }
+
+        let Some(event) = std::task::ready!(self.rx.poll_recv(cx)) else {
+            return Poll::Ready(None);
+        };
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            self.pending = Some(event);
+            return Poll::Ready(Some(StreamMessage::Lagged(dropped)));
+        }
+
+        Poll::Ready(Some(StreamMessage::Event(event)))
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.modular.unsubscribe(self.id);
+    }
+}
+
+/// Sets `data`, `headers` and `seq` of a JSON message from `event`.
+fn put_event(message: &mut serde_json::Value, event: &ModularEvent) {
+    put_data(message, &event.data);
+    if !event.headers.is_empty() {
+        message["headers"] = json!(event.headers);
+    }
+    message["seq"] = event.seq.into();
+}
+
+/// Sets `data` of a JSON message, payloads that aren't UTF-8 are base64 encoded and
+/// marked with `"encoding": "base64"`.
+fn put_data(message: &mut serde_json::Value, data: &[u8]) {
+    match std::str::from_utf8(data) {
+        Ok(v) => message["data"] = v.into(),
+        Err(_) => {
+            message["data"] = base64::engine::general_purpose::STANDARD
+                .encode(data)
+                .into();
+            message["encoding"] = "base64".into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    pub(crate) async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        panic!("timed out");
+    }
+
+    fn topic(message: Option<StreamMessage>) -> String {
+        match message {
+            Some(StreamMessage::Event(event)) => event["topic"].as_str().unwrap().to_owned(),
+            _ => panic!("expected an event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sse_reports_dropped_events() {
+        let modular = Arc::new(Modular::default());
+        let mut stream = EventStream::subscribe(modular.clone(), "a.>", None, 1).unwrap();
+
+        for topic in ["a.1", "a.2", "a.3"] {
+            modular.publish(ModularEvent::new(topic, Bytes::new()));
+        }
+        eventually(|| stream.dropped.load(Ordering::Relaxed) == 2).await;
+
+        assert!(matches!(
+            stream.next().await,
+            Some(StreamMessage::Lagged(2))
+        ));
+        assert_eq!(topic(stream.next().await), "a.1");
+
+        modular.publish(ModularEvent::new("a.4", Bytes::new()));
+        assert_eq!(topic(stream.next().await), "a.4");
+    }
+
+    async fn post(app: Router, path: &str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request =
+            format!("POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = [0; 1024];
+        let len = client.read(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response[..len]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn system_modules_are_hidden() {
+        let modular = Arc::new(Modular::default());
+        let path = "/modules/$.sys.metrics/prometheus";
+
+        let response = post(router(modular.clone()), path).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        let mut gateway = Gateway::new(modular);
+        gateway.allow_system_modules();
+        let response = post(gateway.into_router(), path).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn sse_subscription_is_removed_when_the_client_disconnects() {
+        let modular = Arc::new(Modular::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(modular.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /events?pattern=a.%3E HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = [0; 1024];
+        let len = client.read(&mut response).await.unwrap();
+        assert!(response[..len].starts_with(b"HTTP/1.1 200"));
+        assert_eq!(modular.subscription_patterns().len(), 1);
+
+        // no event is published, dropping the stream alone removes the subscription
+        drop(client);
+        eventually(|| modular.subscription_patterns().is_empty()).await;
+    }
+}
@@ -0,0 +1,53 @@
+//! Serves a `Modular` instance over HTTP.
+//!
+//! Usage: `modular-http [HTTP_ADDR] [REMOTE_ADDR]`
+//!
+//! When `REMOTE_ADDR` is given, `modular-remote` clients can connect there to register
+//! the modules and publish the events exposed by the gateway.
+//!
+//! `MODULAR_WS_PUBLISH` takes a comma separated list of patterns WebSocket clients are
+//! allowed to publish to.
+
+use modular_http::Gateway;
+use modular_remote::server::Server;
+use modular_rs::core::Modular;
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = std::env::args().skip(1);
+    let http_addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    let remote_addr = args.next();
+
+    let modular = Arc::new(Modular::default());
+
+    if let Some(addr) = remote_addr {
+        let listener = TcpListener::bind(&addr).await?;
+        let server = Server::new(modular.clone());
+        tracing::info!(%addr, "accepting remote connections");
+
+        tokio::spawn(async move {
+            if let Err(e) = server.serve_tcp(listener).await {
+                tracing::error!(error = %e, "remote server stopped");
+            }
+        });
+    }
+
+    let listener = TcpListener::bind(&http_addr).await?;
+    tracing::info!(addr = %http_addr, "serving http");
+
+    let mut gateway = Gateway::new(modular);
+    if let Ok(patterns) = std::env::var("MODULAR_WS_PUBLISH") {
+        for pattern in patterns.split(',').filter(|i| !i.is_empty()) {
+            gateway
+                .allow_publish(pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+    }
+
+    axum::serve(listener, gateway.into_router()).await
+}
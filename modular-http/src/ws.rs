@@ -0,0 +1,347 @@
+//! WebSocket bridge to the event bus.
+//!
+//! Messages are JSON text frames tagged with `type`. Clients send:
+//!
+//! * `{"type": "subscribe", "id": 1, "pattern": "a.>"}`, with an optional `"filter"`
+//!   expression events have to match
+//! * `{"type": "unsubscribe", "id": 1}`
+//! * `{"type": "publish", "topic": "a.b", "data": "..."}`, with `"encoding": "base64"`
+//!   for binary payloads and optional string `"headers"`. Only topics allowed by
+//!   [`Gateway::allow_publish`] are accepted.
+//!
+//! The server sends:
+//!
+//! * `{"type": "subscribed", "id": 1}`
+//! * `{"type": "event", "id": 1, "topic": "a.b", "data": "...", "seq": 5}`, with
+//!   `"headers"` if the event has any
+//! * `{"type": "lagged", "dropped": 10}` when events were dropped because the
+//!   connection's buffer was full
+//! * `{"type": "error", "message": "..."}`, with the `id` of the subscription if any
+
+use crate::{put_event, subscribe, Gateway, LaggingSink};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use base64::Engine;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use modular_core::modular::Modular as _;
+use modular_core::modules::{Event, PayloadTooLarge};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Request {
+    Subscribe {
+        id: u64,
+        pattern: String,
+        filter: Option<String>,
+    },
+    Unsubscribe {
+        id: u64,
+    },
+    Publish {
+        topic: String,
+        data: String,
+        #[serde(default)]
+        encoding: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+pub(crate) async fn upgrade(State(gateway): State<Arc<Gateway>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve(gateway, socket))
+}
+
+async fn serve(gateway: Arc<Gateway>, socket: WebSocket) {
+    let (mut socket_tx, mut socket_rx) = socket.split();
+    let (tx, mut rx) = mpsc::channel(gateway.ws_buffer);
+    let mut connection = Connection {
+        gateway,
+        tx,
+        dropped: Default::default(),
+        subscriptions: Default::default(),
+    };
+
+    loop {
+        let message = tokio::select! {
+            message = socket_rx.next() => match message {
+                Some(Ok(Message::Text(text))) => match connection.handle(&text) {
+                    Some(reply) => reply,
+                    None => continue,
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            Some(event) = rx.recv() => {
+                let dropped = connection.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    let lagged = json!({ "type": "lagged", "dropped": dropped });
+                    if socket_tx.send(text(&lagged)).await.is_err() {
+                        break;
+                    }
+                }
+
+                event
+            }
+        };
+
+        if socket_tx.send(text(&message)).await.is_err() {
+            break;
+        }
+    }
+
+    connection.close();
+}
+
+fn text(message: &Value) -> Message {
+    Message::Text(message.to_string().into())
+}
+
+struct Connection {
+    gateway: Arc<Gateway>,
+    tx: mpsc::Sender<Value>,
+    dropped: Arc<AtomicU64>,
+    /// Ids of the bus subscriptions by the ids the client picked.
+    subscriptions: HashMap<u64, u64>,
+}
+
+impl Connection {
+    /// Handles a client message, returns the reply if there is one.
+    fn handle(&mut self, text: &str) -> Option<Value> {
+        let request = match serde_json::from_str::<Request>(text) {
+            Ok(v) => v,
+            Err(e) => return Some(error(None, &e.to_string())),
+        };
+
+        match request {
+            Request::Subscribe {
+                id,
+                pattern,
+                filter,
+            } => {
+                if self.subscriptions.contains_key(&id) {
+                    return Some(error(Some(id), "subscription id is already in use"));
+                }
+
+                let sink = LaggingSink {
+                    tx: self.tx.clone(),
+                    dropped: self.dropped.clone(),
+                    message: move |event: &Event| {
+                        let mut message =
+                            json!({ "type": "event", "id": id, "topic": event.topic });
+                        put_event(&mut message, event);
+                        message
+                    },
+                };
+
+                match subscribe(&self.gateway.modular, &pattern, filter.as_deref(), sink) {
+                    Ok(subscription) => {
+                        self.subscriptions.insert(id, subscription);
+                        Some(json!({ "type": "subscribed", "id": id }))
+                    }
+                    Err(e) => Some(error(Some(id), &e.to_string())),
+                }
+            }
+            Request::Unsubscribe { id } => {
+                if let Some(subscription) = self.subscriptions.remove(&id) {
+                    self.gateway.modular.unsubscribe(subscription);
+                }
+
+                None
+            }
+            Request::Publish {
+                topic,
+                data,
+                encoding,
+                headers,
+            } => {
+                if !self.gateway.can_publish(&topic) {
+                    return Some(error(
+                        None,
+                        &format!("publishing to `{}` is not allowed", topic),
+                    ));
+                }
+
+                let data = match encoding.as_deref() {
+                    None => Bytes::from(data),
+                    Some("base64") => {
+                        match base64::engine::general_purpose::STANDARD.decode(data) {
+                            Ok(v) => Bytes::from(v),
+                            Err(e) => return Some(error(None, &e.to_string())),
+                        }
+                    }
+                    Some(v) => return Some(error(None, &format!("unknown encoding `{}`", v))),
+                };
+
+                let limit = self.gateway.modular.max_publish_payload(&topic);
+                if let Err(e) = PayloadTooLarge::check(data.len(), limit) {
+                    return Some(error(None, &e.to_string()));
+                }
+
+                let mut event = Event::new(&topic, data);
+                event.headers = headers;
+                self.gateway.modular.publish(event);
+
+                None
+            }
+        }
+    }
+
+    /// Removes the subscriptions of a closed connection.
+    fn close(&mut self) {
+        for (_, subscription) in self.subscriptions.drain() {
+            self.gateway.modular.unsubscribe(subscription);
+        }
+    }
+}
+
+fn error(id: Option<u64>, message: &str) -> Value {
+    let mut error = json!({ "type": "error", "message": message });
+    if let Some(id) = id {
+        error["id"] = id.into();
+    }
+
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::eventually;
+    use modular_rs::core::Modular;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn connect(gateway: Gateway) -> Client {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = gateway.into_router();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        client
+    }
+
+    async fn send(client: &mut Client, message: Value) {
+        client
+            .send(ClientMessage::text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    async fn receive(client: &mut Client) -> Value {
+        let message = client.next().await.unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_removes_the_subscription() {
+        let modular = Arc::new(Modular::default());
+        let mut client = connect(Gateway::new(modular.clone())).await;
+
+        send(
+            &mut client,
+            json!({ "type": "subscribe", "id": 1, "pattern": "a.>" }),
+        )
+        .await;
+        assert_eq!(
+            receive(&mut client).await,
+            json!({ "type": "subscribed", "id": 1 })
+        );
+
+        modular.publish(Event::new("a.b", Bytes::from("x")));
+        let event = receive(&mut client).await;
+        assert_eq!(event["id"], 1);
+        assert_eq!(event["topic"], "a.b");
+        assert_eq!(event["data"], "x");
+
+        // removed right away, not once the next event finds the subscription closed
+        send(&mut client, json!({ "type": "unsubscribe", "id": 1 })).await;
+        eventually(|| modular.subscription_patterns().is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn closing_the_connection_removes_its_subscriptions() {
+        let modular = Arc::new(Modular::default());
+        let mut client = connect(Gateway::new(modular.clone())).await;
+
+        for id in [1, 2] {
+            send(
+                &mut client,
+                json!({ "type": "subscribe", "id": id, "pattern": "a.>" }),
+            )
+            .await;
+            assert_eq!(receive(&mut client).await["type"], "subscribed");
+        }
+        assert_eq!(modular.subscription_patterns().len(), 2);
+
+        client.close(None).await.unwrap();
+        eventually(|| modular.subscription_patterns().is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn subscription_ids_are_unique_per_connection() {
+        let modular = Arc::new(Modular::default());
+        let mut client = connect(Gateway::new(modular.clone())).await;
+
+        send(
+            &mut client,
+            json!({ "type": "subscribe", "id": 1, "pattern": "a.>" }),
+        )
+        .await;
+        assert_eq!(receive(&mut client).await["type"], "subscribed");
+        send(
+            &mut client,
+            json!({ "type": "subscribe", "id": 1, "pattern": "b.>" }),
+        )
+        .await;
+
+        let error = receive(&mut client).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["id"], 1);
+        assert_eq!(modular.subscription_patterns().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn publishing_is_limited_to_allowed_topics() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        modular.subscribe("{}.b", Some(tx)).unwrap();
+
+        let mut gateway = Gateway::new(modular.clone());
+        gateway.allow_publish("a.>").unwrap();
+        let mut client = connect(gateway).await;
+
+        // neither other topics nor prefixes of the allowed ones
+        for topic in ["c.b", "a"] {
+            send(
+                &mut client,
+                json!({ "type": "publish", "topic": topic, "data": "x" }),
+            )
+            .await;
+            assert_eq!(receive(&mut client).await["type"], "error");
+        }
+
+        send(
+            &mut client,
+            json!({ "type": "publish", "topic": "a.b", "data": "eQ==", "encoding": "base64", "headers": { "k": "v" } }),
+        )
+        .await;
+        let event = rx.next().await.unwrap();
+        assert_eq!(event.topic, "a.b");
+        assert_eq!(event.data, "y");
+        assert_eq!(event.header("k"), Some("v"));
+    }
+}
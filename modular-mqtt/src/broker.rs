@@ -0,0 +1,209 @@
+//! Connections to an MQTT broker used by the [`Bridge`](crate::Bridge).
+
+use crate::topic::filter_matches;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use std::io;
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+
+pub trait Broker: Send + Sync + 'static {
+    fn subscribe(&self, filter: &str) -> BoxFuture<'_, io::Result<()>>;
+
+    fn publish(&self, topic: &str, payload: Bytes) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Waits for the next message matching one of the subscribed filters. Returns `None`
+    /// when the connection is closed for good.
+    fn recv(&self) -> BoxFuture<'_, Option<(String, Bytes)>>;
+}
+
+/// In-process stand-in for a broker, delivers each message once to every client with
+/// a matching filter.
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    clients: Arc<Mutex<Vec<Weak<MemoryClientState>>>>,
+}
+
+struct MemoryClientState {
+    filters: Mutex<Vec<String>>,
+    tx: mpsc::UnboundedSender<(String, Bytes)>,
+}
+
+pub struct MemoryClient {
+    broker: MemoryBroker,
+    state: Arc<MemoryClientState>,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<(String, Bytes)>>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn client(&self) -> MemoryClient {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let state = Arc::new(MemoryClientState {
+            filters: Default::default(),
+            tx,
+        });
+
+        self.clients.lock().push(Arc::downgrade(&state));
+
+        MemoryClient {
+            broker: self.clone(),
+            state,
+            rx: tokio::sync::Mutex::new(rx),
+        }
+    }
+
+    pub fn publish(&self, topic: &str, payload: Bytes) {
+        self.clients.lock().retain(|client| {
+            let Some(client) = client.upgrade() else {
+                return false;
+            };
+
+            let matches = client
+                .filters
+                .lock()
+                .iter()
+                .any(|filter| filter_matches(filter, topic));
+
+            if matches {
+                let _ = client.tx.send((topic.to_owned(), payload.clone()));
+            }
+
+            true
+        });
+    }
+}
+
+impl Broker for MemoryClient {
+    fn subscribe(&self, filter: &str) -> BoxFuture<'_, io::Result<()>> {
+        self.state.filters.lock().push(filter.to_owned());
+        futures::future::ok(()).boxed()
+    }
+
+    fn publish(&self, topic: &str, payload: Bytes) -> BoxFuture<'_, io::Result<()>> {
+        self.broker.publish(topic, payload);
+        futures::future::ok(()).boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<(String, Bytes)>> {
+        async move { self.rx.lock().await.recv().await }.boxed()
+    }
+}
+
+#[cfg(feature = "rumqttc")]
+pub use self::rumqttc_broker::RumqttcBroker;
+
+#[cfg(feature = "rumqttc")]
+mod rumqttc_broker {
+    use super::Broker;
+    use bytes::Bytes;
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+    use parking_lot::Mutex;
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::task::AbortHandle;
+
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    /// Broker connection over `rumqttc`. Reconnects on errors and restores the
+    /// subscriptions after each reconnect.
+    pub struct RumqttcBroker {
+        client: AsyncClient,
+        filters: Arc<Mutex<Vec<String>>>,
+        rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<(String, Bytes)>>,
+        task: AbortHandle,
+    }
+
+    impl RumqttcBroker {
+        /// Must be called within a tokio runtime.
+        pub fn new(options: MqttOptions) -> Self {
+            let (client, mut eventloop) = AsyncClient::new(options, 64);
+            let filters = Arc::new(Mutex::new(Vec::<String>::new()));
+            let (tx, rx) = mpsc::unbounded_channel();
+
+            let task = tokio::spawn({
+                let client = client.clone();
+                let filters = filters.clone();
+
+                async move {
+                    loop {
+                        match eventloop.poll().await {
+                            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                                let filters = filters.lock().clone();
+                                for filter in filters {
+                                    if let Err(e) = client.subscribe(filter, QoS::AtLeastOnce).await
+                                    {
+                                        tracing::warn!(error = %e, "failed to restore subscription");
+                                    }
+                                }
+                            }
+                            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                                if tx.send((publish.topic, publish.payload)).is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                tracing::debug!(error = %e, "mqtt connection error");
+                                tokio::time::sleep(RECONNECT_DELAY).await;
+                            }
+                        }
+                    }
+                }
+            });
+
+            Self {
+                client,
+                filters,
+                rx: tokio::sync::Mutex::new(rx),
+                task: task.abort_handle(),
+            }
+        }
+    }
+
+    impl Drop for RumqttcBroker {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    impl Broker for RumqttcBroker {
+        fn subscribe(&self, filter: &str) -> BoxFuture<'_, io::Result<()>> {
+            self.filters.lock().push(filter.to_owned());
+            let filter = filter.to_owned();
+
+            async move {
+                self.client
+                    .subscribe(filter, QoS::AtLeastOnce)
+                    .await
+                    .map_err(io::Error::other)
+            }
+            .boxed()
+        }
+
+        fn publish(&self, topic: &str, payload: Bytes) -> BoxFuture<'_, io::Result<()>> {
+            let topic = topic.to_owned();
+
+            async move {
+                self.client
+                    .publish_bytes(topic, QoS::AtLeastOnce, false, payload)
+                    .await
+                    .map_err(io::Error::other)
+            }
+            .boxed()
+        }
+
+        fn recv(&self) -> BoxFuture<'_, Option<(String, Bytes)>> {
+            async move { self.rx.lock().await.recv().await }.boxed()
+        }
+    }
+}
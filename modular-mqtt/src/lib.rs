@@ -0,0 +1,284 @@
+//! MQTT bridge for `modular-rs`.
+//!
+//! A [`Bridge`] forwards MQTT messages matching its inbound filters to the event bus and
+//! events matching its outbound patterns to the broker. Topics are translated with
+//! [`topic`], e.g. the MQTT topic `devices/1/temp` becomes `devices.1.temp`.
+
+use crate::broker::Broker;
+use crate::topic::*;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use modular_core::modular::Modular as _;
+use modular_core::modules::{Event, ModuleRequest};
+use modular_rs::core::pattern::Pattern;
+use modular_rs::core::Modular;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod broker;
+pub mod topic;
+
+/// How long a forwarded message is expected to come back, it's forgotten after that
+/// in case it never does, e.g. because an interceptor dropped it.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Bridge {
+    modular: Arc<Modular>,
+    inbound: Vec<String>,
+    outbound: Vec<Pattern>,
+}
+
+impl Bridge {
+    pub fn new(modular: Arc<Modular>) -> Self {
+        Self {
+            modular,
+            inbound: vec![],
+            outbound: vec![],
+        }
+    }
+
+    /// Forwards MQTT messages matching the topic `filter` to the event bus.
+    pub fn inbound(&mut self, filter: &str) -> Result<(), MappingError> {
+        filter_to_pattern(filter)?;
+        self.inbound.push(filter.to_owned());
+        Ok(())
+    }
+
+    /// Forwards events matching `pattern` to the broker.
+    pub fn outbound(&mut self, pattern: &str) -> Result<(), MappingError> {
+        let pattern = Pattern::parse(pattern)?;
+        pattern_to_filter(&pattern)?;
+        self.outbound.push(pattern);
+        Ok(())
+    }
+
+    /// Forwards messages until the broker connection is closed.
+    ///
+    /// Messages are not sent back to where they came from when a topic matches both an
+    /// inbound filter and an outbound pattern.
+    pub async fn run<B: Broker>(self, broker: B) -> io::Result<()> {
+        for filter in &self.inbound {
+            broker.subscribe(filter).await?;
+        }
+
+        let mut outbound = vec![];
+        for (idx, pattern) in self.outbound.iter().enumerate() {
+            let (tx, rx) = mpsc::unbounded::<Event>();
+            self.modular
+                .subscribe(pattern.as_str(), Some(tx))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+            outbound.push(rx.map(move |event| (idx, event.topic, event.data)));
+        }
+        let mut outbound = futures::stream::select_all(outbound);
+
+        // messages this bridge forwarded itself, which would otherwise come back to it
+        let mut from_broker = Echoes::new(ECHO_TIMEOUT);
+        let mut from_modular = Echoes::new(ECHO_TIMEOUT);
+
+        loop {
+            tokio::select! {
+                message = broker.recv() => {
+                    let Some((topic, payload)) = message else {
+                        return Ok(());
+                    };
+
+                    if from_modular.take((None, &topic, &payload)) {
+                        continue;
+                    }
+
+                    let topic = match topic_from_mqtt(&topic) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            tracing::debug!(error = %e, topic, "dropping mqtt message");
+                            continue;
+                        }
+                    };
+
+                    self.modular.publish(ModuleRequest::new(&topic, payload.clone()));
+
+                    for (idx, pattern) in self.outbound.iter().enumerate() {
+                        if pattern.matches(&topic) {
+                            from_broker.put((Some(idx), &topic, &payload));
+                        }
+                    }
+                }
+                Some((idx, topic, data)) = outbound.next() => {
+                    if from_broker.take((Some(idx), &topic, &data)) {
+                        continue;
+                    }
+
+                    let topic = match topic_to_mqtt(&topic) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            tracing::debug!(error = %e, topic, "dropping event");
+                            continue;
+                        }
+                    };
+
+                    // the echo is only received after this, while the loop is back at select
+                    broker.publish(&topic, data.clone()).await?;
+
+                    if self.inbound.iter().any(|filter| filter_matches(filter, &topic)) {
+                        from_modular.put((None, &topic, &data));
+                    }
+                }
+            }
+        }
+    }
+}
+
+type EchoKey = (Option<usize>, String, Bytes);
+
+/// Messages by outbound subscription, topic and payload, each with the deadline until
+/// it's expected to come back.
+struct Echoes {
+    timeout: Duration,
+    pending: HashMap<EchoKey, VecDeque<Instant>>,
+    pruned: Instant,
+}
+
+impl Echoes {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    fn put(&mut self, (idx, topic, data): (Option<usize>, &str, &Bytes)) {
+        let now = Instant::now();
+        if now.duration_since(self.pruned) >= self.timeout {
+            self.pending.retain(|_, deadlines| {
+                deadlines.retain(|v| *v > now);
+                !deadlines.is_empty()
+            });
+            self.pruned = now;
+        }
+
+        self.pending
+            .entry((idx, topic.to_owned(), data.clone()))
+            .or_default()
+            .push_back(now + self.timeout);
+    }
+
+    fn take(&mut self, (idx, topic, data): (Option<usize>, &str, &Bytes)) -> bool {
+        let key = (idx, topic.to_owned(), data.clone());
+        let Some(deadlines) = self.pending.get_mut(&key) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        while deadlines.front().is_some_and(|v| *v <= now) {
+            deadlines.pop_front();
+        }
+
+        let taken = deadlines.pop_front().is_some();
+        if deadlines.is_empty() {
+            self.pending.remove(&key);
+        }
+
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::{MemoryBroker, MemoryClient};
+
+    async fn recv(client: &MemoryClient) -> Option<(String, Bytes)> {
+        tokio::time::timeout(Duration::from_millis(100), client.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn bridge(broker: &MemoryBroker, inbound: &str, outbound: &str) -> Arc<Modular> {
+        let modular = Arc::new(Modular::default());
+        let mut bridge = Bridge::new(modular.clone());
+        bridge.inbound(inbound).unwrap();
+        bridge.outbound(outbound).unwrap();
+
+        let client = broker.client();
+        tokio::spawn(bridge.run(client));
+        // let the bridge subscribe on both sides
+        tokio::task::yield_now().await;
+
+        modular
+    }
+
+    #[tokio::test]
+    async fn messages_cross_in_both_directions() {
+        let broker = MemoryBroker::new();
+        let modular = bridge(&broker, "devices/+/temp", "commands.>").await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        modular.subscribe("devices.>", Some(tx)).unwrap();
+        let device = broker.client();
+        device.subscribe("commands/#").await.unwrap();
+
+        device
+            .publish("devices/1/temp", Bytes::from("21"))
+            .await
+            .unwrap();
+        let event = rx.next().await.unwrap();
+        assert_eq!(event.topic, "devices.1.temp");
+        assert_eq!(event.data, "21");
+
+        modular.publish(Event::new("commands.1.reset", Bytes::from("now")));
+        assert_eq!(
+            recv(&device).await,
+            Some(("commands/1/reset".to_owned(), Bytes::from("now")))
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_are_not_echoed() {
+        let broker = MemoryBroker::new();
+        let modular = bridge(&broker, "a/#", "a.>").await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        modular.subscribe("a.>", Some(tx)).unwrap();
+        let observer = broker.client();
+        observer.subscribe("a/#").await.unwrap();
+
+        // from the broker: on the bus once and not published back to the broker
+        broker.publish("a/1", Bytes::from("x"));
+        assert_eq!(recv(&observer).await.unwrap().0, "a/1");
+        assert_eq!(rx.next().await.unwrap().topic, "a.1");
+        assert_eq!(recv(&observer).await, None);
+
+        // from the bus: on the broker once and not published back to the bus
+        modular.publish(Event::new("a.2", Bytes::from("y")));
+        assert_eq!(recv(&observer).await.unwrap().0, "a/2");
+        assert_eq!(rx.next().await.unwrap().topic, "a.2");
+        assert_eq!(recv(&observer).await, None);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn echoes_expire() {
+        let data = Bytes::from("x");
+        let mut echoes = Echoes::new(Duration::from_millis(10));
+
+        echoes.put((None, "a", &data));
+        echoes.put((None, "a", &data));
+        assert!(echoes.take((None, "a", &data)));
+        assert!(!echoes.take((Some(0), "a", &data)));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!echoes.take((None, "a", &data)));
+        assert!(echoes.pending.is_empty());
+
+        // expired entries are pruned when new ones are recorded
+        echoes.put((None, "b", &data));
+        std::thread::sleep(Duration::from_millis(20));
+        echoes.put((None, "c", &data));
+        assert_eq!(echoes.pending.len(), 1);
+    }
+}
@@ -0,0 +1,139 @@
+//! Translation between MQTT topics (`/` separated, `+` and `#` wildcards) and modular
+//! topics (`.` separated, `{}` and `>` wildcards).
+
+use modular_core::error::PatternError;
+use modular_rs::core::pattern::{Pattern, Segment};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MappingError {
+    #[error("invalid MQTT topic filter `{0}`")]
+    InvalidFilter(String),
+    #[error("level `{0}` can't be represented on the other side")]
+    UnmappableLevel(String),
+    #[error("invalid pattern: {0}")]
+    InvalidPattern(#[from] PatternError),
+}
+
+/// Converts an MQTT topic filter, e.g. `a/+/#`, to a pattern, e.g. `a.{}.>`.
+pub fn filter_to_pattern(filter: &str) -> Result<Pattern, MappingError> {
+    let levels = filter.split('/').collect::<Vec<_>>();
+    let mut segments = Vec::with_capacity(levels.len());
+
+    for (idx, level) in levels.iter().enumerate() {
+        match *level {
+            "+" => segments.push("{}".to_owned()),
+            "#" if idx == levels.len() - 1 => segments.push(">".to_owned()),
+            level if level.contains(['+', '#']) => {
+                return Err(MappingError::InvalidFilter(filter.to_owned()))
+            }
+            level if level.contains('.') => {
+                return Err(MappingError::UnmappableLevel(level.to_owned()))
+            }
+            level => segments.push(Pattern::escape(level)),
+        }
+    }
+
+    Ok(Pattern::parse(segments.join("."))?)
+}
+
+/// Converts a pattern, e.g. `a.{id}.>`, to an MQTT topic filter, e.g. `a/+/#`.
+pub fn pattern_to_filter(pattern: &Pattern) -> Result<String, MappingError> {
+    let mut levels = vec![];
+    for segment in pattern.segments() {
+        match segment {
+            Segment::Arg(_) => levels.push("+"),
+            Segment::Const(v) => levels.push(level_to_mqtt(v)?),
+        }
+    }
+
+    if pattern.is_trailing_any() {
+        levels.push("#");
+    }
+
+    Ok(levels.join("/"))
+}
+
+/// Converts a modular topic to an MQTT topic.
+pub fn topic_to_mqtt(topic: &str) -> Result<String, MappingError> {
+    let levels = topic
+        .split('.')
+        .map(level_to_mqtt)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(levels.join("/"))
+}
+
+/// Converts an MQTT topic to a modular topic.
+pub fn topic_from_mqtt(topic: &str) -> Result<String, MappingError> {
+    if let Some(level) = topic.split('/').find(|i| i.contains('.')) {
+        return Err(MappingError::UnmappableLevel(level.to_owned()));
+    }
+
+    Ok(topic.replace('/', "."))
+}
+
+/// Whether the MQTT `topic` matches the topic `filter`.
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn level_to_mqtt(level: &str) -> Result<&str, MappingError> {
+    if level.contains(['/', '+', '#', '.']) {
+        return Err(MappingError::UnmappableLevel(level.to_owned()));
+    }
+
+    Ok(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_map_to_patterns() {
+        let pattern = filter_to_pattern("a/+/#").unwrap();
+        assert_eq!(pattern.as_str(), "a.{}.>");
+        assert_eq!(pattern_to_filter(&pattern).unwrap(), "a/+/#");
+    }
+
+    #[test]
+    fn pattern_characters_in_levels_are_escaped() {
+        let pattern = filter_to_pattern("a/{id}/>").unwrap();
+        assert!(pattern.matches("a.{id}.>"));
+        assert!(!pattern.matches("a.1.>"));
+        assert!(!pattern.matches("a.{id}.b"));
+    }
+
+    #[test]
+    fn unmappable_levels_are_rejected() {
+        assert_eq!(
+            filter_to_pattern("a.b/c").err(),
+            Some(MappingError::UnmappableLevel("a.b".to_owned()))
+        );
+        assert_eq!(
+            filter_to_pattern("a/b#").err(),
+            Some(MappingError::InvalidFilter("a/b#".to_owned()))
+        );
+        assert!(topic_to_mqtt("a.b/c").is_err());
+        assert!(topic_from_mqtt("a/b.c").is_err());
+    }
+
+    #[test]
+    fn topics_match_filters() {
+        assert!(filter_matches("a/+/c", "a/b/c"));
+        assert!(filter_matches("a/#", "a/b/c"));
+        assert!(!filter_matches("a/+", "a/b/c"));
+        assert!(!filter_matches("a/b", "a"));
+    }
+}
@@ -1,58 +1,70 @@
 #![allow(clippy::missing_safety_doc)]
 
+mod lifecycle;
+mod log;
 mod module;
 
+use crate::lifecycle::{CloseFlag, Lifecycle, SubscriptionHandle};
 use crate::module::NativeCModule;
 use bytes::Bytes;
 use futures::Sink;
+use modular_core::filter::Filter;
 use modular_core::modular::Modular;
 use modular_core::module::Module;
 use modular_core::modules::*;
+use modular_rs::core::acl::{Acl, Permission, ScopedModular, ScopedModule};
 use modular_sys::*;
-use parking_lot::RwLock;
 use std::ffi::{CStr, CString};
 use std::future::Future;
 use std::os::raw::c_char;
 use std::panic::catch_unwind;
 use std::pin::Pin;
-use std::ptr::{drop_in_place, null, null_mut};
+use std::ptr::{null, null_mut};
 use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
 #[macro_export]
 macro_rules! cstr_to_string {
     ($arg:expr) => {
-        unsafe { cstr_to_str!($arg).map(|i| i.to_string()) }
+        cstr_to_str!($arg).map(|i| i.to_string())
     };
 }
 
 #[macro_export]
 macro_rules! cstr_to_str {
-    ($arg:expr) => {
-        if $arg.is_null() {
+    ($arg:expr) => {{
+        let ptr: *const c_char = $arg;
+        if ptr.is_null() {
             None
         } else {
-            Some(CStr::from_ptr($arg).to_string_lossy())
+            Some(unsafe { CStr::from_ptr(ptr) }.to_string_lossy())
         }
-    };
+    }};
 }
 
 pub struct NativeModular {
     tokio_runtime: Arc<Runtime>,
-    modular: modular_rs::core::Modular,
+    modular: Arc<modular_rs::core::Modular>,
+    /// Set on handles created with [`__modular_create_scoped`], which share the runtime
+    /// and lifecycle of their root instance.
+    scope: Option<ScopedModular>,
+    lifecycle: Arc<Lifecycle>,
 }
 
 #[repr(C)]
 pub struct VTable<M> {
     create_instance: unsafe extern "system" fn(threads: u32) -> *mut M,
-    destroy_instance: unsafe extern "system" fn(modular: *mut M),
+    destroy_instance: unsafe extern "system" fn(modular: *mut M, timeout_ms: u64),
     subscribe: unsafe extern "system" fn(
         modular: &M,
         subscribe: CSubscribe,
         subscription: *mut CSubscriptionRef,
+        error: *mut CPatternError,
+        filter_error: *mut CFilterError,
     ) -> i32,
-    publish: unsafe extern "system" fn(modular: &M, topic: *const c_char, data: CBuf),
+    publish: unsafe extern "system" fn(modular: &M, event: *const CEvent) -> i32,
     register_module: unsafe extern "system" fn(
         modular: &M,
         name: *const c_char,
@@ -61,6 +73,19 @@ pub struct VTable<M> {
     ) -> i32,
     remove_module: unsafe extern "system" fn(modular: &M, name: *const c_char),
     get_module_ref: unsafe extern "system" fn(modular: &M, name: *const c_char) -> CModuleRef,
+    set_log_handler: unsafe extern "system" fn(handler: CLogHandler),
+    create_scoped: unsafe extern "system" fn(modular: &M, scope: *const CScope) -> *mut M,
+    set_max_payload: unsafe extern "system" fn(
+        modular: &M,
+        target: CPayloadTarget,
+        pattern: *const c_char,
+        limit: usize,
+    ) -> i32,
+}
+
+#[no_mangle]
+pub extern "system" fn __modular_abi_version() -> u32 {
+    ABI_VERSION
 }
 
 #[no_mangle]
@@ -73,6 +98,9 @@ pub unsafe extern "system" fn __modular_vtable() -> *const NativeModularVTable {
         register_module: __modular_register_module,
         remove_module: __modular_remove_module,
         get_module_ref: __modular_get_module_ref,
+        set_log_handler: __modular_set_log_handler,
+        create_scoped: __modular_create_scoped,
+        set_max_payload: __modular_set_max_payload,
     };
 
     VTABLE as *const VTable<_> as _
@@ -100,16 +128,152 @@ pub unsafe extern "system" fn __modular_create(threads: u32) -> *mut NativeModul
 
     Box::into_raw(Box::new(NativeModular {
         tokio_runtime: Arc::new(runtime),
-        modular,
+        modular: Arc::new(modular),
+        scope: None,
+        lifecycle: Default::default(),
+    }))
+}
+
+/// Sets the maximum payload size of `target`, `usize::MAX` removes it. Returns `0` on
+/// success, `-1` for an invalid pattern and `-2` on scoped handles.
+pub unsafe extern "system" fn __modular_set_max_payload(
+    modular: &NativeModular,
+    target: CPayloadTarget,
+    pattern: *const c_char,
+    limit: usize,
+) -> i32 {
+    if modular.scope.is_some() {
+        return -2;
+    }
+
+    let limit = (limit != usize::MAX).then_some(limit);
+    let pattern = cstr_to_str!(pattern).unwrap_or_default();
+    let result = match target {
+        CPayloadTarget::Instance => {
+            modular.modular.set_max_payload(limit);
+            Ok(())
+        }
+        CPayloadTarget::Topic => modular.modular.set_topic_max_payload(&pattern, limit),
+        CPayloadTarget::Module => modular.modular.set_module_max_payload(&pattern, limit),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Derives a handle from a root instance that can only register modules under a name
+/// prefix, subscribe to and publish on the patterns of `scope` and invoke the modules it
+/// lists.
+pub unsafe extern "system" fn __modular_create_scoped(
+    modular: &NativeModular,
+    scope: *const CScope,
+) -> *mut NativeModular {
+    assert!(!scope.is_null(), "scope must not be null");
+    if modular.scope.is_some() {
+        return null_mut();
+    }
+
+    let Some(acl) = scope_acl(&*scope) else {
+        return null_mut();
+    };
+    let identity = cstr_to_str!((*scope).identity).unwrap_or("plugin".into());
+
+    Box::into_raw(Box::new(NativeModular {
+        tokio_runtime: modular.tokio_runtime.clone(),
+        modular: modular.modular.clone(),
+        scope: Some(modular.modular.scoped(&identity, acl)),
+        lifecycle: modular.lifecycle.clone(),
     }))
 }
 
-pub unsafe extern "system" fn __modular_destroy(modular: *mut NativeModular) {
-    let _ = Box::from_raw(modular);
+unsafe fn scope_acl(scope: &CScope) -> Option<Acl> {
+    let strings = |ptr: *const *const c_char, len: usize| match ptr.is_null() {
+        true => vec![],
+        false => std::slice::from_raw_parts(ptr, len)
+            .iter()
+            .filter_map(|v| cstr_to_str!(*v))
+            .collect::<Vec<_>>(),
+    };
+
+    let mut acl = Acl::new();
+    if let Some(prefix) = cstr_to_str!(scope.register_prefix) {
+        let pattern = format!("{}.>", literal_name(&prefix)?);
+        acl = acl.allow(Permission::Register, &pattern).ok()?;
+    }
+    for pattern in strings(scope.subscribe, scope.subscribe_len) {
+        acl = acl.allow(Permission::Subscribe, &pattern).ok()?;
+    }
+    for name in strings(scope.invoke, scope.invoke_len) {
+        acl = acl.allow(Permission::Invoke, literal_name(&name)?).ok()?;
+    }
+    for pattern in strings(scope.publish, scope.publish_len) {
+        acl = acl.allow(Permission::Publish, &pattern).ok()?;
+    }
+
+    Some(acl)
+}
+
+/// Returns `name` if its `.`-separated segments are non-empty and free of wildcards and
+/// escapes, so the pattern parsed from it matches exactly the name.
+fn literal_name(name: &str) -> Option<&str> {
+    name.split('.')
+        .all(|v| !v.is_empty() && !v.contains(['{', '}', '>', '\\']))
+        .then_some(name)
+}
+
+/// Shuts the instance down: new work is rejected, subscriptions are closed and
+/// in-flight invokes get up to `timeout_ms` to finish before the rest are failed
+/// with `destroyed`.
+pub unsafe extern "system" fn __modular_destroy(modular: *mut NativeModular, timeout_ms: u64) {
+    let NativeModular {
+        tokio_runtime,
+        modular,
+        scope,
+        lifecycle,
+    } = *Box::from_raw(modular);
+
+    // scoped handles leave the instance to the root
+    if scope.is_some() {
+        drop(scope);
+        drop(modular);
+        release_runtime(tokio_runtime, Duration::ZERO);
+        return;
+    }
+
+    let timeout = Duration::from_millis(timeout_ms);
+    let shutdown = move || {
+        if !lifecycle.shutdown(timeout) {
+            tracing::warn!("shutdown timed out, cancelling in-flight invokes");
+        }
+
+        drop(modular);
+        release_runtime(tokio_runtime, timeout);
+    };
+
+    // waiting for in-flight invokes would block a worker they may be running on
+    if tokio::runtime::Handle::try_current().is_ok() {
+        std::thread::spawn(shutdown);
+    } else {
+        shutdown()
+    }
+}
+
+/// Drops a reference to the runtime, shutting it down if it was the last one.
+fn release_runtime(runtime: Arc<Runtime>, timeout: Duration) {
+    match Arc::try_unwrap(runtime) {
+        // blocking on worker threads isn't allowed from inside a runtime
+        Ok(runtime) if tokio::runtime::Handle::try_current().is_ok() => {
+            runtime.shutdown_background()
+        }
+        Ok(runtime) => runtime.shutdown_timeout(timeout),
+        Err(runtime) => drop(runtime),
+    }
 }
 
 struct Subscribe {
-    close_flag: Arc<RwLock<Option<()>>>,
+    close_flag: Arc<CloseFlag>,
     on_event: OnEvent,
     subscription: CSubscriptionRef,
     is_closed: bool,
@@ -117,14 +281,12 @@ struct Subscribe {
 
 impl Subscribe {
     #[allow(clippy::complexity)]
-    fn poll_state(
-        mut self: Pin<&mut Self>,
-    ) -> Poll<Result<(), <Subscribe as Sink<(String, Bytes)>>::Error>> {
+    fn poll_state(mut self: Pin<&mut Self>) -> Poll<Result<(), <Subscribe as Sink<Event>>::Error>> {
         if self.is_closed {
             return Poll::Ready(Err(()));
         }
 
-        if self.close_flag.read().is_none() {
+        if self.close_flag.is_closed() {
             self.is_closed = true;
             return Poll::Ready(Err(()));
         }
@@ -133,27 +295,31 @@ impl Subscribe {
     }
 }
 
-impl Sink<(String, Bytes)> for Subscribe {
+impl Sink<Event> for Subscribe {
     type Error = ();
 
     fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.poll_state()
     }
 
-    fn start_send(self: Pin<&mut Self>, item: (String, Bytes)) -> Result<(), Self::Error> {
-        let _lock = self.close_flag.read();
-        if _lock.is_none() {
+    fn start_send(self: Pin<&mut Self>, item: Event) -> Result<(), Self::Error> {
+        let event = match CEventBuf::new(item) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!(error = %e, "dropping event that can't cross the ABI");
+                return Ok(());
+            }
+        };
+        let c_event = event.as_c();
+
+        // the subscriber isn't released while it handles the event
+        if !self.close_flag.enter() {
+            unsafe { c_event.data.release() };
             return Err(());
         }
 
-        let topic = CString::new(item.0).expect("`null` in topic");
-        let data = CBuf {
-            data: item.1.as_ptr(),
-            len: item.1.len(),
-        };
-
-        drop(_lock);
-        unsafe { (self.on_event)(self.subscription, topic.as_ptr(), data) };
+        unsafe { (self.on_event)(self.subscription, &c_event) };
+        self.close_flag.leave();
 
         Ok(())
     }
@@ -171,20 +337,13 @@ unsafe impl Send for Subscribe {}
 unsafe impl Sync for Subscribe {}
 
 pub struct Subscription {
-    pub user_data: Obj,
-    pub close_flag: Weak<RwLock<Option<()>>>,
-    pub on_unsubscribe: Option<Cleanup>,
+    pub close_flag: Weak<CloseFlag>,
 }
 
 impl Drop for Subscription {
     fn drop(&mut self) {
         if let Some(v) = self.close_flag.upgrade() {
-            let mut guard = v.write();
-            if guard.take().is_some() {
-                if let Some(v) = self.on_unsubscribe {
-                    unsafe { v(self.user_data) }
-                }
-            }
+            v.close()
         }
     }
 }
@@ -193,17 +352,36 @@ pub unsafe extern "system" fn __modular_events_subscribe(
     modular: &NativeModular,
     subscribe: CSubscribe,
     subscription: *mut CSubscriptionRef,
+    error: *mut CPatternError,
+    filter_error: *mut CFilterError,
 ) -> i32 {
     let Some(topic) = cstr_to_str!(subscribe.topic) else {
-        return 1
+        return 1;
+    };
+
+    let filter = match cstr_to_str!(subscribe.filter).map(|v| Filter::parse(&v)) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(err)) => {
+            if !filter_error.is_null() {
+                (*filter_error).write(&err);
+            }
+
+            return -3;
+        }
+        None => None,
     };
 
-    let flag = Arc::new(RwLock::new(Some(())));
+    if modular.lifecycle.is_closing() {
+        return -2;
+    }
+
+    let flag = CloseFlag::new(SubscriptionHandle {
+        user_data: subscribe.user_data,
+        on_unsubscribe: subscribe.on_unsubscribe,
+    });
     let weak_flag = Arc::downgrade(&flag);
     let subscription_ptr = Box::into_raw(Box::new(Subscription {
-        user_data: subscribe.user_data,
         close_flag: weak_flag,
-        on_unsubscribe: subscribe.on_unsubscribe,
     }));
 
     let subscription_ref = CSubscriptionRef {
@@ -213,7 +391,7 @@ pub unsafe extern "system" fn __modular_events_subscribe(
     };
 
     let subscribe = Subscribe {
-        close_flag: flag,
+        close_flag: flag.clone(),
         on_event: subscribe.on_event,
         subscription: subscription_ref,
         is_closed: false,
@@ -222,17 +400,52 @@ pub unsafe extern "system" fn __modular_events_subscribe(
     let handle = modular.tokio_runtime.handle();
     let _guard = handle.enter();
 
-    match modular.modular.subscribe(&topic, Some(subscribe)) {
+    let result = match (&modular.scope, filter) {
+        (Some(scope), filter) => scope
+            .subscribe_filtered(
+                &topic,
+                move |event| filter.as_ref().is_none_or(|v| v.matches(event)),
+                subscribe,
+            )
+            .map(drop),
+        (None, Some(filter)) => modular
+            .modular
+            .subscribe_filtered(&topic, move |event| filter.matches(event), subscribe)
+            .map(drop),
+        (None, None) => modular.modular.subscribe(&topic, Some(subscribe)),
+    };
+
+    match result {
         Ok(_) => {
+            modular.lifecycle.track_subscription(flag);
             *subscription = subscription_ref;
 
             0
         }
         Err(err) => {
-            drop_in_place(subscription_ptr);
+            // the caller keeps `user_data` when subscribing fails
+            flag.forget();
+            let _ = Box::from_raw(subscription_ptr);
 
             match err {
-                SubscribeError::InvalidPattern(_) => -1,
+                SubscribeError::InvalidPattern(err) => {
+                    if !error.is_null() {
+                        *error = CPatternError::new(&topic, &err);
+                    }
+
+                    -1
+                }
+                SubscribeError::InvalidFilter(err) => {
+                    if !filter_error.is_null() {
+                        (*filter_error).write(&err);
+                    }
+
+                    -3
+                }
+                SubscribeError::ShuttingDown => -2,
+                SubscribeError::Denied => -4,
+                // queue groups aren't subscribed across the ABI
+                SubscribeError::InvalidGroupKey(_) => -1,
             }
         }
     }
@@ -240,20 +453,39 @@ pub unsafe extern "system" fn __modular_events_subscribe(
 
 pub unsafe extern "system" fn __modular_events_publish(
     modular: &NativeModular,
-    topic: *const c_char,
-    buf: CBuf,
-) {
-    let topic = cstr_to_str!(topic).expect("topic must not be null");
-    let bytes = Bytes::copy_from_slice(std::slice::from_raw_parts(buf.data, buf.len));
+    event: *const CEvent,
+) -> i32 {
+    assert!(
+        !event.is_null() && !(*event).topic.is_null(),
+        "topic must not be null"
+    );
+
+    let topic = CStr::from_ptr((*event).topic).to_string_lossy();
+    let limit = modular.modular.max_publish_payload(&topic);
+    if PayloadTooLarge::check((*event).data.len, limit).is_err() {
+        std::ptr::read(&(*event).data).release();
+        return -1;
+    }
+
+    let event = (*event).to_event();
 
-    modular.modular.publish(ModuleRequest::new(&topic, bytes))
+    if modular.lifecycle.is_closing() {
+        return 0;
+    }
+
+    match &modular.scope {
+        Some(scope) => scope.publish(event),
+        None => modular.modular.publish(event),
+    }
+
+    0
 }
 
 pub unsafe extern "system" fn __modular_events_unsubscribe(subscription: Obj) {
     let _ = catch_unwind(|| {
         let _ = Box::from_raw(subscription.0 as *mut Subscription);
     })
-    .map_err(|e| eprintln!("failed to drop subscription: {:?}", e));
+    .map_err(|e| tracing::error!("failed to drop subscription: {:?}", e));
 }
 
 pub unsafe extern "system" fn __modular_register_module(
@@ -264,20 +496,33 @@ pub unsafe extern "system" fn __modular_register_module(
 ) -> i32 {
     let name = cstr_to_str!(name).expect("module name can't be null");
 
-    let module = NativeCModule(module);
+    let module = NativeCModule {
+        module,
+        name: name.to_string(),
+        modular: Arc::downgrade(&modular.modular),
+    };
 
-    let result = if replace {
-        modular.modular.register_or_replace_module(&name, module);
+    if modular.lifecycle.is_closing() {
+        return -2;
+    }
 
-        Ok(())
-    } else {
-        modular.modular.register_module(&name, module)
+    let result = match (&modular.scope, replace) {
+        (Some(scope), true) => scope.register_or_replace_module(&name, module),
+        (Some(scope), false) => scope.register_module(&name, module),
+        (None, true) => {
+            modular.modular.register_or_replace_module(&name, module);
+
+            Ok(())
+        }
+        (None, false) => modular.modular.register_module(&name, module),
     };
 
     match result {
         Ok(_) => 0,
         Err(err) => match err {
             RegistryError::AlreadyExists => -1,
+            RegistryError::ShuttingDown => -2,
+            RegistryError::Denied => -3,
         },
     }
 }
@@ -287,7 +532,10 @@ pub unsafe extern "system" fn __modular_remove_module(
     name: *const c_char,
 ) {
     if let Some(v) = cstr_to_str!(name) {
-        modular.modular.deregister_module(&v)
+        match &modular.scope {
+            Some(scope) => scope.deregister_module(&v),
+            None => modular.modular.deregister_module(&v),
+        }
     }
 }
 
@@ -304,11 +552,27 @@ pub unsafe extern "system" fn __modular_get_module_ref(
     #[derive(Clone)]
     pub struct RtModule {
         runtime: Weak<Runtime>,
-        module: modular_rs::core::modules::Module<Bytes, Bytes>,
+        lifecycle: Arc<Lifecycle>,
+        modular: Weak<modular_rs::core::Modular>,
+        name: Arc<str>,
+        module: RtModuleInner,
+    }
+
+    #[derive(Clone)]
+    pub enum RtModuleInner {
+        Root(modular_rs::core::modules::Module<Bytes, Bytes>),
+        Scoped(ScopedModule),
     }
 
     let name = cstr_to_str!(name).expect("name can't be empty");
-    let Some(module) = modular.modular.get_module(&name) else {
+    let module = match modular.lifecycle.is_closing() {
+        true => None,
+        false => match &modular.scope {
+            Some(scope) => scope.get_module(&name).map(RtModuleInner::Scoped),
+            None => modular.modular.get_module(&name).map(RtModuleInner::Root),
+        },
+    };
+    let Some(module) = module else {
         return CModuleRef {
             ptr: Obj(null_mut()),
             vtable: C_MODULE_REF_VTABLE,
@@ -317,6 +581,9 @@ pub unsafe extern "system" fn __modular_get_module_ref(
 
     let module = RtModule {
         runtime: Arc::downgrade(&modular.tokio_runtime),
+        lifecycle: modular.lifecycle.clone(),
+        modular: Arc::downgrade(&modular.modular),
+        name: name.as_ref().into(),
         module,
     };
 
@@ -340,49 +607,75 @@ pub unsafe extern "system" fn __modular_get_module_ref(
         data: CBuf,
         callback: CCallback,
     ) {
-        let RtModule { runtime, module } = (*(ptr.0 as *mut RtModule)).clone();
+        let RtModule {
+            runtime,
+            lifecycle,
+            modular,
+            name,
+            module,
+        } = (*(ptr.0 as *mut RtModule)).clone();
+
+        let limit = modular
+            .upgrade()
+            .and_then(|modular| modular.max_invoke_payload(&name));
+        if let Err(e) = PayloadTooLarge::check(data.len, limit) {
+            data.release();
+            (callback.payload_too_large)(callback.ptr, e.size, e.limit);
+            return;
+        }
 
         let action = CStr::from_ptr(action).to_string_lossy().to_string();
-        let data = Bytes::copy_from_slice(std::slice::from_raw_parts(data.data, data.len));
-
-        if let Some(v) = runtime.upgrade() {
-            let task = ModuleTask {
-                task: Box::pin(async move {
-                    match module.invoke(ModuleRequest::new(&action, data)).await {
-                        Ok(response) => {
-                            if let Ok(response) = response.await {
-                                let buf = CBuf {
-                                    data: response.data.as_ptr(),
-                                    len: response.data.len(),
-                                };
-                                (callback.success)(callback.ptr, buf)
-                            }
-                        }
-                        Err(error) => match error {
-                            ModuleError::UnknownMethod => (callback.unknown_method)(callback.ptr),
-                            ModuleError::Custom(v) => {
-                                let name = v.name.map(|v| CString::new(v).unwrap());
-                                let message = v.message.map(|v| CString::new(v).unwrap());
-
-                                let module_error = CModuleError {
-                                    code: v.code,
-                                    name: name.as_ref().map(|i| i.as_ptr()).unwrap_or(null()),
-                                    message: message.as_ref().map(|i| i.as_ptr()).unwrap_or(null()),
-                                };
-
-                                (callback.error)(callback.ptr, module_error)
-                            }
-                            ModuleError::Destroyed => (callback.destroyed)(callback.ptr),
-                        },
-                    };
-                }),
-                on_drop: Some(Box::new(move || (callback.destroyed)(callback.ptr))),
-            };
+        let data = data.into_bytes();
 
-            v.spawn(task);
-        } else {
-            (callback.destroyed)(callback.ptr)
-        }
+        let (Some(v), Some(in_flight)) = (runtime.upgrade(), lifecycle.begin()) else {
+            (callback.destroyed)(callback.ptr);
+            return;
+        };
+
+        let task = ModuleTask {
+            task: Box::pin(async move {
+                let _in_flight = in_flight;
+                let request = ModuleRequest::new(&action, data);
+                let invoke = match &module {
+                    RtModuleInner::Root(module) => module.invoke(request),
+                    RtModuleInner::Scoped(module) => module.invoke(request),
+                };
+                let result = match invoke.await {
+                    Ok(response) => response.await,
+                    Err(error) => Err(error),
+                };
+
+                match result {
+                    Ok(response) => {
+                        let buf = CBuf::from_bytes(&response.data);
+                        (callback.success)(callback.ptr, buf)
+                    }
+                    Err(ModuleError::UnknownMethod) => (callback.unknown_method)(callback.ptr),
+                    Err(ModuleError::Custom(v)) => {
+                        let name = v.name.map(|v| CString::new(v).unwrap());
+                        let message = v.message.map(|v| CString::new(v).unwrap());
+
+                        let module_error = CModuleError {
+                            code: v.code,
+                            name: name.as_ref().map(|i| i.as_ptr()).unwrap_or(null()),
+                            message: message.as_ref().map(|i| i.as_ptr()).unwrap_or(null()),
+                        };
+
+                        (callback.error)(callback.ptr, module_error)
+                    }
+                    Err(ModuleError::Destroyed) => (callback.destroyed)(callback.ptr),
+                    Err(ModuleError::Denied) => (callback.denied)(callback.ptr),
+                    Err(ModuleError::Overloaded) => (callback.overloaded)(callback.ptr),
+                    Err(ModuleError::PayloadTooLarge(e)) => {
+                        (callback.payload_too_large)(callback.ptr, e.size, e.limit)
+                    }
+                    Err(ModuleError::NotFound) => (callback.not_found)(callback.ptr),
+                };
+            }),
+            on_drop: Some(Box::new(move || (callback.destroyed)(callback.ptr))),
+        };
+
+        v.spawn(task);
     }
 
     CModuleRef {
@@ -391,6 +684,10 @@ pub unsafe extern "system" fn __modular_get_module_ref(
     }
 }
 
+pub unsafe extern "system" fn __modular_set_log_handler(handler: CLogHandler) {
+    log::set_handler(handler)
+}
+
 struct ModuleTask<D, F>
 where
     F: Future<Output = ()> + Send + Unpin,
@@ -420,11 +717,345 @@ where
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.on_drop.take();
+        let result = Pin::new(&mut self.task).poll(cx);
 
-        Pin::new(&mut self.task).poll(cx)
+        // a task dropped before completing still has to answer its callback
+        if result.is_ready() {
+            self.on_drop.take();
+        }
+
+        result
     }
 }
 
 #[test]
 fn a() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    unsafe fn publish(modular: &NativeModular, topic: &str) {
+        let topic = CString::new(topic).unwrap();
+        let event = CEvent {
+            topic: topic.as_ptr(),
+            data: CBuf::borrowed(b"data"),
+            headers: null(),
+            headers_len: 0,
+            seq: 0,
+        };
+
+        assert_eq!(__modular_events_publish(modular, &event), 0);
+    }
+
+    #[test]
+    fn callback_can_unsubscribe_itself() {
+        static EVENTS: AtomicUsize = AtomicUsize::new(0);
+        static UNSUBSCRIBED: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "system" fn on_event(subscription: CSubscriptionRef, _: *const CEvent) {
+            EVENTS.fetch_add(1, Ordering::SeqCst);
+            (subscription.unsubscribe)(subscription.subscription_ref);
+        }
+
+        unsafe extern "system" fn on_unsubscribe(_: Obj) {
+            UNSUBSCRIBED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe {
+            let modular = __modular_create(1);
+            let topic = CString::new("a.b").unwrap();
+            let mut subscription = CSubscriptionRef::default();
+            let result = __modular_events_subscribe(
+                &*modular,
+                CSubscribe {
+                    user_data: Obj(null_mut()),
+                    topic: topic.as_ptr(),
+                    filter: null(),
+                    on_event,
+                    on_unsubscribe: Some(on_unsubscribe),
+                },
+                &mut subscription,
+                null_mut(),
+                null_mut(),
+            );
+            assert_eq!(result, 0);
+
+            publish(&*modular, "a.b");
+            wait_for(|| UNSUBSCRIBED.load(Ordering::SeqCst) == 1);
+
+            publish(&*modular, "a.b");
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(EVENTS.load(Ordering::SeqCst), 1);
+
+            __modular_destroy(modular, 1000);
+            assert_eq!(UNSUBSCRIBED.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn events_carry_headers_across_the_abi() {
+        static EVENTS: parking_lot::Mutex<Vec<Event>> = parking_lot::Mutex::new(vec![]);
+
+        unsafe extern "system" fn on_event(_: CSubscriptionRef, event: *const CEvent) {
+            EVENTS.lock().push((*event).to_event());
+        }
+
+        unsafe {
+            let modular = __modular_create(1);
+            let topic = CString::new("a.>").unwrap();
+            let result = __modular_events_subscribe(
+                &*modular,
+                CSubscribe {
+                    user_data: Obj(null_mut()),
+                    topic: topic.as_ptr(),
+                    filter: null(),
+                    on_event,
+                    on_unsubscribe: None,
+                },
+                &mut CSubscriptionRef::default(),
+                null_mut(),
+                null_mut(),
+            );
+            assert_eq!(result, 0);
+
+            let headers = [CHeader {
+                name: c"trace".as_ptr(),
+                value: c"1".as_ptr(),
+            }];
+            let event = CEvent {
+                topic: c"a.b".as_ptr(),
+                data: CBuf::borrowed(b"data"),
+                headers: headers.as_ptr(),
+                headers_len: headers.len(),
+                seq: 0,
+            };
+            assert_eq!(__modular_events_publish(&*modular, &event), 0);
+            publish(&*modular, "a.c");
+            wait_for(|| EVENTS.lock().len() == 2);
+
+            let events = EVENTS.lock();
+            assert_eq!(events[0].topic, "a.b");
+            assert_eq!(events[0].data, "data");
+            assert_eq!(events[0].header("trace"), Some("1"));
+            assert!(events[1].headers.is_empty());
+            assert!(events[1].seq > events[0].seq);
+            drop(events);
+
+            __modular_destroy(modular, 1000);
+        }
+    }
+
+    #[test]
+    fn events_with_nul_bytes_are_skipped() {
+        static TOPICS: parking_lot::Mutex<Vec<String>> = parking_lot::Mutex::new(vec![]);
+
+        unsafe extern "system" fn on_event(_: CSubscriptionRef, event: *const CEvent) {
+            TOPICS.lock().push((*event).to_event().topic);
+        }
+
+        unsafe {
+            let modular = __modular_create(1);
+            let result = __modular_events_subscribe(
+                &*modular,
+                CSubscribe {
+                    user_data: Obj(null_mut()),
+                    topic: c"a.>".as_ptr(),
+                    filter: null(),
+                    on_event,
+                    on_unsubscribe: None,
+                },
+                &mut CSubscriptionRef::default(),
+                null_mut(),
+                null_mut(),
+            );
+            assert_eq!(result, 0);
+
+            let event = Event::new("a.b", Bytes::new()).with_header("k", "x\0y");
+            (*modular).modular.publish(event);
+            publish(&*modular, "a.c");
+            wait_for(|| !TOPICS.lock().is_empty());
+            assert_eq!(*TOPICS.lock(), ["a.c"]);
+
+            __modular_destroy(modular, 1000);
+        }
+    }
+
+    unsafe extern "system" fn ignore_event(_: CSubscriptionRef, _: *const CEvent) {}
+
+    #[test]
+    fn subscribe_errors_are_described() {
+        unsafe {
+            let modular = __modular_create(1);
+            let subscribe = |topic: &CStr, filter: Option<&CStr>| {
+                let mut error = CPatternError::default();
+                let mut message = [0 as c_char; 64];
+                let mut filter_error = CFilterError {
+                    position: 0,
+                    message: message.as_mut_ptr(),
+                    message_len: message.len(),
+                };
+
+                let result = __modular_events_subscribe(
+                    &*modular,
+                    CSubscribe {
+                        user_data: Obj(null_mut()),
+                        topic: topic.as_ptr(),
+                        filter: filter.map_or(null(), |v| v.as_ptr()),
+                        on_event: ignore_event,
+                        on_unsubscribe: None,
+                    },
+                    &mut CSubscriptionRef::default(),
+                    &mut error,
+                    &mut filter_error,
+                );
+
+                (result, error, filter_error.to_filter_error())
+            };
+
+            let (result, error, _) = subscribe(c"a.{b.c", None);
+            assert_eq!(result, -1);
+            assert_eq!(error.position, 4);
+            assert_eq!(error.expected, CPatternExpected::ClosingBrace);
+            assert_eq!((error.segment_start, error.segment_len), (2, 2));
+
+            let (result, _, filter_error) = subscribe(c"a.b", Some(c"data == \"x"));
+            assert_eq!(result, -3);
+            assert_eq!(filter_error.position, 10);
+            assert_eq!(filter_error.message, "unterminated string");
+
+            __modular_destroy(modular, 1000);
+        }
+    }
+
+    unsafe extern "system" fn ignore_invoke(_: Obj, _: *const c_char, _: CBuf, _: CCallback) {}
+
+    unsafe extern "system" fn ignore_drop(_: Obj) {}
+
+    unsafe fn create_scoped(modular: &NativeModular, prefix: &CStr) -> *mut NativeModular {
+        let scope = CScope {
+            identity: null(),
+            register_prefix: prefix.as_ptr(),
+            subscribe: null(),
+            subscribe_len: 0,
+            invoke: null(),
+            invoke_len: 0,
+            publish: null(),
+            publish_len: 0,
+        };
+        __modular_create_scoped(modular, &scope)
+    }
+
+    #[test]
+    fn scoped_handles_register_under_their_prefix() {
+        unsafe {
+            let modular = __modular_create(1);
+            let scoped = create_scoped(&*modular, c"plugins.foo");
+            assert!(!scoped.is_null());
+
+            let register = |name: &CStr| {
+                let module = CModule {
+                    ptr: Obj(null_mut()),
+                    on_invoke: ignore_invoke,
+                    on_drop: ignore_drop,
+                };
+                __modular_register_module(&*scoped, name.as_ptr(), module, false)
+            };
+            assert_eq!(register(c"plugins"), -3);
+            assert_eq!(register(c"plugins.foo"), -3);
+            assert_eq!(register(c"plugins.foobar"), -3);
+            assert_eq!(register(c"plugins.foo.bar"), 0);
+
+            for prefix in [
+                c"plugins.>",
+                c"plugins.{}",
+                c"plugins..foo",
+                c"plugins\\.foo",
+                c"",
+            ] {
+                assert!(create_scoped(&*modular, prefix).is_null());
+            }
+
+            __modular_destroy(scoped, 1000);
+            __modular_destroy(modular, 1000);
+        }
+    }
+
+    #[test]
+    fn payload_limits_are_set_on_root_handles() {
+        unsafe {
+            let modular = __modular_create(1);
+            let scoped = create_scoped(&*modular, c"plugins");
+
+            let limit = |modular: &NativeModular, target, pattern: &CStr, limit| {
+                __modular_set_max_payload(modular, target, pattern.as_ptr(), limit)
+            };
+            assert_eq!(limit(&*modular, CPayloadTarget::Topic, c"a.>", 4), 0);
+            assert_eq!(limit(&*modular, CPayloadTarget::Module, c"a.{", 4), -1);
+            assert_eq!(limit(&*scoped, CPayloadTarget::Instance, c"", 4), -2);
+
+            let publish = |topic: &CStr, data: &[u8]| {
+                let event = CEvent {
+                    topic: topic.as_ptr(),
+                    data: CBuf::borrowed(data),
+                    headers: null(),
+                    headers_len: 0,
+                    seq: 0,
+                };
+                __modular_events_publish(&*modular, &event)
+            };
+            assert_eq!(publish(c"a.b", b"data"), 0);
+            assert_eq!(publish(c"a.b", b"data!"), -1);
+            assert_eq!(publish(c"b.b", b"data!"), 0);
+
+            assert_eq!(
+                limit(&*modular, CPayloadTarget::Topic, c"a.>", usize::MAX),
+                0
+            );
+            assert_eq!(publish(c"a.b", b"data!"), 0);
+
+            __modular_destroy(scoped, 1000);
+            __modular_destroy(modular, 1000);
+        }
+    }
+
+    #[test]
+    fn destroy_inside_a_runtime() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            unsafe {
+                let modular = __modular_create(1);
+                let scope = CScope {
+                    identity: null(),
+                    register_prefix: null(),
+                    subscribe: null(),
+                    subscribe_len: 0,
+                    invoke: null(),
+                    invoke_len: 0,
+                    publish: null(),
+                    publish_len: 0,
+                };
+                let scoped = __modular_create_scoped(&*modular, &scope);
+                assert!(!scoped.is_null());
+
+                // neither blocks this worker nor drops the last runtime reference in it
+                __modular_destroy(modular, 1000);
+                __modular_destroy(scoped, 1000);
+            }
+        });
+    }
+}
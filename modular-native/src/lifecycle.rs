@@ -0,0 +1,176 @@
+use modular_sys::{Cleanup, Obj};
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct SubscriptionHandle {
+    pub user_data: Obj,
+    pub on_unsubscribe: Option<Cleanup>,
+}
+
+impl SubscriptionHandle {
+    pub fn close(self) {
+        if let Some(v) = self.on_unsubscribe {
+            unsafe { v(self.user_data) }
+        }
+    }
+}
+
+const CLOSED: usize = 1 << (usize::BITS - 1);
+
+/// Shared by a subscription's sink, its `CSubscriptionRef` and the lifecycle.
+///
+/// The handle is closed once the subscription is closed and no callback is running
+/// anymore, so a callback can unsubscribe itself without waiting on itself.
+pub struct CloseFlag {
+    /// [`CLOSED`] and the number of running callbacks.
+    state: AtomicUsize,
+    handle: Mutex<Option<SubscriptionHandle>>,
+}
+
+impl CloseFlag {
+    pub fn new(handle: SubscriptionHandle) -> Arc<Self> {
+        Arc::new(Self {
+            state: AtomicUsize::new(0),
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.load(Ordering::Acquire) & CLOSED != 0
+    }
+
+    /// Marks a callback as running. Returns `false` if the subscription is closed.
+    pub fn enter(&self) -> bool {
+        if self.state.fetch_add(1, Ordering::AcqRel) & CLOSED != 0 {
+            self.leave();
+            return false;
+        }
+
+        true
+    }
+
+    pub fn leave(&self) {
+        if self.state.fetch_sub(1, Ordering::AcqRel) == CLOSED | 1 {
+            self.release();
+        }
+    }
+
+    pub fn close(&self) {
+        if self.state.fetch_or(CLOSED, Ordering::AcqRel) == 0 {
+            self.release();
+        }
+    }
+
+    /// Closes without calling back, for subscriptions that never started.
+    pub fn forget(&self) {
+        self.state.fetch_or(CLOSED, Ordering::AcqRel);
+        self.handle.lock().take();
+    }
+
+    fn release(&self) {
+        let handle = self.handle.lock().take();
+        if let Some(handle) = handle {
+            handle.close()
+        }
+    }
+}
+
+/// Tracks the work of a `NativeModular` instance so it can be shut down in order.
+#[derive(Default)]
+pub(crate) struct Lifecycle {
+    closing: AtomicBool,
+    in_flight: Mutex<usize>,
+    idle: Condvar,
+    subscriptions: Mutex<Vec<Arc<CloseFlag>>>,
+}
+
+impl Lifecycle {
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Acquire)
+    }
+
+    /// Registers an operation that has to finish before the instance is freed.
+    pub fn begin(self: &Arc<Self>) -> Option<InFlight> {
+        let mut in_flight = self.in_flight.lock();
+        if self.is_closing() {
+            return None;
+        }
+
+        *in_flight += 1;
+
+        Some(InFlight(self.clone()))
+    }
+
+    pub fn track_subscription(&self, flag: Arc<CloseFlag>) {
+        let mut subscriptions = self.subscriptions.lock();
+        subscriptions.retain(|i| !i.is_closed());
+        subscriptions.push(flag);
+    }
+
+    /// Stops accepting work, closes all subscriptions and waits up to `timeout`
+    /// for in-flight operations. Returns `false` if some of them are still running.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        {
+            let _guard = self.in_flight.lock();
+            self.closing.store(true, Ordering::Release);
+        }
+
+        let subscriptions = std::mem::take(&mut *self.subscriptions.lock());
+        for flag in subscriptions {
+            flag.close();
+        }
+
+        let mut in_flight = self.in_flight.lock();
+        !self
+            .idle
+            .wait_while_for(&mut in_flight, |v| *v > 0, timeout)
+            .timed_out()
+    }
+}
+
+pub(crate) struct InFlight(Arc<Lifecycle>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.0.in_flight.lock();
+        *in_flight -= 1;
+
+        if *in_flight == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::null_mut;
+
+    static CLOSED_HANDLES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "system" fn on_unsubscribe(_: Obj) {
+        CLOSED_HANDLES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn close_waits_for_running_callbacks() {
+        let flag = CloseFlag::new(SubscriptionHandle {
+            user_data: Obj(null_mut()),
+            on_unsubscribe: Some(on_unsubscribe),
+        });
+
+        assert!(flag.enter());
+        flag.close();
+        assert!(flag.is_closed());
+        assert!(!flag.enter());
+        assert_eq!(CLOSED_HANDLES.load(Ordering::SeqCst), 0);
+
+        flag.leave();
+        assert_eq!(CLOSED_HANDLES.load(Ordering::SeqCst), 1);
+
+        flag.close();
+        assert_eq!(CLOSED_HANDLES.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,262 @@
+use modular_sys::{CLogField, CLogHandler, CLogLevel, CLogRecord};
+use parking_lot::RwLock;
+use std::ffi::CString;
+use std::fmt::Debug;
+use std::ptr::null;
+use std::sync::{Arc, Once};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Cloned out before calling the host, so it can log or replace the handler meanwhile.
+static HANDLER: RwLock<Option<Arc<LogHandler>>> = RwLock::new(None);
+
+struct LogHandler(CLogHandler);
+
+unsafe impl Send for LogHandler {}
+unsafe impl Sync for LogHandler {}
+
+impl Drop for LogHandler {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.0.on_drop {
+            unsafe { on_drop(self.0.user_data) }
+        }
+    }
+}
+
+pub(crate) fn set_handler(handler: CLogHandler) {
+    static INIT: Once = Once::new();
+
+    let previous = HANDLER.write().replace(Arc::new(LogHandler(handler)));
+    drop(previous);
+
+    INIT.call_once(|| {
+        let subscriber = tracing_subscriber::registry().with(HostLayer);
+        let _ = tracing::subscriber::set_global_default(subscriber);
+    });
+}
+
+fn level(level: &Level) -> CLogLevel {
+    match *level {
+        Level::ERROR => CLogLevel::Error,
+        Level::WARN => CLogLevel::Warn,
+        Level::INFO => CLogLevel::Info,
+        Level::DEBUG => CLogLevel::Debug,
+        Level::TRACE => CLogLevel::Trace,
+    }
+}
+
+#[derive(Default)]
+struct FieldsVisitor {
+    message: Option<String>,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldsVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_owned());
+        } else {
+            self.fields
+                .push((field.name().to_owned(), value.to_owned()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        } else {
+            self.fields
+                .push((field.name().to_owned(), format!("{:?}", value)));
+        }
+    }
+}
+
+struct SpanFields(Vec<(String, String)>);
+
+struct HostLayer;
+
+impl<S> Layer<S> for HostLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // the handler and the host's level can change at any time, so nothing is cached
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        let Some(handler) = HANDLER.read().clone() else {
+            return false;
+        };
+
+        let max_level = unsafe { (handler.0.max_level)(handler.0.user_data) };
+        level(metadata.level()) as u8 <= max_level as u8
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldsVisitor::default();
+        attrs.record(&mut visitor);
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldsVisitor::default();
+        values.record(&mut visitor);
+
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.extend(visitor.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(handler) = HANDLER.read().clone() else {
+            return;
+        };
+
+        let mut visitor = FieldsVisitor::default();
+        event.record(&mut visitor);
+
+        let mut spans = vec![];
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                spans.push(span.name());
+
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    visitor.fields.extend(
+                        fields
+                            .0
+                            .iter()
+                            .map(|(k, v)| (format!("{}.{}", span.name(), k), v.clone())),
+                    );
+                }
+            }
+        }
+
+        let target = to_cstring(event.metadata().target());
+        let message = visitor.message.as_deref().map(to_cstring);
+        let spans = to_cstring(&spans.join(":"));
+        let fields = visitor
+            .fields
+            .iter()
+            .map(|(k, v)| (to_cstring(k), to_cstring(v)))
+            .collect::<Vec<_>>();
+        let c_fields = fields
+            .iter()
+            .map(|(name, value)| CLogField {
+                name: name.as_ptr(),
+                value: value.as_ptr(),
+            })
+            .collect::<Vec<_>>();
+
+        let record = CLogRecord {
+            level: level(event.metadata().level()),
+            target: target.as_ptr(),
+            message: message.as_ref().map(|i| i.as_ptr()).unwrap_or(null()),
+            spans: spans.as_ptr(),
+            fields: c_fields.as_ptr(),
+            fields_len: c_fields.len(),
+        };
+
+        unsafe { (handler.0.on_log)(handler.0.user_data, &record) }
+    }
+}
+
+fn to_cstring(str: &str) -> CString {
+    CString::new(str.replace('\0', "")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modular_sys::Obj;
+    use std::ptr::null_mut;
+    use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+    static MAX_LEVEL: AtomicU8 = AtomicU8::new(CLogLevel::Info as u8);
+    static RECORDS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "system" fn max_level(_: Obj) -> CLogLevel {
+        match MAX_LEVEL.load(Ordering::SeqCst) {
+            0 => CLogLevel::Off,
+            1 => CLogLevel::Error,
+            3 => CLogLevel::Info,
+            _ => CLogLevel::Trace,
+        }
+    }
+
+    unsafe extern "system" fn on_log(_: Obj, _: *const CLogRecord) {
+        RECORDS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    /// The handler is global, tests installing one can't run in parallel.
+    static SERIAL: parking_lot::Mutex<()> = parking_lot::Mutex::new(());
+
+    /// Replaces itself with the counting handler, like a host reconfiguring its logging
+    /// from the callback.
+    unsafe extern "system" fn on_log_replacing(_: Obj, _: *const CLogRecord) {
+        set_handler(handler(None));
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 0);
+    }
+
+    unsafe extern "system" fn on_drop(_: Obj) {
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn handler(on_drop: Option<unsafe extern "system" fn(Obj)>) -> CLogHandler {
+        CLogHandler {
+            user_data: Obj(null_mut()),
+            max_level,
+            on_log,
+            on_drop,
+        }
+    }
+
+    #[test]
+    fn level_is_queried_per_record() {
+        let _serial = SERIAL.lock();
+        set_handler(handler(None));
+
+        let emit = || {
+            tracing::error!("error");
+            tracing::info!("info");
+            tracing::trace!("trace");
+        };
+
+        emit();
+        assert_eq!(RECORDS.swap(0, Ordering::SeqCst), 2);
+
+        MAX_LEVEL.store(CLogLevel::Trace as u8, Ordering::SeqCst);
+        emit();
+        assert_eq!(RECORDS.swap(0, Ordering::SeqCst), 3);
+
+        MAX_LEVEL.store(CLogLevel::Off as u8, Ordering::SeqCst);
+        emit();
+        assert_eq!(RECORDS.swap(0, Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn the_host_can_replace_the_handler_while_called() {
+        let _serial = SERIAL.lock();
+        MAX_LEVEL.store(CLogLevel::Info as u8, Ordering::SeqCst);
+        set_handler(CLogHandler {
+            on_log: on_log_replacing,
+            ..handler(Some(on_drop))
+        });
+
+        tracing::info!("info");
+        // dropped once the call returned
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::cstr_to_string;
 use crate::*;
 use bytes::Bytes;
-use modular_core::error::{CustomModuleError, ModuleError};
+use modular_core::error::{CustomModuleError, ModuleError, PayloadTooLarge};
 use modular_sys::*;
 use parking_lot::RwLock;
 use std::ffi::CString;
@@ -10,12 +10,16 @@ use std::pin::Pin;
 use std::sync::{Arc, Weak};
 use std::task::{Context, Poll, Waker};
 
-#[repr(transparent)]
-pub struct NativeCModule(pub CModule);
+pub struct NativeCModule {
+    pub module: CModule,
+    pub name: String,
+    /// Instance the module is registered on, to check responses against its payload limit.
+    pub modular: Weak<modular_rs::core::Modular>,
+}
 
 impl Drop for NativeCModule {
     fn drop(&mut self) {
-        unsafe { (self.0.on_drop)(self.0.ptr) }
+        unsafe { (self.module.on_drop)(self.module.ptr) }
     }
 }
 
@@ -29,28 +33,34 @@ impl tower::Service<ModuleRequest> for NativeCModule {
     }
 
     fn call(&mut self, req: ModuleRequest) -> Self::Future {
-        let user_data = self.0.ptr;
-        let f = self.0.on_invoke;
+        let user_data = self.module.ptr;
+        let f = self.module.on_invoke;
+        let max_payload = self
+            .modular
+            .upgrade()
+            .and_then(|modular| modular.max_invoke_payload(&self.name));
 
         let f = Box::new(move |state: CModuleFutureState| {
             let method = req.action;
             let action = CString::new(method).unwrap();
 
-            let buf = CBuf {
-                data: req.body.as_ptr(),
-                len: req.body.len(),
-            };
+            let buf = CBuf::from_bytes(&req.body);
 
             let state = Box::into_raw(Box::new(state));
 
             unsafe extern "system" fn on_success(ptr: Obj, data: CBuf) {
                 let state = Box::from_raw(ptr.0 as *mut CModuleFutureState);
+                // checked before copying, responses come from plugins
+                let result = match PayloadTooLarge::check(data.len, state.max_payload) {
+                    Ok(()) => Ok(data.into_bytes()),
+                    Err(e) => {
+                        data.release();
+                        Err(ModuleError::PayloadTooLarge(e))
+                    }
+                };
 
                 if let Some(v) = state.data.upgrade() {
-                    let slice = std::slice::from_raw_parts(data.data, data.len);
-                    let data = Bytes::copy_from_slice(slice);
-
-                    *v.write() = Some(Ok(data));
+                    *v.write() = Some(result);
                     state.waker.wake();
                 }
             }
@@ -88,12 +98,53 @@ impl tower::Service<ModuleRequest> for NativeCModule {
                 }
             }
 
+            unsafe extern "system" fn on_denied(ptr: Obj) {
+                let state = Box::from_raw(ptr.0 as *mut CModuleFutureState);
+
+                if let Some(v) = state.data.upgrade() {
+                    *v.write() = Some(Err(ModuleError::Denied));
+                    state.waker.wake();
+                }
+            }
+
+            unsafe extern "system" fn on_overloaded(ptr: Obj) {
+                let state = Box::from_raw(ptr.0 as *mut CModuleFutureState);
+
+                if let Some(v) = state.data.upgrade() {
+                    *v.write() = Some(Err(ModuleError::Overloaded));
+                    state.waker.wake();
+                }
+            }
+
+            unsafe extern "system" fn on_payload_too_large(ptr: Obj, size: usize, limit: usize) {
+                let state = Box::from_raw(ptr.0 as *mut CModuleFutureState);
+
+                if let Some(v) = state.data.upgrade() {
+                    let err = PayloadTooLarge { size, limit };
+                    *v.write() = Some(Err(ModuleError::PayloadTooLarge(err)));
+                    state.waker.wake();
+                }
+            }
+
+            unsafe extern "system" fn on_not_found(ptr: Obj) {
+                let state = Box::from_raw(ptr.0 as *mut CModuleFutureState);
+
+                if let Some(v) = state.data.upgrade() {
+                    *v.write() = Some(Err(ModuleError::NotFound));
+                    state.waker.wake();
+                }
+            }
+
             let c_callback = CCallback {
                 ptr: Obj(state.cast()),
                 success: on_success,
                 error: on_error,
                 unknown_method: on_unknown_method,
                 destroyed: on_destroyed,
+                denied: on_denied,
+                overloaded: on_overloaded,
+                payload_too_large: on_payload_too_large,
+                not_found: on_not_found,
             };
 
             unsafe { f(user_data, action.as_ptr(), buf, c_callback) }
@@ -101,6 +152,7 @@ impl tower::Service<ModuleRequest> for NativeCModule {
 
         CModuleFuture {
             f: Some(f),
+            max_payload,
             data: Default::default(),
         }
     }
@@ -108,6 +160,7 @@ impl tower::Service<ModuleRequest> for NativeCModule {
 
 pub struct CModuleFuture {
     f: Option<Box<dyn FnOnce(CModuleFutureState)>>,
+    max_payload: Option<usize>,
     data: Arc<RwLock<Option<Result<Bytes, ModuleError>>>>,
 }
 
@@ -121,6 +174,7 @@ impl Future for CModuleFuture {
         if let Some(f) = self.f.take() {
             let state = CModuleFutureState {
                 waker: cx.waker().clone(),
+                max_payload: self.max_payload,
                 data: Arc::downgrade(&self.data),
             };
 
@@ -137,5 +191,6 @@ impl Future for CModuleFuture {
 
 struct CModuleFutureState {
     waker: Waker,
+    max_payload: Option<usize>,
     data: Weak<RwLock<Option<Result<Bytes, ModuleError>>>>,
 }
@@ -0,0 +1,102 @@
+use modular_core::error::{
+    CustomModuleError, ModuleError as NativeModuleError, PayloadTooLarge, SubscribeError,
+};
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+
+pyo3::create_exception!(
+    modular_py,
+    ModuleError,
+    PyException,
+    "Error returned by a module, `args` are `(code, name, message)`."
+);
+pyo3::create_exception!(modular_py, UnknownMethodError, ModuleError);
+pyo3::create_exception!(modular_py, ModuleDestroyedError, ModuleError);
+pyo3::create_exception!(modular_py, PermissionDeniedError, ModuleError);
+pyo3::create_exception!(modular_py, OverloadedError, ModuleError);
+pyo3::create_exception!(modular_py, NotFoundError, ModuleError);
+pyo3::create_exception!(
+    modular_py,
+    PayloadTooLargeError,
+    ModuleError,
+    "Payload over the size limit, `args` are `(size, limit)`."
+);
+
+pub fn to_py_err(err: NativeModuleError) -> PyErr {
+    match err {
+        NativeModuleError::UnknownMethod => UnknownMethodError::new_err(()),
+        NativeModuleError::Custom(err) => ModuleError::new_err((err.code, err.name, err.message)),
+        NativeModuleError::Destroyed => ModuleDestroyedError::new_err(()),
+        NativeModuleError::Denied => PermissionDeniedError::new_err(()),
+        NativeModuleError::Overloaded => OverloadedError::new_err(()),
+        NativeModuleError::PayloadTooLarge(e) => PayloadTooLargeError::new_err((e.size, e.limit)),
+        NativeModuleError::NotFound => NotFoundError::new_err(()),
+    }
+}
+
+/// Maps an exception raised by a Python module back to a [`NativeModuleError`].
+///
+/// Exceptions that aren't a `ModuleError` are reported with code `-1`, the exception
+/// type as the name and its string representation as the message.
+pub fn from_py_err(py: Python, err: PyErr) -> NativeModuleError {
+    if err.is_instance_of::<UnknownMethodError>(py) {
+        return NativeModuleError::UnknownMethod;
+    }
+
+    if err.is_instance_of::<ModuleDestroyedError>(py) {
+        return NativeModuleError::Destroyed;
+    }
+
+    if err.is_instance_of::<PermissionDeniedError>(py) {
+        return NativeModuleError::Denied;
+    }
+
+    if err.is_instance_of::<OverloadedError>(py) {
+        return NativeModuleError::Overloaded;
+    }
+
+    if err.is_instance_of::<NotFoundError>(py) {
+        return NativeModuleError::NotFound;
+    }
+
+    if err.is_instance_of::<PayloadTooLargeError>(py) {
+        if let Ok((size, limit)) = err.value(py).getattr("args").and_then(|v| v.extract()) {
+            return NativeModuleError::PayloadTooLarge(PayloadTooLarge { size, limit });
+        }
+    }
+
+    if err.is_instance_of::<ModuleError>(py) {
+        let args = err
+            .value(py)
+            .getattr("args")
+            .and_then(|args| args.downcast_into::<PyTuple>().map_err(Into::into))
+            .ok();
+
+        if let Some(args) = args {
+            let arg = |idx: usize| args.get_item(idx).ok().filter(|v| !v.is_none());
+
+            return NativeModuleError::Custom(CustomModuleError {
+                code: arg(0).and_then(|v| v.extract().ok()).unwrap_or(-1),
+                name: arg(1).and_then(|v| v.extract().ok()),
+                message: arg(2).and_then(|v| v.extract().ok()),
+            });
+        }
+    }
+
+    NativeModuleError::Custom(CustomModuleError {
+        code: -1,
+        name: err.get_type(py).name().ok().map(|v| v.to_string()),
+        message: Some(err.value(py).to_string()),
+    })
+}
+
+pub fn subscribe_err(err: SubscribeError) -> PyErr {
+    match err {
+        SubscribeError::InvalidPattern(err) => PyValueError::new_err(err.to_string()),
+        SubscribeError::InvalidFilter(err) => PyValueError::new_err(err.to_string()),
+        SubscribeError::ShuttingDown => ModuleDestroyedError::new_err(()),
+        SubscribeError::Denied => PermissionDeniedError::new_err(()),
+        SubscribeError::InvalidGroupKey(_) => PyValueError::new_err(err.to_string()),
+    }
+}
@@ -0,0 +1,188 @@
+//! Python bindings over the `modular-native` C ABI.
+//!
+//! The native library is loaded with [`LibraryModular`], so `libmodular_native`
+//! has to be on the library search path.
+
+mod error;
+mod module;
+mod subscription;
+
+use crate::error::*;
+use crate::module::PyModuleService;
+use crate::subscription::{PyEvent, Subscription};
+use bytes::Bytes;
+use modular_core::filter::Filter;
+use modular_core::modular::Modular as _;
+use modular_core::modules::{Event, ModuleRequest};
+use modular_sys::dll::LibraryModular;
+use pyo3::exceptions::{PyLookupError, PyRuntimeError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::collections::BTreeMap;
+
+#[pyclass(module = "modular_py")]
+pub struct Modular {
+    inner: Option<LibraryModular>,
+}
+
+impl Modular {
+    fn inner(&self) -> PyResult<&LibraryModular> {
+        self.inner
+            .as_ref()
+            .ok_or_else(|| ModuleDestroyedError::new_err(()))
+    }
+}
+
+impl Drop for Modular {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            // in-flight invokes may need the GIL to finish
+            Python::with_gil(|py| py.allow_threads(move || drop(inner)))
+        }
+    }
+}
+
+#[pymethods]
+impl Modular {
+    #[new]
+    fn new() -> PyResult<Self> {
+        let inner = LibraryModular::new().map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+
+        Ok(Self { inner: Some(inner) })
+    }
+
+    /// Shuts the native instance down, failing in-flight invokes and closing subscriptions.
+    fn close(&mut self, py: Python) {
+        if let Some(inner) = self.inner.take() {
+            py.allow_threads(move || drop(inner))
+        }
+    }
+
+    /// Registers `handler`, an `async def handler(action: str, body: bytes) -> bytes`.
+    ///
+    /// Must be called while an event loop is running, the handler is run on that loop.
+    fn register_module(&self, py: Python, name: &str, handler: Py<PyAny>) -> PyResult<()> {
+        let locals = pyo3_async_runtimes::tokio::get_current_locals(py)?;
+        let _guard = pyo3_async_runtimes::tokio::get_runtime().enter();
+
+        self.inner()?
+            .register_module(name, PyModuleService::new(handler, locals))
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+    }
+
+    fn deregister_module(&self, name: &str) -> PyResult<()> {
+        self.inner()?.deregister_module(name);
+        Ok(())
+    }
+
+    /// Subscribes to events matching `topic`, only yielding those accepted by the
+    /// `filter` expression if given.
+    #[pyo3(signature = (topic, filter=None))]
+    fn subscribe(&self, topic: &str, filter: Option<&str>) -> PyResult<Subscription> {
+        let filter = filter
+            .map(Filter::parse)
+            .transpose()
+            .map_err(|e| subscribe_err(e.into()))?;
+        let stream = self
+            .inner()?
+            .subscribe_filtered(topic, filter.as_ref())
+            .map_err(subscribe_err)?;
+
+        Ok(Subscription::new(stream))
+    }
+
+    #[pyo3(signature = (topic, data, headers=None))]
+    fn publish(
+        &self,
+        topic: &str,
+        data: &[u8],
+        headers: Option<BTreeMap<String, String>>,
+    ) -> PyResult<()> {
+        let mut event = Event::new(topic, Bytes::copy_from_slice(data));
+        event.headers = headers.unwrap_or_default();
+        self.inner()?.publish(event);
+        Ok(())
+    }
+
+    /// Invokes `action` of the module `name`, raising `ModuleError` on failure.
+    fn invoke<'py>(
+        &self,
+        py: Python<'py>,
+        name: &str,
+        action: &str,
+        body: &[u8],
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let Some(module) = self.inner()?.get_module(name) else {
+            return Err(PyLookupError::new_err(format!(
+                "module `{}` not found",
+                name
+            )));
+        };
+
+        let response = module.invoke(ModuleRequest::new(action, Bytes::copy_from_slice(body)));
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let response = response.await.map_err(to_py_err)?;
+
+            Python::with_gil(|py| Ok(PyBytes::new(py, &response.data).unbind()))
+        })
+    }
+}
+
+#[pymodule]
+fn modular_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+
+    m.add_class::<Modular>()?;
+    m.add_class::<Subscription>()?;
+    m.add_class::<PyEvent>()?;
+    m.add("ModuleError", py.get_type::<ModuleError>())?;
+    m.add("UnknownMethodError", py.get_type::<UnknownMethodError>())?;
+    m.add(
+        "ModuleDestroyedError",
+        py.get_type::<ModuleDestroyedError>(),
+    )?;
+    m.add(
+        "PermissionDeniedError",
+        py.get_type::<PermissionDeniedError>(),
+    )?;
+    m.add("OverloadedError", py.get_type::<OverloadedError>())?;
+    m.add("NotFoundError", py.get_type::<NotFoundError>())?;
+    m.add(
+        "PayloadTooLargeError",
+        py.get_type::<PayloadTooLargeError>(),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    /// Runs the `test_*` functions of `tests/test_modular.py` in an embedded interpreter.
+    #[test]
+    fn python() {
+        pyo3::append_to_inittab!(modular_py);
+        pyo3::prepare_freethreaded_python();
+
+        Python::with_gil(|py| {
+            let code = CString::new(include_str!("../tests/test_modular.py")).unwrap();
+            let module =
+                PyModule::from_code(py, &code, c"test_modular.py", c"test_modular").unwrap();
+
+            for name in module.dir().unwrap() {
+                let name = name.extract::<String>().unwrap();
+                if !name.starts_with("test_") {
+                    continue;
+                }
+
+                if let Err(err) = module.getattr(&name).unwrap().call0() {
+                    err.display(py);
+                    panic!("{} failed", name);
+                }
+            }
+        });
+    }
+}
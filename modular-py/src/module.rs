@@ -0,0 +1,86 @@
+use crate::error::from_py_err;
+use bytes::Bytes;
+use futures::future::Map;
+use futures::FutureExt;
+use modular_core::error::{CustomModuleError, ModuleError};
+use modular_core::modules::{ModuleRequest, ModuleResponse};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3_async_runtimes::TaskLocals;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::task::{JoinError, JoinHandle};
+use tower::Service;
+
+type ModuleResult = Result<ModuleResponse, ModuleError>;
+
+/// Module backed by a Python coroutine function `async def f(action: str, body: bytes) -> bytes`.
+///
+/// The coroutines are scheduled on the event loop that was running when the module
+/// was registered.
+#[derive(Clone)]
+pub struct PyModuleService {
+    handler: Arc<Py<PyAny>>,
+    locals: Arc<TaskLocals>,
+}
+
+impl PyModuleService {
+    pub fn new(handler: Py<PyAny>, locals: TaskLocals) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            locals: Arc::new(locals),
+        }
+    }
+}
+
+impl Service<ModuleRequest> for PyModuleService {
+    type Response = ModuleResponse;
+    type Error = ModuleError;
+    type Future =
+        Map<JoinHandle<ModuleResult>, fn(Result<ModuleResult, JoinError>) -> ModuleResult>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ModuleRequest) -> Self::Future {
+        let handler = self.handler.clone();
+        let locals = self.locals.clone();
+
+        // spawned so the returned future is `Sync`, which python futures are not
+        tokio::spawn(call_handler(handler, locals, req)).map(|v| match v {
+            Ok(v) => v,
+            Err(err) => Err(ModuleError::Custom(CustomModuleError {
+                code: -1,
+                name: Some("JoinError".to_owned()),
+                message: Some(err.to_string()),
+            })),
+        })
+    }
+}
+
+async fn call_handler(
+    handler: Arc<Py<PyAny>>,
+    locals: Arc<TaskLocals>,
+    req: ModuleRequest,
+) -> ModuleResult {
+    let future = Python::with_gil(|py| {
+        let coroutine = handler
+            .bind(py)
+            .call1((req.action.as_str(), PyBytes::new(py, &req.body)))?;
+
+        pyo3_async_runtimes::into_future_with_locals(&locals, coroutine)
+    })
+    .map_err(|err| Python::with_gil(|py| from_py_err(py, err)))?;
+
+    let result = future.await;
+
+    Python::with_gil(|py| match result {
+        Ok(v) if v.is_none(py) => Ok(ModuleResponse::new(Bytes::new())),
+        Ok(v) => v
+            .extract::<Vec<u8>>(py)
+            .map(|v| ModuleResponse::new(Bytes::from(v)))
+            .map_err(|err| from_py_err(py, err)),
+        Err(err) => Err(from_py_err(py, err)),
+    })
+}
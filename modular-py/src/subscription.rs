@@ -0,0 +1,66 @@
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use modular_core::modules::Event;
+use pyo3::exceptions::PyStopAsyncIteration;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// An event yielded by a [`Subscription`].
+#[pyclass(module = "modular_py", name = "Event", frozen, get_all)]
+pub struct PyEvent {
+    topic: String,
+    data: Py<PyBytes>,
+    headers: BTreeMap<String, String>,
+    seq: u64,
+}
+
+#[pymethods]
+impl PyEvent {
+    fn __repr__(&self) -> String {
+        format!("Event(topic={:?}, seq={})", self.topic, self.seq)
+    }
+}
+
+/// Async iterator over the [`PyEvent`]s of a subscription.
+#[pyclass(module = "modular_py")]
+pub struct Subscription {
+    stream: Arc<Mutex<BoxStream<'static, Event>>>,
+}
+
+impl Subscription {
+    pub fn new(stream: BoxStream<'static, Event>) -> Self {
+        Self {
+            stream: Arc::new(Mutex::new(stream)),
+        }
+    }
+}
+
+#[pymethods]
+impl Subscription {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let stream = self.stream.clone();
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let next = stream.lock().await.next().await;
+
+            match next {
+                Some(event) => Python::with_gil(|py| {
+                    Ok(PyEvent {
+                        data: PyBytes::new(py, &event.data).unbind(),
+                        topic: event.topic,
+                        headers: event.headers,
+                        seq: event.seq,
+                    })
+                }),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+}
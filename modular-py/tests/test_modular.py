@@ -0,0 +1,114 @@
+"""Behaviour of the `modular_py` bindings.
+
+Run with pytest against an installed `modular_py`, or through `cargo test -p modular-py`,
+which embeds the interpreter. `libmodular_native` has to be on the library search path.
+"""
+
+import asyncio
+import threading
+import time
+
+import modular_py
+
+
+async def upper(action, body):
+    await asyncio.sleep(0)
+    if action == "fail":
+        raise ValueError("failed")
+    return body.upper()
+
+
+def test_invoke():
+    async def main():
+        modular = modular_py.Modular()
+        modular.register_module("svc.upper", upper)
+
+        assert await modular.invoke("svc.upper", "run", b"abc") == b"ABC"
+
+        try:
+            await modular.invoke("svc.upper", "fail", b"")
+            assert False, "expected an error"
+        except modular_py.ModuleError:
+            pass
+
+        try:
+            modular.invoke("svc.missing", "run", b"")
+            assert False, "expected an error"
+        except LookupError:
+            pass
+
+        modular.close()
+
+    asyncio.run(main())
+
+
+def test_publish_subscribe():
+    async def main():
+        modular = modular_py.Modular()
+        subscription = modular.subscribe("orders.>", "data contains \"2\"")
+
+        modular.publish("orders.small", b"1")
+        modular.publish("orders.big", b"22")
+        modular.publish("other.big", b"22")
+
+        event = await asyncio.wait_for(subscription.__anext__(), 5)
+        assert (event.topic, event.data) == ("orders.big", b"22")
+
+        modular.close()
+
+    asyncio.run(main())
+
+
+def test_events_carry_headers():
+    async def main():
+        modular = modular_py.Modular()
+        subscription = modular.subscribe("orders.>", "headers.trace == \"1\"")
+
+        modular.publish("orders.a", b"", headers={"trace": "2"})
+        modular.publish("orders.b", b"x", headers={"trace": "1"})
+
+        event = await asyncio.wait_for(subscription.__anext__(), 5)
+        assert event.topic == "orders.b"
+        assert event.data == b"x"
+        assert event.headers == {"trace": "1"}
+        assert event.seq > 0
+
+        modular.close()
+
+    asyncio.run(main())
+
+
+def test_closed_instance_raises():
+    modular = modular_py.Modular()
+    modular.close()
+
+    try:
+        modular.publish("a.b", b"")
+        assert False, "expected an error"
+    except modular_py.ModuleDestroyedError:
+        pass
+
+
+def test_drop_releases_the_gil():
+    loop = asyncio.new_event_loop()
+    thread = threading.Thread(target=loop.run_forever, daemon=True)
+    thread.start()
+
+    async def slow(action, body):
+        await asyncio.sleep(0.2)
+        return body
+
+    async def start(modular):
+        modular.register_module("svc.slow", slow)
+        return asyncio.ensure_future(modular.invoke("svc.slow", "run", b"x"))
+
+    modular = modular_py.Modular()
+    asyncio.run_coroutine_threadsafe(start(modular), loop).result(5)
+
+    # the in-flight invoke finishes on the loop thread while the instance is dropped
+    started = time.monotonic()
+    del modular
+    assert time.monotonic() - started < 2
+
+    loop.call_soon_threadsafe(loop.stop)
+    thread.join(5)
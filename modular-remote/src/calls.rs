@@ -0,0 +1,61 @@
+use crate::outbox::Outbox;
+use crate::protocol::Frame;
+use bytes::Bytes;
+use futures::future::Map;
+use futures::FutureExt;
+use modular_core::error::ModuleError;
+use modular_core::modules::ModuleResponse;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::oneshot;
+use tokio::sync::oneshot::error::RecvError;
+
+type CallResult = Result<Bytes, ModuleError>;
+
+pub(crate) type CallFuture = Map<
+    oneshot::Receiver<CallResult>,
+    fn(Result<CallResult, RecvError>) -> Result<ModuleResponse, ModuleError>,
+>;
+
+/// Invokes sent to the other side of a connection that still wait for a `Response`.
+#[derive(Default)]
+pub(crate) struct Calls {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<CallResult>>>,
+}
+
+impl Calls {
+    pub fn invoke(&self, tx: &Outbox, module: String, action: String, data: Bytes) -> CallFuture {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.pending.lock().insert(id, result_tx);
+
+        let frame = Frame::Invoke {
+            id,
+            module,
+            action,
+            data,
+        };
+
+        if tx.send(frame).is_err() {
+            // dropping the sender resolves the call with `Destroyed`
+            self.pending.lock().remove(&id);
+        }
+
+        result_rx.map(|v| match v {
+            Ok(v) => v.map(ModuleResponse::new),
+            Err(_) => Err(ModuleError::Destroyed),
+        })
+    }
+
+    pub fn complete(&self, id: u64, result: CallResult) {
+        if let Some(tx) = self.pending.lock().remove(&id) {
+            let _ = tx.send(result);
+        }
+    }
+
+    pub fn fail_all(&self) {
+        self.pending.lock().clear();
+    }
+}
@@ -0,0 +1,464 @@
+use crate::calls::Calls;
+use crate::outbox::Outbox;
+use crate::protocol::{framed, Frame, DEFAULT_MAX_FRAME_LENGTH, DEFAULT_WRITE_QUEUE};
+use bytes::Bytes;
+use futures::channel::mpsc as stream_mpsc;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use modular_core::error::*;
+use modular_core::filter::Filter;
+use modular_core::modular::{BoxModule, Modular};
+use modular_core::module::Module;
+use modular_core::modules::{Event, ModuleRequest, ModuleResponse};
+use modular_rs::core::pattern::Pattern;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tower::util::BoxService;
+use tower::{Service, ServiceExt};
+
+type LocalModule = Arc<tokio::sync::Mutex<BoxService<ModuleRequest, ModuleResponse, ModuleError>>>;
+
+/// How [`RemoteModular`] connects to a server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Credentials for servers that require authentication.
+    pub token: Option<Bytes>,
+    /// Frames longer than this close the connection.
+    pub max_frame_length: usize,
+    /// The connection is closed if the server lets more frames than this queue up.
+    pub write_queue: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            token: None,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            write_queue: DEFAULT_WRITE_QUEUE,
+        }
+    }
+}
+
+/// [`Modular`] implementation backed by a [`Server`](crate::server::Server) on the other
+/// side of a socket.
+///
+/// Modules registered here stay in this process, the server forwards invokes of them
+/// over the connection. When the connection is lost pending invokes fail with
+/// [`ModuleError::Destroyed`] and all subscription streams end.
+///
+/// [`Modular::register_module`] returns before the server accepted the module, see
+/// [`RemoteModular::register_module_confirmed`].
+pub struct RemoteModular {
+    connection: Arc<Connection>,
+    tasks: [AbortHandle; 2],
+}
+
+struct Connection {
+    tx: Outbox,
+    calls: Calls,
+    next_subscription: AtomicU64,
+    subscriptions: Mutex<HashMap<u64, stream_mpsc::UnboundedSender<Event>>>,
+    local_modules: Mutex<HashMap<String, LocalModule>>,
+    /// Registrations waiting for `Registered` or `Rejected`.
+    registrations: Mutex<HashMap<String, oneshot::Sender<Result<(), RegistryError>>>>,
+    remote_modules: RwLock<HashSet<String>>,
+}
+
+impl RemoteModular {
+    pub async fn connect_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::connect_tcp_with(addr, ClientOptions::default()).await
+    }
+
+    pub async fn connect_tcp_with(
+        addr: impl ToSocketAddrs,
+        options: ClientOptions,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        Self::from_io_with(stream, options).await
+    }
+
+    #[cfg(unix)]
+    pub async fn connect_unix(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        Self::connect_unix_with(path, ClientOptions::default()).await
+    }
+
+    #[cfg(unix)]
+    pub async fn connect_unix_with(
+        path: impl AsRef<std::path::Path>,
+        options: ClientOptions,
+    ) -> io::Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+
+        Self::from_io_with(stream, options).await
+    }
+
+    /// Runs the protocol over an already connected socket. Waits for the server to
+    /// send the list of its modules.
+    pub async fn from_io<IO>(io: IO) -> io::Result<Self>
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::from_io_with(io, ClientOptions::default()).await
+    }
+
+    /// Like [`RemoteModular::from_io`], fails with [`io::ErrorKind::PermissionDenied`] if
+    /// the server rejects the credentials.
+    pub async fn from_io_with<IO>(io: IO, options: ClientOptions) -> io::Result<Self>
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut sink, mut stream) = framed(io, options.max_frame_length);
+
+        let token = options.token.unwrap_or_default();
+        sink.send(Frame::Auth { token }).await?;
+
+        let names = match stream.next().await {
+            Some(Ok(Frame::Modules { names })) => names,
+            Some(Ok(Frame::Unauthorized)) => return Err(io::ErrorKind::PermissionDenied.into()),
+            Some(Ok(frame)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected handshake frame {:?}", frame),
+                ))
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+
+        let (tx, rx) = Outbox::new(options.write_queue);
+        let connection = Arc::new(Connection {
+            tx,
+            calls: Calls::default(),
+            next_subscription: AtomicU64::new(0),
+            subscriptions: Default::default(),
+            local_modules: Default::default(),
+            registrations: Default::default(),
+            remote_modules: RwLock::new(names.into_iter().collect()),
+        });
+
+        let mut writer = tokio::spawn(rx.write_to(sink));
+        let writer_handle = writer.abort_handle();
+
+        let reader = tokio::spawn({
+            let connection = connection.clone();
+            async move {
+                loop {
+                    let frame = tokio::select! {
+                        frame = stream.next() => frame,
+                        written = &mut writer => {
+                            if let Ok(Err(e)) = written {
+                                tracing::debug!(error = %e, "failed to write frame");
+                            }
+                            break;
+                        }
+                    };
+
+                    match frame {
+                        Some(Ok(frame)) => connection.handle(frame),
+                        Some(Err(e)) => {
+                            tracing::debug!(error = %e, "failed to read frame");
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+
+                connection.close();
+            }
+        });
+
+        Ok(Self {
+            connection,
+            tasks: [writer_handle, reader.abort_handle()],
+        })
+    }
+
+    /// Subscribes to events matching `topic` that are accepted by `filter`, which is
+    /// evaluated by the server so rejected events aren't sent over the connection.
+    pub fn subscribe_filtered(
+        &self,
+        topic: &str,
+        filter: Option<&Filter>,
+    ) -> Result<BoxStream<'static, Event>, SubscribeError> {
+        Pattern::parse(topic)?;
+
+        let id = self
+            .connection
+            .next_subscription
+            .fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = stream_mpsc::unbounded();
+        self.connection.subscriptions.lock().insert(id, tx);
+
+        let frame = Frame::Subscribe {
+            id,
+            pattern: topic.to_owned(),
+            filter: filter.map(|v| v.as_str().to_owned()),
+        };
+
+        if self.connection.tx.send(frame).is_err() {
+            self.connection.subscriptions.lock().remove(&id);
+            return Err(SubscribeError::ShuttingDown);
+        }
+
+        let stream = SubscriptionStream {
+            id,
+            rx,
+            connection: Arc::downgrade(&self.connection),
+        };
+
+        Ok(stream.boxed())
+    }
+
+    /// Like [`Modular::register_module`], but waits for the server to accept or reject
+    /// the module.
+    pub async fn register_module_confirmed<S>(
+        &self,
+        name: &str,
+        service: S,
+    ) -> Result<(), RegistryError>
+    where
+        S: Service<ModuleRequest> + 'static + Send + Sync,
+        S::Response: Into<ModuleResponse> + Send + 'static,
+        S::Error: Into<ModuleError> + Send + 'static,
+        S::Future: Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.connection
+            .registrations
+            .lock()
+            .insert(name.to_owned(), tx);
+
+        if let Err(e) = self.register_module(name, service) {
+            self.connection.registrations.lock().remove(name);
+            return Err(e);
+        }
+
+        // the sender is dropped when the connection closes
+        rx.await.unwrap_or(Err(RegistryError::ShuttingDown))
+    }
+}
+
+impl Drop for RemoteModular {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        self.connection.close();
+    }
+}
+
+impl Connection {
+    fn handle(self: &Arc<Self>, frame: Frame) {
+        match frame {
+            Frame::Response { id, result } => self.calls.complete(id, result),
+            Frame::Event { id, event } => {
+                let mut subscriptions = self.subscriptions.lock();
+                let delivered = match subscriptions.get(&id) {
+                    Some(tx) => tx.unbounded_send(event).is_ok(),
+                    None => true,
+                };
+
+                if !delivered {
+                    subscriptions.remove(&id);
+                    let _ = self.tx.send(Frame::Unsubscribe { id });
+                }
+            }
+            Frame::Closed { id } => {
+                self.subscriptions.lock().remove(&id);
+            }
+            Frame::Invoke {
+                id,
+                module,
+                action,
+                data,
+            } => {
+                let module = self.local_modules.lock().get(&module).cloned();
+                let tx = self.tx.clone();
+
+                tokio::spawn(async move {
+                    let result = match module {
+                        Some(module) => {
+                            let fut = {
+                                let mut module = module.lock().await;
+                                match module.ready().await {
+                                    Ok(module) => {
+                                        module.call(ModuleRequest::new(&action, data)).left_future()
+                                    }
+                                    Err(e) => futures::future::err(e).right_future(),
+                                }
+                            };
+
+                            fut.await.map(|v| v.data)
+                        }
+                        None => Err(ModuleError::NotFound),
+                    };
+
+                    let _ = tx.send(Frame::Response { id, result });
+                });
+            }
+            Frame::Registered { name } => {
+                if let Some(tx) = self.registrations.lock().remove(&name) {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            Frame::Rejected { name, reason } => {
+                tracing::warn!(module = %name, %reason, "server rejected module registration");
+                self.local_modules.lock().remove(&name);
+
+                if let Some(tx) = self.registrations.lock().remove(&name) {
+                    let _ = tx.send(Err(reason));
+                }
+            }
+            Frame::Modules { names } => {
+                *self.remote_modules.write() = names.into_iter().collect();
+            }
+            Frame::ModuleAdded { name } => {
+                self.remote_modules.write().insert(name);
+            }
+            Frame::ModuleRemoved { name } => {
+                self.remote_modules.write().remove(&name);
+            }
+            frame => tracing::debug!(?frame, "unexpected frame from server"),
+        }
+    }
+
+    fn close(&self) {
+        self.calls.fail_all();
+        self.subscriptions.lock().clear();
+        self.registrations.lock().clear();
+        self.remote_modules.write().clear();
+    }
+}
+
+impl Modular for RemoteModular {
+    type Stream = BoxStream<'static, Event>;
+    type Module = BoxModule;
+
+    fn register_module<S>(&self, name: &str, service: S) -> Result<(), RegistryError>
+    where
+        S: Service<ModuleRequest> + 'static + Send + Sync,
+        S::Response: Into<ModuleResponse> + Send + 'static,
+        S::Error: Into<ModuleError> + Send + 'static,
+        S::Future: Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync + 'static,
+    {
+        if self.connection.remote_modules.read().contains(name) {
+            return Err(RegistryError::AlreadyExists);
+        }
+
+        let service = service.map_response(Into::into).map_err(Into::into);
+        let module = Arc::new(tokio::sync::Mutex::new(BoxService::new(service)));
+
+        {
+            let mut local_modules = self.connection.local_modules.lock();
+            if local_modules.contains_key(name) {
+                return Err(RegistryError::AlreadyExists);
+            }
+
+            local_modules.insert(name.to_owned(), module);
+        }
+
+        let frame = Frame::Register {
+            name: name.to_owned(),
+        };
+
+        if self.connection.tx.send(frame).is_err() {
+            self.connection.local_modules.lock().remove(name);
+            return Err(RegistryError::ShuttingDown);
+        }
+
+        Ok(())
+    }
+
+    fn subscribe<S, Err>(
+        &self,
+        topic: &str,
+        _sink: Option<S>,
+    ) -> Result<Self::Stream, SubscribeError>
+    where
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
+    {
+        self.subscribe_filtered(topic, None)
+    }
+
+    fn publish<E>(&self, event: E)
+    where
+        E: Into<Event>,
+    {
+        let _ = self.connection.tx.send(Frame::Publish {
+            event: event.into(),
+        });
+    }
+
+    fn get_module(&self, name: &str) -> Option<Self::Module> {
+        if !self.connection.remote_modules.read().contains(name) {
+            return None;
+        }
+
+        Some(Box::new(RemoteModule {
+            name: name.to_owned(),
+            connection: self.connection.clone(),
+        }))
+    }
+
+    fn deregister_module(&self, name: &str) {
+        if self.connection.local_modules.lock().remove(name).is_some() {
+            let _ = self.connection.tx.send(Frame::Deregister {
+                name: name.to_owned(),
+            });
+        }
+    }
+}
+
+struct SubscriptionStream {
+    id: u64,
+    rx: stream_mpsc::UnboundedReceiver<Event>,
+    connection: Weak<Connection>,
+}
+
+impl Stream for SubscriptionStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for SubscriptionStream {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.upgrade() {
+            if connection.subscriptions.lock().remove(&self.id).is_some() {
+                let _ = connection.tx.send(Frame::Unsubscribe { id: self.id });
+            }
+        }
+    }
+}
+
+struct RemoteModule {
+    name: String,
+    connection: Arc<Connection>,
+}
+
+impl Module for RemoteModule {
+    type Future = BoxFuture<'static, Result<ModuleResponse, ModuleError>>;
+
+    fn invoke(&self, req: ModuleRequest<Bytes>) -> Self::Future {
+        self.connection
+            .calls
+            .invoke(&self.connection.tx, self.name.clone(), req.action, req.body)
+            .boxed()
+    }
+}
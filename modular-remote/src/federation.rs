@@ -0,0 +1,859 @@
+use crate::calls::Calls;
+use crate::outbox::Outbox;
+use crate::protocol::{framed, Frame, DEFAULT_MAX_FRAME_LENGTH, DEFAULT_WRITE_QUEUE};
+use crate::server::{forward_module_changes, serve_invoke, Authenticate};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink, SinkExt, StreamExt};
+use modular_core::error::*;
+use modular_core::modular::BoxModule;
+use modular_core::modular::Modular as _;
+use modular_core::module::Module;
+use modular_core::modules::{Event, ModuleRequest, ModuleResponse};
+use modular_rs::core::acl::{Acl, Permission, ScopedModular};
+use modular_rs::core::modules::{is_system_module, Module as LocalModuleRef};
+use modular_rs::core::pattern::Pattern;
+use modular_rs::core::{Forwarder, Modular};
+use parking_lot::{Mutex, RwLock};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::AbortHandle;
+use tokio::time::MissedTickBehavior;
+use tower::Service;
+
+/// How many links an event or a subscription is propagated over by default.
+pub const DEFAULT_MAX_HOPS: u8 = 8;
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Number of recent sequence numbers remembered per origin to drop duplicates.
+const SEEN_WINDOW: usize = 4096;
+
+/// How often nodes advertise their interest again, the interest of a node that missed
+/// three refreshes is forgotten.
+const INTEREST_REFRESH: Duration = Duration::from_secs(30);
+
+/// Links a [`Modular`] instance with peers.
+///
+/// Events published on any node are forwarded to peers that have a matching
+/// subscription, directly or through their own peers. Every node advertises the
+/// patterns of its subscriptions with a version that increases with each change, and
+/// peers relay only the latest version of each node, so only matching events cross a
+/// link and unsubscribing withdraws the patterns everywhere. Every event carries the id
+/// of the node it was published on and a sequence number, duplicates arriving over
+/// different paths are dropped and each event is forwarded at most
+/// [`DEFAULT_MAX_HOPS`] times.
+///
+/// Peers authenticated with [`with_authenticator`](Self::with_authenticator) act as the
+/// identity it returns: events they forward need the publish permission, events are only
+/// forwarded to them with the subscribe permission and invoking needs the invoke
+/// permission.
+///
+/// [`get_module`](modular_core::modular::Modular::get_module) falls through to modules
+/// of directly linked peers when no local module has the name.
+pub struct Federation {
+    node: Arc<Node>,
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+struct Node {
+    id: u64,
+    modular: Arc<Modular>,
+    max_hops: AtomicU8,
+    next_seq: AtomicU64,
+    next_link: AtomicU64,
+    links: RwLock<HashMap<u64, Arc<Link>>>,
+    seen: Mutex<HashMap<u64, Seen>>,
+    /// Version and patterns of the local subscriptions last advertised.
+    local: Mutex<(u64, Vec<String>)>,
+    /// Latest interest of the other nodes by node id.
+    interests: RwLock<HashMap<u64, Interest>>,
+    token: RwLock<Bytes>,
+    authenticate: RwLock<Option<Authenticate>>,
+}
+
+struct Link {
+    peer: u64,
+    /// Set if the peer authenticated, what it does is checked against its ACL.
+    scope: Option<ScopedModular>,
+    tx: Outbox,
+    calls: Calls,
+    modules: RwLock<HashSet<String>>,
+}
+
+struct Interest {
+    version: u64,
+    hops: u8,
+    patterns: Vec<Pattern>,
+    /// Links the latest version arrived over, events for the node are sent over them.
+    links: HashSet<u64>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Seen {
+    set: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl Seen {
+    fn insert(&mut self, seq: u64) -> bool {
+        if !self.set.insert(seq) {
+            return false;
+        }
+
+        self.order.push_back(seq);
+        if self.order.len() > SEEN_WINDOW {
+            if let Some(v) = self.order.pop_front() {
+                self.set.remove(&v);
+            }
+        }
+
+        true
+    }
+}
+
+impl Federation {
+    /// Must be called within a tokio runtime.
+    pub fn new(modular: Arc<Modular>) -> Self {
+        let node = Arc::new(Node {
+            id: RandomState::new().build_hasher().finish(),
+            modular,
+            max_hops: AtomicU8::new(DEFAULT_MAX_HOPS),
+            next_seq: AtomicU64::new(0),
+            next_link: AtomicU64::new(0),
+            links: Default::default(),
+            seen: Default::default(),
+            local: Default::default(),
+            interests: Default::default(),
+            token: Default::default(),
+            authenticate: Default::default(),
+        });
+
+        node.modular
+            .set_forwarder(Some(Arc::new(NodeForwarder(Arc::downgrade(&node)))));
+
+        let mut subscriptions = node.modular.watch_subscriptions();
+        let weak = Arc::downgrade(&node);
+        let watcher = tokio::spawn(async move {
+            let mut refresh = tokio::time::interval(INTEREST_REFRESH);
+            refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                let refreshed = tokio::select! {
+                    changed = subscriptions.changed() => match changed {
+                        Ok(()) => false,
+                        Err(_) => break,
+                    },
+                    _ = refresh.tick() => true,
+                };
+
+                let Some(node) = weak.upgrade() else {
+                    break;
+                };
+                if refreshed {
+                    node.expire();
+                }
+                node.advertise(refreshed);
+            }
+        });
+
+        Self {
+            node,
+            tasks: Mutex::new(vec![watcher.abort_handle()]),
+        }
+    }
+
+    /// Random id of this node, sent to peers as the origin of its events.
+    pub fn node_id(&self) -> u64 {
+        self.node.id
+    }
+
+    /// Token sent to peers when linking, checked by their authenticator.
+    pub fn with_token(self, token: impl Into<Bytes>) -> Self {
+        *self.node.token.write() = token.into();
+        self
+    }
+
+    /// Requires peers to authenticate with a token, see [`with_token`](Self::with_token).
+    /// Authenticated peers act as the identity `authenticate` returns, limited to what
+    /// its ACL allows.
+    pub fn with_authenticator<F>(self, authenticate: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<(String, Acl)> + Send + Sync + 'static,
+    {
+        *self.node.authenticate.write() = Some(Arc::new(authenticate));
+        self
+    }
+
+    pub fn set_max_hops(&self, hops: u8) {
+        self.node.max_hops.store(hops.max(1), Ordering::Relaxed);
+    }
+
+    pub fn modular(&self) -> &Arc<Modular> {
+        &self.node.modular
+    }
+
+    /// Ids of the currently linked peers.
+    pub fn peers(&self) -> Vec<u64> {
+        self.node.links.read().values().map(|i| i.peer).collect()
+    }
+
+    /// Accepts links from peers.
+    pub async fn listen_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+
+            tracing::debug!(%addr, "accepted federation link");
+            self.spawn(stream);
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn listen_unix(&self, listener: tokio::net::UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            self.spawn(stream);
+        }
+    }
+
+    /// Links to the peer at `addr`, reconnecting with backoff whenever the link is lost.
+    pub fn connect_tcp(&self, addr: impl Into<String>) {
+        let addr = addr.into();
+        self.reconnect(move || {
+            let addr = addr.clone();
+            async move {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Ok(stream)
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(&self, path: impl Into<std::path::PathBuf>) {
+        let path = path.into();
+        self.reconnect(move || tokio::net::UnixStream::connect(path.clone()));
+    }
+
+    /// Runs a link over an already connected socket until it closes.
+    pub async fn link<IO>(&self, io: IO) -> io::Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.node.clone().run_link(io).await
+    }
+
+    fn spawn<IO>(&self, io: IO)
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let node = self.node.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = node.run_link(io).await {
+                tracing::debug!(error = %e, "federation link closed with error");
+            }
+        });
+
+        self.track(task.abort_handle());
+    }
+
+    fn reconnect<F, Fut, IO>(&self, connect: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<IO>> + Send,
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let node = self.node.clone();
+        let task = tokio::spawn(async move {
+            let mut delay = RECONNECT_MIN_DELAY;
+            loop {
+                match connect().await {
+                    Ok(io) => {
+                        delay = RECONNECT_MIN_DELAY;
+                        if let Err(e) = node.clone().run_link(io).await {
+                            tracing::debug!(error = %e, "federation link closed with error");
+                        }
+                    }
+                    Err(e) => tracing::debug!(error = %e, "failed to connect to peer"),
+                }
+
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            }
+        });
+
+        self.track(task.abort_handle());
+    }
+
+    fn track(&self, task: AbortHandle) {
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|i| !i.is_finished());
+        tasks.push(task);
+    }
+}
+
+impl Drop for Federation {
+    fn drop(&mut self) {
+        self.node.modular.set_forwarder(None);
+
+        for task in self.tasks.lock().drain(..) {
+            task.abort();
+        }
+
+        for (_, link) in self.node.links.write().drain() {
+            link.calls.fail_all();
+        }
+    }
+}
+
+impl Node {
+    async fn run_link<IO>(self: Arc<Self>, io: IO) -> io::Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut sink, mut stream) = framed(io, DEFAULT_MAX_FRAME_LENGTH);
+        let token = self.token.read().clone();
+        sink.send(Frame::Hello { node: self.id }).await?;
+        sink.send(Frame::Auth { token }).await?;
+
+        let peer = match stream.next().await {
+            Some(Ok(Frame::Hello { node })) => node,
+            Some(Ok(frame)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected handshake frame {:?}", frame),
+                ))
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+
+        if peer == self.id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "node is linked to itself",
+            ));
+        }
+
+        let token = match stream.next().await {
+            Some(Ok(Frame::Auth { token })) => token,
+            Some(Ok(Frame::Unauthorized)) => return Err(io::ErrorKind::PermissionDenied.into()),
+            Some(Ok(frame)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected handshake frame {:?}", frame),
+                ))
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+
+        let authenticate = self.authenticate.read().clone();
+        let scope = match authenticate {
+            Some(authenticate) => match authenticate(&token) {
+                Some((identity, acl)) => Some(self.modular.scoped(&identity, acl)),
+                None => {
+                    sink.send(Frame::Unauthorized).await?;
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+            },
+            None => None,
+        };
+
+        let (tx, rx) = Outbox::new(DEFAULT_WRITE_QUEUE);
+
+        // subscribe before taking the snapshot so no change is lost in between
+        let visible = scope.clone();
+        let visible = move |name: &str| {
+            !is_system_module(name)
+                && visible
+                    .as_ref()
+                    .is_none_or(|v| v.acl().is_allowed(Permission::Invoke, name))
+        };
+        let modules_closed = forward_module_changes(&self.modular, &tx, visible.clone());
+        let mut names = self.modular.module_names();
+        names.retain(|name| visible(name));
+        let _ = tx.send(Frame::Modules { names });
+
+        let link = Arc::new(Link {
+            peer,
+            scope,
+            tx,
+            calls: Calls::default(),
+            modules: Default::default(),
+        });
+
+        let link_id = self.next_link.fetch_add(1, Ordering::Relaxed);
+        self.links.write().insert(link_id, link.clone());
+        self.sync(&link);
+
+        let mut writer = tokio::spawn(rx.write_to(sink));
+        let _guard = LinkGuard {
+            node: &self,
+            link_id,
+            link: &link,
+            modules_closed: &modules_closed,
+            writer: writer.abort_handle(),
+        };
+
+        loop {
+            let frame = tokio::select! {
+                frame = stream.next() => frame,
+                written = &mut writer => break written.unwrap_or_else(|e| Err(io::Error::other(e))),
+            };
+
+            match frame {
+                Some(Ok(Frame::Unauthorized)) => break Err(io::ErrorKind::PermissionDenied.into()),
+                Some(Ok(frame)) => self.handle(link_id, &link, frame),
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            }
+        }
+    }
+
+    fn handle(&self, link_id: u64, link: &Link, frame: Frame) {
+        match frame {
+            Frame::Forward {
+                origin,
+                seq,
+                hops,
+                event,
+            } => {
+                if origin == self.id || !self.seen.lock().entry(origin).or_default().insert(seq) {
+                    return;
+                }
+
+                let allowed = match &link.scope {
+                    Some(scope) => scope.publish_local(event.clone()),
+                    None => {
+                        self.modular.publish_local(event.clone());
+                        true
+                    }
+                };
+
+                if allowed && hops > 1 {
+                    self.forward(Some(link_id), origin, seq, hops - 1, &event);
+                }
+            }
+            Frame::Interest {
+                origin,
+                version,
+                hops,
+                patterns,
+            } => self.learn(link_id, origin, version, hops, patterns),
+            Frame::Modules { names } => {
+                *link.modules.write() = names.into_iter().collect();
+            }
+            Frame::ModuleAdded { name } => {
+                link.modules.write().insert(name);
+            }
+            Frame::ModuleRemoved { name } => {
+                link.modules.write().remove(&name);
+            }
+            Frame::Invoke {
+                id,
+                module,
+                action,
+                data,
+            } => match &link.scope {
+                Some(scope) => serve_invoke(scope.get_module(&module), &link.tx, id, action, data),
+                None => serve_invoke(self.modular.get_module(&module), &link.tx, id, action, data),
+            },
+            Frame::Response { id, result } => link.calls.complete(id, result),
+            frame => tracing::debug!(?frame, "unexpected frame from peer"),
+        }
+    }
+
+    /// Sends an event to every link except `from` that leads to a node interested in
+    /// its topic, unless the peer isn't allowed to subscribe to it.
+    fn forward(&self, from: Option<u64>, origin: u64, seq: u64, hops: u8, event: &Event) {
+        let interests = self.interests.read();
+        for (id, link) in self.links.read().iter() {
+            if Some(*id) == from || link.peer == origin {
+                continue;
+            }
+
+            let interested = interests.values().any(|interest| {
+                interest.links.contains(id)
+                    && interest.patterns.iter().any(|i| i.matches(&event.topic))
+            });
+            let allowed = link
+                .scope
+                .as_ref()
+                .is_none_or(|v| v.acl().is_allowed(Permission::Subscribe, &event.topic));
+
+            if interested && allowed {
+                let _ = link.tx.send(Frame::Forward {
+                    origin,
+                    seq,
+                    hops,
+                    event: event.clone(),
+                });
+            }
+        }
+    }
+
+    /// Sends every peer the patterns of local subscriptions with a new version if they
+    /// changed since the last time, or `always`.
+    fn advertise(&self, always: bool) {
+        let mut patterns = self
+            .modular
+            .subscription_patterns()
+            .into_iter()
+            .filter(|i| !i.as_str().starts_with("$.sys."))
+            .map(|i| i.as_str().to_owned())
+            .collect::<Vec<_>>();
+        patterns.sort();
+        patterns.dedup();
+
+        let version = {
+            let mut local = self.local.lock();
+            if !always && local.1 == patterns {
+                return;
+            }
+
+            local.0 += 1;
+            local.1 = patterns.clone();
+            local.0
+        };
+
+        for link in self.links.read().values() {
+            let _ = link.tx.send(Frame::Interest {
+                origin: self.id,
+                version,
+                hops: 1,
+                patterns: patterns.clone(),
+            });
+        }
+    }
+
+    /// Sends a new peer the local interest and the interest of every node it may reach
+    /// through this one.
+    fn sync(&self, link: &Link) {
+        let max_hops = self.max_hops.load(Ordering::Relaxed);
+        let (version, patterns) = self.local.lock().clone();
+        let _ = link.tx.send(Frame::Interest {
+            origin: self.id,
+            version,
+            hops: 1,
+            patterns,
+        });
+
+        for (origin, interest) in self.interests.read().iter() {
+            if *origin == link.peer || interest.hops >= max_hops {
+                continue;
+            }
+
+            let _ = link.tx.send(Frame::Interest {
+                origin: *origin,
+                version: interest.version,
+                hops: interest.hops + 1,
+                patterns: interest.patterns.iter().map(|i| i.to_string()).collect(),
+            });
+        }
+    }
+
+    /// Records the interest of `origin` that arrived over `link_id` and relays it to the
+    /// other peers if it's a new version.
+    fn learn(&self, link_id: u64, origin: u64, version: u64, hops: u8, patterns: Vec<String>) {
+        if origin == self.id {
+            return;
+        }
+
+        let mut interests = self.interests.write();
+        if let Some(known) = interests.get_mut(&origin) {
+            if known.version > version {
+                return;
+            }
+            if known.version == version {
+                known.links.insert(link_id);
+                return;
+            }
+        }
+
+        let parsed = patterns
+            .iter()
+            .filter_map(|pattern| match Pattern::parse(pattern) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    tracing::debug!(error = %e, pattern, "invalid pattern from peer");
+                    None
+                }
+            })
+            .collect();
+
+        interests.insert(
+            origin,
+            Interest {
+                version,
+                hops,
+                patterns: parsed,
+                links: HashSet::from([link_id]),
+                expires: Instant::now() + 3 * INTEREST_REFRESH,
+            },
+        );
+        drop(interests);
+
+        if hops >= self.max_hops.load(Ordering::Relaxed) {
+            return;
+        }
+
+        for (id, link) in self.links.read().iter() {
+            if *id != link_id && link.peer != origin {
+                let _ = link.tx.send(Frame::Interest {
+                    origin,
+                    version,
+                    hops: hops + 1,
+                    patterns: patterns.clone(),
+                });
+            }
+        }
+    }
+
+    /// Forgets the interest of nodes that stopped refreshing it.
+    fn expire(&self) {
+        let now = Instant::now();
+        self.interests.write().retain(|_, i| i.expires > now);
+    }
+
+    fn unlink(&self, link_id: u64) {
+        self.links.write().remove(&link_id);
+        self.interests.write().retain(|_, interest| {
+            interest.links.remove(&link_id);
+            !interest.links.is_empty()
+        });
+    }
+}
+
+/// Unlinks a peer when its connection is closed or the link task is aborted.
+struct LinkGuard<'a> {
+    node: &'a Node,
+    link_id: u64,
+    link: &'a Link,
+    modules_closed: &'a AtomicBool,
+    writer: AbortHandle,
+}
+
+impl Drop for LinkGuard<'_> {
+    fn drop(&mut self) {
+        self.modules_closed.store(true, Ordering::Release);
+        self.node.unlink(self.link_id);
+        self.link.calls.fail_all();
+        self.writer.abort();
+    }
+}
+
+struct NodeForwarder(Weak<Node>);
+
+impl Forwarder for NodeForwarder {
+    fn forward(&self, event: &Event) {
+        let Some(node) = self.0.upgrade() else {
+            return;
+        };
+
+        let seq = node.next_seq.fetch_add(1, Ordering::Relaxed);
+        let hops = node.max_hops.load(Ordering::Relaxed);
+        node.forward(None, node.id, seq, hops, event);
+    }
+}
+
+impl modular_core::modular::Modular for Federation {
+    type Stream = ();
+    type Module = BoxModule;
+
+    fn register_module<S>(&self, name: &str, service: S) -> Result<(), RegistryError>
+    where
+        S: Service<ModuleRequest> + 'static + Send + Sync,
+        S::Response: Into<ModuleResponse> + Send + 'static,
+        S::Error: Into<ModuleError> + Send + 'static,
+        S::Future: Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync + 'static,
+    {
+        self.node.modular.register_module(name, service)
+    }
+
+    fn subscribe<S, Err>(
+        &self,
+        topic: &str,
+        sink: Option<S>,
+    ) -> Result<Self::Stream, SubscribeError>
+    where
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
+    {
+        self.node.modular.subscribe(topic, sink)
+    }
+
+    fn publish<E>(&self, event: E)
+    where
+        E: Into<Event>,
+    {
+        self.node.modular.publish(event)
+    }
+
+    fn get_module(&self, name: &str) -> Option<Self::Module> {
+        if let Some(module) = self.node.modular.get_module(name) {
+            return Some(Box::new(LocalModule(module)));
+        }
+
+        let links = self.node.links.read();
+        let link = links.values().find(|i| i.modules.read().contains(name))?;
+
+        Some(Box::new(PeerModule {
+            name: name.to_owned(),
+            link: link.clone(),
+        }))
+    }
+
+    fn deregister_module(&self, name: &str) {
+        self.node.modular.deregister_module(name)
+    }
+}
+
+struct LocalModule(LocalModuleRef<Bytes, Bytes>);
+
+impl Module for LocalModule {
+    type Future = BoxFuture<'static, Result<ModuleResponse, ModuleError>>;
+
+    fn invoke(&self, req: ModuleRequest<Bytes>) -> Self::Future {
+        let fut = self.0.invoke(req);
+        async move { fut.await?.await }.boxed()
+    }
+}
+
+struct PeerModule {
+    name: String,
+    link: Arc<Link>,
+}
+
+impl Module for PeerModule {
+    type Future = BoxFuture<'static, Result<ModuleResponse, ModuleError>>;
+
+    fn invoke(&self, req: ModuleRequest<Bytes>) -> Self::Future {
+        self.link
+            .calls
+            .invoke(&self.link.tx, self.name.clone(), req.action, req.body)
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        panic!("timed out");
+    }
+
+    fn link(a: &Arc<Federation>, b: &Arc<Federation>) {
+        let (a_io, b_io) = tokio::io::duplex(1 << 16);
+        for (federation, io) in [(a.clone(), a_io), (b.clone(), b_io)] {
+            tokio::spawn(async move { federation.link(io).await });
+        }
+    }
+
+    fn patterns(federation: &Federation, origin: u64) -> Option<Vec<String>> {
+        let interests = federation.node.interests.read();
+        let interest = interests.get(&origin)?;
+        Some(interest.patterns.iter().map(|i| i.to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_withdraws_interest_in_a_cycle() {
+        let nodes = (0..3)
+            .map(|_| Arc::new(Federation::new(Arc::new(Modular::default()))))
+            .collect::<Vec<_>>();
+        let (a, b, c) = (&nodes[0], &nodes[1], &nodes[2]);
+        link(a, b);
+        link(b, c);
+        link(c, a);
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        c.subscribe("t.>", Some(tx)).unwrap();
+
+        let subscribed = Some(vec!["t.>".to_owned()]);
+        eventually(|| {
+            patterns(a, c.node_id()) == subscribed && patterns(b, c.node_id()) == subscribed
+        })
+        .await;
+
+        // the subscription is closed once an event finds its receiver gone
+        drop(rx);
+        c.publish(Event::new("t.x", Bytes::new()));
+
+        let withdrawn = Some(vec![]);
+        eventually(|| {
+            patterns(a, c.node_id()) == withdrawn && patterns(b, c.node_id()) == withdrawn
+        })
+        .await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(patterns(a, c.node_id()), withdrawn);
+        assert_eq!(patterns(b, c.node_id()), withdrawn);
+    }
+
+    fn peer_modules(federation: &Federation) -> Vec<String> {
+        let mut names = federation
+            .node
+            .links
+            .read()
+            .values()
+            .flat_map(|link| link.modules.read().iter().cloned().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn system_modules_are_not_advertised() {
+        let a = Arc::new(Federation::new(Arc::new(Modular::default())));
+        let b = Arc::new(Federation::new(Arc::new(Modular::default())));
+        let module = || {
+            tower::service_fn(|_: ModuleRequest<Bytes>| async {
+                Ok::<_, ModuleError>(ModuleResponse::new(Bytes::new()))
+            })
+        };
+        b.register_module("x", module()).unwrap();
+        link(&a, &b);
+
+        eventually(|| peer_modules(&a) == ["x"]).await;
+        b.register_module("$.sys.custom", module()).unwrap();
+        b.register_module("y", module()).unwrap();
+        eventually(|| peer_modules(&a) == ["x", "y"]).await;
+    }
+
+    #[tokio::test]
+    async fn interest_learned_over_a_link_is_dropped_with_it() {
+        let a = Arc::new(Federation::new(Arc::new(Modular::default())));
+        let b = Federation::new(Arc::new(Modular::default()));
+        let (tx, _rx) = futures::channel::mpsc::unbounded();
+        b.subscribe("t.>", Some(tx)).unwrap();
+
+        let (a_io, b_io) = tokio::io::duplex(1 << 16);
+        let linked = a.clone();
+        let task = tokio::spawn(async move { linked.link(a_io).await });
+        let b_task = tokio::spawn(async move { b.link(b_io).await });
+
+        eventually(|| a.node.interests.read().len() == 1).await;
+        b_task.abort();
+        let _ = task.await;
+
+        assert!(a.node.interests.read().is_empty());
+        assert!(a.peers().is_empty());
+    }
+}
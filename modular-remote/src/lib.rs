@@ -0,0 +1,15 @@
+//! Socket transport for `modular-rs`.
+//!
+//! A [`server::Server`] exposes a [`modular_rs::core::Modular`] instance over TCP or
+//! Unix sockets and [`client::RemoteModular`] implements the `Modular` trait on top of
+//! such a connection. [`federation::Federation`] links several instances as peers.
+
+mod calls;
+pub mod client;
+pub mod federation;
+mod outbox;
+mod protocol;
+pub mod server;
+
+pub use protocol::{DEFAULT_MAX_FRAME_LENGTH, DEFAULT_WRITE_QUEUE};
+mod sink;
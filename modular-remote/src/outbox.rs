@@ -0,0 +1,108 @@
+use crate::protocol::Frame;
+use futures::{Sink, SinkExt};
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+/// Frames waiting to be written to a connection.
+///
+/// Frames can't be dropped without breaking calls and subscriptions, so a peer that
+/// lets `capacity` frames pile up is disconnected instead.
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    tx: mpsc::Sender<Frame>,
+    overflowed: Arc<Notify>,
+}
+
+pub(crate) struct OutboxReceiver {
+    rx: mpsc::Receiver<Frame>,
+    overflowed: Arc<Notify>,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> (Self, OutboxReceiver) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let overflowed = Arc::new(Notify::new());
+
+        (
+            Self {
+                tx,
+                overflowed: overflowed.clone(),
+            },
+            OutboxReceiver { rx, overflowed },
+        )
+    }
+
+    /// Fails if the connection is closed or closing because the queue is full.
+    pub fn send(&self, frame: Frame) -> Result<(), ()> {
+        match self.tx.try_send(frame) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.notify_one();
+                Err(())
+            }
+            Err(TrySendError::Closed(_)) => Err(()),
+        }
+    }
+}
+
+impl OutboxReceiver {
+    /// Writes the queued frames to `sink` until all [`Outbox`]es are dropped, fails if
+    /// writing fails or the queue overflowed.
+    pub async fn write_to<S>(mut self, mut sink: S) -> io::Result<()>
+    where
+        S: Sink<Frame, Error = io::Error> + Unpin,
+    {
+        loop {
+            let frame = tokio::select! {
+                biased;
+                _ = self.overflowed.notified() => {
+                    return Err(io::Error::other("peer doesn't keep up with the frames sent to it"));
+                }
+                frame = self.rx.recv() => match frame {
+                    Some(v) => v,
+                    None => return Ok(()),
+                },
+            };
+
+            sink.send(frame).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn overflowing_the_queue_closes_the_connection() {
+        let (outbox, rx) = Outbox::new(2);
+        for id in 0..2 {
+            assert!(outbox.send(Frame::Closed { id }).is_ok());
+        }
+        assert!(outbox.send(Frame::Closed { id: 2 }).is_err());
+
+        let (sink, mut written) = futures::channel::mpsc::unbounded();
+        let sink = sink.sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(rx.write_to(sink).await.is_err());
+        assert!(written.try_recv().is_err());
+        assert!(outbox.send(Frame::Closed { id: 3 }).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_are_written_until_the_outboxes_are_dropped() {
+        let (outbox, rx) = Outbox::new(2);
+        for id in 0..2 {
+            assert!(outbox.send(Frame::Closed { id }).is_ok());
+        }
+        drop(outbox);
+
+        let (sink, written) = futures::channel::mpsc::unbounded();
+        let sink = sink.sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(rx.write_to(sink).await.is_ok());
+
+        let written: Vec<_> = futures::StreamExt::collect(written).await;
+        assert_eq!(written.len(), 2);
+    }
+}
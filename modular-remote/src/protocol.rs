@@ -0,0 +1,534 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use modular_core::error::{CustomModuleError, ModuleError, PayloadTooLarge, RegistryError};
+use modular_core::event::Event;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Frames longer than this are rejected unless configured otherwise, see
+/// [`Server::with_max_frame_length`](crate::server::Server::with_max_frame_length).
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Peers that let more frames queue up than this are disconnected unless configured
+/// otherwise, see [`Server::with_write_queue`](crate::server::Server::with_write_queue).
+pub const DEFAULT_WRITE_QUEUE: usize = 4096;
+
+/// Frames exchanged between a client and a server.
+///
+/// `Invoke` and `Response` travel in both directions: clients invoke modules of the
+/// server and the server invokes modules registered by the client. Each side matches
+/// a `Response` against the invokes it sent itself.
+#[derive(Debug)]
+pub enum Frame {
+    Register {
+        name: String,
+    },
+    Deregister {
+        name: String,
+    },
+    Subscribe {
+        id: u64,
+        pattern: String,
+        /// Filter expression evaluated by the server.
+        filter: Option<String>,
+    },
+    Unsubscribe {
+        id: u64,
+    },
+    Publish {
+        event: Event,
+    },
+    Invoke {
+        id: u64,
+        module: String,
+        action: String,
+        data: Bytes,
+    },
+    Response {
+        id: u64,
+        result: Result<Bytes, ModuleError>,
+    },
+    Event {
+        id: u64,
+        event: Event,
+    },
+    /// The subscription `id` was closed by the server.
+    Closed {
+        id: u64,
+    },
+    /// The server registered the client's module `name`.
+    Registered {
+        name: String,
+    },
+    /// The server couldn't register the client's module `name`.
+    Rejected {
+        name: String,
+        reason: RegistryError,
+    },
+    /// First frame sent by a client, `token` is empty if it has no credentials.
+    Auth {
+        token: Bytes,
+    },
+    /// The server rejected the credentials and closes the connection.
+    Unauthorized,
+    /// First frame sent by the server, lists the modules registered at that point.
+    Modules {
+        names: Vec<String>,
+    },
+    ModuleAdded {
+        name: String,
+    },
+    ModuleRemoved {
+        name: String,
+    },
+    /// First frame sent by both sides of a federation link.
+    Hello {
+        node: u64,
+    },
+    /// Patterns node `origin` wants events for, `hops` links away from the receiver.
+    /// Replaces the patterns of every lower `version` the origin advertised.
+    Interest {
+        origin: u64,
+        version: u64,
+        hops: u8,
+        patterns: Vec<String>,
+    },
+    /// Event published on node `origin`, forwarded at most `hops` more times.
+    Forward {
+        origin: u64,
+        seq: u64,
+        hops: u8,
+        event: Event,
+    },
+}
+
+mod tag {
+    pub const REGISTER: u8 = 1;
+    pub const DEREGISTER: u8 = 2;
+    pub const SUBSCRIBE: u8 = 3;
+    pub const UNSUBSCRIBE: u8 = 4;
+    pub const PUBLISH: u8 = 5;
+    pub const INVOKE: u8 = 6;
+    pub const RESPONSE: u8 = 7;
+    pub const EVENT: u8 = 8;
+    pub const CLOSED: u8 = 9;
+    pub const MODULE_ADDED: u8 = 10;
+    pub const MODULE_REMOVED: u8 = 11;
+    pub const REJECTED: u8 = 12;
+    pub const MODULES: u8 = 13;
+    pub const HELLO: u8 = 14;
+    pub const INTEREST: u8 = 15;
+    pub const FORWARD: u8 = 16;
+    pub const REGISTERED: u8 = 17;
+    pub const AUTH: u8 = 18;
+    pub const UNAUTHORIZED: u8 = 19;
+}
+
+mod registry_error_tag {
+    pub const ALREADY_EXISTS: u8 = 0;
+    pub const SHUTTING_DOWN: u8 = 1;
+    pub const DENIED: u8 = 2;
+}
+
+mod error_tag {
+    pub const OK: u8 = 0;
+    pub const UNKNOWN_METHOD: u8 = 1;
+    pub const CUSTOM: u8 = 2;
+    pub const DESTROYED: u8 = 3;
+    pub const DENIED: u8 = 4;
+    pub const OVERLOADED: u8 = 5;
+    pub const PAYLOAD_TOO_LARGE: u8 = 6;
+    pub const NOT_FOUND: u8 = 7;
+}
+
+impl Frame {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+
+        match self {
+            Frame::Register { name } => {
+                buf.put_u8(tag::REGISTER);
+                put_str(&mut buf, name);
+            }
+            Frame::Deregister { name } => {
+                buf.put_u8(tag::DEREGISTER);
+                put_str(&mut buf, name);
+            }
+            Frame::Subscribe {
+                id,
+                pattern,
+                filter,
+            } => {
+                buf.put_u8(tag::SUBSCRIBE);
+                buf.put_u64(*id);
+                put_str(&mut buf, pattern);
+                put_opt_str(&mut buf, filter);
+            }
+            Frame::Unsubscribe { id } => {
+                buf.put_u8(tag::UNSUBSCRIBE);
+                buf.put_u64(*id);
+            }
+            Frame::Publish { event } => {
+                buf.put_u8(tag::PUBLISH);
+                put_event(&mut buf, event);
+            }
+            Frame::Invoke {
+                id,
+                module,
+                action,
+                data,
+            } => {
+                buf.put_u8(tag::INVOKE);
+                buf.put_u64(*id);
+                put_str(&mut buf, module);
+                put_str(&mut buf, action);
+                put_bytes(&mut buf, data);
+            }
+            Frame::Response { id, result } => {
+                buf.put_u8(tag::RESPONSE);
+                buf.put_u64(*id);
+                put_result(&mut buf, result);
+            }
+            Frame::Event { id, event } => {
+                buf.put_u8(tag::EVENT);
+                buf.put_u64(*id);
+                put_event(&mut buf, event);
+            }
+            Frame::Closed { id } => {
+                buf.put_u8(tag::CLOSED);
+                buf.put_u64(*id);
+            }
+            Frame::Registered { name } => {
+                buf.put_u8(tag::REGISTERED);
+                put_str(&mut buf, name);
+            }
+            Frame::Rejected { name, reason } => {
+                buf.put_u8(tag::REJECTED);
+                put_str(&mut buf, name);
+                buf.put_u8(match reason {
+                    RegistryError::AlreadyExists => registry_error_tag::ALREADY_EXISTS,
+                    RegistryError::ShuttingDown => registry_error_tag::SHUTTING_DOWN,
+                    RegistryError::Denied => registry_error_tag::DENIED,
+                });
+            }
+            Frame::Auth { token } => {
+                buf.put_u8(tag::AUTH);
+                put_bytes(&mut buf, token);
+            }
+            Frame::Unauthorized => buf.put_u8(tag::UNAUTHORIZED),
+            Frame::Modules { names } => {
+                buf.put_u8(tag::MODULES);
+                buf.put_u32(names.len() as u32);
+                for name in names {
+                    put_str(&mut buf, name);
+                }
+            }
+            Frame::ModuleAdded { name } => {
+                buf.put_u8(tag::MODULE_ADDED);
+                put_str(&mut buf, name);
+            }
+            Frame::ModuleRemoved { name } => {
+                buf.put_u8(tag::MODULE_REMOVED);
+                put_str(&mut buf, name);
+            }
+            Frame::Hello { node } => {
+                buf.put_u8(tag::HELLO);
+                buf.put_u64(*node);
+            }
+            Frame::Interest {
+                origin,
+                version,
+                hops,
+                patterns,
+            } => {
+                buf.put_u8(tag::INTEREST);
+                buf.put_u64(*origin);
+                buf.put_u64(*version);
+                buf.put_u8(*hops);
+                buf.put_u32(patterns.len() as u32);
+                for pattern in patterns {
+                    put_str(&mut buf, pattern);
+                }
+            }
+            Frame::Forward {
+                origin,
+                seq,
+                hops,
+                event,
+            } => {
+                buf.put_u8(tag::FORWARD);
+                buf.put_u64(*origin);
+                buf.put_u64(*seq);
+                buf.put_u8(*hops);
+                put_event(&mut buf, event);
+            }
+        }
+
+        buf.freeze()
+    }
+
+    pub fn decode(mut buf: Bytes) -> io::Result<Self> {
+        let frame = match get_u8(&mut buf)? {
+            tag::REGISTER => Frame::Register {
+                name: get_str(&mut buf)?,
+            },
+            tag::DEREGISTER => Frame::Deregister {
+                name: get_str(&mut buf)?,
+            },
+            tag::SUBSCRIBE => Frame::Subscribe {
+                id: get_u64(&mut buf)?,
+                pattern: get_str(&mut buf)?,
+                filter: get_opt_str(&mut buf)?,
+            },
+            tag::UNSUBSCRIBE => Frame::Unsubscribe {
+                id: get_u64(&mut buf)?,
+            },
+            tag::PUBLISH => Frame::Publish {
+                event: get_event(&mut buf)?,
+            },
+            tag::INVOKE => Frame::Invoke {
+                id: get_u64(&mut buf)?,
+                module: get_str(&mut buf)?,
+                action: get_str(&mut buf)?,
+                data: get_bytes(&mut buf)?,
+            },
+            tag::RESPONSE => Frame::Response {
+                id: get_u64(&mut buf)?,
+                result: get_result(&mut buf)?,
+            },
+            tag::EVENT => Frame::Event {
+                id: get_u64(&mut buf)?,
+                event: get_event(&mut buf)?,
+            },
+            tag::CLOSED => Frame::Closed {
+                id: get_u64(&mut buf)?,
+            },
+            tag::REGISTERED => Frame::Registered {
+                name: get_str(&mut buf)?,
+            },
+            tag::REJECTED => Frame::Rejected {
+                name: get_str(&mut buf)?,
+                reason: match get_u8(&mut buf)? {
+                    registry_error_tag::ALREADY_EXISTS => RegistryError::AlreadyExists,
+                    registry_error_tag::SHUTTING_DOWN => RegistryError::ShuttingDown,
+                    registry_error_tag::DENIED => RegistryError::Denied,
+                    v => return Err(invalid_data(format!("unknown registry error tag {}", v))),
+                },
+            },
+            tag::AUTH => Frame::Auth {
+                token: get_bytes(&mut buf)?,
+            },
+            tag::UNAUTHORIZED => Frame::Unauthorized,
+            tag::MODULES => {
+                ensure(&buf, 4)?;
+                let len = buf.get_u32();
+                let names = (0..len)
+                    .map(|_| get_str(&mut buf))
+                    .collect::<io::Result<_>>()?;
+
+                Frame::Modules { names }
+            }
+            tag::MODULE_ADDED => Frame::ModuleAdded {
+                name: get_str(&mut buf)?,
+            },
+            tag::MODULE_REMOVED => Frame::ModuleRemoved {
+                name: get_str(&mut buf)?,
+            },
+            tag::HELLO => Frame::Hello {
+                node: get_u64(&mut buf)?,
+            },
+            tag::INTEREST => {
+                let origin = get_u64(&mut buf)?;
+                let version = get_u64(&mut buf)?;
+                let hops = get_u8(&mut buf)?;
+
+                ensure(&buf, 4)?;
+                let len = buf.get_u32();
+                let patterns = (0..len)
+                    .map(|_| get_str(&mut buf))
+                    .collect::<io::Result<_>>()?;
+
+                Frame::Interest {
+                    origin,
+                    version,
+                    hops,
+                    patterns,
+                }
+            }
+            tag::FORWARD => Frame::Forward {
+                origin: get_u64(&mut buf)?,
+                seq: get_u64(&mut buf)?,
+                hops: get_u8(&mut buf)?,
+                event: get_event(&mut buf)?,
+            },
+            v => return Err(invalid_data(format!("unknown frame tag {}", v))),
+        };
+
+        Ok(frame)
+    }
+}
+
+/// Splits a socket into a sink and a stream of [`Frame`]s, frames longer than
+/// `max_frame_length` fail the stream.
+pub fn framed<IO>(
+    io: IO,
+    max_frame_length: usize,
+) -> (
+    impl Sink<Frame, Error = io::Error>,
+    impl Stream<Item = io::Result<Frame>>,
+)
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_codec();
+    let (sink, stream) = Framed::new(io, codec).split();
+
+    (
+        sink.with(|frame: Frame| futures::future::ready(Ok(frame.encode()))),
+        stream.map(|v| v.and_then(|v| Frame::decode(v.freeze()))),
+    )
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn put_bytes(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+}
+
+fn put_str(buf: &mut BytesMut, str: &str) {
+    put_bytes(buf, str.as_bytes())
+}
+
+fn put_event(buf: &mut BytesMut, event: &Event) {
+    put_str(buf, &event.topic);
+    put_bytes(buf, &event.data);
+    buf.put_u32(event.headers.len() as u32);
+    for (name, value) in &event.headers {
+        put_str(buf, name);
+        put_str(buf, value);
+    }
+    buf.put_u64(event.seq);
+}
+
+fn put_opt_str(buf: &mut BytesMut, str: &Option<String>) {
+    match str {
+        Some(v) => {
+            buf.put_u8(1);
+            put_str(buf, v);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+fn put_result(buf: &mut BytesMut, result: &Result<Bytes, ModuleError>) {
+    match result {
+        Ok(data) => {
+            buf.put_u8(error_tag::OK);
+            put_bytes(buf, data);
+        }
+        Err(ModuleError::UnknownMethod) => buf.put_u8(error_tag::UNKNOWN_METHOD),
+        Err(ModuleError::Custom(err)) => {
+            buf.put_u8(error_tag::CUSTOM);
+            buf.put_i32(err.code);
+            put_opt_str(buf, &err.name);
+            put_opt_str(buf, &err.message);
+        }
+        Err(ModuleError::Destroyed) => buf.put_u8(error_tag::DESTROYED),
+        Err(ModuleError::Denied) => buf.put_u8(error_tag::DENIED),
+        Err(ModuleError::Overloaded) => buf.put_u8(error_tag::OVERLOADED),
+        Err(ModuleError::PayloadTooLarge(e)) => {
+            buf.put_u8(error_tag::PAYLOAD_TOO_LARGE);
+            buf.put_u64(e.size as u64);
+            buf.put_u64(e.limit as u64);
+        }
+        Err(ModuleError::NotFound) => buf.put_u8(error_tag::NOT_FOUND),
+    }
+}
+
+fn ensure(buf: &Bytes, len: usize) -> io::Result<()> {
+    if buf.remaining() < len {
+        return Err(invalid_data("truncated frame".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn get_u8(buf: &mut Bytes) -> io::Result<u8> {
+    ensure(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+fn get_u64(buf: &mut Bytes) -> io::Result<u64> {
+    ensure(buf, 8)?;
+    Ok(buf.get_u64())
+}
+
+fn get_bytes(buf: &mut Bytes) -> io::Result<Bytes> {
+    ensure(buf, 4)?;
+    let len = buf.get_u32() as usize;
+    ensure(buf, len)?;
+
+    Ok(buf.split_to(len))
+}
+
+fn get_str(buf: &mut Bytes) -> io::Result<String> {
+    let data = get_bytes(buf)?;
+    String::from_utf8(data.to_vec()).map_err(|e| invalid_data(e.to_string()))
+}
+
+fn get_event(buf: &mut Bytes) -> io::Result<Event> {
+    let topic = get_str(buf)?;
+    let data = get_bytes(buf)?;
+
+    ensure(buf, 4)?;
+    let len = buf.get_u32();
+    let headers = (0..len)
+        .map(|_| Ok((get_str(buf)?, get_str(buf)?)))
+        .collect::<io::Result<_>>()?;
+
+    Ok(Event {
+        topic,
+        data,
+        headers,
+        seq: get_u64(buf)?,
+    })
+}
+
+fn get_opt_str(buf: &mut Bytes) -> io::Result<Option<String>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        _ => get_str(buf).map(Some),
+    }
+}
+
+fn get_result(buf: &mut Bytes) -> io::Result<Result<Bytes, ModuleError>> {
+    let result = match get_u8(buf)? {
+        error_tag::OK => Ok(get_bytes(buf)?),
+        error_tag::UNKNOWN_METHOD => Err(ModuleError::UnknownMethod),
+        error_tag::CUSTOM => {
+            ensure(buf, 4)?;
+            Err(ModuleError::Custom(CustomModuleError {
+                code: buf.get_i32(),
+                name: get_opt_str(buf)?,
+                message: get_opt_str(buf)?,
+            }))
+        }
+        error_tag::DESTROYED => Err(ModuleError::Destroyed),
+        error_tag::DENIED => Err(ModuleError::Denied),
+        error_tag::OVERLOADED => Err(ModuleError::Overloaded),
+        error_tag::PAYLOAD_TOO_LARGE => Err(ModuleError::PayloadTooLarge(PayloadTooLarge {
+            size: get_u64(buf)? as usize,
+            limit: get_u64(buf)? as usize,
+        })),
+        error_tag::NOT_FOUND => Err(ModuleError::NotFound),
+        v => return Err(invalid_data(format!("unknown result tag {}", v))),
+    };
+
+    Ok(result)
+}
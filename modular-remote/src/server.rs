@@ -0,0 +1,405 @@
+use crate::calls::{CallFuture, Calls};
+use crate::outbox::Outbox;
+use crate::protocol::{framed, Frame, DEFAULT_MAX_FRAME_LENGTH, DEFAULT_WRITE_QUEUE};
+use crate::sink::ForwardSink;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use modular_core::error::{ModuleError, SubscribeError};
+use modular_core::filter::Filter;
+use modular_core::modular::Modular as _;
+use modular_core::module::Module;
+use modular_core::modules::{Event, ModuleRequest, ModuleResponse};
+use modular_rs::core::acl::{Acl, Permission, ScopedModular};
+use modular_rs::core::modules::is_system_module;
+use modular_rs::core::{Modular, MODULE_DEREGISTERED_TOPIC, MODULE_REGISTERED_TOPIC};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tower::Service;
+
+/// Room for everything but the payload in a frame, see [`Server::with_max_frame_length`].
+const FRAME_OVERHEAD: usize = 64 * 1024;
+
+/// Maps the token a client presents to its identity and ACL, `None` rejects the client.
+pub type Authenticate = Arc<dyn Fn(&[u8]) -> Option<(String, Acl)> + Send + Sync>;
+
+type InvokeFuture = BoxFuture<
+    'static,
+    Result<BoxFuture<'static, Result<ModuleResponse, ModuleError>>, ModuleError>,
+>;
+
+/// Exposes a [`Modular`] instance to [`RemoteModular`](crate::client::RemoteModular)
+/// clients.
+///
+/// Modules registered by a client are deregistered when its connection closes.
+#[derive(Clone)]
+pub struct Server {
+    modular: Arc<Modular>,
+    max_frame_length: Option<usize>,
+    write_queue: usize,
+    authenticate: Option<Authenticate>,
+}
+
+struct ServerConnection {
+    modular: Arc<Modular>,
+    /// Set if the client authenticated, its operations are checked against its ACL.
+    scope: Option<ScopedModular>,
+    tx: Outbox,
+    calls: Calls,
+    subscriptions: Mutex<HashMap<u64, Arc<AtomicBool>>>,
+    modules: Mutex<Vec<String>>,
+}
+
+impl Server {
+    pub fn new(modular: Arc<Modular>) -> Self {
+        Self {
+            modular,
+            max_frame_length: None,
+            write_queue: DEFAULT_WRITE_QUEUE,
+            authenticate: None,
+        }
+    }
+
+    /// Limits the length of frames, connections sending longer ones are closed.
+    ///
+    /// By default the limit leaves room for the largest payload the instance accepts,
+    /// see [`Modular::max_payload`], or is [`DEFAULT_MAX_FRAME_LENGTH`] if payloads are
+    /// unlimited.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = Some(max_frame_length);
+        self
+    }
+
+    /// Limits how many frames are queued for a client, clients that don't read them
+    /// fast enough are disconnected. Defaults to [`DEFAULT_WRITE_QUEUE`].
+    pub fn with_write_queue(mut self, capacity: usize) -> Self {
+        self.write_queue = capacity;
+        self
+    }
+
+    /// Requires clients to authenticate with a token, see
+    /// [`ClientOptions::token`](crate::client::ClientOptions::token). Authenticated clients
+    /// act as the identity `authenticate` returns, limited to what its ACL allows.
+    pub fn with_authenticator<F>(mut self, authenticate: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<(String, Acl)> + Send + Sync + 'static,
+    {
+        self.authenticate = Some(Arc::new(authenticate));
+        self
+    }
+
+    fn max_frame_length(&self) -> usize {
+        self.max_frame_length.unwrap_or_else(|| {
+            self.modular
+                .max_payload()
+                .map_or(DEFAULT_MAX_FRAME_LENGTH, |v| {
+                    v.saturating_add(FRAME_OVERHEAD)
+                })
+        })
+    }
+
+    pub async fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    tracing::debug!(error = %e, %addr, "connection closed with error");
+                }
+            });
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, listener: tokio::net::UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    tracing::debug!(error = %e, "connection closed with error");
+                }
+            });
+        }
+    }
+
+    /// Serves a single client until it disconnects.
+    pub async fn serve_connection<IO>(&self, io: IO) -> io::Result<()>
+    where
+        IO: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut sink, mut stream) = framed(io, self.max_frame_length());
+
+        let scope = match &self.authenticate {
+            Some(authenticate) => {
+                let scope = match stream.next().await {
+                    Some(Ok(Frame::Auth { token })) => authenticate(&token)
+                        .map(|(identity, acl)| self.modular.scoped(&identity, acl)),
+                    Some(Ok(_)) => None,
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                };
+
+                if scope.is_none() {
+                    sink.send(Frame::Unauthorized).await?;
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+
+                scope
+            }
+            None => None,
+        };
+
+        let (tx, rx) = Outbox::new(self.write_queue);
+
+        let connection = Arc::new(ServerConnection {
+            modular: self.modular.clone(),
+            scope,
+            tx,
+            calls: Calls::default(),
+            subscriptions: Default::default(),
+            modules: Default::default(),
+        });
+
+        // subscribe before taking the snapshot so no change is lost in between
+        let visible = connection.scope.clone();
+        let visible = move |name: &str| {
+            !is_system_module(name)
+                && visible
+                    .as_ref()
+                    .is_none_or(|v| v.acl().is_allowed(Permission::Invoke, name))
+        };
+        let registry_closed =
+            forward_module_changes(&self.modular, &connection.tx, visible.clone());
+
+        let mut names = self.modular.module_names();
+        names.retain(|name| visible(name));
+        sink.send(Frame::Modules { names }).await?;
+
+        let mut writer = tokio::spawn(rx.write_to(sink));
+
+        let result = loop {
+            let frame = tokio::select! {
+                frame = stream.next() => frame,
+                written = &mut writer => break written.unwrap_or_else(|e| Err(io::Error::other(e))),
+            };
+
+            match frame {
+                Some(Ok(frame)) => connection.handle(frame),
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            }
+        };
+
+        registry_closed.store(true, Ordering::Release);
+        connection.close();
+        writer.abort();
+
+        result
+    }
+}
+
+impl ServerConnection {
+    fn handle(self: &Arc<Self>, frame: Frame) {
+        match frame {
+            Frame::Register { name } => {
+                let service = ProxyService {
+                    name: name.clone(),
+                    connection: self.clone(),
+                };
+
+                let result = match &self.scope {
+                    Some(scope) => scope.register_module(&name, service),
+                    None => self.modular.register_module(&name, service),
+                };
+
+                match result {
+                    Ok(()) => {
+                        self.modules.lock().push(name.clone());
+                        let _ = self.tx.send(Frame::Registered { name });
+                    }
+                    Err(reason) => {
+                        let _ = self.tx.send(Frame::Rejected { name, reason });
+                    }
+                }
+            }
+            Frame::Deregister { name } => {
+                let mut modules = self.modules.lock();
+                if let Some(idx) = modules.iter().position(|i| *i == name) {
+                    modules.swap_remove(idx);
+                    self.modular.deregister_module(&name);
+                }
+            }
+            Frame::Subscribe {
+                id,
+                pattern,
+                filter,
+            } => {
+                let closed = Arc::new(AtomicBool::new(false));
+                let sink = ForwardSink {
+                    tx: self.tx.clone(),
+                    closed: closed.clone(),
+                    frame: move |event| Some(Frame::Event { id, event }),
+                };
+
+                if self.subscribe_sink(&pattern, filter.as_deref(), sink) {
+                    self.subscriptions.lock().insert(id, closed);
+                } else {
+                    let _ = self.tx.send(Frame::Closed { id });
+                }
+            }
+            Frame::Unsubscribe { id } => {
+                if let Some(closed) = self.subscriptions.lock().remove(&id) {
+                    closed.store(true, Ordering::Release);
+                }
+            }
+            Frame::Publish { event } => match &self.scope {
+                Some(scope) => scope.publish(event),
+                None => self.modular.publish(event),
+            },
+            Frame::Invoke {
+                id,
+                module,
+                action,
+                data,
+            } => match &self.scope {
+                Some(scope) => serve_invoke(scope.get_module(&module), &self.tx, id, action, data),
+                None => serve_invoke(self.modular.get_module(&module), &self.tx, id, action, data),
+            },
+            Frame::Response { id, result } => self.calls.complete(id, result),
+            // only checked with an authenticator
+            Frame::Auth { .. } => {}
+            frame => tracing::debug!(?frame, "unexpected frame from client"),
+        }
+    }
+
+    fn subscribe_sink<F>(&self, pattern: &str, filter: Option<&str>, sink: ForwardSink<F>) -> bool
+    where
+        F: Fn(Event) -> Option<Frame> + Send + Sync + Unpin + 'static,
+    {
+        let result = filter
+            .map(Filter::parse)
+            .transpose()
+            .map_err(SubscribeError::from)
+            .and_then(|filter| {
+                let filter = move |event: &Event| filter.as_ref().is_none_or(|v| v.matches(event));
+                match &self.scope {
+                    Some(scope) => scope.subscribe_filtered(pattern, filter, sink),
+                    None => self.modular.subscribe_filtered(pattern, filter, sink),
+                }
+            });
+
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                tracing::debug!(error = %e, pattern, filter, "subscription rejected");
+                false
+            }
+        }
+    }
+
+    fn close(&self) {
+        for (_, closed) in self.subscriptions.lock().drain() {
+            closed.store(true, Ordering::Release);
+        }
+
+        for name in std::mem::take(&mut *self.modules.lock()) {
+            self.modular.deregister_module(&name);
+        }
+
+        self.calls.fail_all();
+    }
+}
+
+/// Module registered on behalf of a client, invokes are sent over its connection.
+struct ProxyService {
+    name: String,
+    connection: Arc<ServerConnection>,
+}
+
+impl Service<ModuleRequest> for ProxyService {
+    type Response = ModuleResponse;
+    type Error = ModuleError;
+    type Future = CallFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ModuleRequest) -> Self::Future {
+        self.connection
+            .calls
+            .invoke(&self.connection.tx, self.name.clone(), req.action, req.body)
+    }
+}
+
+fn subscribe_sink<F>(modular: &Modular, pattern: &str, sink: ForwardSink<F>) -> bool
+where
+    F: Fn(Event) -> Option<Frame> + Send + Sync + Unpin + 'static,
+{
+    match modular.subscribe(pattern, Some(sink)) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::debug!(error = %e, pattern, "subscription rejected");
+            false
+        }
+    }
+}
+
+/// Sends `ModuleAdded` and `ModuleRemoved` frames for changes of the registry until the
+/// returned flag is set, skipping modules `visible` rejects.
+pub(crate) fn forward_module_changes(
+    modular: &Modular,
+    tx: &Outbox,
+    visible: impl Fn(&str) -> bool + Send + Sync + Unpin + 'static,
+) -> Arc<AtomicBool> {
+    let closed = Arc::new(AtomicBool::new(false));
+    let sink = ForwardSink {
+        tx: tx.clone(),
+        closed: closed.clone(),
+        frame: move |event: Event| {
+            let name = String::from_utf8_lossy(&event.data).into_owned();
+            if !visible(&name) {
+                return None;
+            }
+
+            match event.topic.as_str() {
+                MODULE_REGISTERED_TOPIC => Some(Frame::ModuleAdded { name }),
+                MODULE_DEREGISTERED_TOPIC => Some(Frame::ModuleRemoved { name }),
+                _ => None,
+            }
+        },
+    };
+
+    subscribe_sink(modular, "$.sys.modules.>", sink);
+    closed
+}
+
+/// Invokes a local module on behalf of the other side and sends back the `Response`.
+pub(crate) fn serve_invoke<M>(module: Option<M>, tx: &Outbox, id: u64, action: String, data: Bytes)
+where
+    M: Module<Future = InvokeFuture> + Send + 'static,
+{
+    let tx = tx.clone();
+
+    tokio::spawn(async move {
+        let result = match module {
+            Some(module) => match module.invoke(ModuleRequest::new(&action, data)).await {
+                Ok(fut) => fut.await.map(|v| v.data),
+                Err(e) => Err(e),
+            },
+            None => Err(ModuleError::NotFound),
+        };
+
+        let _ = tx.send(Frame::Response { id, result });
+    });
+}
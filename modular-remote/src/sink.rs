@@ -0,0 +1,49 @@
+use crate::outbox::Outbox;
+use crate::protocol::Frame;
+use futures::Sink;
+use modular_core::event::Event;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Forwards events of a subscription over a connection until `closed` is set.
+pub(crate) struct ForwardSink<F> {
+    pub tx: Outbox,
+    pub closed: Arc<AtomicBool>,
+    pub frame: F,
+}
+
+impl<F> Sink<Event> for ForwardSink<F>
+where
+    F: Fn(Event) -> Option<Frame> + Unpin,
+{
+    type Error = ();
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.closed.load(Ordering::Acquire) {
+            return Poll::Ready(Err(()));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, event: Event) -> Result<(), Self::Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(());
+        }
+
+        match (self.frame)(event) {
+            Some(frame) => self.tx.send(frame),
+            None => Ok(()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,22 @@
+use modular_core::error::ModuleError;
+use modular_core::modules::{ModuleRequest, ModuleResponse};
+use std::time::Duration;
+
+pub fn echo() -> impl tower::Service<
+    ModuleRequest,
+    Response = ModuleResponse,
+    Error = ModuleError,
+    Future = futures::future::Ready<Result<ModuleResponse, ModuleError>>,
+> + Clone {
+    tower::service_fn(|req: ModuleRequest| futures::future::ok(ModuleResponse::new(req.body)))
+}
+
+pub async fn eventually(condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    panic!("timed out");
+}
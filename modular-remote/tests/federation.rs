@@ -0,0 +1,206 @@
+mod common;
+
+use bytes::Bytes;
+use common::{echo, eventually};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use modular_core::modular::Modular as _;
+use modular_core::modules::{Event, ModuleRequest};
+use modular_remote::federation::Federation;
+use modular_rs::core::acl::{Acl, Permission};
+use modular_rs::core::Modular;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Publishes `event` on `from` until it arrives at `rx`, the interest of the receiving
+/// node takes a moment to reach the publisher.
+async fn deliver(
+    from: &Federation,
+    event: impl Fn() -> Event,
+    rx: &mut mpsc::UnboundedReceiver<Event>,
+) -> Event {
+    for _ in 0..500 {
+        from.publish(event());
+        if let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(10), rx.next()).await {
+            return event;
+        }
+    }
+    panic!("timed out");
+}
+
+/// Links `connecting` to `listening` over a TCP socket on the loopback interface.
+async fn link(listening: &Arc<Federation>, connecting: &Federation) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let federation = listening.clone();
+    tokio::spawn(async move { federation.listen_tcp(listener).await });
+    connecting.connect_tcp(addr.to_string());
+}
+
+#[tokio::test]
+async fn two_nodes_share_events_and_modules() {
+    let a = Arc::new(Federation::new(Arc::new(Modular::default())));
+    let b = Federation::new(Arc::new(Modular::default()));
+    link(&a, &b).await;
+
+    b.register_module("svc.echo", echo()).unwrap();
+    let (tx, mut rx) = mpsc::unbounded();
+    b.subscribe("a.>", Some(tx)).unwrap();
+
+    let event = deliver(
+        &a,
+        || Event::new("a.x", Bytes::from("hello")).with_header("trace", "1"),
+        &mut rx,
+    )
+    .await;
+    assert_eq!(event.topic, "a.x");
+    assert_eq!(event.data, "hello");
+    assert_eq!(event.header("trace"), Some("1"));
+
+    eventually(|| a.get_module("svc.echo").is_some()).await;
+    let response = a
+        .get_module("svc.echo")
+        .unwrap()
+        .invoke(ModuleRequest::new("run", Bytes::from("ping")))
+        .await
+        .unwrap();
+    assert_eq!(response.data, "ping");
+}
+
+#[tokio::test]
+async fn peers_are_limited_by_their_acl() {
+    let a = Arc::new(
+        Federation::new(Arc::new(Modular::default())).with_authenticator(|token| {
+            let acl = Acl::new()
+                .allow(Permission::Publish, "a.>")
+                .and_then(|acl| acl.allow(Permission::Subscribe, "b.>"))
+                .and_then(|acl| acl.allow(Permission::Invoke, "svc.public"))
+                .unwrap();
+
+            (token == b"secret").then(|| ("peer".to_owned(), acl))
+        }),
+    );
+    let b = Federation::new(Arc::new(Modular::default())).with_token("secret");
+    link(&a, &b).await;
+
+    a.register_module("svc.public", echo()).unwrap();
+    a.register_module("svc.private", echo()).unwrap();
+    eventually(|| b.get_module("svc.public").is_some()).await;
+    assert!(b.get_module("svc.private").is_none());
+
+    // events the peer may not publish are dropped, those after them arrive
+    let (tx, mut rx) = mpsc::unbounded();
+    a.subscribe("{}.x", Some(tx)).unwrap();
+    let event = deliver(
+        &b,
+        || {
+            b.publish(Event::new("c.x", Bytes::new()));
+            Event::new("a.x", Bytes::new())
+        },
+        &mut rx,
+    )
+    .await;
+    assert_eq!(event.topic, "a.x");
+
+    // events the peer may not subscribe to aren't forwarded to it
+    let (tx, mut rx) = mpsc::unbounded();
+    b.subscribe("{}.y", Some(tx)).unwrap();
+    let event = deliver(
+        &a,
+        || {
+            a.publish(Event::new("c.y", Bytes::new()));
+            Event::new("b.y", Bytes::new())
+        },
+        &mut rx,
+    )
+    .await;
+    assert_eq!(event.topic, "b.y");
+}
+
+#[tokio::test]
+async fn peers_have_to_authenticate() {
+    let a = Federation::new(Arc::new(Modular::default()))
+        .with_authenticator(|token| (token == b"secret").then(|| ("peer".to_owned(), Acl::new())));
+    let b = Federation::new(Arc::new(Modular::default())).with_token("wrong");
+
+    let (a_io, b_io) = tokio::io::duplex(1 << 16);
+    let (a_result, b_result) = tokio::join!(a.link(a_io), b.link(b_io));
+
+    assert_eq!(
+        a_result.unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        b_result.unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert!(a.peers().is_empty());
+}
+
+#[tokio::test]
+async fn connect_tcp_reconnects_after_the_link_drops() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let a = Arc::new(Federation::new(Arc::new(Modular::default())));
+    let listening = tokio::spawn({
+        let a = a.clone();
+        async move { a.listen_tcp(listener).await }
+    });
+
+    let b = Federation::new(Arc::new(Modular::default()));
+    b.connect_tcp(addr.to_string());
+    eventually(|| b.peers() == [a.node_id()]).await;
+
+    // dropping the node closes its links and the listener frees the address
+    listening.abort();
+    let _ = listening.await;
+    drop(Arc::into_inner(a).unwrap());
+    eventually(|| b.peers().is_empty()).await;
+
+    // connecting fails until the next node listens, b keeps retrying with backoff
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let c = Arc::new(Federation::new(Arc::new(Modular::default())));
+    let listener = TcpListener::bind(addr).await.unwrap();
+    tokio::spawn({
+        let c = c.clone();
+        async move { c.listen_tcp(listener).await }
+    });
+    eventually(|| b.peers() == [c.node_id()]).await;
+
+    let (tx, mut rx) = mpsc::unbounded();
+    c.subscribe("a.>", Some(tx)).unwrap();
+    let event = deliver(&b, || Event::new("a.x", Bytes::new()), &mut rx).await;
+    assert_eq!(event.topic, "a.x");
+}
+
+#[tokio::test]
+async fn events_arrive_once_around_a_cycle() {
+    let a = Arc::new(Federation::new(Arc::new(Modular::default())));
+    let b = Arc::new(Federation::new(Arc::new(Modular::default())));
+    let c = Arc::new(Federation::new(Arc::new(Modular::default())));
+    link(&a, &b).await;
+    link(&b, &c).await;
+    link(&c, &a).await;
+    for node in [&a, &b, &c] {
+        eventually(|| node.peers().len() == 2).await;
+    }
+
+    // wait until c's interest reached both other nodes
+    let (tx, mut rx) = mpsc::unbounded();
+    c.subscribe("a.>", Some(tx)).unwrap();
+    deliver(&a, || Event::new("a.warmup", Bytes::new()), &mut rx).await;
+    deliver(&b, || Event::new("a.warmup", Bytes::new()), &mut rx).await;
+
+    a.publish(Event::new("a.once", Bytes::new()));
+    let mut received = 0;
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(200), rx.next()).await {
+        if event.topic == "a.once" {
+            received += 1;
+        }
+    }
+    assert_eq!(received, 1);
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use bytes::Bytes;
+use common::{echo, eventually};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use modular_core::error::{ModuleError, RegistryError};
+use modular_core::modular::Modular as _;
+use modular_core::module::Module as _;
+use modular_core::modules::{Event, ModuleRequest};
+use modular_remote::client::{ClientOptions, RemoteModular};
+use modular_remote::server::Server;
+use modular_rs::core::acl::{Acl, Permission};
+use modular_rs::core::Modular;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+async fn connect(
+    server: Server,
+    options: ClientOptions,
+) -> io::Result<(RemoteModular, JoinHandle<io::Result<()>>)> {
+    let (server_io, client_io) = tokio::io::duplex(1 << 20);
+    let serving = tokio::spawn(async move { server.serve_connection(server_io).await });
+    let client = RemoteModular::from_io_with(client_io, options).await?;
+
+    Ok((client, serving))
+}
+
+fn authenticated(server: Server) -> Server {
+    server.with_authenticator(|token| {
+        let acl = Acl::new()
+            .allow(Permission::Register, "plugins.>")
+            .and_then(|acl| acl.allow(Permission::Invoke, "svc.public"))
+            .and_then(|acl| acl.allow(Permission::Publish, "a.>"))
+            .unwrap();
+
+        (token == b"secret").then(|| ("plugin".to_owned(), acl))
+    })
+}
+
+fn token(token: &str) -> ClientOptions {
+    ClientOptions {
+        token: Some(Bytes::copy_from_slice(token.as_bytes())),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn invoking_a_removed_module_is_not_found() {
+    let modular = Arc::new(Modular::default());
+    modular.register_module("svc.echo", echo()).unwrap();
+
+    let (client, _serving) = connect(Server::new(modular.clone()), Default::default())
+        .await
+        .unwrap();
+    let module = client.get_module("svc.echo").unwrap();
+
+    modular.deregister_module("svc.echo");
+    eventually(|| client.get_module("svc.echo").is_none()).await;
+
+    let result = module.invoke(ModuleRequest::new("run", Bytes::new())).await;
+    assert!(matches!(result, Err(ModuleError::NotFound)));
+}
+
+#[tokio::test]
+async fn registration_is_confirmed() {
+    let modular = Arc::new(Modular::default());
+    modular.register_module("svc.taken", echo()).unwrap();
+
+    let (client, _serving) = connect(Server::new(modular.clone()), Default::default())
+        .await
+        .unwrap();
+
+    client
+        .register_module_confirmed("svc.client", echo())
+        .await
+        .unwrap();
+    let module = modular.get_module("svc.client").unwrap();
+    let response = module
+        .invoke(ModuleRequest::new("run", Bytes::from("ping")))
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+    assert_eq!(response.data, "ping");
+
+    let result = client.register_module_confirmed("svc.taken", echo()).await;
+    assert!(matches!(result, Err(RegistryError::AlreadyExists)));
+}
+
+#[tokio::test]
+async fn registration_is_rejected_by_the_acl() {
+    let modular = Arc::new(Modular::default());
+    let server = authenticated(Server::new(modular.clone()));
+    let (client, _serving) = connect(server, token("secret")).await.unwrap();
+
+    let result = client.register_module_confirmed("other.x", echo()).await;
+    assert!(matches!(result, Err(RegistryError::Denied)));
+    assert!(modular.get_module("other.x").is_none());
+
+    client
+        .register_module_confirmed("plugins.x", echo())
+        .await
+        .unwrap();
+    assert!(modular.get_module("plugins.x").is_some());
+}
+
+#[tokio::test]
+async fn clients_have_to_authenticate() {
+    let modular = Arc::new(Modular::default());
+    let server = authenticated(Server::new(modular));
+
+    for options in [token("wrong"), ClientOptions::default()] {
+        let error = connect(server.clone(), options).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
+
+#[tokio::test]
+async fn authenticated_clients_are_limited_by_their_acl() {
+    let modular = Arc::new(Modular::default());
+    modular.register_module("svc.public", echo()).unwrap();
+    modular.register_module("svc.private", echo()).unwrap();
+
+    let (tx, mut rx) = mpsc::unbounded::<Event>();
+    modular.subscribe("{}.x", Some(tx)).unwrap();
+
+    let server = authenticated(Server::new(modular.clone()));
+    let (client, _serving) = connect(server, token("secret")).await.unwrap();
+
+    assert!(client.get_module("svc.public").is_some());
+    assert!(client.get_module("svc.private").is_none());
+
+    client.publish(Event::new("b.x", Bytes::new()));
+    client.publish(Event::new("a.x", Bytes::new()));
+
+    let event = tokio::time::timeout(Duration::from_secs(5), rx.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.topic, "a.x");
+}
+
+#[tokio::test]
+async fn oversized_frames_close_the_connection() {
+    let modular = Arc::new(Modular::default());
+    let server = Server::new(modular).with_max_frame_length(1024);
+    let (client, serving) = connect(server, Default::default()).await.unwrap();
+
+    client.publish(Event::new("a.x", Bytes::from(vec![0; 2048])));
+
+    let error = serving.await.unwrap().err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn frame_limit_follows_the_payload_limit() {
+    let modular = Arc::new(Modular::default());
+    modular.set_max_payload(Some(16));
+    let (client, serving) = connect(Server::new(modular), Default::default())
+        .await
+        .unwrap();
+
+    client.publish(Event::new("a.x", Bytes::from(vec![0; 1024])));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!serving.is_finished());
+
+    client.publish(Event::new("a.x", Bytes::from(vec![0; 1 << 20])));
+
+    let error = serving.await.unwrap().err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
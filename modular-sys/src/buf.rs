@@ -0,0 +1,146 @@
+use crate::{CBuf, Obj};
+use bytes::Bytes;
+use std::ptr::null_mut;
+
+/// Payloads of at least this size are handed over as owned buffers instead of being copied.
+///
+/// Below one page a `memcpy` is cheaper than the boxed owner and the release call an owned
+/// buffer costs, so small payloads are borrowed and copied by the receiver.
+pub const ZERO_COPY_THRESHOLD: usize = 4096;
+
+impl CBuf {
+    /// Borrows `data` for the duration of a single call.
+    pub fn borrowed(data: &[u8]) -> Self {
+        Self {
+            data: data.as_ptr(),
+            len: data.len(),
+            owner: Obj(null_mut()),
+            release: None,
+        }
+    }
+
+    /// Moves `data` across the ABI without copying it.
+    pub fn owned(data: Bytes) -> Self {
+        unsafe extern "system" fn release(owner: Obj) {
+            let _ = Box::from_raw(owner.0 as *mut Bytes);
+        }
+
+        let data = Box::new(data);
+
+        Self {
+            data: data.as_ptr(),
+            len: data.len(),
+            owner: Obj(Box::into_raw(data).cast()),
+            release: Some(release),
+        }
+    }
+
+    /// Borrows small payloads and transfers ownership of large ones.
+    ///
+    /// `data` has to outlive the call the buffer is passed to.
+    pub fn from_bytes(data: &Bytes) -> Self {
+        if data.len() >= ZERO_COPY_THRESHOLD {
+            Self::owned(data.clone())
+        } else {
+            Self::borrowed(data)
+        }
+    }
+
+    pub fn is_owned(&self) -> bool {
+        self.release.is_some()
+    }
+
+    /// Takes the payload, reusing the sender's allocation when the buffer is owned.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to `len` readable bytes and an owned buffer must not be used again.
+    pub unsafe fn into_bytes(self) -> Bytes {
+        if self.release.is_some() {
+            return Bytes::from_owner(OwnedCBuf(self));
+        }
+
+        if self.len == 0 {
+            return Bytes::new();
+        }
+
+        Bytes::copy_from_slice(std::slice::from_raw_parts(self.data, self.len))
+    }
+
+    /// Frees an owned buffer the receiver is not going to read.
+    ///
+    /// # Safety
+    ///
+    /// An owned buffer must not be used again.
+    pub unsafe fn release(self) {
+        if let Some(release) = self.release {
+            release(self.owner)
+        }
+    }
+}
+
+struct OwnedCBuf(CBuf);
+
+unsafe impl Send for OwnedCBuf {}
+
+impl AsRef<[u8]> for OwnedCBuf {
+    fn as_ref(&self) -> &[u8] {
+        if self.0.len == 0 {
+            return &[];
+        }
+
+        unsafe { std::slice::from_raw_parts(self.0.data, self.0.len) }
+    }
+}
+
+impl Drop for OwnedCBuf {
+    fn drop(&mut self) {
+        unsafe { std::mem::take(&mut self.0).release() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_payloads_are_borrowed_and_copied() {
+        let data = Bytes::from(vec![7u8; ZERO_COPY_THRESHOLD - 1]);
+        let buf = CBuf::from_bytes(&data);
+        assert!(!buf.is_owned());
+
+        let bytes = unsafe { buf.into_bytes() };
+        assert_eq!(bytes, data);
+        assert_ne!(bytes.as_ptr(), data.as_ptr());
+    }
+
+    #[test]
+    fn large_payloads_are_handed_over() {
+        let data = Bytes::from(vec![7u8; ZERO_COPY_THRESHOLD]);
+        let buf = CBuf::from_bytes(&data);
+        assert!(buf.is_owned());
+
+        let bytes = unsafe { buf.into_bytes() };
+        assert_eq!(bytes, data);
+        assert_eq!(bytes.as_ptr(), data.as_ptr());
+    }
+
+    #[test]
+    fn owned_buffer_is_released_once() {
+        let data = Bytes::from(vec![7u8; ZERO_COPY_THRESHOLD]);
+        let bytes = unsafe { CBuf::owned(data.clone()).into_bytes() };
+        assert!(!data.is_unique());
+
+        drop(bytes);
+        assert!(data.is_unique());
+
+        unsafe { CBuf::owned(data.clone()).release() };
+        assert!(data.is_unique());
+    }
+
+    #[test]
+    fn empty_buffers() {
+        assert!(unsafe { CBuf::borrowed(&[]).into_bytes() }.is_empty());
+        assert!(unsafe { CBuf::owned(Bytes::new()).into_bytes() }.is_empty());
+    }
+}
@@ -1,12 +1,15 @@
 use crate::{
-    CBuf, CCallback, CModule, CModuleError, CModuleRef, CSubscribe, CSubscriptionRef,
-    NativeModularVTable, Obj,
+    CBuf, CCallback, CEvent, CEventBuf, CFilterError, CLogHandler, CLogLevel, CLogRecord, CModule,
+    CModuleError, CModuleRef, CPatternError, CPayloadTarget, CScope, CSubscribe, CSubscriptionRef,
+    NativeModularVTable, Obj, ABI_VERSION,
 };
 use bytes::Bytes;
 use futures_util::future::BoxFuture;
 use futures_util::stream::BoxStream;
 use futures_util::{FutureExt, Sink, Stream, StreamExt};
 use modular_core::error::*;
+use modular_core::event::Event;
+use modular_core::filter::Filter;
 use modular_core::modular::{BoxModule, Modular};
 use modular_core::module::Module;
 use modular_core::modules::{ModuleRequest, ModuleResponse};
@@ -16,16 +19,49 @@ use std::collections::VecDeque;
 use std::ffi::{c_char, CStr, CString};
 use std::future::Future;
 use std::pin::Pin;
-use std::ptr::null;
+use std::ptr::{null, null_mut};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
-use tokio::runtime::Handle;
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
 use tokio::spawn;
 use tower::Service;
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+
+/// How long dropping a [`LibraryModular`] waits for in-flight invokes.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct LibraryModular {
     ptr: Obj,
     vtable: NativeModularVTable,
+    shutdown_timeout: Duration,
+}
+
+/// Capabilities of a handle created with [`LibraryModular::scoped`].
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    /// Name of the handle reported when an operation is denied.
+    pub identity: Option<String>,
+    /// Modules can only be registered under `{register_prefix}.`, or not at all if unset.
+    /// Prefixes and module names with wildcards or empty segments are rejected.
+    pub register_prefix: Option<String>,
+    /// Patterns that can be subscribed to, along with the patterns they cover.
+    pub subscribe: Vec<String>,
+    /// Names of the modules that can be invoked.
+    pub invoke: Vec<String>,
+    /// Patterns of the topics that can be published to.
+    pub publish: Vec<String>,
+}
+
+/// What a limit set with [`LibraryModular::set_max_payload`] applies to.
+#[derive(Debug, Copy, Clone)]
+pub enum PayloadTarget<'a> {
+    Instance,
+    /// Topics matching the pattern.
+    Topic(&'a str),
+    /// Modules whose name matches the pattern.
+    Module(&'a str),
 }
 
 impl LibraryModular {
@@ -35,7 +71,24 @@ impl LibraryModular {
             let name = libloading::library_filename("modular_native");
             let lib = unsafe { libloading::Library::new(name)? };
 
-            Result::<_, libloading::Error>::Ok(lib)
+            let version =
+                unsafe { lib.get::<extern "system" fn() -> u32>(b"__modular_abi_version") }
+                    .map(|version| version())
+                    .unwrap_or(0);
+            anyhow::ensure!(
+                version == ABI_VERSION,
+                "modular_native ABI version {version} is not supported, expected {ABI_VERSION}"
+            );
+
+            // the handler lives in the library, so it's installed once per load
+            unsafe {
+                let vtable = lib.get::<extern "system" fn() -> *const NativeModularVTable>(
+                    b"__modular_vtable",
+                )?();
+                ((*vtable).set_log_handler)(host_log_handler());
+            }
+
+            anyhow::Ok(lib)
         })?;
 
         let (ptr, vtable) = unsafe {
@@ -48,11 +101,247 @@ impl LibraryModular {
             (ptr, *vtable)
         };
 
-        Ok(Self { ptr, vtable })
+        Ok(Self {
+            ptr,
+            vtable,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        })
+    }
+
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Derives a handle that can only do what `scope` allows. Returns `None` if this is
+    /// a scoped handle itself or a pattern of `scope` is invalid.
+    pub fn scoped(&self, scope: &Scope) -> Option<Self> {
+        let cstr = |v: &str| CString::new(v).unwrap();
+        let cstrs = |v: &[String]| v.iter().map(|v| cstr(v)).collect::<Vec<_>>();
+        let ptrs = |v: &[CString]| v.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
+
+        let identity = scope.identity.as_deref().map(cstr);
+        let register_prefix = scope.register_prefix.as_deref().map(cstr);
+        let subscribe = cstrs(&scope.subscribe);
+        let invoke = cstrs(&scope.invoke);
+        let publish = cstrs(&scope.publish);
+        let (subscribe_ptrs, invoke_ptrs, publish_ptrs) =
+            (ptrs(&subscribe), ptrs(&invoke), ptrs(&publish));
+
+        let c_scope = CScope {
+            identity: identity.as_ref().map_or(null(), |v| v.as_ptr()),
+            register_prefix: register_prefix.as_ref().map_or(null(), |v| v.as_ptr()),
+            subscribe: subscribe_ptrs.as_ptr(),
+            subscribe_len: subscribe_ptrs.len(),
+            invoke: invoke_ptrs.as_ptr(),
+            invoke_len: invoke_ptrs.len(),
+            publish: publish_ptrs.as_ptr(),
+            publish_len: publish_ptrs.len(),
+        };
+
+        let ptr = unsafe { (self.vtable.create_scoped)(self.ptr, &c_scope) };
+        if ptr.0.is_null() {
+            return None;
+        }
+
+        Some(Self {
+            ptr,
+            vtable: self.vtable,
+            shutdown_timeout: self.shutdown_timeout,
+        })
+    }
+
+    /// The instance and its vtable, e.g. to hand a scoped handle to a plugin. The
+    /// instance stays owned by `self`.
+    pub fn as_raw(&self) -> (Obj, NativeModularVTable) {
+        (self.ptr, self.vtable)
+    }
+
+    /// Sets or, with `None`, removes the maximum payload size of `target`. Returns false
+    /// if the pattern is invalid or this is a scoped handle.
+    pub fn set_max_payload(&self, target: PayloadTarget<'_>, limit: Option<usize>) -> bool {
+        let (target, pattern) = match target {
+            PayloadTarget::Instance => (CPayloadTarget::Instance, None),
+            PayloadTarget::Topic(v) => (CPayloadTarget::Topic, Some(v)),
+            PayloadTarget::Module(v) => (CPayloadTarget::Module, Some(v)),
+        };
+        let pattern = pattern.map(|v| CString::new(v).unwrap());
+        let pattern = pattern.as_ref().map_or(null(), |v| v.as_ptr());
+        let limit = limit.unwrap_or(usize::MAX);
+
+        unsafe { (self.vtable.set_max_payload)(self.ptr, target, pattern, limit) == 0 }
+    }
+
+    /// Like [`Modular::publish`], returns false if the event was dropped because its
+    /// payload exceeds the limit of the topic or its topic or headers contain a NUL byte.
+    pub fn try_publish(&self, event: Event) -> bool {
+        let Ok(event) = CEventBuf::new(event) else {
+            return false;
+        };
+        unsafe { (self.vtable.publish)(self.ptr, &event.as_c()) == 0 }
+    }
+
+    /// Subscribes to events matching `topic` that are accepted by `filter`, which is
+    /// evaluated by the library before events are passed to the host.
+    pub fn subscribe_filtered(
+        &self,
+        topic: &str,
+        filter: Option<&Filter>,
+    ) -> Result<BoxStream<'static, Event>, SubscribeError> {
+        let c_topic = CString::new(topic.to_string()).unwrap();
+        let c_filter = filter.map(|v| CString::new(v.as_str()).unwrap());
+        let sink = NativeSubscriberSink {
+            state: Arc::new(Mutex::new(SubscriberState {
+                inner: Some(VecDeque::new()),
+                waker: None,
+            })),
+        };
+
+        let state = sink.state.clone();
+
+        let user_data = Box::into_raw(Box::new(sink));
+        let subscribe = CSubscribe {
+            user_data: Obj(user_data.cast()),
+            topic: c_topic.as_ptr(),
+            filter: c_filter.as_ref().map_or(null(), |v| v.as_ptr()),
+            on_event: NativeSubscriberSink::on_event,
+            on_unsubscribe: Some(NativeSubscriberSink::on_close),
+        };
+
+        let mut subscription = CSubscriptionRef::default();
+        let mut error = CPatternError::default();
+        let mut message = [0 as c_char; 256];
+        let mut filter_error = CFilterError {
+            position: 0,
+            message: message.as_mut_ptr(),
+            message_len: message.len(),
+        };
+        let res = unsafe {
+            (self.vtable.subscribe)(
+                self.ptr,
+                subscribe,
+                &mut subscription,
+                &mut error,
+                &mut filter_error,
+            )
+        };
+
+        if res != 0 {
+            let _ = unsafe { Box::from_raw(user_data) };
+
+            return Err(match res {
+                -2 => SubscribeError::ShuttingDown,
+                -4 => SubscribeError::Denied,
+                -3 => SubscribeError::InvalidFilter(unsafe { filter_error.to_filter_error() }),
+                _ => SubscribeError::InvalidPattern(error.to_pattern_error(topic)),
+            });
+        }
+
+        let stream = SubscriberStream {
+            buffer: Default::default(),
+            state,
+            subscription_ref: subscription,
+        };
+
+        Ok(stream.boxed())
+    }
+}
+
+impl Drop for LibraryModular {
+    fn drop(&mut self) {
+        let timeout_ms = self.shutdown_timeout.as_millis().min(u64::MAX as u128) as u64;
+        let (ptr, destroy) = (self.ptr, self.vtable.destroy_instance);
+        let destroy = move || unsafe { destroy(ptr, timeout_ms) };
+
+        // waiting for in-flight invokes must not stall a worker of the host's runtime
+        match Handle::try_current().map(|v| v.runtime_flavor()) {
+            Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(destroy),
+            Ok(_) => drop(std::thread::spawn(destroy)),
+            Err(_) => destroy(),
+        }
     }
 }
+
+/// Forwards records of the native side into the host's `tracing` subscriber.
+fn host_log_handler() -> CLogHandler {
+    unsafe extern "system" fn on_log(_: Obj, record: *const CLogRecord) {
+        let record = &*record;
+
+        let target = cstr_or_empty(record.target);
+        let message = cstr_or_empty(record.message);
+        let spans = cstr_or_empty(record.spans);
+        let fields = if record.fields_len == 0 {
+            String::new()
+        } else {
+            std::slice::from_raw_parts(record.fields, record.fields_len)
+                .iter()
+                .map(|field| {
+                    format!(
+                        "{}={}",
+                        cstr_or_empty(field.name),
+                        cstr_or_empty(field.value)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        macro_rules! forward {
+            ($level:expr) => {
+                tracing::event!(
+                    target: "modular_native",
+                    $level,
+                    native_target = %target,
+                    spans = %spans,
+                    fields = %fields,
+                    "{}",
+                    message
+                )
+            };
+        }
+
+        match record.level {
+            CLogLevel::Error => forward!(Level::ERROR),
+            CLogLevel::Warn => forward!(Level::WARN),
+            CLogLevel::Info => forward!(Level::INFO),
+            CLogLevel::Debug => forward!(Level::DEBUG),
+            CLogLevel::Trace => forward!(Level::TRACE),
+            CLogLevel::Off => {}
+        }
+    }
+
+    unsafe extern "system" fn max_level(_: Obj) -> CLogLevel {
+        c_level(LevelFilter::current())
+    }
+
+    CLogHandler {
+        user_data: Obj(null_mut()),
+        max_level,
+        on_log,
+        on_drop: None,
+    }
+}
+
+fn c_level(filter: LevelFilter) -> CLogLevel {
+    match filter.into_level() {
+        Some(Level::TRACE) => CLogLevel::Trace,
+        Some(Level::DEBUG) => CLogLevel::Debug,
+        Some(Level::INFO) => CLogLevel::Info,
+        Some(Level::WARN) => CLogLevel::Warn,
+        Some(Level::ERROR) => CLogLevel::Error,
+        None => CLogLevel::Off,
+    }
+}
+
+unsafe fn cstr_or_empty<'a>(str: *const c_char) -> std::borrow::Cow<'a, str> {
+    if str.is_null() {
+        Default::default()
+    } else {
+        CStr::from_ptr(str).to_string_lossy()
+    }
+}
+
 impl Modular for LibraryModular {
-    type Stream = BoxStream<'static, (String, Bytes)>;
+    type Stream = BoxStream<'static, Event>;
     type Module = BoxModule;
 
     fn register_module<S>(&self, name: &str, service: S) -> Result<(), RegistryError>
@@ -80,6 +369,8 @@ impl Modular for LibraryModular {
         let res = unsafe { (self.vtable.register_module)(self.ptr, name.as_ptr(), module, false) };
         match res {
             0 => Ok(()),
+            -2 => Err(RegistryError::ShuttingDown),
+            -3 => Err(RegistryError::Denied),
             _ => Err(RegistryError::AlreadyExists),
         }
     }
@@ -90,49 +381,16 @@ impl Modular for LibraryModular {
         _sink: Option<S>,
     ) -> Result<Self::Stream, SubscribeError>
     where
-        S: Sink<(String, Bytes), Error = Err> + Send + Sync + 'static,
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
     {
-        let topic = CString::new(topic.to_string()).unwrap();
-        let sink = NativeSubscriberSink {
-            state: Arc::new(Mutex::new(SubscriberState {
-                inner: Some(VecDeque::new()),
-                waker: None,
-            })),
-        };
-
-        let state = sink.state.clone();
-
-        let subscribe = CSubscribe {
-            user_data: Obj(Box::into_raw(Box::new(sink)).cast()),
-            topic: topic.as_ptr(),
-            on_event: NativeSubscriberSink::on_event,
-            on_unsubscribe: Some(NativeSubscriberSink::on_close),
-        };
-
-        let mut subscription = CSubscriptionRef::default();
-        unsafe { (self.vtable.subscribe)(self.ptr, subscribe, &mut subscription) };
-
-        let stream = SubscriberStream {
-            buffer: Default::default(),
-            state,
-            subscription_ref: subscription,
-        };
-
-        Ok(stream.boxed())
+        self.subscribe_filtered(topic, None)
     }
 
-    fn publish<Request>(&self, event: Request)
+    fn publish<E>(&self, event: E)
     where
-        Request: Into<ModuleRequest<Bytes>>,
+        E: Into<Event>,
     {
-        let event = event.into();
-        let topic = CString::new(event.action.to_string()).unwrap();
-        let buf = CBuf {
-            data: event.body.as_ptr(),
-            len: event.body.len(),
-        };
-
-        unsafe { (self.vtable.publish)(self.ptr, topic.as_ptr(), buf) }
+        self.try_publish(event.into());
     }
 
     fn get_module(&self, name: &str) -> Option<Self::Module> {
@@ -154,7 +412,7 @@ impl Modular for LibraryModular {
 
 #[derive(Clone)]
 struct SubscriberState {
-    inner: Option<VecDeque<(String, Bytes)>>,
+    inner: Option<VecDeque<Event>>,
     waker: Option<Waker>,
 }
 
@@ -163,19 +421,14 @@ struct NativeSubscriberSink {
 }
 
 impl NativeSubscriberSink {
-    unsafe extern "system" fn on_event(
-        subscription: CSubscriptionRef,
-        topic: *const c_char,
-        data: CBuf,
-    ) {
-        let topic = CStr::from_ptr(topic).to_string_lossy().to_string();
-        let data = Bytes::copy_from_slice(std::slice::from_raw_parts(data.data, data.len));
+    unsafe extern "system" fn on_event(subscription: CSubscriptionRef, event: *const CEvent) {
+        let event = (*event).to_event();
 
         let this = &*(subscription.user_data.0 as *const Self);
         let mut state = this.state.lock();
 
-        if let Some(mut v) = state.inner.as_mut() {
-            v.push_back((topic, data));
+        if let Some(v) = state.inner.as_mut() {
+            v.push_back(event);
         } else {
             (subscription.unsubscribe)(subscription.subscription_ref)
         }
@@ -192,7 +445,7 @@ impl NativeSubscriberSink {
 }
 
 struct SubscriberStream {
-    buffer: VecDeque<(String, Bytes)>,
+    buffer: VecDeque<Event>,
     state: Arc<Mutex<SubscriberState>>,
     subscription_ref: CSubscriptionRef,
 }
@@ -204,7 +457,7 @@ impl Drop for SubscriberStream {
 }
 
 impl Stream for SubscriberStream {
-    type Item = (String, Bytes);
+    type Item = Event;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         {
@@ -260,22 +513,19 @@ where
         let this = &*(ptr.0 as *const Self);
         let _guard = this.handle.enter();
 
-        let mut service = this.inner.clone();
+        let service = this.inner.clone();
         let method = CStr::from_ptr(method).to_string_lossy().to_string();
-        let data = Bytes::copy_from_slice(std::slice::from_raw_parts(data.data, data.len));
+        let data = data.into_bytes();
 
         spawn(async move {
-            let mut service = service.lock();
-            let v = service
+            let call = service
+                .lock()
                 .service
-                .call(ModuleRequest::new(&method, data))
-                .await;
+                .call(ModuleRequest::new(&method, data));
+            let v = call.await;
             match v {
                 Ok(v) => unsafe {
-                    let buf = CBuf {
-                        data: v.data.as_ptr(),
-                        len: v.data.len(),
-                    };
+                    let buf = CBuf::from_bytes(&v.data);
 
                     (callback.success)(callback.ptr, buf)
                 },
@@ -295,6 +545,12 @@ where
                         )
                     }
                     ModuleError::Destroyed => (callback.destroyed)(callback.ptr),
+                    ModuleError::Denied => (callback.denied)(callback.ptr),
+                    ModuleError::Overloaded => (callback.overloaded)(callback.ptr),
+                    ModuleError::PayloadTooLarge(e) => {
+                        (callback.payload_too_large)(callback.ptr, e.size, e.limit)
+                    }
+                    ModuleError::NotFound => (callback.not_found)(callback.ptr),
                 },
             }
         });
@@ -345,9 +601,11 @@ impl Module for ModuleRef {
     fn invoke(&self, req: ModuleRequest<Bytes>) -> Self::Future {
         let method = CString::new(req.action).unwrap();
 
-        let inner = self.0;
+        // the reference must stay alive until the call is handed over on the first poll
+        let inner = self.clone();
 
         ModuleCallbackFuture::new(move |state| {
+            let inner = inner;
             let state = Box::into_raw(Box::new(state));
 
             let callback = CCallback {
@@ -356,14 +614,15 @@ impl Module for ModuleRef {
                 error: ModuleCallbackFutureState::error,
                 unknown_method: ModuleCallbackFutureState::unknown_method,
                 destroyed: ModuleCallbackFutureState::destroyed,
+                denied: ModuleCallbackFutureState::denied,
+                overloaded: ModuleCallbackFutureState::overloaded,
+                payload_too_large: ModuleCallbackFutureState::payload_too_large,
+                not_found: ModuleCallbackFutureState::not_found,
             };
 
-            let buf = CBuf {
-                data: req.body.as_ptr(),
-                len: req.body.len(),
-            };
+            let buf = CBuf::from_bytes(&req.body);
 
-            unsafe { (inner.vtable.invoke)(inner.ptr, method.as_ptr(), buf, callback) }
+            unsafe { (inner.0.vtable.invoke)(inner.0.ptr, method.as_ptr(), buf, callback) }
         })
         .boxed()
     }
@@ -389,19 +648,17 @@ impl ModuleCallbackFutureState {
     }
 
     unsafe extern "system" fn on_success(this: Obj, data: CBuf) {
-        let data = ModuleResponse::new(Bytes::copy_from_slice(std::slice::from_raw_parts(
-            data.data, data.len,
-        )));
+        let data = ModuleResponse::new(data.into_bytes());
 
-        Self::with(this, |state| Ok(data));
+        Self::with(this, |_| Ok(data));
     }
 
     unsafe extern "system" fn unknown_method(this: Obj) {
-        Self::with(this, |state| Err(ModuleError::UnknownMethod));
+        Self::with(this, |_| Err(ModuleError::UnknownMethod));
     }
 
     unsafe extern "system" fn error(this: Obj, error: CModuleError) {
-        Self::with(this, |state| {
+        Self::with(this, |_| {
             Err(ModuleError::Custom(CustomModuleError {
                 code: error.code,
                 name: if !error.name.is_null() {
@@ -421,7 +678,28 @@ impl ModuleCallbackFutureState {
     }
 
     unsafe extern "system" fn destroyed(this: Obj) {
-        Self::with(this, |state| Err(ModuleError::Destroyed));
+        Self::with(this, |_| Err(ModuleError::Destroyed));
+    }
+
+    unsafe extern "system" fn denied(this: Obj) {
+        Self::with(this, |_| Err(ModuleError::Denied));
+    }
+
+    unsafe extern "system" fn overloaded(this: Obj) {
+        Self::with(this, |_| Err(ModuleError::Overloaded));
+    }
+
+    unsafe extern "system" fn payload_too_large(this: Obj, size: usize, limit: usize) {
+        Self::with(this, |_| {
+            Err(ModuleError::PayloadTooLarge(PayloadTooLarge {
+                size,
+                limit,
+            }))
+        });
+    }
+
+    unsafe extern "system" fn not_found(this: Obj) {
+        Self::with(this, |_| Err(ModuleError::NotFound));
     }
 }
 
@@ -471,3 +749,15 @@ impl<F: FnOnce(ModuleCallbackFutureState) + Unpin> Future for ModuleCallbackFutu
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn off_disables_logging() {
+        assert!(matches!(c_level(LevelFilter::OFF), CLogLevel::Off));
+        assert!(matches!(c_level(LevelFilter::ERROR), CLogLevel::Error));
+        assert!(matches!(c_level(LevelFilter::TRACE), CLogLevel::Trace));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{CBuf, CEvent, CHeader};
+use bytes::Bytes;
+use modular_core::event::Event;
+use std::ffi::{CStr, CString, NulError};
+
+/// Owns the strings a [`CEvent`] points to.
+pub struct CEventBuf {
+    topic: CString,
+    data: Bytes,
+    _strings: Vec<(CString, CString)>,
+    headers: Vec<CHeader>,
+    seq: u64,
+}
+
+impl CEventBuf {
+    /// Fails if the topic or a header contains a NUL byte, C strings can't hold it.
+    pub fn new(event: Event) -> Result<Self, NulError> {
+        let strings = event
+            .headers
+            .into_iter()
+            .map(|(name, value)| Ok((CString::new(name)?, CString::new(value)?)))
+            .collect::<Result<Vec<_>, NulError>>()?;
+        let headers = strings
+            .iter()
+            .map(|(name, value)| CHeader {
+                name: name.as_ptr(),
+                value: value.as_ptr(),
+            })
+            .collect();
+
+        Ok(Self {
+            topic: CString::new(event.topic)?,
+            data: event.data,
+            _strings: strings,
+            headers,
+            seq: event.seq,
+        })
+    }
+
+    /// The event with its payload as [`CBuf::from_bytes`], valid while `self` is.
+    pub fn as_c(&self) -> CEvent {
+        CEvent {
+            topic: self.topic.as_ptr(),
+            data: CBuf::from_bytes(&self.data),
+            headers: self.headers.as_ptr(),
+            headers_len: self.headers.len(),
+            seq: self.seq,
+        }
+    }
+}
+
+impl CEvent {
+    /// Copies the event, taking over its payload.
+    ///
+    /// # Safety
+    ///
+    /// The pointers have to be valid as described on [`CEvent`] and an owned payload must
+    /// not be used again.
+    pub unsafe fn to_event(&self) -> Event {
+        let string = |v: *const std::ffi::c_char| {
+            if v.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(v).to_string_lossy().to_string()
+            }
+        };
+
+        let headers = match self.headers_len {
+            0 => &[][..],
+            len => std::slice::from_raw_parts(self.headers, len),
+        };
+
+        Event {
+            topic: string(self.topic),
+            // takes over an owned payload, the caller doesn't use `self.data` again
+            data: std::ptr::read(&self.data).into_bytes(),
+            headers: headers
+                .iter()
+                .map(|header| (string(header.name), string(header.value)))
+                .collect(),
+            seq: self.seq,
+        }
+    }
+}
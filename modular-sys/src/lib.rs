@@ -1,8 +1,17 @@
+#[cfg(feature = "core")]
+mod buf;
+#[cfg(feature = "core")]
+pub use buf::ZERO_COPY_THRESHOLD;
 #[cfg(feature = "dll")]
 pub mod dll;
+#[cfg(feature = "core")]
+mod event;
+#[cfg(feature = "core")]
+pub use event::CEventBuf;
 
 pub mod core;
 
+use modular_core::error::{Expected, FilterError, PatternError};
 use std::ffi::c_void;
 use std::os::raw::c_char;
 use std::ptr::{null, null_mut};
@@ -14,17 +23,35 @@ pub struct Obj(pub *mut c_void);
 unsafe impl Send for Obj {}
 unsafe impl Sync for Obj {}
 
+/// Version of [`NativeModularVTable`]. Bumped whenever the layout or the semantics of an
+/// entry change, a host refuses to load a library exporting a different one.
+pub const ABI_VERSION: u32 = 1;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct NativeModularVTable {
     pub create: unsafe extern "system" fn(threads: u32) -> Obj,
-    pub destroy_instance: unsafe extern "system" fn(modular: Obj),
+    pub destroy_instance: unsafe extern "system" fn(modular: Obj, timeout_ms: u64),
+    /// Returns `0` on success, `-1` if the topic is not a valid pattern (described in
+    /// `error` when it's not null), `-2` if the instance is shutting down, `-3` if the
+    /// filter is not a valid filter expression (described in `filter_error` when it's not
+    /// null) and `-4` if subscribing is not allowed.
+    ///
+    /// On failure `on_unsubscribe` is not called and `user_data` stays owned by the caller.
     pub subscribe: unsafe extern "system" fn(
         modular: Obj,
         subscribe: CSubscribe,
         *mut CSubscriptionRef,
+        error: *mut CPatternError,
+        filter_error: *mut CFilterError,
     ) -> i32,
-    pub publish: unsafe extern "system" fn(modular: Obj, topic: *const c_char, data: CBuf),
+    /// `seq` of the event is ignored.
+    ///
+    /// Returns `0` on success and `-1` if the payload exceeds the limit of the topic, in
+    /// which case an owned payload is released without being read.
+    pub publish: unsafe extern "system" fn(modular: Obj, event: *const CEvent) -> i32,
+    /// Returns `0` on success, `-1` if the module already exists, `-2` if the instance is
+    /// shutting down and `-3` if registering the name is not allowed.
     pub register_module: unsafe extern "system" fn(
         modular: Obj,
         name: *const c_char,
@@ -33,13 +60,72 @@ pub struct NativeModularVTable {
     ) -> i32,
     pub remove_module: unsafe extern "system" fn(modular: Obj, name: *const c_char),
     pub get_module_ref: unsafe extern "system" fn(modular: Obj, name: *const c_char) -> CModuleRef,
+    pub set_log_handler: unsafe extern "system" fn(handler: CLogHandler),
+    /// Derives a handle that can only do what `scope` allows, e.g. to pass to plugins in
+    /// place of the root instance. It's used with this vtable like the root instance and
+    /// released with `destroy_instance`, which doesn't shut the root instance down.
+    ///
+    /// Returns null if `modular` is a scoped handle itself or a pattern of `scope` is
+    /// invalid.
+    pub create_scoped: unsafe extern "system" fn(modular: Obj, scope: *const CScope) -> Obj,
+    /// Sets the maximum payload size of `target`, `usize::MAX` removes the limit. Topic
+    /// and module limits override the instance limit for names matching `pattern`, which
+    /// is ignored for the instance.
+    ///
+    /// Returns `0` on success, `-1` if `pattern` is not a valid pattern and `-2` if
+    /// `modular` is a scoped handle.
+    pub set_max_payload: unsafe extern "system" fn(
+        modular: Obj,
+        target: CPayloadTarget,
+        pattern: *const c_char,
+        limit: usize,
+    ) -> i32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub enum CPayloadTarget {
+    /// Events and module requests on the instance.
+    Instance = 0,
+    Topic = 1,
+    /// Module requests and responses of modules registered across the ABI.
+    Module = 2,
+}
+
+/// Capabilities of a scoped handle. Arrays may be null when their length is `0`.
+#[repr(C)]
+pub struct CScope {
+    /// Name of the handle reported when an operation is denied, may be null.
+    pub identity: *const c_char,
+    /// Modules can only be registered and removed under `{register_prefix}.`, or not at
+    /// all if null. Wildcards, escapes and empty segments are rejected.
+    pub register_prefix: *const c_char,
+    /// Patterns that can be subscribed to, along with the patterns they cover.
+    pub subscribe: *const *const c_char,
+    pub subscribe_len: usize,
+    /// Names of the modules that can be invoked, wildcards are rejected like in
+    /// `register_prefix`.
+    pub invoke: *const *const c_char,
+    pub invoke_len: usize,
+    /// Patterns of the topics that can be published to.
+    pub publish: *const *const c_char,
+    pub publish_len: usize,
+}
+
+/// Payload passed across the ABI.
+///
+/// A buffer without `release` is borrowed: it is valid only for the duration of the call
+/// and the receiver has to copy it. A buffer with `release` is owned: the receiver takes
+/// over `owner` and must call `release(owner)` exactly once when it no longer needs `data`.
+///
+/// `CBuf` is deliberately neither `Copy` nor `Clone`: an owned buffer must be released
+/// exactly once, so it can only be moved.
 #[repr(C)]
 pub struct CBuf {
     pub data: *const u8,
     pub len: usize,
+    pub owner: Obj,
+    pub release: Option<Cleanup>,
 }
 
 impl Default for CBuf {
@@ -47,14 +133,38 @@ impl Default for CBuf {
         Self {
             data: null(),
             len: 0,
+            owner: Obj(null_mut()),
+            release: None,
         }
     }
 }
 
+#[repr(C)]
+pub struct CHeader {
+    pub name: *const c_char,
+    pub value: *const c_char,
+}
+
+/// An event passed across the ABI.
+///
+/// The topic and headers are valid only for the duration of the call, `data` follows the
+/// rules of [`CBuf`].
+#[repr(C)]
+pub struct CEvent {
+    pub topic: *const c_char,
+    pub data: CBuf,
+    pub headers: *const CHeader,
+    pub headers_len: usize,
+    pub seq: u64,
+}
+
 #[repr(C)]
 pub struct CSubscribe {
     pub user_data: Obj,
     pub topic: *const c_char,
+    /// Optional filter expression, see `modular_core::filter::Filter`. Events it rejects
+    /// are not passed to `on_event`.
+    pub filter: *const c_char,
 
     pub on_event: OnEvent,
     pub on_unsubscribe: Option<Cleanup>,
@@ -108,6 +218,10 @@ pub struct CCallback {
     pub error: unsafe extern "system" fn(ptr: Obj, error: CModuleError),
     pub unknown_method: unsafe extern "system" fn(ptr: Obj),
     pub destroyed: unsafe extern "system" fn(ptr: Obj),
+    pub denied: unsafe extern "system" fn(ptr: Obj),
+    pub overloaded: unsafe extern "system" fn(ptr: Obj),
+    pub payload_too_large: unsafe extern "system" fn(ptr: Obj, size: usize, limit: usize),
+    pub not_found: unsafe extern "system" fn(ptr: Obj),
 }
 
 unsafe impl Send for CCallback {}
@@ -129,7 +243,251 @@ pub struct CModuleRefVTable {
         unsafe extern "system" fn(ptr: Obj, action: *const c_char, data: CBuf, callback: CCallback),
 }
 
-pub type OnEvent =
-    unsafe extern "system" fn(subscription: CSubscriptionRef, topic: *const c_char, data: CBuf);
+pub type OnEvent = unsafe extern "system" fn(subscription: CSubscriptionRef, event: *const CEvent);
 
 pub type Cleanup = unsafe extern "system" fn(_: Obj);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub enum CPatternExpected {
+    Segment = 0,
+    ClosingBrace = 1,
+    EscapedChar = 2,
+    End = 3,
+}
+
+impl From<Expected> for CPatternExpected {
+    fn from(value: Expected) -> Self {
+        match value {
+            Expected::Segment => Self::Segment,
+            Expected::ClosingBrace => Self::ClosingBrace,
+            Expected::EscapedChar => Self::EscapedChar,
+            Expected::End => Self::End,
+        }
+    }
+}
+
+impl From<CPatternExpected> for Expected {
+    fn from(value: CPatternExpected) -> Self {
+        match value {
+            CPatternExpected::Segment => Self::Segment,
+            CPatternExpected::ClosingBrace => Self::ClosingBrace,
+            CPatternExpected::EscapedChar => Self::EscapedChar,
+            CPatternExpected::End => Self::End,
+        }
+    }
+}
+
+/// Describes an invalid subscription pattern.
+///
+/// The offending segment is given as a byte range of the subscribed topic.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct CPatternError {
+    pub position: usize,
+    pub expected: CPatternExpected,
+    pub segment_start: usize,
+    pub segment_len: usize,
+}
+
+impl Default for CPatternError {
+    fn default() -> Self {
+        Self {
+            position: 0,
+            expected: CPatternExpected::Segment,
+            segment_start: 0,
+            segment_len: 0,
+        }
+    }
+}
+
+impl CPatternError {
+    pub fn new(topic: &str, error: &PatternError) -> Self {
+        let len = error.segment.len();
+        let segment_start = (error.position.saturating_sub(len)..=error.position)
+            .find(|&start| topic.get(start..start + len) == Some(error.segment.as_str()))
+            .unwrap_or(error.position);
+
+        Self {
+            position: error.position,
+            expected: error.expected.into(),
+            segment_start,
+            segment_len: len,
+        }
+    }
+
+    pub fn to_pattern_error(&self, topic: &str) -> PatternError {
+        let end = self.segment_start.saturating_add(self.segment_len);
+        let segment = topic
+            .as_bytes()
+            .get(self.segment_start..end)
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .unwrap_or_default();
+
+        PatternError {
+            position: self.position,
+            expected: self.expected.into(),
+            segment,
+        }
+    }
+}
+
+/// Describes why a filter expression was rejected.
+///
+/// `message` points to a caller-provided buffer of `message_len` bytes, which receives the
+/// NUL-terminated message, truncated if it doesn't fit.
+#[repr(C)]
+pub struct CFilterError {
+    pub position: usize,
+    pub message: *mut c_char,
+    pub message_len: usize,
+}
+
+impl CFilterError {
+    /// # Safety
+    ///
+    /// `message` must be null or point to `message_len` writable bytes.
+    pub unsafe fn write(&mut self, error: &FilterError) {
+        self.position = error.position;
+
+        if self.message.is_null() || self.message_len == 0 {
+            return;
+        }
+
+        let mut len = error.message.len().min(self.message_len - 1);
+        while !error.message.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        std::ptr::copy_nonoverlapping(error.message.as_ptr().cast(), self.message, len);
+        *self.message.add(len) = 0;
+    }
+
+    /// # Safety
+    ///
+    /// `message` must be null or point to a NUL-terminated string.
+    pub unsafe fn to_filter_error(&self) -> FilterError {
+        let message = match self.message.is_null() {
+            true => String::new(),
+            false => std::ffi::CStr::from_ptr(self.message)
+                .to_string_lossy()
+                .into_owned(),
+        };
+
+        FilterError {
+            position: self.position,
+            message,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(C)]
+pub enum CLogLevel {
+    /// Disables logging, only valid as a maximum level.
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+#[repr(C)]
+pub struct CLogField {
+    pub name: *const c_char,
+    pub value: *const c_char,
+}
+
+#[repr(C)]
+pub struct CLogRecord {
+    pub level: CLogLevel,
+    pub target: *const c_char,
+    pub message: *const c_char,
+    /// Names of the spans the event was recorded in, from the root, joined with `:`.
+    pub spans: *const c_char,
+    /// Event fields followed by span fields, the latter prefixed with `<span name>.`.
+    pub fields: *const CLogField,
+    pub fields_len: usize,
+}
+
+/// Receives every log record of the native side up to `max_level`.
+///
+/// Only one handler is active at a time; installing a new one drops the previous.
+#[repr(C)]
+pub struct CLogHandler {
+    pub user_data: Obj,
+    /// Queried for every record, so changes of the host's level apply immediately.
+    pub max_level: unsafe extern "system" fn(user_data: Obj) -> CLogLevel,
+    pub on_log: unsafe extern "system" fn(user_data: Obj, record: *const CLogRecord),
+    pub on_drop: Option<Cleanup>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_error_round_trip() {
+        let cases = [
+            ("a.{b.c", 4, Expected::ClosingBrace, "{b", 2),
+            ("a..b", 2, Expected::Segment, "", 2),
+            ("a.b\\x", 4, Expected::EscapedChar, "b\\x", 2),
+            ("ab.>.c", 4, Expected::End, ">", 3),
+        ];
+
+        for (topic, position, expected, segment, segment_start) in cases {
+            let error = PatternError {
+                position,
+                expected,
+                segment: segment.to_owned(),
+            };
+
+            let c_error = CPatternError::new(topic, &error);
+            assert_eq!(c_error.segment_start, segment_start, "{}", topic);
+            assert_eq!(c_error.segment_len, segment.len(), "{}", topic);
+            assert_eq!(c_error.to_pattern_error(topic), error, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn filter_error_round_trip() {
+        let error = FilterError {
+            position: 10,
+            message: "unterminated string".to_owned(),
+        };
+
+        let mut message = [0 as c_char; 64];
+        let mut c_error = CFilterError {
+            position: 0,
+            message: message.as_mut_ptr(),
+            message_len: message.len(),
+        };
+
+        unsafe {
+            c_error.write(&error);
+            assert_eq!(c_error.to_filter_error(), error);
+        }
+    }
+
+    #[test]
+    fn filter_error_message_is_truncated() {
+        let error = FilterError {
+            position: 1,
+            message: "aé".to_owned(),
+        };
+
+        // room for `a` and half of `é`
+        let mut message = [1 as c_char; 3];
+        let mut c_error = CFilterError {
+            position: 0,
+            message: message.as_mut_ptr(),
+            message_len: message.len(),
+        };
+
+        unsafe {
+            c_error.write(&error);
+            assert_eq!(c_error.to_filter_error().message, "a");
+        }
+    }
+}
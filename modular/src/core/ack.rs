@@ -0,0 +1,254 @@
+//! Acknowledged subscriptions, see [`Modular::subscribe_acked`](super::Modular::subscribe_acked).
+
+use crate::core::Modular;
+use futures::channel::mpsc;
+use futures::{Sink, SinkExt, StreamExt};
+use modular_core::event::Event;
+use std::collections::HashMap;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct AckOptions {
+    /// Time after which an unacknowledged event is delivered again.
+    pub timeout: Duration,
+    /// Number of deliveries after which an unacknowledged event is given up.
+    pub max_attempts: u32,
+    /// Events that were given up are published to this topic followed by their own topic,
+    /// e.g. `dead.orders.1` for `orders.1`, or dropped if unset.
+    pub dead_letter: Option<String>,
+}
+
+impl Default for AckOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            max_attempts: 5,
+            dead_letter: None,
+        }
+    }
+}
+
+/// An event of an acknowledged subscription, delivered again unless [`ack`](Self::ack)
+/// is called in time.
+#[derive(Debug)]
+pub struct Delivery {
+    pub event: Event,
+    /// Starts at 1 for the first delivery.
+    pub attempt: u32,
+    id: u64,
+    acks: mpsc::UnboundedSender<u64>,
+}
+
+impl Delivery {
+    pub fn ack(&self) {
+        let _ = self.acks.unbounded_send(self.id);
+    }
+}
+
+struct Pending {
+    event: Event,
+    attempt: u32,
+    deadline: Instant,
+}
+
+/// Delivers `events` to `sink` until it fails, redelivering events that weren't
+/// acknowledged.
+pub(crate) async fn deliver<S>(
+    mut events: mpsc::UnboundedReceiver<Event>,
+    modular: Weak<Modular>,
+    options: AckOptions,
+    sink: S,
+) where
+    S: Sink<Delivery>,
+{
+    let mut sink = Box::pin(sink);
+    let (acks_tx, mut acks) = mpsc::unbounded();
+    let mut pending = HashMap::<u64, Pending>::new();
+    let mut next_id = 0;
+
+    loop {
+        let next_deadline = pending.values().map(|p| p.deadline).min();
+        let expired = async {
+            match next_deadline {
+                Some(v) => tokio::time::sleep_until(v).await,
+                None => futures::future::pending().await,
+            }
+        };
+
+        let id = tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    return;
+                };
+
+                let id = next_id;
+                next_id += 1;
+                pending.insert(id, Pending { event, attempt: 0, deadline: Instant::now() });
+                id
+            }
+            Some(id) = acks.next() => {
+                pending.remove(&id);
+                continue;
+            }
+            _ = expired => {
+                let now = Instant::now();
+                let Some((&id, _)) = pending.iter().find(|(_, p)| p.deadline <= now) else {
+                    continue;
+                };
+
+                if pending[&id].attempt >= options.max_attempts {
+                    let event = pending.remove(&id).unwrap().event;
+                    if let (Some(dead_letter), Some(modular)) = (&options.dead_letter, modular.upgrade()) {
+                        let topic = format!("{}.{}", dead_letter, event.topic);
+                        modular.publish_admitted(Event { topic, ..event });
+                    }
+                    continue;
+                }
+
+                id
+            }
+        };
+
+        let entry = pending.get_mut(&id).unwrap();
+        entry.attempt += 1;
+        entry.deadline = Instant::now() + options.timeout;
+
+        let delivery = Delivery {
+            event: entry.event.clone(),
+            attempt: entry.attempt,
+            id,
+            acks: acks_tx.clone(),
+        };
+
+        if sink.send(delivery).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Forwarder;
+    use bytes::Bytes;
+    use modular_core::modular::Modular as _;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    fn options(max_attempts: u32, dead_letter: Option<&str>) -> AckOptions {
+        AckOptions {
+            timeout: Duration::from_millis(20),
+            max_attempts,
+            dead_letter: dead_letter.map(str::to_owned),
+        }
+    }
+
+    async fn next<T>(rx: &mut mpsc::UnboundedReceiver<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn nothing<T: std::fmt::Debug>(rx: &mut mpsc::UnboundedReceiver<T>) {
+        let next = tokio::time::timeout(Duration::from_millis(100), rx.next()).await;
+        assert!(next.is_err(), "unexpected {:?}", next);
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Event>>);
+
+    impl Forwarder for Recorder {
+        fn forward(&self, event: &Event) {
+            self.0.lock().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_events_are_delivered_again() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = mpsc::unbounded();
+        modular
+            .subscribe_acked("a.>", options(3, None), tx)
+            .unwrap();
+
+        modular.publish(Event::new("a.b", Bytes::from("x")));
+        for attempt in 1..=3 {
+            let delivery = next(&mut rx).await;
+            assert_eq!(delivery.attempt, attempt);
+            assert_eq!(delivery.event.data, "x");
+        }
+
+        // given up after the last attempt
+        nothing(&mut rx).await;
+    }
+
+    #[tokio::test]
+    async fn acknowledged_events_are_not_delivered_again() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = mpsc::unbounded();
+        modular
+            .subscribe_acked("a.>", options(3, None), tx)
+            .unwrap();
+
+        modular.publish(Event::new("a.b", Bytes::new()));
+        let delivery = next(&mut rx).await;
+        delivery.ack();
+        nothing(&mut rx).await;
+
+        // an ack of a later attempt counts as well
+        modular.publish(Event::new("a.c", Bytes::new()));
+        assert_eq!(next(&mut rx).await.attempt, 1);
+        let delivery = next(&mut rx).await;
+        assert_eq!(delivery.attempt, 2);
+        delivery.ack();
+        nothing(&mut rx).await;
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_published_like_other_events() {
+        let modular = Arc::new(Modular::default());
+        let forwarded = Arc::new(Recorder::default());
+        modular.set_forwarder(Some(forwarded.clone()));
+
+        let (tx, mut rx) = mpsc::unbounded();
+        modular
+            .subscribe_acked("a.>", options(2, Some("dead")), tx)
+            .unwrap();
+        let (dead_tx, mut dead) = mpsc::unbounded();
+        modular.subscribe("dead.>", Some(dead_tx)).unwrap();
+
+        modular.publish(Event::new("a.b", Bytes::from("x")).with_header("k", "v"));
+        let seq = next(&mut rx).await.event.seq;
+        next(&mut rx).await;
+
+        let event = next(&mut dead).await;
+        assert_eq!(event.topic, "dead.a.b");
+        assert_eq!(event.data, "x");
+        assert_eq!(event.header("k"), Some("v"));
+        assert!(event.seq > seq);
+
+        let forwarded = forwarded.0.lock();
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded[1].topic, "dead.a.b");
+    }
+
+    #[tokio::test]
+    async fn events_are_dropped_without_a_dead_letter_topic() {
+        let modular = Arc::new(Modular::default());
+        let forwarded = Arc::new(Recorder::default());
+        modular.set_forwarder(Some(forwarded.clone()));
+
+        let (tx, mut rx) = mpsc::unbounded();
+        modular
+            .subscribe_acked("a.>", options(1, None), tx)
+            .unwrap();
+
+        modular.publish(Event::new("a.b", Bytes::new()));
+        next(&mut rx).await;
+        nothing(&mut rx).await;
+        assert_eq!(forwarded.0.lock().len(), 1);
+    }
+}
@@ -0,0 +1,499 @@
+//! Access control for [`Modular`] instances, see [`Modular::scoped`].
+
+use crate::core::modules::Module;
+use crate::core::pattern::Pattern;
+use crate::core::request::Request;
+use crate::core::{Modular, ACL_DENIED_TOPIC};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink};
+use modular_core::error::{ModuleError, PatternError, RegistryError, RequestError, SubscribeError};
+use modular_core::event::Event;
+use modular_core::modules::{ModuleRequest, ModuleResponse};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::Service;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// Publishing to topics matching the pattern.
+    Publish,
+    /// Subscribing to patterns covered by the pattern.
+    Subscribe,
+    /// Invoking modules whose name matches the pattern.
+    Invoke,
+    /// Registering and removing modules whose name matches the pattern.
+    Register,
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permission::Publish => write!(f, "publish"),
+            Permission::Subscribe => write!(f, "subscribe"),
+            Permission::Invoke => write!(f, "invoke"),
+            Permission::Register => write!(f, "register"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    permission: Permission,
+    pattern: Pattern,
+}
+
+/// Allow and deny rules, everything no allow rule matches is denied and deny rules take
+/// precedence over allow rules.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+    audit: bool,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(self, permission: Permission, pattern: &str) -> Result<Self, PatternError> {
+        self.rule(true, permission, pattern)
+    }
+
+    pub fn deny(self, permission: Permission, pattern: &str) -> Result<Self, PatternError> {
+        self.rule(false, permission, pattern)
+    }
+
+    /// Publishes denials to [`ACL_DENIED_TOPIC`] with the headers `identity`, `permission`
+    /// and `subject`.
+    pub fn audit(mut self, audit: bool) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    fn rule(
+        mut self,
+        allow: bool,
+        permission: Permission,
+        pattern: &str,
+    ) -> Result<Self, PatternError> {
+        self.rules.push(Rule {
+            allow,
+            permission,
+            pattern: Pattern::parse(pattern)?,
+        });
+        Ok(self)
+    }
+
+    /// Whether `subject`, a topic or module name, is allowed for `permission`. Rules match
+    /// strictly, `a.b` doesn't match `a`, see [`Pattern::matches_strict`].
+    pub fn is_allowed(&self, permission: Permission, subject: &str) -> bool {
+        self.decide(permission, |pattern| pattern.matches_strict(subject))
+    }
+
+    /// Whether subscribing to `pattern` is allowed. Topics matched by deny rules that
+    /// only overlap `pattern` are filtered out of the subscription instead.
+    pub fn is_subscription_allowed(&self, pattern: &Pattern) -> bool {
+        self.decide(Permission::Subscribe, |rule| rule.covers(pattern))
+    }
+
+    fn decide(&self, permission: Permission, matches: impl Fn(&Pattern) -> bool) -> bool {
+        let mut allowed = false;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.permission == permission)
+        {
+            if matches(&rule.pattern) {
+                if !rule.allow {
+                    return false;
+                }
+                allowed = true;
+            }
+        }
+
+        allowed
+    }
+}
+
+/// A handle to a [`Modular`] instance acting as `identity`, operations the ACL doesn't
+/// allow fail with a `Denied` error or, for publishing, drop the event. Events rerouted
+/// by interceptors are checked again.
+#[derive(Clone)]
+pub struct ScopedModular {
+    modular: Arc<Modular>,
+    identity: Arc<str>,
+    acl: Arc<Acl>,
+}
+
+impl ScopedModular {
+    pub(crate) fn new(modular: Arc<Modular>, identity: &str, acl: Acl) -> Self {
+        Self {
+            modular,
+            identity: identity.into(),
+            acl: Arc::new(acl),
+        }
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    /// Like [`Modular::subscribe_filtered`], events of topics the ACL denies are filtered
+    /// out as well.
+    pub fn subscribe_filtered<F, S, Err>(
+        &self,
+        pattern: &str,
+        filter: F,
+        sink: S,
+    ) -> Result<u64, SubscribeError>
+    where
+        F: Fn(&Event) -> bool + Send + Sync + 'static,
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
+    {
+        self.check_subscription(pattern)?;
+
+        let acl = self.acl.clone();
+        self.modular.subscribe_filtered(
+            pattern,
+            move |event| acl.is_allowed(Permission::Subscribe, &event.topic) && filter(event),
+            sink,
+        )
+    }
+
+    /// Like [`Modular::register_or_replace_module`], if registering `name` is allowed.
+    pub fn register_or_replace_module<S, Request>(
+        &self,
+        name: &str,
+        svc: S,
+    ) -> Result<(), RegistryError>
+    where
+        S: Service<Request> + Send + 'static,
+        Request: From<ModuleRequest<Bytes>> + Send + 'static,
+        S::Response: Into<ModuleResponse<Bytes>> + Send + 'static,
+        S::Error: Into<ModuleError> + Send + 'static,
+        S::Future: Send + Sync + 'static,
+    {
+        if !self.check(Permission::Register, name) {
+            return Err(RegistryError::Denied);
+        }
+        self.modular.register_or_replace_module(name, svc);
+        Ok(())
+    }
+
+    /// Like [`Modular::respond`], requests to topics the ACL denies are filtered out.
+    pub fn respond<S, Err>(&self, pattern: &str, sink: S) -> Result<(), SubscribeError>
+    where
+        S: Sink<(String, Request), Error = Err> + Send + Sync + 'static,
+    {
+        self.check_subscription(pattern)?;
+
+        let acl = self.acl.clone();
+        self.modular.respond_filtered(
+            pattern,
+            Some(Arc::new(move |(topic, _): &(String, Request)| {
+                acl.is_allowed(Permission::Subscribe, topic)
+            })),
+            sink,
+        )
+    }
+
+    /// Like [`Modular::request`], if publishing to `topic` is allowed.
+    pub async fn request(
+        &self,
+        topic: &str,
+        body: Bytes,
+        timeout: Duration,
+    ) -> Result<Bytes, RequestError> {
+        let mut replies = self.request_many(topic, body, 1, timeout).await?;
+        Ok(replies.remove(0))
+    }
+
+    /// Like [`Modular::request_many`], if publishing to `topic` is allowed.
+    pub async fn request_many(
+        &self,
+        topic: &str,
+        body: Bytes,
+        max_replies: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Bytes>, RequestError> {
+        if !self.check(Permission::Publish, topic) {
+            return Err(RequestError::Denied);
+        }
+
+        self.modular
+            .request_many_as(Some(self), topic, body, max_replies, timeout)
+            .await
+    }
+
+    /// Like [`Modular::reply`], the reply counts towards the rate limits of the identity.
+    pub fn reply(&self, request: &Request, body: Bytes) {
+        self.modular.reply_as(Some(&self.identity), request, body);
+    }
+
+    /// Like [`Modular::publish_local`], returns whether the ACL allowed publishing.
+    pub fn publish_local<E: Into<Event>>(&self, event: E) -> bool {
+        let event = event.into();
+        let allowed = self.check(Permission::Publish, &event.topic);
+        if allowed {
+            self.modular.publish_local_as(Some(self), event);
+        }
+        allowed
+    }
+
+    fn check_subscription(&self, pattern: &str) -> Result<(), SubscribeError> {
+        if !self.acl.is_subscription_allowed(&Pattern::parse(pattern)?) {
+            self.denied(Permission::Subscribe, pattern);
+            return Err(SubscribeError::Denied);
+        }
+        Ok(())
+    }
+
+    pub(super) fn check(&self, permission: Permission, subject: &str) -> bool {
+        let allowed = self.acl.is_allowed(permission, subject);
+        if !allowed {
+            self.denied(permission, subject);
+        }
+        allowed
+    }
+
+    fn denied(&self, permission: Permission, subject: &str) {
+        tracing::debug!(identity = %self.identity, %permission, subject, "permission denied");
+
+        if self.acl.audit {
+            let event = Event::new(ACL_DENIED_TOPIC, Bytes::new())
+                .with_header("identity", &self.identity)
+                .with_header("permission", &permission.to_string())
+                .with_header("subject", subject);
+            self.modular.publish_event_inner(event);
+        }
+    }
+}
+
+impl modular_core::modular::Modular for ScopedModular {
+    type Stream = ();
+    type Module = ScopedModule;
+
+    fn register_module<S>(&self, name: &str, service: S) -> Result<(), RegistryError>
+    where
+        S: Service<ModuleRequest> + 'static + Send + Sync,
+        S::Response: Into<ModuleResponse> + Send + 'static,
+        S::Error: Into<ModuleError> + Send + 'static,
+        S::Future: Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync + 'static,
+    {
+        if !self.check(Permission::Register, name) {
+            return Err(RegistryError::Denied);
+        }
+        self.modular.register_module(name, service)
+    }
+
+    fn subscribe<S, Err>(
+        &self,
+        topic: &str,
+        sink: Option<S>,
+    ) -> Result<Self::Stream, SubscribeError>
+    where
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
+    {
+        match sink {
+            Some(sink) => self.subscribe_filtered(topic, |_| true, sink).map(drop),
+            None => self.check_subscription(topic),
+        }
+    }
+
+    fn publish<E>(&self, event: E)
+    where
+        E: Into<Event>,
+    {
+        let event = event.into();
+        if self.check(Permission::Publish, &event.topic) {
+            self.modular.publish_as(Some(self), event);
+        }
+    }
+
+    fn get_module(&self, name: &str) -> Option<Self::Module> {
+        let module = self.modular.get_module_as(name, Some(&self.identity))?;
+        if !self.check(Permission::Invoke, name) {
+            return Some(ScopedModule(None));
+        }
+        Some(ScopedModule(Some(module)))
+    }
+
+    fn deregister_module(&self, name: &str) {
+        if self.check(Permission::Register, name) {
+            self.modular.deregister_module(name);
+        }
+    }
+}
+
+/// A module obtained from a [`ScopedModular`], invoking it fails with
+/// [`ModuleError::Denied`] if the ACL doesn't allow it.
+#[derive(Clone)]
+pub struct ScopedModule(Option<Module<Bytes, Bytes>>);
+
+impl modular_core::module::Module for ScopedModule {
+    type Future = BoxFuture<
+        'static,
+        Result<BoxFuture<'static, Result<ModuleResponse, ModuleError>>, ModuleError>,
+    >;
+
+    fn invoke(&self, req: ModuleRequest) -> Self::Future {
+        match &self.0 {
+            Some(module) => module.invoke(req),
+            None => futures::future::err(ModuleError::Denied).boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use modular_core::modular::Modular as _;
+    use modular_core::module::Module as _;
+
+    async fn receive(rx: &mut mpsc::UnboundedReceiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn reroute(modular: &Modular, from: &'static str, to: &'static str) {
+        modular.add_interceptor(Arc::new(move |event: Event| match event.topic == from {
+            true => Some(Event::new(to, event.data)),
+            false => Some(event),
+        }));
+    }
+
+    fn acl(rules: &[(bool, Permission, &str)]) -> Acl {
+        rules
+            .iter()
+            .try_fold(
+                Acl::new(),
+                |acl, (allow, permission, pattern)| match allow {
+                    true => acl.allow(*permission, pattern),
+                    false => acl.deny(*permission, pattern),
+                },
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn prefixes_of_rules_are_denied() {
+        let acl = acl(&[
+            (true, Permission::Invoke, "storage.s3"),
+            (true, Permission::Register, "plugins.foo.>"),
+            (true, Permission::Subscribe, "a.b"),
+            (true, Permission::Subscribe, "c.>"),
+        ]);
+
+        assert!(acl.is_allowed(Permission::Invoke, "storage.s3"));
+        assert!(!acl.is_allowed(Permission::Invoke, "storage"));
+        assert!(!acl.is_allowed(Permission::Invoke, "storage.s3.x"));
+
+        assert!(acl.is_allowed(Permission::Register, "plugins.foo.bar"));
+        assert!(!acl.is_allowed(Permission::Register, "plugins"));
+        assert!(!acl.is_allowed(Permission::Register, "plugins.foo"));
+
+        let subscription = |v: &str| acl.is_subscription_allowed(&Pattern::parse(v).unwrap());
+        assert!(subscription("a.b"));
+        assert!(!subscription("a"));
+        assert!(!subscription("a.>"));
+        assert!(subscription("c.d.>"));
+        assert!(!subscription("c"));
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() {
+        let acl = acl(&[
+            (true, Permission::Publish, "a.>"),
+            (false, Permission::Publish, "a.secret.>"),
+        ]);
+
+        assert!(acl.is_allowed(Permission::Publish, "a.b"));
+        assert!(acl.is_allowed(Permission::Publish, "a.secret"));
+        assert!(!acl.is_allowed(Permission::Publish, "a.secret.b"));
+        assert!(!acl.is_allowed(Permission::Subscribe, "a.b"));
+    }
+
+    fn service() -> impl Service<
+        ModuleRequest,
+        Response = ModuleResponse,
+        Error = ModuleError,
+        Future = impl Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync,
+    > + Send
+           + Sync
+           + 'static {
+        tower::service_fn(|_: ModuleRequest| async { Ok(ModuleResponse::new(Bytes::new())) })
+    }
+
+    #[tokio::test]
+    async fn scoped_handles_deny_prefixes() {
+        let modular = Arc::new(Modular::default());
+        modular.register_module("storage", service()).unwrap();
+        let scoped = modular.scoped(
+            "user",
+            acl(&[
+                (true, Permission::Invoke, "storage.s3"),
+                (true, Permission::Register, "plugins.foo.>"),
+            ]),
+        );
+
+        let module = scoped.get_module("storage").unwrap();
+        let result = module.invoke(ModuleRequest::new("get", Bytes::new())).await;
+        assert!(matches!(result, Err(ModuleError::Denied)));
+
+        for name in ["plugins", "plugins.foo"] {
+            let result = scoped.register_module(name, service());
+            assert!(matches!(result, Err(RegistryError::Denied)));
+        }
+        scoped
+            .register_module("plugins.foo.bar", service())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rerouted_events_are_checked_again() {
+        let modular = Arc::new(Modular::default());
+        reroute(&modular, "a.public", "a.private");
+        reroute(&modular, "a.other", "a.public");
+        let (tx, mut rx) = mpsc::unbounded::<Event>();
+        modular.subscribe_filtered("a.>", |_| true, tx).unwrap();
+        let (tx, mut denied) = mpsc::unbounded::<Event>();
+        modular
+            .subscribe_filtered(ACL_DENIED_TOPIC, |_| true, tx)
+            .unwrap();
+
+        let acl = Acl::new()
+            .allow(Permission::Publish, "a.>")
+            .and_then(|acl| acl.deny(Permission::Publish, "a.private"))
+            .unwrap()
+            .audit(true);
+        let scoped = modular.scoped("user", acl);
+
+        scoped.publish(Event::new("a.public", Bytes::new()));
+        assert!(scoped.publish_local(Event::new("a.public", Bytes::new())));
+        let result = scoped
+            .request("a.public", Bytes::new(), Duration::from_millis(20))
+            .await;
+        assert!(matches!(result, Err(RequestError::Dropped)));
+
+        for _ in 0..3 {
+            let event = receive(&mut denied).await;
+            assert_eq!(event.header("subject"), Some("a.private"));
+        }
+
+        // rerouting to an allowed topic is fine
+        scoped.publish(Event::new("a.other", Bytes::new()));
+        assert_eq!(receive(&mut rx).await.topic, "a.public");
+    }
+}
@@ -1,16 +1,73 @@
 use futures::Sink;
 use futures_util::SinkExt;
 use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
 
-use crate::core::pattern::Pattern;
-use tokio::sync::mpsc;
+use crate::core::metrics::{EventMetrics, SubscriberMetrics};
+use crate::core::pattern::{Pattern, Segment};
+use modular_core::error::SubscribeError;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc, watch};
 
-type EventsHandler<T> = (Pattern, UnboundedSender<(String, T)>);
+/// Predicate deciding whether an event is delivered to a subscription.
+pub type EventFilter<T> = Arc<dyn Fn(&T) -> bool + Send + Sync>;
+
+type EventsHandler<T> = (u64, Pattern, Option<EventFilter<T>>, Queue<T>);
+
+/// Sending half of a subscription's queue, counting the events its listener hasn't
+/// taken yet.
+struct Queue<T> {
+    tx: UnboundedSender<T>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    fn send(&self, data: T) -> Result<(), SendError<T>> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(data).inspect_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// Members of a queue group, each event goes to one of them.
+struct Group<T> {
+    members: Vec<EventsHandler<T>>,
+    /// Name of the pattern argument whose segment picks the member, round-robin if unset.
+    key: Option<String>,
+    next: usize,
+}
 
 pub struct EventsManager<T> {
-    handlers: Mutex<Vec<EventsHandler<T>>>,
+    /// Replaced on changes so publishing doesn't hold the lock while filters run.
+    handlers: Mutex<Arc<Vec<EventsHandler<T>>>>,
+    /// Locked after `handlers`.
+    retained: Mutex<HashMap<String, T>>,
+    /// Locked after `handlers`.
+    groups: Mutex<HashMap<String, Group<T>>>,
+    next_id: AtomicU64,
+    interest: Arc<watch::Sender<()>>,
+    metrics: Option<Arc<EventMetrics>>,
     _pd: PhantomData<T>,
 }
 
@@ -24,43 +81,584 @@ impl<T> EventsManager<T> {
     pub fn new() -> Self {
         Self {
             handlers: Default::default(),
+            retained: Default::default(),
+            groups: Default::default(),
+            next_id: AtomicU64::new(0),
+            interest: Arc::new(watch::channel(()).0),
+            metrics: None,
             _pd: Default::default(),
         }
     }
 
-    pub fn publish(&self, dest: &str, data: T)
+    /// Records published events in `metrics`.
+    pub(crate) fn with_metrics(metrics: Arc<EventMetrics>) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..Self::new()
+        }
+    }
+
+    /// Returns the number of subscriptions the event was delivered to.
+    pub fn publish(&self, dest: &str, data: T) -> usize
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        let handlers = self.handlers.lock().clone();
+        self.deliver(&handlers, dest, data)
+    }
+
+    /// Publishes an event and keeps it as the value of `dest` that new subscriptions
+    /// receive before any other event, replacing the previous one.
+    pub fn publish_retained(&self, dest: &str, data: T) -> usize
     where
         T: Clone + Send + Sync + 'static,
     {
-        let mut guard = self.handlers.lock();
+        let handlers = {
+            let handlers = self.handlers.lock();
+            self.retained.lock().insert(dest.to_owned(), data.clone());
+            handlers.clone()
+        };
+        self.deliver(&handlers, dest, data)
+    }
 
-        guard.retain(|(pattern, handler)| {
-            if pattern.matches(dest) {
-                let data = data.clone();
-                let dest = dest.to_owned();
+    /// Sends an event to the handlers whose pattern and filter match and to the groups,
+    /// removing closed handlers. Returns the number of subscriptions it was delivered to.
+    fn deliver(&self, handlers: &[EventsHandler<T>], dest: &str, data: T) -> usize
+    where
+        T: Clone,
+    {
+        let mut delivered = 0;
+        let mut closed = vec![];
 
-                handler.send((dest, data)).is_ok()
-            } else {
-                true
+        for (id, pattern, filter, queue) in handlers {
+            if !pattern.matches(dest) || !accepts(filter.as_ref(), &data) {
+                continue;
             }
-        });
+
+            match queue.send(data.clone()) {
+                Ok(()) => delivered += 1,
+                Err(_) => closed.push(*id),
+            }
+        }
+
+        if !closed.is_empty() {
+            let mut handlers = self.handlers.lock();
+            Arc::make_mut(&mut handlers).retain(|(id, ..)| !closed.contains(id));
+        }
+
+        let delivered = delivered + self.deliver_groups(dest, data);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_publish(dest, delivered, closed.len());
+        }
+        delivered
+    }
+
+    /// Removes the retained value of `dest`, returns whether there was one.
+    pub fn clear_retained(&self, dest: &str) -> bool {
+        let _handlers = self.handlers.lock();
+        self.retained.lock().remove(dest).is_some()
+    }
+
+    /// Returns an id that can be passed to [`unsubscribe`](Self::unsubscribe). Retained
+    /// values of topics matching `pattern` are delivered first.
+    pub fn subscribe<L, E>(&self, pattern: Pattern, listener: L) -> u64
+    where
+        L: Sink<T, Error = E> + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+    {
+        self.subscribe_filtered(pattern, None, listener)
+    }
+
+    /// Like [`subscribe`](Self::subscribe), only delivering events accepted by `filter`.
+    /// The filter runs on publish, so rejected events never reach the listener.
+    pub fn subscribe_filtered<L, E>(
+        &self,
+        pattern: Pattern,
+        filter: Option<EventFilter<T>>,
+        listener: L,
+    ) -> u64
+    where
+        L: Sink<T, Error = E> + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (queue, rx) = queue();
+
+        let retained = {
+            let mut handlers = self.handlers.lock();
+            let retained = self
+                .retained
+                .lock()
+                .iter()
+                .filter(|(dest, _)| pattern.matches(dest))
+                .map(|(_, data)| data.clone())
+                .collect::<Vec<_>>();
+            Arc::make_mut(&mut handlers).push((id, pattern, filter.clone(), queue));
+            retained
+        };
+
+        // delivered ahead of the queue, so the filter doesn't run under the lock
+        let retained = retained
+            .into_iter()
+            .filter(|data| accepts(filter.as_ref(), data))
+            .collect();
+        self.listen(retained, rx, listener);
+        id
+    }
+
+    /// Subscribes `listener` as a member of the queue group `group`, each event matching
+    /// the patterns of the members is delivered to only one of them.
+    ///
+    /// Members are picked round-robin, or by hashing the segment captured by the pattern
+    /// argument `{key}` so that events with the same key go to the same member while
+    /// the group doesn't change. Every member has to use the key of the group and have
+    /// it as an argument of its pattern. Retained values are not delivered to group
+    /// members.
+    pub fn subscribe_group<L, E>(
+        &self,
+        group: &str,
+        key: Option<&str>,
+        pattern: Pattern,
+        listener: L,
+    ) -> Result<u64, SubscribeError>
+    where
+        L: Sink<T, Error = E> + Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        if let Some(key) = key {
+            if !pattern.segments().any(|v| v == Segment::Arg(Some(key))) {
+                return Err(SubscribeError::InvalidGroupKey(key.to_owned()));
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (queue, rx) = queue();
+
+        {
+            let _handlers = self.handlers.lock();
+            let mut groups = self.groups.lock();
+            let group = groups.entry(group.to_owned()).or_insert_with(|| Group {
+                members: vec![],
+                key: key.map(str::to_owned),
+                next: 0,
+            });
+
+            if group.key.as_deref() != key {
+                return Err(SubscribeError::InvalidGroupKey(
+                    key.unwrap_or_default().to_owned(),
+                ));
+            }
+            group.members.push((id, pattern, None, queue));
+        }
+
+        self.listen(vec![], rx, listener);
+        Ok(id)
     }
 
-    pub fn subscribe<L, E>(&self, pattern: Pattern, listener: L)
+    /// Forwards `first` and then events from `rx` to `listener` until it fails.
+    fn listen<L, E>(&self, first: Vec<T>, (mut rx, depth): Receiver<T>, listener: L)
     where
-        L: Sink<(String, T), Error = E> + Send + Sync + 'static,
+        L: Sink<T, Error = E> + Send + Sync + 'static,
         T: Send + Sync + 'static,
     {
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        self.handlers.lock().push((pattern, tx));
+        self.interest.send_replace(());
 
+        let interest = self.interest.clone();
         tokio::spawn(async move {
             let mut listener = Box::pin(listener);
-            while let Some((dest, data)) = rx.recv().await {
-                if listener.send((dest, data)).await.is_err() {
-                    break;
+            let mut first = futures::stream::iter(first.into_iter().map(Ok));
+            if listener.send_all(&mut first).await.is_ok() {
+                while let Some(data) = rx.recv().await {
+                    depth.fetch_sub(1, Ordering::Relaxed);
+                    if listener.send(data).await.is_err() {
+                        break;
+                    }
                 }
             }
+
+            drop(rx);
+            interest.send_replace(());
         });
     }
+
+    /// Delivers an event to one matching member of every group, returns the number of
+    /// groups it was delivered to.
+    fn deliver_groups(&self, dest: &str, data: T) -> usize
+    where
+        T: Clone,
+    {
+        let mut groups = self.groups.lock();
+        let mut delivered = 0;
+
+        for group in groups.values_mut() {
+            loop {
+                let matching = group
+                    .members
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, pattern, ..))| pattern.matches(dest))
+                    .map(|(idx, _)| idx)
+                    .collect::<Vec<_>>();
+                if matching.is_empty() {
+                    break;
+                }
+
+                let key = group.key.as_deref().and_then(|key| {
+                    let (_, pattern, ..) = &group.members[matching[0]];
+                    pattern.capture(dest, key)
+                });
+                let pick = match key {
+                    Some(key) => {
+                        let mut hasher = DefaultHasher::new();
+                        key.hash(&mut hasher);
+                        hasher.finish() as usize
+                    }
+                    None => {
+                        group.next = group.next.wrapping_add(1);
+                        group.next
+                    }
+                };
+
+                let idx = matching[pick % matching.len()];
+                let (.., handler) = &group.members[idx];
+                if handler.send(data.clone()).is_ok() {
+                    delivered += 1;
+                    break;
+                }
+
+                group.members.remove(idx);
+            }
+        }
+
+        groups.retain(|_, group| !group.members.is_empty());
+        delivered
+    }
+
+    /// Removes a subscription without waiting for its listener to fail.
+    pub fn unsubscribe(&self, id: u64) {
+        let mut handlers = self.handlers.lock();
+        Arc::make_mut(&mut handlers).retain(|(i, ..)| *i != id);
+
+        let mut groups = self.groups.lock();
+        for group in groups.values_mut() {
+            group.members.retain(|(i, ..)| *i != id);
+        }
+        groups.retain(|_, group| !group.members.is_empty());
+    }
+
+    /// Patterns of the subscriptions that are still alive.
+    pub fn patterns(&self) -> Vec<Pattern> {
+        let handlers = self.handlers.lock();
+        let groups = self.groups.lock();
+
+        handlers
+            .iter()
+            .chain(groups.values().flat_map(|group| group.members.iter()))
+            .filter(|(.., handler)| !handler.is_closed())
+            .map(|(_, pattern, ..)| pattern.clone())
+            .collect()
+    }
+
+    /// Changes whenever a subscription is added or closed.
+    pub fn watch_patterns(&self) -> watch::Receiver<()> {
+        self.interest.subscribe()
+    }
+
+    /// Live subscriptions and their queued events, grouped by pattern.
+    pub(crate) fn subscriber_metrics(&self) -> Vec<SubscriberMetrics> {
+        let handlers = self.handlers.lock();
+        let groups = self.groups.lock();
+
+        let mut by_pattern = BTreeMap::<&str, SubscriberMetrics>::new();
+        for (_, pattern, _, queue) in handlers
+            .iter()
+            .chain(groups.values().flat_map(|group| group.members.iter()))
+            .filter(|(.., queue)| !queue.is_closed())
+        {
+            let metrics = by_pattern
+                .entry(pattern.as_str())
+                .or_insert_with(|| SubscriberMetrics {
+                    pattern: pattern.as_str().to_owned(),
+                    subscriptions: 0,
+                    queue_depth: 0,
+                });
+            metrics.subscriptions += 1;
+            metrics.queue_depth += queue.depth.load(Ordering::Relaxed);
+        }
+
+        by_pattern.into_values().collect()
+    }
+}
+
+type Receiver<T> = (mpsc::UnboundedReceiver<T>, Arc<AtomicUsize>);
+
+fn queue<T>() -> (Queue<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let depth = Arc::new(AtomicUsize::new(0));
+    (
+        Queue {
+            tx,
+            depth: depth.clone(),
+        },
+        (rx, depth),
+    )
+}
+
+/// Runs the filter of a subscription, a panicking filter rejects the event.
+fn accepts<T>(filter: Option<&EventFilter<T>>, data: &T) -> bool {
+    let Some(filter) = filter else {
+        return true;
+    };
+
+    std::panic::catch_unwind(AssertUnwindSafe(|| filter(data))).unwrap_or_else(|_| {
+        tracing::error!("event filter panicked, dropping the event");
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn pattern(pattern: &str) -> Pattern {
+        Pattern::parse(pattern).unwrap()
+    }
+
+    async fn receive(rx: &mut UnboundedReceiver<String>) -> String {
+        tokio::time::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn nothing(rx: &mut UnboundedReceiver<String>) {
+        let next = tokio::time::timeout(Duration::from_millis(20), rx.next()).await;
+        assert!(next.is_err(), "unexpected {:?}", next);
+    }
+
+    #[tokio::test]
+    async fn retained_values_are_delivered_before_live_events() {
+        let events = EventsManager::new();
+        events.publish_retained("a.b", "old".to_owned());
+        events.publish_retained("a.b", "retained".to_owned());
+        events.publish_retained("c.d", "other".to_owned());
+        events.publish("a.e", "not retained".to_owned());
+
+        let (tx, mut rx) = unbounded();
+        events.subscribe(pattern("a.>"), tx);
+        events.publish("a.e", "live".to_owned());
+
+        assert_eq!(receive(&mut rx).await, "retained");
+        assert_eq!(receive(&mut rx).await, "live");
+        nothing(&mut rx).await;
+    }
+
+    #[tokio::test]
+    async fn retained_values_pass_the_filter() {
+        let events = EventsManager::new();
+        events.publish_retained("a.b", "x".to_owned());
+        events.publish_retained("a.c", "y".to_owned());
+
+        let (tx, mut rx) = unbounded();
+        events.subscribe_filtered(pattern("a.>"), Some(Arc::new(|v: &String| v == "y")), tx);
+
+        assert_eq!(receive(&mut rx).await, "y");
+        nothing(&mut rx).await;
+    }
+
+    #[tokio::test]
+    async fn filters_can_publish_and_subscribe() {
+        let events = Arc::new(EventsManager::new());
+        let (tx, mut rx) = unbounded();
+        events.subscribe(pattern("b.>"), tx);
+
+        let (tx, mut filtered) = unbounded();
+        let inner = events.clone();
+        events.subscribe_filtered(
+            pattern("a.>"),
+            Some(Arc::new(move |v: &String| {
+                inner.publish("b.c", format!("seen {}", v));
+                inner.subscribe(pattern("c.>"), futures::sink::drain());
+                true
+            })),
+            tx,
+        );
+
+        events.publish("a.b", "x".to_owned());
+        assert_eq!(receive(&mut filtered).await, "x");
+        assert_eq!(receive(&mut rx).await, "seen x");
+    }
+
+    #[tokio::test]
+    async fn panicking_filters_drop_the_event() {
+        let events = EventsManager::new();
+        let (tx, mut panicking) = unbounded();
+        events.subscribe_filtered(
+            pattern("a.>"),
+            Some(Arc::new(|v: &String| v != "panic" || panic!("filter"))),
+            tx,
+        );
+        let (tx, mut rx) = unbounded();
+        events.subscribe(pattern("a.>"), tx);
+
+        events.publish("a.b", "panic".to_owned());
+        events.publish("a.b", "x".to_owned());
+
+        assert_eq!(receive(&mut rx).await, "panic");
+        assert_eq!(receive(&mut rx).await, "x");
+        assert_eq!(receive(&mut panicking).await, "x");
+        nothing(&mut panicking).await;
+    }
+
+    #[tokio::test]
+    async fn cleared_values_are_not_delivered() {
+        let events = EventsManager::new();
+        events.publish_retained("a.b", "x".to_owned());
+        assert!(events.clear_retained("a.b"));
+        assert!(!events.clear_retained("a.b"));
+
+        let (tx, mut rx) = unbounded();
+        events.subscribe(pattern("a.>"), tx);
+        nothing(&mut rx).await;
+    }
+
+    #[tokio::test]
+    async fn retained_values_are_not_delivered_to_groups() {
+        let events = EventsManager::new();
+        events.publish_retained("a.b", "x".to_owned());
+
+        let (tx, mut rx) = unbounded();
+        events
+            .subscribe_group("workers", None, pattern("a.>"), tx)
+            .unwrap();
+        nothing(&mut rx).await;
+    }
+
+    /// Publishes `topics` and returns which of the `members` received each of them.
+    async fn spread(
+        events: &EventsManager<String>,
+        members: &mut [UnboundedReceiver<String>],
+        topics: &[&str],
+    ) -> Vec<usize> {
+        let mut picks = vec![];
+        for topic in topics {
+            assert_eq!(events.publish(topic, topic.to_string()), 1);
+
+            let mut received = None;
+            for _ in 0..500 {
+                received = members.iter_mut().position(|rx| rx.try_recv().is_ok());
+                if received.is_some() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            picks.push(received.expect("no member received the event"));
+        }
+        picks
+    }
+
+    fn members(
+        events: &EventsManager<String>,
+        key: Option<&str>,
+        n: usize,
+    ) -> Vec<UnboundedReceiver<String>> {
+        (0..n)
+            .map(|_| {
+                let (tx, rx) = unbounded();
+                events
+                    .subscribe_group("workers", key, pattern("jobs.{tenant}.>"), tx)
+                    .unwrap();
+                rx
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn group_members_take_turns() {
+        let events = EventsManager::new();
+        let mut members = members(&events, None, 2);
+
+        let picks = spread(&events, &mut members, &["jobs.a.x"; 4]).await;
+        assert_ne!(picks[0], picks[1]);
+        assert_eq!(picks[0], picks[2]);
+        assert_eq!(picks[1], picks[3]);
+    }
+
+    #[tokio::test]
+    async fn keyed_groups_stick_to_a_member() {
+        let events = EventsManager::new();
+        let mut members = members(&events, Some("tenant"), 3);
+
+        let topics = (0..20)
+            .map(|i| format!("jobs.t{}.x", i % 10))
+            .collect::<Vec<_>>();
+        let topics = topics.iter().map(String::as_str).collect::<Vec<_>>();
+        let picks = spread(&events, &mut members, &topics).await;
+
+        assert_eq!(picks[..10], picks[10..]);
+        // ten keys are very unlikely to hash to a single member
+        assert!(picks.iter().any(|v| *v != picks[0]));
+    }
+
+    #[tokio::test]
+    async fn closed_members_are_skipped() {
+        let events = EventsManager::new();
+        let mut members = members(&events, None, 2);
+        drop(members.remove(0));
+
+        // a member is known to be closed once its listener failed to take an event
+        for _ in 0..500 {
+            if events.patterns().len() == 1 {
+                break;
+            }
+            events.publish("jobs.a.x", String::new());
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        while members[0].try_recv().is_ok() {}
+
+        let picks = spread(&events, &mut members, &["jobs.a.x"; 3]).await;
+        assert_eq!(picks, [0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_members_leave_the_group() {
+        let events = EventsManager::new();
+        let (tx, _rx) = unbounded();
+        let id = events
+            .subscribe_group("workers", None, pattern("jobs.>"), tx)
+            .unwrap();
+
+        events.unsubscribe(id);
+        assert_eq!(events.publish("jobs.a", "x".to_owned()), 0);
+        assert!(events.patterns().is_empty());
+    }
+
+    #[tokio::test]
+    async fn members_have_to_agree_on_the_key() {
+        let events = EventsManager::<String>::new();
+        let (tx, _rx) = unbounded::<String>();
+        events
+            .subscribe_group(
+                "workers",
+                Some("tenant"),
+                pattern("jobs.{tenant}.>"),
+                tx.clone(),
+            )
+            .unwrap();
+
+        let result =
+            events.subscribe_group("workers", None, pattern("jobs.{tenant}.>"), tx.clone());
+        assert!(matches!(result, Err(SubscribeError::InvalidGroupKey(key)) if key.is_empty()));
+        let result =
+            events.subscribe_group("workers", Some("job"), pattern("jobs.{job}.>"), tx.clone());
+        assert!(matches!(result, Err(SubscribeError::InvalidGroupKey(key)) if key == "job"));
+
+        // the key has to be an argument of the pattern
+        let result = events.subscribe_group("others", Some("tenant"), pattern("jobs.>"), tx);
+        assert!(matches!(result, Err(SubscribeError::InvalidGroupKey(key)) if key == "tenant"));
+        assert_eq!(events.patterns().len(), 1);
+    }
 }
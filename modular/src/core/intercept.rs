@@ -0,0 +1,60 @@
+//! Publish interceptors, see [`Modular::add_interceptor`](super::Modular::add_interceptor).
+
+use modular_core::event::Event;
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Runs on every event published on a [`Modular`](super::Modular) instance before it's
+/// delivered, e.g. to validate, redact or audit events.
+pub trait Interceptor: Send + Sync {
+    /// Returns the event to publish in place of `event`, possibly with a different topic,
+    /// or `None` to drop it.
+    fn intercept(&self, event: Event) -> Option<Event>;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(Event) -> Option<Event> + Send + Sync,
+{
+    fn intercept(&self, event: Event) -> Option<Event> {
+        self(event)
+    }
+}
+
+type Chain = Arc<Vec<(u64, Arc<dyn Interceptor>)>>;
+
+#[derive(Default)]
+pub(crate) struct Interceptors {
+    next_id: AtomicU64,
+    /// Replaced on changes so publishing doesn't hold the lock while interceptors run.
+    chain: RwLock<Chain>,
+}
+
+impl Interceptors {
+    pub(crate) fn add(&self, interceptor: Arc<dyn Interceptor>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut chain = self.chain.write();
+        let mut next = Vec::clone(&chain);
+        next.push((id, interceptor));
+        *chain = Arc::new(next);
+        id
+    }
+
+    pub(crate) fn remove(&self, id: u64) -> bool {
+        let mut chain = self.chain.write();
+        let mut next = Vec::clone(&chain);
+        next.retain(|(i, _)| *i != id);
+        let removed = next.len() != chain.len();
+        *chain = Arc::new(next);
+        removed
+    }
+
+    /// Passes `event` through the interceptors in the order they were added.
+    pub(crate) fn apply(&self, event: Event) -> Option<Event> {
+        let chain = self.chain.read().clone();
+        chain.iter().try_fold(event, |event, (_, interceptor)| {
+            interceptor.intercept(event)
+        })
+    }
+}
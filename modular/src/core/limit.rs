@@ -0,0 +1,380 @@
+//! Token-bucket rate limits, see [`Modular::set_topic_limit`](super::Modular::set_topic_limit)
+//! and [`Modular::set_module_limit`](super::Modular::set_module_limit).
+
+use crate::core::pattern::Pattern;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Operations allowed per second on average.
+    pub per_second: f64,
+    /// Operations allowed at once after being idle.
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: f64) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitStats {
+    /// Topic pattern or module name pattern of the limit.
+    pub pattern: String,
+    /// Action of module limits that only apply to one action.
+    pub action: Option<String>,
+    pub limit: RateLimit,
+    /// Operations rejected by the limit so far.
+    pub rejected: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets are evicted once the map has grown to this size, or double its size after the
+/// last eviction.
+const MIN_EVICT_AT: usize = 64;
+
+/// Buckets of a rule by caller identity, callers without one share a bucket.
+struct Buckets {
+    by_identity: HashMap<Option<String>, Bucket>,
+    evict_at: usize,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            by_identity: HashMap::new(),
+            evict_at: MIN_EVICT_AT,
+        }
+    }
+}
+
+impl Buckets {
+    /// Drops the buckets that refilled completely, a new bucket starts out full anyway.
+    fn evict_full(&mut self, limit: &RateLimit, now: Instant) {
+        self.by_identity
+            .retain(|_, bucket| refilled(bucket, limit, now) < limit.burst);
+        self.evict_at = (self.by_identity.len() * 2).max(MIN_EVICT_AT);
+    }
+}
+
+fn refilled(bucket: &Bucket, limit: &RateLimit, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * limit.per_second).min(limit.burst)
+}
+
+struct Rule {
+    pattern: Pattern,
+    action: Option<String>,
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+    rejected: AtomicU64,
+}
+
+impl Rule {
+    fn stats(&self) -> LimitStats {
+        LimitStats {
+            pattern: self.pattern.to_string(),
+            action: self.action.clone(),
+            limit: self.limit,
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RateLimits {
+    topics: RwLock<Vec<Arc<Rule>>>,
+    modules: RwLock<Vec<Arc<Rule>>>,
+}
+
+impl RateLimits {
+    /// Sets or, with `None`, removes the limit of publishing to topics matching `pattern`.
+    pub(crate) fn set_topic(&self, pattern: Pattern, limit: Option<RateLimit>) {
+        set(&self.topics, pattern, None, limit)
+    }
+
+    /// Sets or, with `None`, removes the limit of invoking modules whose name matches
+    /// `pattern`, optionally only for `action`.
+    pub(crate) fn set_module(
+        &self,
+        pattern: Pattern,
+        action: Option<&str>,
+        limit: Option<RateLimit>,
+    ) {
+        set(&self.modules, pattern, action, limit)
+    }
+
+    /// Takes a token from every limit of `topic`, returns false without taking any if one
+    /// of them is exhausted.
+    pub(crate) fn acquire_publish(&self, topic: &str, identity: Option<&str>) -> bool {
+        acquire(&self.topics.read(), identity, |rule| {
+            rule.pattern.matches_strict(topic)
+        })
+    }
+
+    pub(crate) fn acquire_invoke(
+        &self,
+        module: &str,
+        action: &str,
+        identity: Option<&str>,
+    ) -> bool {
+        acquire(&self.modules.read(), identity, |rule| {
+            rule.pattern.matches_strict(module)
+                && rule.action.as_deref().is_none_or(|v| v == action)
+        })
+    }
+
+    pub(crate) fn topic_stats(&self) -> Vec<LimitStats> {
+        self.topics.read().iter().map(|rule| rule.stats()).collect()
+    }
+
+    pub(crate) fn module_stats(&self) -> Vec<LimitStats> {
+        self.modules
+            .read()
+            .iter()
+            .map(|rule| rule.stats())
+            .collect()
+    }
+}
+
+fn set(
+    rules: &RwLock<Vec<Arc<Rule>>>,
+    pattern: Pattern,
+    action: Option<&str>,
+    limit: Option<RateLimit>,
+) {
+    let mut rules = rules.write();
+    rules.retain(|rule| {
+        rule.pattern.as_str() != pattern.as_str() || rule.action.as_deref() != action
+    });
+
+    if let Some(limit) = limit {
+        rules.push(Arc::new(Rule {
+            pattern,
+            action: action.map(str::to_owned),
+            limit,
+            buckets: Default::default(),
+            rejected: AtomicU64::new(0),
+        }));
+    }
+}
+
+fn acquire(rules: &[Arc<Rule>], identity: Option<&str>, matches: impl Fn(&Rule) -> bool) -> bool {
+    let now = Instant::now();
+    let key = identity.map(str::to_owned);
+
+    // rules are always locked in the same order
+    let mut locked = Vec::<MutexGuard<'_, Buckets>>::new();
+    for rule in rules.iter().filter(|rule| matches(rule)) {
+        let mut buckets = rule.buckets.lock();
+        if buckets.by_identity.len() >= buckets.evict_at {
+            buckets.evict_full(&rule.limit, now);
+        }
+
+        let bucket = buckets.by_identity.entry(key.clone()).or_insert(Bucket {
+            tokens: rule.limit.burst,
+            updated: now,
+        });
+        bucket.tokens = refilled(bucket, &rule.limit, now);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            rule.rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        locked.push(buckets);
+    }
+
+    for mut buckets in locked {
+        if let Some(bucket) = buckets.by_identity.get_mut(&key) {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    true
+}
+
+/// Payload size limits, see [`Modular::set_max_payload`](super::Modular::set_max_payload).
+#[derive(Default)]
+pub(crate) struct PayloadLimits {
+    default: RwLock<Option<usize>>,
+    topics: RwLock<Vec<(Pattern, usize)>>,
+    modules: RwLock<Vec<(Pattern, usize)>>,
+}
+
+impl PayloadLimits {
+    pub(crate) fn set_default(&self, limit: Option<usize>) {
+        *self.default.write() = limit;
+    }
+
+    pub(crate) fn set_topic(&self, pattern: Pattern, limit: Option<usize>) {
+        set_override(&self.topics, pattern, limit)
+    }
+
+    pub(crate) fn set_module(&self, pattern: Pattern, limit: Option<usize>) {
+        set_override(&self.modules, pattern, limit)
+    }
+
+    pub(crate) fn for_topic(&self, topic: &str) -> Option<usize> {
+        self.resolve(&self.topics.read(), topic)
+    }
+
+    pub(crate) fn for_module(&self, module: &str) -> Option<usize> {
+        self.resolve(&self.modules.read(), module)
+    }
+
+    /// The largest limit of any subject, `None` if some are unlimited.
+    pub(crate) fn largest(&self) -> Option<usize> {
+        let default = (*self.default.read())?;
+        let topics = self.topics.read();
+        let modules = self.modules.read();
+
+        let largest = topics
+            .iter()
+            .chain(modules.iter())
+            .map(|(_, limit)| *limit)
+            .fold(default, usize::max);
+
+        Some(largest)
+    }
+
+    /// The smallest override matching `subject`, or the default if none does.
+    fn resolve(&self, overrides: &[(Pattern, usize)], subject: &str) -> Option<usize> {
+        overrides
+            .iter()
+            .filter(|(pattern, _)| pattern.matches_strict(subject))
+            .map(|(_, limit)| *limit)
+            .min()
+            .or(*self.default.read())
+    }
+}
+
+fn set_override(overrides: &RwLock<Vec<(Pattern, usize)>>, pattern: Pattern, limit: Option<usize>) {
+    let mut overrides = overrides.write();
+    overrides.retain(|(v, _)| v.as_str() != pattern.as_str());
+    if let Some(limit) = limit {
+        overrides.push((pattern, limit));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pattern(pattern: &str) -> Pattern {
+        Pattern::parse(pattern).unwrap()
+    }
+
+    fn limits() -> RateLimits {
+        let limits = RateLimits::default();
+        limits.set_topic(pattern("a.>"), Some(RateLimit::new(0.001, 2.0)));
+        limits.set_module(
+            pattern("storage"),
+            Some("put"),
+            Some(RateLimit::new(0.001, 1.0)),
+        );
+        limits
+    }
+
+    #[test]
+    fn bursts_are_limited_per_identity() {
+        let limits = limits();
+        assert!(limits.acquire_publish("a.b", Some("x")));
+        assert!(limits.acquire_publish("a.c", Some("x")));
+        assert!(!limits.acquire_publish("a.b", Some("x")));
+
+        assert!(limits.acquire_publish("a.b", Some("y")));
+        assert!(limits.acquire_publish("a.b", None));
+        assert_eq!(limits.topic_stats()[0].rejected, 1);
+    }
+
+    #[test]
+    fn rules_match_strictly() {
+        let limits = limits();
+        for _ in 0..3 {
+            assert!(limits.acquire_publish("a", None));
+            assert!(limits.acquire_invoke("storage.s3", "put", None));
+        }
+    }
+
+    #[test]
+    fn module_limits_apply_to_their_action() {
+        let limits = limits();
+        assert!(limits.acquire_invoke("storage", "put", None));
+        assert!(!limits.acquire_invoke("storage", "put", None));
+        assert!(limits.acquire_invoke("storage", "get", None));
+    }
+
+    #[test]
+    fn no_token_is_taken_when_a_limit_is_exhausted() {
+        let limits = limits();
+        limits.set_topic(pattern("a.b"), Some(RateLimit::new(0.001, 1.0)));
+        assert!(limits.acquire_publish("a.b", None));
+        assert!(!limits.acquire_publish("a.b", None));
+
+        // `a.>` still has its second token
+        assert!(limits.acquire_publish("a.c", None));
+        assert!(!limits.acquire_publish("a.c", None));
+    }
+
+    #[test]
+    fn removed_limits_no_longer_apply() {
+        let limits = limits();
+        limits.set_topic(pattern("a.>"), None);
+        for _ in 0..3 {
+            assert!(limits.acquire_publish("a.b", None));
+        }
+        assert!(limits.topic_stats().is_empty());
+    }
+
+    #[test]
+    fn payload_overrides_match_strictly() {
+        let limits = PayloadLimits::default();
+        limits.set_default(Some(10));
+        limits.set_topic(pattern("a.b"), Some(5));
+        limits.set_topic(pattern("a.>"), Some(20));
+
+        assert_eq!(limits.for_topic("a.b"), Some(5));
+        assert_eq!(limits.for_topic("a.c"), Some(20));
+        assert_eq!(limits.for_topic("a"), Some(10));
+        assert_eq!(limits.largest(), Some(20));
+    }
+
+    #[test]
+    fn full_buckets_are_evicted() {
+        let limits = RateLimits::default();
+        limits.set_topic(pattern("a.>"), Some(RateLimit::new(1000.0, 1.0)));
+        let buckets = || limits.topics.read()[0].buckets.lock().by_identity.len();
+
+        for idx in 0..MIN_EVICT_AT {
+            assert!(limits.acquire_publish("a.b", Some(&idx.to_string())));
+        }
+        assert_eq!(buckets(), MIN_EVICT_AT);
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limits.acquire_publish("a.b", Some("new")));
+        assert_eq!(buckets(), 1);
+
+        // buckets that didn't refill yet are kept
+        let limits = RateLimits::default();
+        limits.set_topic(pattern("a.>"), Some(RateLimit::new(0.001, 1.0)));
+        for idx in 0..=MIN_EVICT_AT {
+            assert!(limits.acquire_publish("a.b", Some(&idx.to_string())));
+        }
+        assert_eq!(
+            limits.topics.read()[0].buckets.lock().evict_at,
+            MIN_EVICT_AT * 2
+        );
+        assert!(!limits.acquire_publish("a.b", Some("0")));
+    }
+}
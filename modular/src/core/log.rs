@@ -0,0 +1,882 @@
+//! Persistent log of events.
+//!
+//! Events of topics matching the configured patterns are appended to segment files in
+//! a directory, each named after the offset of its first event. A segment is closed
+//! once it reaches [`LogOptions::segment_size`], closed segments are removed when the
+//! log exceeds [`LogOptions::max_bytes`] or their newest event is older than
+//! [`LogOptions::max_age`].
+//!
+//! Files are written by a background thread so publishing doesn't wait for the disk,
+//! [`LogOptions::sync`] decides when written events are synced to it.
+//!
+//! Every event is stored as a record:
+//!
+//! ```text
+//! len: u32 | offset: u64 | timestamp_ms: u64 | seq: u64 | topic_len: u16 |
+//! headers_len: u32 | topic | headers | data
+//! ```
+//!
+//! where `len` is the length of everything after it and `headers` is a sequence of
+//! `name_len: u16 | name | value_len: u16 | value`. Integers are big endian.
+
+use crate::core::events::EventsManager;
+use crate::core::pattern::Pattern;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::channel::oneshot;
+use futures::{Sink, SinkExt, StreamExt};
+use modular_core::error::PatternError;
+use modular_core::event::Event;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SEGMENT_EXTENSION: &str = "log";
+const HEADER_LEN: usize = 4 + 8 + 8 + 8 + 2 + 4;
+
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// Topics of the events that are logged.
+    pub patterns: Vec<Pattern>,
+    /// Size after which a new segment is started.
+    pub segment_size: u64,
+    /// Oldest segments are removed while the log is larger than this.
+    pub max_bytes: Option<u64>,
+    /// Segments are removed once their newest event is older than this.
+    pub max_age: Option<Duration>,
+    pub sync: SyncPolicy,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            patterns: vec![],
+            segment_size: 16 * 1024 * 1024,
+            max_bytes: None,
+            max_age: None,
+            sync: SyncPolicy::Never,
+        }
+    }
+}
+
+/// When written events are synced to disk, see [`EventLog::flush`] to sync on demand.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Left to the operating system, events can be lost if the machine crashes.
+    Never,
+    /// After every batch of written events.
+    Always,
+    /// At most this long after an event was written.
+    Interval(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub offset: u64,
+    pub timestamp: SystemTime,
+    /// The event as it was published, with its headers and sequence number.
+    pub event: Event,
+}
+
+/// Where [`EventLog::subscribe_from`] starts replaying.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReplayFrom {
+    /// The event with this offset, or the oldest retained one if it was removed.
+    Offset(u64),
+    /// The first event logged at or after this time.
+    Timestamp(SystemTime),
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    path: PathBuf,
+    base: u64,
+    /// Offset after the last event in the segment.
+    end: u64,
+    size: u64,
+    last_timestamp: u64,
+}
+
+struct Inner {
+    segments: Vec<Segment>,
+    /// Taken when the log is dropped so the writer finishes.
+    writer: Option<mpsc::Sender<Command>>,
+}
+
+impl Inner {
+    fn send(&self, command: Command) {
+        let sent = self
+            .writer
+            .as_ref()
+            .is_some_and(|writer| writer.send(command).is_ok());
+        if !sent {
+            tracing::error!("event log writer is gone");
+        }
+    }
+}
+
+pub struct EventLog {
+    dir: PathBuf,
+    options: LogOptions,
+    inner: Mutex<Inner>,
+    live: EventsManager<Record>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl EventLog {
+    /// Opens the log in `dir`, creating the directory if needed. A partially written
+    /// record at the end of the log is discarded.
+    pub fn open(dir: impl AsRef<Path>, options: LogOptions) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut bases = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|v| v.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+
+            if let Some(base) = path
+                .file_stem()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.parse::<u64>().ok())
+            {
+                bases.push(base);
+            }
+        }
+        bases.sort_unstable();
+
+        let mut segments = vec![];
+        for base in bases {
+            let path = segment_path(&dir, base);
+            let data = Bytes::from(std::fs::read(&path)?);
+            let (records, valid) = decode(data);
+
+            let segment = Segment {
+                path,
+                base,
+                end: records.last().map(|r| r.offset + 1).unwrap_or(base),
+                size: valid as u64,
+                last_timestamp: records.last().map(|r| millis(r.timestamp)).unwrap_or(0),
+            };
+
+            if segment.size < std::fs::metadata(&segment.path)?.len() {
+                tracing::warn!(path = %segment.path.display(), "truncating damaged segment");
+                OpenOptions::new()
+                    .write(true)
+                    .open(&segment.path)?
+                    .set_len(segment.size)?;
+            }
+
+            segments.push(segment);
+        }
+
+        if segments.is_empty() {
+            segments.push(Segment {
+                path: segment_path(&dir, 0),
+                base: 0,
+                end: 0,
+                size: 0,
+                last_timestamp: 0,
+            });
+        }
+
+        let active = &segments[segments.len() - 1];
+        let writer = Writer {
+            file: open_segment(&active.path)?,
+            size: active.size,
+            sync: options.sync,
+            dirty: false,
+            synced: Instant::now(),
+            error: None,
+        };
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("modular-event-log".to_owned())
+            .spawn(move || writer.run(rx))?;
+
+        let log = Self {
+            dir,
+            options,
+            inner: Mutex::new(Inner {
+                segments,
+                writer: Some(tx),
+            }),
+            live: EventsManager::new(),
+            writer: Some(handle),
+        };
+        log.apply_retention(&mut log.inner.lock());
+
+        Ok(log)
+    }
+
+    /// Whether events published to `topic` are logged.
+    pub fn is_logged(&self, topic: &str) -> bool {
+        self.options.patterns.iter().any(|i| i.matches(topic))
+    }
+
+    /// Offsets of the oldest retained event and the next event to be logged.
+    pub fn offsets(&self) -> (u64, u64) {
+        let inner = self.inner.lock();
+        (
+            inner.segments[0].base,
+            inner.segments[inner.segments.len() - 1].end,
+        )
+    }
+
+    /// Appends an event regardless of the configured patterns and returns its offset.
+    /// The event is written in the background, failed writes are logged and reported by
+    /// the next [`flush`](Self::flush).
+    pub fn append(&self, event: &Event) -> io::Result<u64> {
+        let mut inner = self.inner.lock();
+
+        let timestamp = SystemTime::now();
+        let offset = inner.segments[inner.segments.len() - 1].end;
+        let record = encode(offset, timestamp, event)?;
+
+        let active = inner.segments.len() - 1;
+        if inner.segments[active].size >= self.options.segment_size {
+            let path = segment_path(&self.dir, offset);
+            inner.send(Command::Roll(path.clone()));
+            inner.segments.push(Segment {
+                path,
+                base: offset,
+                end: offset,
+                size: 0,
+                last_timestamp: 0,
+            });
+        }
+
+        let size = record.len() as u64;
+        inner.send(Command::Write(record));
+
+        let segment = inner.segments.last_mut().unwrap();
+        segment.end += 1;
+        segment.size += size;
+        segment.last_timestamp = millis(timestamp);
+        self.apply_retention(&mut inner);
+
+        let record = Record {
+            offset,
+            timestamp,
+            event: event.clone(),
+        };
+        self.live.publish(&event.topic, record);
+
+        Ok(offset)
+    }
+
+    /// Waits until the events appended so far are written and synced to disk, fails with
+    /// the first error since the last flush.
+    pub async fn flush(&self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.inner.lock().send(Command::Flush(tx));
+        rx.await
+            .unwrap_or_else(|_| Err(io::Error::other("event log writer is gone")))
+    }
+
+    /// Subscribes `sink` to events of topics matching `pattern`, starting with the logged
+    /// events from `from` and continuing with events as they are appended.
+    pub fn subscribe_from<S, E>(
+        &self,
+        pattern: &str,
+        from: ReplayFrom,
+        sink: S,
+    ) -> Result<(), PatternError>
+    where
+        S: Sink<Record, Error = E> + Send + 'static,
+    {
+        let pattern = Pattern::parse(pattern)?;
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+
+        // events appended after this point are delivered live, older ones are replayed
+        // once the writer caught up with them
+        let (written_tx, written) = oneshot::channel();
+        let (segments, end) = {
+            let inner = self.inner.lock();
+            self.live.subscribe(pattern.clone(), tx);
+            inner.send(Command::Written(written_tx));
+            (inner.segments.clone(), inner.segments.last().unwrap().end)
+        };
+
+        tokio::spawn(async move {
+            let mut sink = Box::pin(sink);
+            let _ = written.await;
+            let mut started = false;
+
+            for segment in segments {
+                let skip = match from {
+                    ReplayFrom::Offset(offset) => segment.end <= offset,
+                    ReplayFrom::Timestamp(ts) => !started && segment.last_timestamp < millis(ts),
+                };
+                if skip || segment.base >= end {
+                    continue;
+                }
+
+                let path = segment.path.clone();
+                let data = match tokio::task::spawn_blocking(move || std::fs::read(path)).await {
+                    Ok(Ok(v)) => Bytes::from(v),
+                    // removed by retention in the meantime
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Ok(Err(e)) => {
+                        tracing::error!(error = %e, path = %segment.path.display(), "failed to replay segment");
+                        return;
+                    }
+                    Err(_) => return,
+                };
+
+                for record in decode(data).0 {
+                    if record.offset >= end {
+                        break;
+                    }
+
+                    started = started
+                        || match from {
+                            ReplayFrom::Offset(offset) => record.offset >= offset,
+                            ReplayFrom::Timestamp(ts) => record.timestamp >= ts,
+                        };
+
+                    if started
+                        && pattern.matches(&record.event.topic)
+                        && sink.send(record).await.is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+
+            let _ = rx
+                .filter(|record| {
+                    futures::future::ready(match from {
+                        ReplayFrom::Offset(offset) => record.offset >= offset,
+                        ReplayFrom::Timestamp(_) => true,
+                    })
+                })
+                .map(Ok)
+                .forward(sink)
+                .await;
+        });
+
+        Ok(())
+    }
+
+    /// Removes the oldest closed segments that exceed the configured limits.
+    fn apply_retention(&self, inner: &mut Inner) {
+        let now = millis(SystemTime::now());
+
+        while inner.segments.len() > 1 {
+            let total = inner.segments.iter().map(|s| s.size).sum::<u64>();
+            let oldest = &inner.segments[0];
+
+            let too_large = self.options.max_bytes.is_some_and(|max| total > max);
+            let too_old = self
+                .options
+                .max_age
+                .is_some_and(|max| oldest.last_timestamp + (max.as_millis() as u64) < now);
+
+            if !too_large && !too_old {
+                break;
+            }
+
+            let segment = inner.segments.remove(0);
+            inner.send(Command::Remove(segment.path));
+        }
+    }
+}
+
+impl Drop for EventLog {
+    /// Waits for the writer to write and sync the remaining events.
+    fn drop(&mut self) {
+        self.inner.get_mut().writer = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Command {
+    Write(Bytes),
+    /// Closes the active segment and continues writing to a new one.
+    Roll(PathBuf),
+    Remove(PathBuf),
+    Flush(oneshot::Sender<io::Result<()>>),
+    /// Acknowledges that the preceding records were written.
+    Written(oneshot::Sender<()>),
+}
+
+/// Writes the segment files on a thread of its own, in the order of the commands.
+struct Writer {
+    file: File,
+    /// Length of the records written to `file` completely.
+    size: u64,
+    sync: SyncPolicy,
+    /// Whether there are written events that weren't synced yet.
+    dirty: bool,
+    synced: Instant,
+    error: Option<io::Error>,
+}
+
+impl Writer {
+    fn run(mut self, rx: mpsc::Receiver<Command>) {
+        let mut batch = BytesMut::new();
+
+        loop {
+            let command = match rx.try_recv() {
+                Ok(command) => command,
+                Err(mpsc::TryRecvError::Empty) => {
+                    self.write(&mut batch);
+                    if self.sync == SyncPolicy::Always {
+                        self.sync();
+                    }
+
+                    match self.recv(&rx) {
+                        Some(command) => command,
+                        None => break,
+                    }
+                }
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            };
+
+            match command {
+                Command::Write(record) => batch.extend_from_slice(&record),
+                command => {
+                    self.write(&mut batch);
+                    self.handle(command);
+                }
+            }
+        }
+
+        self.write(&mut batch);
+        self.sync();
+    }
+
+    /// Waits for the next command, syncing in the meantime when the interval elapses.
+    fn recv(&mut self, rx: &mpsc::Receiver<Command>) -> Option<Command> {
+        loop {
+            let SyncPolicy::Interval(interval) = self.sync else {
+                return rx.recv().ok();
+            };
+            if !self.dirty {
+                return rx.recv().ok();
+            }
+
+            let timeout = (self.synced + interval).saturating_duration_since(Instant::now());
+            match rx.recv_timeout(timeout) {
+                Ok(command) => return Some(command),
+                Err(mpsc::RecvTimeoutError::Timeout) => self.sync(),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Write(_) => unreachable!("writes are batched"),
+            Command::Roll(path) => {
+                if self.sync != SyncPolicy::Never {
+                    self.sync();
+                }
+
+                match open_segment(&path) {
+                    Ok(file) => {
+                        self.file = file;
+                        self.size = 0;
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, path = %path.display(), "failed to create segment");
+                        self.error.get_or_insert(e);
+                    }
+                }
+            }
+            Command::Remove(path) => {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!(error = %e, path = %path.display(), "failed to remove segment");
+                }
+            }
+            Command::Flush(tx) => {
+                self.sync();
+                let _ = tx.send(self.error.take().map_or(Ok(()), Err));
+            }
+            Command::Written(tx) => {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    fn write(&mut self, batch: &mut BytesMut) {
+        if batch.is_empty() {
+            return;
+        }
+
+        match self.file.write_all(batch) {
+            Ok(()) => {
+                self.size += batch.len() as u64;
+                self.dirty = true;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "failed to write events to the log");
+                // don't leave a partial record in front of the next one
+                let _ = self.file.set_len(self.size);
+                self.error.get_or_insert(e);
+            }
+        }
+        batch.clear();
+    }
+
+    fn sync(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        if let Err(e) = self.file.sync_data() {
+            tracing::error!(error = %e, "failed to sync the log");
+            self.error.get_or_insert(e);
+        }
+        self.dirty = false;
+        self.synced = Instant::now();
+    }
+}
+
+fn segment_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", base, SEGMENT_EXTENSION))
+}
+
+fn open_segment(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn encode(offset: u64, timestamp: SystemTime, event: &Event) -> io::Result<Bytes> {
+    let mut headers = BytesMut::new();
+    for (name, value) in &event.headers {
+        put_string(&mut headers, name)?;
+        put_string(&mut headers, value)?;
+    }
+
+    let topic_len = u16::try_from(event.topic.len()).map_err(|_| invalid("topic is too long"))?;
+    let headers_len = u32::try_from(headers.len()).map_err(|_| invalid("headers are too large"))?;
+    let len = u32::try_from(HEADER_LEN - 4 + event.topic.len() + headers.len() + event.data.len())
+        .map_err(|_| invalid("event is too large"))?;
+
+    let mut buf = BytesMut::with_capacity(4 + len as usize);
+    buf.put_u32(len);
+    buf.put_u64(offset);
+    buf.put_u64(millis(timestamp));
+    buf.put_u64(event.seq);
+    buf.put_u16(topic_len);
+    buf.put_u32(headers_len);
+    buf.put_slice(event.topic.as_bytes());
+    buf.put_slice(&headers);
+    buf.put_slice(&event.data);
+    Ok(buf.freeze())
+}
+
+fn put_string(buf: &mut BytesMut, value: &str) -> io::Result<()> {
+    let len = u16::try_from(value.len()).map_err(|_| invalid("header is too long"))?;
+    buf.put_u16(len);
+    buf.put_slice(value.as_bytes());
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Decodes the records of a segment, returns them with the length of the valid prefix.
+fn decode(mut data: Bytes) -> (Vec<Record>, usize) {
+    let total = data.len();
+    let mut records = vec![];
+
+    while data.len() >= HEADER_LEN {
+        let len = (&data[..4]).get_u32() as usize;
+        if len < HEADER_LEN - 4 || data.len() < 4 + len {
+            break;
+        }
+
+        let Some(record) = decode_record(data.slice(4..4 + len)) else {
+            break;
+        };
+        data.advance(4 + len);
+        records.push(record);
+    }
+
+    (records, total - data.len())
+}
+
+fn decode_record(mut buf: Bytes) -> Option<Record> {
+    let offset = buf.get_u64();
+    let timestamp = UNIX_EPOCH + Duration::from_millis(buf.get_u64());
+    let seq = buf.get_u64();
+    let topic_len = buf.get_u16() as usize;
+    let headers_len = buf.get_u32() as usize;
+    if buf.len() < topic_len + headers_len {
+        return None;
+    }
+
+    let topic = String::from_utf8(buf.split_to(topic_len).to_vec()).ok()?;
+    let mut encoded = buf.split_to(headers_len);
+    let mut headers = BTreeMap::new();
+    while !encoded.is_empty() {
+        let name = get_string(&mut encoded)?;
+        headers.insert(name, get_string(&mut encoded)?);
+    }
+
+    Some(Record {
+        offset,
+        timestamp,
+        event: Event {
+            topic,
+            data: buf,
+            headers,
+            seq,
+        },
+    })
+}
+
+fn get_string(buf: &mut Bytes) -> Option<String> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let len = buf.get_u16() as usize;
+    if buf.len() < len {
+        return None;
+    }
+    String::from_utf8(buf.split_to(len).to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Modular;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use modular_core::modular::Modular as _;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// A directory removed when the test is done.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicU64 = AtomicU64::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "modular-log-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn options(segment_size: u64) -> LogOptions {
+        LogOptions {
+            patterns: vec![Pattern::parse("a.>").unwrap()],
+            segment_size,
+            ..Default::default()
+        }
+    }
+
+    fn segments(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    async fn receive(rx: &mut UnboundedReceiver<Record>, count: usize) -> Vec<Record> {
+        let mut records = vec![];
+        while records.len() < count {
+            let record = tokio::time::timeout(Duration::from_secs(5), rx.next())
+                .await
+                .unwrap()
+                .unwrap();
+            records.push(record);
+        }
+        records
+    }
+
+    #[tokio::test]
+    async fn events_are_replayed_from_an_offset_with_their_envelope() {
+        let dir = TempDir::new();
+        let log = EventLog::open(&dir.0, options(64)).unwrap();
+        for i in 0..5 {
+            let mut event = Event::new(&format!("a.{}", i), Bytes::from(vec![i as u8]))
+                .with_header("k", &i.to_string());
+            event.seq = 100 + i;
+            assert_eq!(log.append(&event).unwrap(), i);
+        }
+        log.flush().await.unwrap();
+        assert!(segments(&dir.0) > 1);
+
+        let (tx, mut rx) = unbounded();
+        log.subscribe_from("a.>", ReplayFrom::Offset(2), tx)
+            .unwrap();
+        let records = receive(&mut rx, 3).await;
+        for (record, i) in records.iter().zip(2..) {
+            assert_eq!(record.offset, i);
+            assert_eq!(record.event.topic, format!("a.{}", i));
+            assert_eq!(record.event.data, vec![i as u8]);
+            assert_eq!(record.event.header("k"), Some(i.to_string().as_str()));
+            assert_eq!(record.event.seq, 100 + i);
+        }
+
+        // replay continues with live events
+        log.append(&Event::new("a.5", Bytes::new())).unwrap();
+        assert_eq!(receive(&mut rx, 1).await[0].offset, 5);
+
+        // the envelope survives reopening the log
+        drop(log);
+        let log = EventLog::open(&dir.0, options(64)).unwrap();
+        assert_eq!(log.offsets(), (0, 6));
+        let (tx, mut rx) = unbounded();
+        log.subscribe_from("{}.4", ReplayFrom::Offset(0), tx)
+            .unwrap();
+        let record = receive(&mut rx, 1).await.remove(0);
+        assert_eq!(record.offset, 4);
+        assert_eq!(record.event.header("k"), Some("4"));
+        assert_eq!(record.event.seq, 104);
+    }
+
+    #[tokio::test]
+    async fn published_events_are_logged_with_their_sequence_number() {
+        let dir = TempDir::new();
+        let log = Arc::new(EventLog::open(&dir.0, options(1024)).unwrap());
+        let modular = Modular::default();
+        modular.set_event_log(Some(log.clone()));
+
+        modular.publish(Event::new("b.x", Bytes::new()));
+        modular.publish(Event::new("a.x", Bytes::from("x")).with_header("k", "v"));
+
+        let (tx, mut rx) = unbounded();
+        log.subscribe_from("a.>", ReplayFrom::Offset(0), tx)
+            .unwrap();
+        let record = receive(&mut rx, 1).await.remove(0);
+        assert_eq!(record.offset, 0);
+        assert_eq!(record.event.seq, 1);
+        assert_eq!(record.event.header("k"), Some("v"));
+    }
+
+    #[tokio::test]
+    async fn truncated_tail_is_discarded_on_open() {
+        let dir = TempDir::new();
+        let log = EventLog::open(&dir.0, options(1024)).unwrap();
+        for i in 0..3 {
+            log.append(&Event::new("a.x", Bytes::from(vec![i])))
+                .unwrap();
+        }
+        drop(log);
+
+        // cut the last record short, as if writing it was interrupted
+        let path = segment_path(&dir.0, 0);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let log = EventLog::open(&dir.0, options(1024)).unwrap();
+        assert_eq!(log.offsets(), (0, 2));
+        assert_eq!(
+            log.append(&Event::new("a.x", Bytes::from("new"))).unwrap(),
+            2
+        );
+        log.flush().await.unwrap();
+
+        let (tx, mut rx) = unbounded();
+        log.subscribe_from("a.>", ReplayFrom::Offset(0), tx)
+            .unwrap();
+        let records = receive(&mut rx, 3).await;
+        assert_eq!(records[1].event.data, vec![1]);
+        assert_eq!(records[2].offset, 2);
+        assert_eq!(records[2].event.data, "new");
+    }
+
+    #[tokio::test]
+    async fn oldest_segments_are_removed_over_the_size_limit() {
+        let dir = TempDir::new();
+        let log = EventLog::open(
+            &dir.0,
+            LogOptions {
+                max_bytes: Some(200),
+                ..options(1)
+            },
+        )
+        .unwrap();
+        for _ in 0..10 {
+            log.append(&Event::new("a.x", Bytes::from(vec![0; 50])))
+                .unwrap();
+        }
+        log.flush().await.unwrap();
+
+        // every event has a segment of its own, two of which fit the limit
+        assert_eq!(log.offsets(), (8, 10));
+        assert_eq!(segments(&dir.0), 2);
+
+        // replaying from a removed offset starts with the oldest retained event
+        let (tx, mut rx) = unbounded();
+        log.subscribe_from("a.>", ReplayFrom::Offset(0), tx)
+            .unwrap();
+        assert_eq!(receive(&mut rx, 1).await[0].offset, 8);
+    }
+
+    #[tokio::test]
+    async fn segments_are_removed_once_they_are_too_old() {
+        let dir = TempDir::new();
+        let log = EventLog::open(
+            &dir.0,
+            LogOptions {
+                max_age: Some(Duration::from_millis(50)),
+                ..options(1)
+            },
+        )
+        .unwrap();
+        log.append(&Event::new("a.x", Bytes::new())).unwrap();
+        log.append(&Event::new("a.x", Bytes::new())).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        log.append(&Event::new("a.x", Bytes::new())).unwrap();
+        log.flush().await.unwrap();
+        assert_eq!(log.offsets(), (2, 3));
+        assert_eq!(segments(&dir.0), 1);
+    }
+
+    #[tokio::test]
+    async fn events_are_written_with_every_sync_policy() {
+        for sync in [
+            SyncPolicy::Never,
+            SyncPolicy::Always,
+            SyncPolicy::Interval(Duration::from_millis(10)),
+        ] {
+            let dir = TempDir::new();
+            let log = EventLog::open(
+                &dir.0,
+                LogOptions {
+                    sync,
+                    ..options(1024)
+                },
+            )
+            .unwrap();
+            log.append(&Event::new("a.x", Bytes::from("x"))).unwrap();
+            log.flush().await.unwrap();
+
+            let (records, _) = decode(Bytes::from(std::fs::read(segment_path(&dir.0, 0)).unwrap()));
+            assert_eq!(records.len(), 1, "{:?}", sync);
+        }
+    }
+}
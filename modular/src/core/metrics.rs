@@ -0,0 +1,561 @@
+//! Counters and histograms of a [`Modular`](super::Modular) instance, see
+//! [`Modular::metrics`](super::Modular::metrics).
+
+use crate::core::events::EventsManager;
+use crate::core::limit::{LimitStats, RateLimits};
+use crate::core::pattern::Pattern;
+use bytes::Bytes;
+use modular_core::error::ModuleError;
+use modular_core::modules::{Event, ModuleRequest, ModuleResponse};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 10] =
+    [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Label value of the topic metrics that cover every topic.
+pub const ALL_TOPICS: &str = "*";
+
+/// Label value of invokes of actions the module doesn't know, or that were rejected before
+/// the module handled the action once.
+pub const UNKNOWN_ACTION: &str = "<unknown>";
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    /// Events of all topics followed by the tracked patterns.
+    pub topics: Vec<TopicMetrics>,
+    /// Subscriptions grouped by pattern.
+    pub subscribers: Vec<SubscriberMetrics>,
+    /// Invokes grouped by module and action.
+    pub invokes: Vec<InvokeMetrics>,
+    pub topic_limits: Vec<LimitStats>,
+    pub module_limits: Vec<LimitStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TopicMetrics {
+    /// The tracked pattern or [`ALL_TOPICS`].
+    pub pattern: String,
+    pub published: u64,
+    /// Deliveries to subscriptions, an event can be delivered to several.
+    pub delivered: u64,
+    /// Events dropped by interceptors or limits, and deliveries to closed subscriptions.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriberMetrics {
+    pub pattern: String,
+    pub subscriptions: usize,
+    /// Events queued for the subscriptions that they haven't received yet.
+    pub queue_depth: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvokeMetrics {
+    pub module: String,
+    pub action: String,
+    pub invokes: u64,
+    /// Failed invokes by the kind of [`ModuleError`], see [`error_kind`].
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Time until the module responded, invokes rejected before reaching the module
+    /// are not included.
+    pub latency: Histogram,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Observations per bucket of [`LATENCY_BUCKETS`], not cumulative, followed by the
+    /// observations above the last bound.
+    pub buckets: Vec<u64>,
+    /// Sum of the observations in seconds.
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+        }
+
+        let idx = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Label value of `error` in [`InvokeMetrics::errors`].
+pub fn error_kind(error: &ModuleError) -> &'static str {
+    match error {
+        ModuleError::UnknownMethod => "unknown_method",
+        ModuleError::Custom(_) => "custom",
+        ModuleError::Destroyed => "destroyed",
+        ModuleError::Denied => "denied",
+        ModuleError::Overloaded => "overloaded",
+        ModuleError::PayloadTooLarge(_) => "payload_too_large",
+        ModuleError::NotFound => "not_found",
+    }
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let topics = [
+            (
+                "modular_events_published_total",
+                "Events published.",
+                (|v: &TopicMetrics| v.published) as fn(&TopicMetrics) -> u64,
+            ),
+            (
+                "modular_events_delivered_total",
+                "Events delivered to subscriptions.",
+                |v| v.delivered,
+            ),
+            (
+                "modular_events_dropped_total",
+                "Events dropped before reaching subscriptions.",
+                |v| v.dropped,
+            ),
+        ];
+        for (name, help, value) in topics {
+            header(&mut out, name, help, "counter");
+            for topic in &self.topics {
+                sample(&mut out, name, &[("pattern", &topic.pattern)], value(topic));
+            }
+        }
+
+        header(
+            &mut out,
+            "modular_subscriptions",
+            "Active subscriptions.",
+            "gauge",
+        );
+        for v in &self.subscribers {
+            sample(
+                &mut out,
+                "modular_subscriptions",
+                &[("pattern", &v.pattern)],
+                v.subscriptions,
+            );
+        }
+
+        header(
+            &mut out,
+            "modular_subscriber_queue_depth",
+            "Events queued for subscriptions.",
+            "gauge",
+        );
+        for v in &self.subscribers {
+            sample(
+                &mut out,
+                "modular_subscriber_queue_depth",
+                &[("pattern", &v.pattern)],
+                v.queue_depth,
+            );
+        }
+
+        header(
+            &mut out,
+            "modular_module_invokes_total",
+            "Module invokes.",
+            "counter",
+        );
+        for v in &self.invokes {
+            let labels = [("module", v.module.as_str()), ("action", v.action.as_str())];
+            sample(&mut out, "modular_module_invokes_total", &labels, v.invokes);
+        }
+
+        header(
+            &mut out,
+            "modular_module_errors_total",
+            "Failed module invokes.",
+            "counter",
+        );
+        for v in &self.invokes {
+            for (kind, count) in &v.errors {
+                let labels = [
+                    ("module", v.module.as_str()),
+                    ("action", v.action.as_str()),
+                    ("error", kind),
+                ];
+                sample(&mut out, "modular_module_errors_total", &labels, count);
+            }
+        }
+
+        let name = "modular_module_invoke_duration_seconds";
+        header(&mut out, name, "Module invoke latency.", "histogram");
+        for v in &self.invokes {
+            let labels = [("module", v.module.as_str()), ("action", v.action.as_str())];
+            let mut cumulative = 0;
+            for (idx, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                cumulative += v.latency.buckets.get(idx).copied().unwrap_or_default();
+                let le = bound.to_string();
+                let labels = [labels[0], labels[1], ("le", le.as_str())];
+                sample(&mut out, &format!("{name}_bucket"), &labels, cumulative);
+            }
+            let labels_inf = [labels[0], labels[1], ("le", "+Inf")];
+            sample(
+                &mut out,
+                &format!("{name}_bucket"),
+                &labels_inf,
+                v.latency.count,
+            );
+            sample(&mut out, &format!("{name}_sum"), &labels, v.latency.sum);
+            sample(&mut out, &format!("{name}_count"), &labels, v.latency.count);
+        }
+
+        header(
+            &mut out,
+            "modular_rate_limit_rejected_total",
+            "Operations rejected by rate limits.",
+            "counter",
+        );
+        let limits = self
+            .topic_limits
+            .iter()
+            .map(|v| ("topic", v))
+            .chain(self.module_limits.iter().map(|v| ("module", v)));
+        for (kind, v) in limits {
+            let action = v.action.as_deref().unwrap_or_default();
+            let labels = [
+                ("kind", kind),
+                ("pattern", v.pattern.as_str()),
+                ("action", action),
+            ];
+            sample(
+                &mut out,
+                "modular_rate_limit_rejected_total",
+                &labels,
+                v.rejected,
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape_label(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let _ = writeln!(out, "{name}{{{labels}}} {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Default)]
+struct TopicCounters {
+    published: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl TopicCounters {
+    fn snapshot(&self, pattern: &str) -> TopicMetrics {
+        TopicMetrics {
+            pattern: pattern.to_owned(),
+            published: self.published.load(Ordering::Relaxed),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Event counters of all topics and of the tracked patterns.
+#[derive(Default)]
+pub(crate) struct EventMetrics {
+    all: TopicCounters,
+    patterns: RwLock<Vec<(Pattern, Arc<TopicCounters>)>>,
+}
+
+impl EventMetrics {
+    pub(crate) fn track(&self, pattern: Pattern) {
+        let mut patterns = self.patterns.write();
+        if !patterns.iter().any(|(v, _)| v.as_str() == pattern.as_str()) {
+            patterns.push((pattern, Default::default()));
+        }
+    }
+
+    pub(crate) fn untrack(&self, pattern: &str) -> bool {
+        let mut patterns = self.patterns.write();
+        let len = patterns.len();
+        patterns.retain(|(v, _)| v.as_str() != pattern);
+        patterns.len() != len
+    }
+
+    pub(crate) fn record_publish(&self, topic: &str, delivered: usize, dropped: usize) {
+        self.record(topic, |counters| {
+            counters.published.fetch_add(1, Ordering::Relaxed);
+            counters
+                .delivered
+                .fetch_add(delivered as u64, Ordering::Relaxed);
+            counters
+                .dropped
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+        })
+    }
+
+    pub(crate) fn record_dropped(&self, topic: &str) {
+        self.record(topic, |counters| {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    fn record(&self, topic: &str, f: impl Fn(&TopicCounters)) {
+        f(&self.all);
+        for (pattern, counters) in self.patterns.read().iter() {
+            if pattern.matches(topic) {
+                f(counters);
+            }
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<TopicMetrics> {
+        let mut out = vec![self.all.snapshot(ALL_TOPICS)];
+        out.extend(
+            self.patterns
+                .read()
+                .iter()
+                .map(|(pattern, counters)| counters.snapshot(pattern.as_str())),
+        );
+        out
+    }
+}
+
+#[derive(Default)]
+struct InvokeStats {
+    invokes: u64,
+    errors: BTreeMap<&'static str, u64>,
+    latency: Histogram,
+}
+
+/// Invoke counters by module and action.
+#[derive(Default)]
+pub(crate) struct ModuleMetrics {
+    invokes: Mutex<HashMap<(String, String), InvokeStats>>,
+}
+
+impl ModuleMetrics {
+    /// Records an invoke, `elapsed` is `None` for invokes rejected before reaching the
+    /// module. Actions are recorded as [`UNKNOWN_ACTION`] unless the module handled them,
+    /// so callers can't add a label value per invoke.
+    pub(crate) fn record(
+        &self,
+        module: &str,
+        action: &str,
+        error: Option<&ModuleError>,
+        elapsed: Option<Duration>,
+    ) {
+        let mut invokes = self.invokes.lock();
+        let mut key = (module.to_owned(), action.to_owned());
+        let known = match (error, elapsed) {
+            (Some(ModuleError::UnknownMethod), _) => false,
+            (_, Some(_)) => true,
+            (_, None) => invokes.contains_key(&key),
+        };
+        if !known {
+            key.1 = UNKNOWN_ACTION.to_owned();
+        }
+        let stats = invokes.entry(key).or_default();
+
+        stats.invokes += 1;
+        if let Some(error) = error {
+            *stats.errors.entry(error_kind(error)).or_default() += 1;
+        }
+        if let Some(elapsed) = elapsed {
+            stats.latency.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<InvokeMetrics> {
+        let mut out = self
+            .invokes
+            .lock()
+            .iter()
+            .map(|((module, action), stats)| InvokeMetrics {
+                module: module.clone(),
+                action: action.clone(),
+                invokes: stats.invokes,
+                errors: stats.errors.clone(),
+                latency: stats.latency.clone(),
+            })
+            .collect::<Vec<_>>();
+        out.sort_by(|a, b| (&a.module, &a.action).cmp(&(&b.module, &b.action)));
+        out
+    }
+}
+
+/// Takes snapshots of an instance's metrics, shared with the metrics module.
+#[derive(Clone)]
+pub(crate) struct Collector {
+    pub(crate) events: Arc<EventsManager<Event>>,
+    pub(crate) event_metrics: Arc<EventMetrics>,
+    pub(crate) module_metrics: Arc<ModuleMetrics>,
+    pub(crate) limits: Arc<RateLimits>,
+}
+
+impl Collector {
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            topics: self.event_metrics.snapshot(),
+            subscribers: self.events.subscriber_metrics(),
+            invokes: self.module_metrics.snapshot(),
+            topic_limits: self.limits.topic_stats(),
+            module_limits: self.limits.module_stats(),
+        }
+    }
+
+    /// Serves the action `prometheus` of [`METRICS_MODULE`](super::METRICS_MODULE).
+    pub(crate) fn invoke(
+        &self,
+        req: ModuleRequest<Bytes>,
+    ) -> Result<ModuleResponse<Bytes>, ModuleError> {
+        match req.action.as_str() {
+            "prometheus" => Ok(ModuleResponse::new(Bytes::from(
+                self.snapshot().to_prometheus(),
+            ))),
+            _ => Err(ModuleError::UnknownMethod),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::limit::RateLimit;
+    use crate::core::modules::Completion;
+    use crate::core::{Modular, METRICS_MODULE};
+    use modular_core::modular::Modular as _;
+    use modular_core::module::Module as _;
+
+    fn module() -> impl tower::Service<
+        ModuleRequest,
+        Response = ModuleResponse,
+        Error = ModuleError,
+        Future = impl std::future::Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync,
+    > + Send
+           + Sync
+           + 'static {
+        tower::service_fn(|req: ModuleRequest| async move {
+            match req.action.as_str() {
+                "get" => Ok(ModuleResponse::new(Bytes::new())),
+                _ => Err(ModuleError::UnknownMethod),
+            }
+        })
+    }
+
+    async fn invoke(modular: &Modular, name: &str, action: &str) -> Result<(), ModuleError> {
+        let module = modular.get_module(name).unwrap();
+        module
+            .invoke(ModuleRequest::new(action, Bytes::new()))
+            .await?
+            .await
+            .map(drop)
+    }
+
+    fn invokes(modular: &Modular) -> Vec<(String, String, u64)> {
+        modular
+            .metrics()
+            .invokes
+            .into_iter()
+            .map(|v| (v.module, v.action, v.invokes))
+            .collect()
+    }
+
+    fn entry(module: &str, action: &str, invokes: u64) -> (String, String, u64) {
+        (module.to_owned(), action.to_owned(), invokes)
+    }
+
+    #[tokio::test]
+    async fn unknown_actions_share_a_label() {
+        let modular = Modular::default();
+        modular.register_module("a", module()).unwrap();
+
+        invoke(&modular, "a", "get").await.unwrap();
+        for action in ["x", "y", "z"] {
+            assert!(invoke(&modular, "a", action).await.is_err());
+        }
+
+        // rejected invokes keep the action once the module handled it
+        modular
+            .set_module_limit("a", None, Some(RateLimit::new(0.001, 1.0)))
+            .unwrap();
+        invoke(&modular, "a", "get").await.unwrap();
+        assert!(invoke(&modular, "a", "get").await.is_err());
+        assert!(invoke(&modular, "a", "w").await.is_err());
+
+        assert_eq!(
+            invokes(&modular),
+            [entry("a", UNKNOWN_ACTION, 4), entry("a", "get", 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejections_of_removed_modules_are_not_recorded() {
+        let modular = Modular::default();
+        modular.register_module("a", module()).unwrap();
+        modular.set_module_max_payload("a", Some(0)).unwrap();
+        let module = modular.get_module("a").unwrap();
+        modular.deregister_module("a");
+
+        let result = module
+            .invoke(ModuleRequest::new("get", Bytes::from("x")))
+            .await;
+        assert!(result.is_err());
+        assert!(invokes(&modular).is_empty());
+    }
+
+    #[tokio::test]
+    async fn system_modules_are_hidden() {
+        let modular = Modular::default();
+        modular.register_module("a.b", module()).unwrap();
+        assert_eq!(modular.module_names(), ["a.b"]);
+
+        let names = |results: Vec<(String, Result<ModuleResponse, ModuleError>)>| {
+            results
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        let results = modular
+            .invoke_many(
+                "{}.>",
+                ModuleRequest::new("get", Bytes::new()),
+                Completion::All,
+            )
+            .await
+            .unwrap();
+        assert_eq!(names(results), ["a.b"]);
+
+        let request = ModuleRequest::new("prometheus", Bytes::new());
+        let results = modular
+            .invoke_many("$.sys.>", request, Completion::All)
+            .await
+            .unwrap();
+        assert_eq!(names(results), [METRICS_MODULE]);
+    }
+}
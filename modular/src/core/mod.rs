@@ -1,14 +1,26 @@
 use bytes::Bytes;
-use futures::Sink;
+use futures::{Sink, StreamExt};
 use modular_core::modules::*;
+use parking_lot::RwLock;
 use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
 use tower::Service;
 
+pub mod ack;
+pub mod acl;
 pub mod events;
+pub mod intercept;
+pub mod limit;
+pub mod log;
+pub mod metrics;
 mod module;
 mod modules_registry;
 pub mod pattern;
+pub mod request;
+pub mod schedule;
 
 pub mod modules {
     pub use super::module::*;
@@ -18,10 +30,68 @@ pub mod modules {
 use crate::core::pattern::Pattern;
 use modules::*;
 
-#[derive(Default)]
+/// Published with the module name as body when a module is registered or replaced.
+pub const MODULE_REGISTERED_TOPIC: &str = "$.sys.modules.registered";
+/// Published with the module name as body when a module is removed.
+pub const MODULE_DEREGISTERED_TOPIC: &str = "$.sys.modules.deregistered";
+/// Published by audited [`acl::ScopedModular`] handles when an operation is denied.
+pub const ACL_DENIED_TOPIC: &str = "$.sys.acl.denied";
+/// Module serving [`Modular::metrics`] in the Prometheus text format with the action
+/// `prometheus`.
+pub const METRICS_MODULE: &str = "$.sys.metrics";
+
+/// Receives the events published on a [`Modular`] instance, used to link it with other
+/// instances.
+pub trait Forwarder: Send + Sync {
+    fn forward(&self, event: &Event);
+}
+
 pub struct Modular {
     modules: Arc<ModulesRegistry<Bytes, Bytes>>,
-    events: Arc<events::EventsManager<Bytes>>,
+    events: Arc<events::EventsManager<Event>>,
+    requests: Arc<events::EventsManager<(String, request::Request)>>,
+    /// Inboxes of pending requests, kept apart from events so they can't be subscribed to.
+    replies: Arc<events::EventsManager<Event>>,
+    next_inbox: AtomicU64,
+    next_seq: AtomicU64,
+    forwarder: RwLock<Option<Arc<dyn Forwarder>>>,
+    log: RwLock<Option<Arc<log::EventLog>>>,
+    schedules: Arc<schedule::Schedules>,
+    interceptors: intercept::Interceptors,
+    limits: Arc<limit::RateLimits>,
+    payloads: Arc<limit::PayloadLimits>,
+    event_metrics: Arc<metrics::EventMetrics>,
+}
+
+impl Default for Modular {
+    fn default() -> Self {
+        let event_metrics = Arc::new(metrics::EventMetrics::default());
+        let modular = Self {
+            modules: Default::default(),
+            events: Arc::new(events::EventsManager::with_metrics(event_metrics.clone())),
+            requests: Default::default(),
+            replies: Default::default(),
+            next_inbox: Default::default(),
+            next_seq: Default::default(),
+            forwarder: Default::default(),
+            log: Default::default(),
+            schedules: Default::default(),
+            interceptors: Default::default(),
+            limits: Default::default(),
+            payloads: Default::default(),
+            event_metrics,
+        };
+
+        let collector = modular.collector();
+        modular.modules.register_or_replace(
+            METRICS_MODULE,
+            tower::service_fn(move |req: ModuleRequest<Bytes>| {
+                futures::future::ready(collector.invoke(req))
+            }),
+        );
+
+        modular
+    }
 }
 
 impl modular_core::modular::Modular for Modular {
@@ -36,6 +106,10 @@ impl modular_core::modular::Modular for Modular {
         S::Future: Future<Output = Result<ModuleResponse, ModuleError>> + Send + Sync + 'static,
     {
         self.modules.register(name, service)?;
+        self.publish_event_inner(Event::new(
+            MODULE_REGISTERED_TOPIC,
+            Bytes::from(name.to_owned()),
+        ));
         Ok(())
     }
 
@@ -45,7 +119,7 @@ impl modular_core::modular::Modular for Modular {
         sink: Option<S>,
     ) -> Result<Self::Stream, SubscribeError>
     where
-        S: Sink<(String, Bytes), Error = Err> + Send + Sync + 'static,
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
     {
         if let Some(sink) = sink {
             let pattern = Pattern::parse(topic).map_err(SubscribeError::InvalidPattern)?;
@@ -54,23 +128,24 @@ impl modular_core::modular::Modular for Modular {
         Ok(())
     }
 
-    fn publish<Request>(&self, event: Request)
+    fn publish<E>(&self, event: E)
     where
-        Request: Into<ModuleRequest<Bytes>>,
+        E: Into<Event>,
     {
-        let event = event.into();
-        if event.action.starts_with("$.sys.") {
-            return;
-        }
-        self.publish_event_inner(event.action(), event.body.clone());
+        self.publish_as(None, event.into());
     }
 
     fn get_module(&self, name: &str) -> Option<Self::Module> {
-        self.modules.get(name)
+        self.get_module_as(name, None)
     }
 
     fn deregister_module(&self, name: &str) {
-        self.modules.remove(name);
+        if self.modules.remove(name) {
+            self.publish_event_inner(Event::new(
+                MODULE_DEREGISTERED_TOPIC,
+                Bytes::from(name.to_owned()),
+            ));
+        }
     }
 }
 
@@ -84,9 +159,593 @@ impl Modular {
         S::Future: Send + Sync + 'static,
     {
         self.modules.register_or_replace(name, svc);
+        self.publish_event_inner(Event::new(
+            MODULE_REGISTERED_TOPIC,
+            Bytes::from(name.to_owned()),
+        ));
+    }
+
+    /// Names of the registered modules, without system modules.
+    pub fn module_names(&self) -> Vec<String> {
+        let mut names = self.modules.names();
+        names.retain(|name| !is_system_module(name));
+        names
+    }
+
+    /// Invokes every module whose name matches `pattern`, see
+    /// [`ModulesRegistry::invoke_many`].
+    pub async fn invoke_many(
+        &self,
+        pattern: &str,
+        req: ModuleRequest<Bytes>,
+        completion: Completion,
+    ) -> Result<Vec<(String, Result<ModuleResponse<Bytes>, ModuleError>)>, PatternError> {
+        let pattern = Pattern::parse(pattern)?;
+        let action = req.action.clone();
+        let size = req.body.len();
+        Ok(self
+            .modules
+            .invoke_many_admitted(&pattern, req, completion, |name| {
+                admit_invoke(
+                    &self.limits,
+                    &self.payloads,
+                    &self.modules,
+                    name,
+                    &action,
+                    size,
+                    None,
+                )
+            })
+            .await)
+    }
+
+    /// Sets the forwarder that receives every event published with
+    /// [`publish`](modular_core::modular::Modular::publish).
+    pub fn set_forwarder(&self, forwarder: Option<Arc<dyn Forwarder>>) {
+        *self.forwarder.write() = forwarder;
+    }
+
+    /// Sets the log that events of its topics are appended to, subscribers replay it with
+    /// [`EventLog::subscribe_from`](log::EventLog::subscribe_from).
+    pub fn set_event_log(&self, log: Option<Arc<log::EventLog>>) {
+        *self.log.write() = log;
+    }
+
+    /// Publishes an event and keeps it as the retained value of `topic`, subscriptions
+    /// matching `topic` receive the latest retained value before live events.
+    pub fn publish_retained<E: Into<Event>>(&self, event: E) {
+        let Some(event) = self.admit(None, event.into()) else {
+            return;
+        };
+        self.forward(&event);
+        let event = self.prepare(event);
+        self.events.publish_retained(&event.topic.clone(), event);
+    }
+
+    /// Publishes an event at `at`, or right away if it already passed.
+    pub fn publish_at(
+        self: &Arc<Self>,
+        topic: &str,
+        data: Bytes,
+        at: SystemTime,
+    ) -> schedule::ScheduleHandle {
+        self.schedules
+            .add(Arc::downgrade(self), topic, data, at, None)
+    }
+
+    pub fn publish_after(
+        self: &Arc<Self>,
+        topic: &str,
+        data: Bytes,
+        delay: Duration,
+    ) -> schedule::ScheduleHandle {
+        self.publish_at(topic, data, SystemTime::now() + delay)
+    }
+
+    /// Publishes an event at every time matching `cron`.
+    pub fn publish_cron(
+        self: &Arc<Self>,
+        topic: &str,
+        data: Bytes,
+        cron: schedule::Cron,
+    ) -> Option<schedule::ScheduleHandle> {
+        let next = cron.next_after(SystemTime::now())?;
+        Some(
+            self.schedules
+                .add(Arc::downgrade(self), topic, data, next, Some(cron)),
+        )
+    }
+
+    /// Active schedules ordered by the time they publish next.
+    pub fn schedules(&self) -> Vec<schedule::ScheduleInfo> {
+        self.schedules.list()
+    }
+
+    /// Returns whether the schedule was still active.
+    pub fn cancel_schedule(&self, id: u64) -> bool {
+        self.schedules.cancel(id)
+    }
+
+    /// Removes the retained value of `topic`, returns whether there was one.
+    pub fn clear_retained(&self, topic: &str) -> bool {
+        self.events.clear_retained(topic)
+    }
+
+    /// Delivers an event to local subscribers only, without passing it to the forwarder.
+    pub fn publish_local<E: Into<Event>>(&self, event: E) {
+        self.publish_local_as(None, event.into());
+    }
+
+    /// Appends `interceptor` to the chain that events published with
+    /// [`publish`](modular_core::modular::Modular::publish), [`publish_retained`](Self::publish_retained)
+    /// and [`publish_local`](Self::publish_local) pass through, in the order interceptors
+    /// were added. Requests and replies are intercepted too, system events are not. Returns an id for
+    /// [`remove_interceptor`](Self::remove_interceptor).
+    pub fn add_interceptor(&self, interceptor: Arc<dyn intercept::Interceptor>) -> u64 {
+        self.interceptors.add(interceptor)
+    }
+
+    /// A handle acting as `identity` that can only do what `acl` allows.
+    pub fn scoped(self: &Arc<Self>, identity: &str, acl: acl::Acl) -> acl::ScopedModular {
+        acl::ScopedModular::new(self.clone(), identity, acl)
+    }
+
+    /// Returns whether the interceptor was still registered.
+    pub fn remove_interceptor(&self, id: u64) -> bool {
+        self.interceptors.remove(id)
+    }
+
+    /// Sets or, with `None`, removes the rate limit of publishing to topics matching
+    /// `pattern`. Events over the limit are dropped, every matching limit has to allow an
+    /// event. Publishers with an identity, like [`acl::ScopedModular`] handles, are
+    /// limited separately.
+    pub fn set_topic_limit(
+        &self,
+        pattern: &str,
+        limit: Option<limit::RateLimit>,
+    ) -> Result<(), PatternError> {
+        self.limits.set_topic(Pattern::parse(pattern)?, limit);
+        Ok(())
+    }
+
+    /// Sets or, with `None`, removes the rate limit of invoking modules whose name matches
+    /// `pattern`, of only `action` if given. Invokes over the limit fail with
+    /// [`ModuleError::Overloaded`].
+    pub fn set_module_limit(
+        &self,
+        pattern: &str,
+        action: Option<&str>,
+        limit: Option<limit::RateLimit>,
+    ) -> Result<(), PatternError> {
+        self.limits
+            .set_module(Pattern::parse(pattern)?, action, limit);
+        Ok(())
+    }
+
+    /// Sets the maximum size of event payloads and module requests on this instance, which
+    /// topic and module overrides take precedence over. Events over the limit are dropped
+    /// and invokes fail with [`ModuleError::PayloadTooLarge`].
+    pub fn set_max_payload(&self, limit: Option<usize>) {
+        self.payloads.set_default(limit);
+    }
+
+    /// Overrides the maximum payload size of topics matching `pattern`, the smallest
+    /// override applies when several match. `None` removes the override.
+    pub fn set_topic_max_payload(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+    ) -> Result<(), PatternError> {
+        self.payloads.set_topic(Pattern::parse(pattern)?, limit);
+        Ok(())
+    }
+
+    /// Overrides the maximum request size of modules whose name matches `pattern`, also
+    /// applied to responses of modules registered across the C ABI.
+    pub fn set_module_max_payload(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+    ) -> Result<(), PatternError> {
+        self.payloads.set_module(Pattern::parse(pattern)?, limit);
+        Ok(())
+    }
+
+    /// The largest payload any event or request may have, `None` if some are unlimited.
+    pub fn max_payload(&self) -> Option<usize> {
+        self.payloads.largest()
+    }
+
+    /// The maximum payload size of events published to `topic`.
+    pub fn max_publish_payload(&self, topic: &str) -> Option<usize> {
+        self.payloads.for_topic(topic)
     }
 
-    fn publish_event_inner(&self, path: &str, event: Bytes) {
-        self.events.publish(path, event);
+    /// The maximum payload size of requests to the module `name`.
+    pub fn max_invoke_payload(&self, name: &str) -> Option<usize> {
+        self.payloads.for_module(name)
+    }
+
+    /// Counters of the instance, events are counted for all topics and for every pattern
+    /// added with [`track_topic_metrics`](Self::track_topic_metrics).
+    pub fn metrics(&self) -> metrics::MetricsSnapshot {
+        self.collector().snapshot()
+    }
+
+    /// Counts published, delivered and dropped events of topics matching `pattern`
+    /// separately.
+    pub fn track_topic_metrics(&self, pattern: &str) -> Result<(), PatternError> {
+        self.event_metrics.track(Pattern::parse(pattern)?);
+        Ok(())
+    }
+
+    /// Returns whether the pattern was tracked.
+    pub fn untrack_topic_metrics(&self, pattern: &str) -> bool {
+        self.event_metrics.untrack(pattern)
+    }
+
+    /// Topic limits with the number of events they dropped.
+    pub fn topic_limits(&self) -> Vec<limit::LimitStats> {
+        self.limits.topic_stats()
+    }
+
+    /// Module limits with the number of invokes they rejected.
+    pub fn module_limits(&self) -> Vec<limit::LimitStats> {
+        self.limits.module_stats()
+    }
+
+    /// Patterns of all active subscriptions.
+    pub fn subscription_patterns(&self) -> Vec<Pattern> {
+        self.events.patterns()
+    }
+
+    /// Changes whenever a subscription is added or closed.
+    pub fn watch_subscriptions(&self) -> watch::Receiver<()> {
+        self.events.watch_patterns()
+    }
+
+    /// Subscribes `sink` to requests sent to topics matching `pattern`. Replies are sent
+    /// with [`reply`](Self::reply).
+    pub fn respond<S, Err>(&self, pattern: &str, sink: S) -> Result<(), SubscribeError>
+    where
+        S: Sink<(String, request::Request), Error = Err> + Send + Sync + 'static,
+    {
+        self.respond_filtered(pattern, None, sink)
+    }
+
+    pub(crate) fn respond_filtered<S, Err>(
+        &self,
+        pattern: &str,
+        filter: Option<events::EventFilter<(String, request::Request)>>,
+        sink: S,
+    ) -> Result<(), SubscribeError>
+    where
+        S: Sink<(String, request::Request), Error = Err> + Send + Sync + 'static,
+    {
+        let pattern = Pattern::parse(pattern).map_err(SubscribeError::InvalidPattern)?;
+        self.requests.subscribe_filtered(pattern, filter, sink);
+        Ok(())
+    }
+
+    /// Subscribes `sink` to events matching `pattern` for which `filter` returns true.
+    /// Declarative [`Filter`](modular_core::filter::Filter)s can be used with
+    /// `move |event| filter.matches(event)`. Returns an id for
+    /// [`unsubscribe`](Self::unsubscribe).
+    pub fn subscribe_filtered<F, S, Err>(
+        &self,
+        pattern: &str,
+        filter: F,
+        sink: S,
+    ) -> Result<u64, SubscribeError>
+    where
+        F: Fn(&Event) -> bool + Send + Sync + 'static,
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
+    {
+        let pattern = Pattern::parse(pattern).map_err(SubscribeError::InvalidPattern)?;
+        Ok(self
+            .events
+            .subscribe_filtered(pattern, Some(Arc::new(filter)), sink))
+    }
+
+    /// Removes a subscription right away instead of when its sink fails.
+    pub fn unsubscribe(&self, id: u64) {
+        self.events.unsubscribe(id);
+    }
+
+    /// Subscribes `sink` to events matching `pattern` that have to be acknowledged with
+    /// [`Delivery::ack`](ack::Delivery::ack). Unacknowledged events are delivered again
+    /// after [`AckOptions::timeout`](ack::AckOptions::timeout) until they run out of
+    /// attempts and are published to the dead-letter topic.
+    pub fn subscribe_acked<S, Err>(
+        self: &Arc<Self>,
+        pattern: &str,
+        options: ack::AckOptions,
+        sink: S,
+    ) -> Result<(), SubscribeError>
+    where
+        S: Sink<ack::Delivery, Error = Err> + Send + 'static,
+    {
+        let pattern = Pattern::parse(pattern).map_err(SubscribeError::InvalidPattern)?;
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        self.events.subscribe(pattern, tx);
+        tokio::spawn(ack::deliver(rx, Arc::downgrade(self), options, sink));
+        Ok(())
+    }
+
+    /// Subscribes `sink` as a member of the queue group `group`, see
+    /// [`EventsManager::subscribe_group`](events::EventsManager::subscribe_group).
+    pub fn subscribe_group<S, Err>(
+        &self,
+        group: &str,
+        pattern: &str,
+        key: Option<&str>,
+        sink: S,
+    ) -> Result<(), SubscribeError>
+    where
+        S: Sink<Event, Error = Err> + Send + Sync + 'static,
+    {
+        let pattern = Pattern::parse(pattern).map_err(SubscribeError::InvalidPattern)?;
+        self.events.subscribe_group(group, key, pattern, sink)?;
+        Ok(())
+    }
+
+    /// Sends a reply to the requester. Replies pass the interceptors and limits like
+    /// published events, but can't be rerouted away from the requester's inbox.
+    pub fn reply(&self, request: &request::Request, body: Bytes) {
+        self.reply_as(None, request, body);
+    }
+
+    /// Sends a request to the responders of `topic` and waits for the first reply.
+    pub async fn request(
+        &self,
+        topic: &str,
+        body: Bytes,
+        timeout: Duration,
+    ) -> Result<Bytes, RequestError> {
+        let mut replies = self.request_many(topic, body, 1, timeout).await?;
+        Ok(replies.remove(0))
+    }
+
+    /// Sends a request to the responders of `topic` and collects replies until
+    /// `max_replies` arrived or `timeout` elapsed. Fails if there was no reply at all.
+    pub async fn request_many(
+        &self,
+        topic: &str,
+        body: Bytes,
+        max_replies: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Bytes>, RequestError> {
+        self.request_many_as(None, topic, body, max_replies, timeout)
+            .await
+    }
+
+    pub(crate) async fn request_many_as(
+        &self,
+        scope: Option<&acl::ScopedModular>,
+        topic: &str,
+        body: Bytes,
+        max_replies: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Bytes>, RequestError> {
+        let event = self
+            .admit(scope, Event::new(topic, body))
+            .ok_or(RequestError::Dropped)?;
+
+        let inbox = format!(
+            "{}{}",
+            request::INBOX_PREFIX,
+            self.next_inbox.fetch_add(1, Ordering::Relaxed)
+        );
+        let pattern = Pattern::parse(&inbox).expect("inbox topics are valid patterns");
+
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let _subscription = Unsubscribe(&self.replies, self.replies.subscribe(pattern, tx));
+
+        let request = request::Request {
+            body: event.data,
+            reply_to: inbox,
+        };
+
+        if self
+            .requests
+            .publish(&event.topic, (event.topic.clone(), request))
+            == 0
+        {
+            return Err(RequestError::NoResponders);
+        }
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut replies = vec![];
+        while replies.len() < max_replies.max(1) {
+            match tokio::time::timeout_at(deadline, rx.next()).await {
+                Ok(Some(event)) => replies.push(event.data),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        if replies.is_empty() {
+            return Err(RequestError::Timeout);
+        }
+
+        Ok(replies)
+    }
+
+    pub(crate) fn publish_as(&self, scope: Option<&acl::ScopedModular>, event: Event) {
+        if let Some(event) = self.admit(scope, event) {
+            self.publish_admitted(event);
+        }
+    }
+
+    /// Forwards and publishes an event that passed the interceptors and limits already,
+    /// or doesn't have to like dead letters.
+    pub(crate) fn publish_admitted(&self, event: Event) {
+        self.forward(&event);
+        self.publish_event_inner(event);
+    }
+
+    pub(crate) fn reply_as(&self, identity: Option<&str>, request: &request::Request, body: Bytes) {
+        let inbox = request.reply_to.as_str();
+        if let Some(event) = self.admit_to(identity, Event::new(inbox, body), |v| v == inbox) {
+            self.replies.publish(inbox, event);
+        }
+    }
+
+    pub(crate) fn publish_local_as(&self, scope: Option<&acl::ScopedModular>, event: Event) {
+        if let Some(event) = self.admit(scope, event) {
+            self.publish_event_inner(event);
+        }
+    }
+
+    pub(crate) fn get_module_as(
+        &self,
+        name: &str,
+        identity: Option<&str>,
+    ) -> Option<Module<Bytes, Bytes>> {
+        let mut module = self.modules.get(name)?;
+        let name = name.to_owned();
+        let identity = identity.map(str::to_owned);
+        let limits = self.limits.clone();
+        let payloads = self.payloads.clone();
+        let modules = Arc::downgrade(&self.modules);
+        module.1 = Some(Arc::new(move |req: &ModuleRequest<Bytes>| {
+            // the invoke fails on its own once the registry is gone
+            let Some(modules) = modules.upgrade() else {
+                return Ok(());
+            };
+            admit_invoke(
+                &limits,
+                &payloads,
+                &modules,
+                &name,
+                &req.action,
+                req.body.len(),
+                identity.as_deref(),
+            )
+        }));
+        Some(module)
+    }
+
+    fn collector(&self) -> metrics::Collector {
+        metrics::Collector {
+            events: self.events.clone(),
+            event_metrics: self.event_metrics.clone(),
+            module_metrics: self.modules.metrics().clone(),
+            limits: self.limits.clone(),
+        }
+    }
+
+    fn publish_event_inner(&self, event: Event) {
+        let event = self.prepare(event);
+        self.events.publish(&event.topic.clone(), event);
+    }
+
+    /// Assigns the sequence number and appends the event to the log.
+    fn prepare(&self, mut event: Event) -> Event {
+        event.seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+
+        let log = self.log.read().clone();
+        if let Some(log) = log.filter(|log| log.is_logged(&event.topic)) {
+            if let Err(e) = log.append(&event) {
+                tracing::error!(error = %e, topic = event.topic, "failed to log event");
+            }
+        }
+
+        event
+    }
+
+    /// Runs the interceptors and limits on an event published by a user, events to
+    /// reserved topics, or topics the ACL of `scope` denies, are dropped before and after
+    /// the interceptors.
+    fn admit(&self, scope: Option<&acl::ScopedModular>, event: Event) -> Option<Event> {
+        self.admit_to(scope.map(|v| v.identity()), event, |topic| {
+            !is_reserved(topic) && scope.is_none_or(|v| v.check(acl::Permission::Publish, topic))
+        })
+    }
+
+    /// Runs the interceptors, payload and rate limits on an event, events to topics
+    /// `allowed` rejects are dropped before and after the interceptors.
+    fn admit_to(
+        &self,
+        identity: Option<&str>,
+        event: Event,
+        allowed: impl Fn(&str) -> bool,
+    ) -> Option<Event> {
+        if !allowed(&event.topic) {
+            self.event_metrics.record_dropped(&event.topic);
+            return None;
+        }
+
+        let topic = event.topic.clone();
+        let Some(event) = self.interceptors.apply(event) else {
+            self.event_metrics.record_dropped(&topic);
+            return None;
+        };
+
+        if !allowed(&event.topic) {
+            tracing::debug!(topic = %event.topic, "dropping event rerouted to a reserved topic");
+            self.event_metrics.record_dropped(&event.topic);
+            return None;
+        }
+
+        if let Err(e) =
+            PayloadTooLarge::check(event.data.len(), self.payloads.for_topic(&event.topic))
+        {
+            tracing::debug!(topic = %event.topic, error = %e, "dropping event");
+            self.event_metrics.record_dropped(&event.topic);
+            return None;
+        }
+
+        if !self.limits.acquire_publish(&event.topic, identity) {
+            tracing::debug!(topic = %event.topic, identity, "dropping event over the rate limit");
+            self.event_metrics.record_dropped(&event.topic);
+            return None;
+        }
+
+        Some(event)
+    }
+
+    fn forward(&self, event: &Event) {
+        let forwarder = self.forwarder.read().clone();
+        if let Some(forwarder) = forwarder {
+            forwarder.forward(event);
+        }
+    }
+}
+
+/// Whether `topic` is reserved for system events and replies, users can't publish to it.
+fn is_reserved(topic: &str) -> bool {
+    topic.starts_with("$.sys.") || topic.starts_with(request::INBOX_PREFIX)
+}
+
+/// Checks the payload and rate limits of an invoke, rejections are recorded in the metrics
+/// of `modules` while the module is registered.
+fn admit_invoke(
+    limits: &limit::RateLimits,
+    payloads: &limit::PayloadLimits,
+    modules: &ModulesRegistry<Bytes, Bytes>,
+    name: &str,
+    action: &str,
+    size: usize,
+    identity: Option<&str>,
+) -> Result<(), ModuleError> {
+    let result = match PayloadTooLarge::check(size, payloads.for_module(name)) {
+        Err(e) => Err(ModuleError::PayloadTooLarge(e)),
+        Ok(()) if !limits.acquire_invoke(name, action, identity) => Err(ModuleError::Overloaded),
+        Ok(()) => Ok(()),
+    };
+
+    if let Err(e) = &result {
+        if modules.contains(name) {
+            modules.metrics().record(name, action, Some(e), None);
+        }
+    }
+    result
+}
+
+struct Unsubscribe<'a, T>(&'a events::EventsManager<T>, u64);
+
+impl<T> Drop for Unsubscribe<'_, T> {
+    fn drop(&mut self) {
+        self.0.unsubscribe(self.1);
     }
 }
@@ -4,13 +4,21 @@ use futures_util::{FutureExt, TryFutureExt};
 use modular_core::error::ModuleError;
 use modular_core::modules::{ModuleRequest, ModuleResponse};
 use std::marker::PhantomData;
-use std::sync::Weak;
+use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
 use tokio::sync::Mutex;
 use tower::Service;
 
+/// Checked before every invoke of a [`Module`], the error is returned instead of calling
+/// the module.
+pub(crate) type Admit<Request> =
+    Arc<dyn Fn(&ModuleRequest<Request>) -> Result<(), ModuleError> + Send + Sync>;
+
 #[derive(Clone)]
-pub struct Module<Request, Response>(pub(crate) Weak<Mutex<BoxModuleService<Request, Response>>>);
+pub struct Module<Request, Response>(
+    pub(crate) Weak<Mutex<BoxModuleService<Request, Response>>>,
+    pub(crate) Option<Admit<Request>>,
+);
 
 impl<Request, Response> modular_core::module::Module<Request, Response>
     for Module<Request, Response>
@@ -30,6 +38,9 @@ where
                 return futures::future::err(ModuleError::Destroyed).boxed();
             }
         };
+        if let Some(Err(e)) = self.1.as_ref().map(|admit| admit(&req)) {
+            return futures::future::err(e).boxed();
+        }
         async move {
             let mut v = module.lock().await;
             Ok(v.call(req))
@@ -1,24 +1,55 @@
+use crate::core::metrics::ModuleMetrics;
 use crate::core::module::{Module, ModuleService};
+use crate::core::pattern::Pattern;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
 use modular_core::error::ModuleError;
 use modular_core::modules::*;
 use parking_lot::RwLock;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
 use tokio::sync::Mutex;
 use tower::Service;
 
 pub(crate) type BoxModuleService<Req, Resp> =
     tower::util::BoxService<ModuleRequest<Req>, ModuleResponse<Resp>, ModuleError>;
 
+type SharedModuleService<Req, Resp> = Arc<Mutex<BoxModuleService<Req, Resp>>>;
+
+/// Prefix of system modules like [`METRICS_MODULE`](crate::core::METRICS_MODULE), they are
+/// left out of [`Modular::module_names`](crate::core::Modular::module_names) and only
+/// matched by [`ModulesRegistry::invoke_many`] patterns that start with the prefix too.
+pub const SYSTEM_MODULE_PREFIX: &str = "$.sys.";
+
+pub fn is_system_module(name: &str) -> bool {
+    name.starts_with(SYSTEM_MODULE_PREFIX)
+}
+
+/// When [`ModulesRegistry::invoke_many`] stops waiting for the remaining modules.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Completion {
+    /// Wait for every module.
+    All,
+    /// Stop once this many modules responded successfully.
+    Quorum(usize),
+    /// Stop once this many modules responded, successfully or not.
+    First(usize),
+}
+
 pub struct ModulesRegistry<Req, Resp> {
-    modules: RwLock<HashMap<String, Arc<Mutex<BoxModuleService<Req, Resp>>>>>,
+    modules: RwLock<HashMap<String, SharedModuleService<Req, Resp>>>,
+    metrics: Arc<ModuleMetrics>,
 }
 
 impl<Req, Resp> Default for ModulesRegistry<Req, Resp> {
     fn default() -> Self {
         Self {
             modules: Default::default(),
+            metrics: Default::default(),
         }
     }
 }
@@ -37,7 +68,7 @@ where
         S::Future: Send + Sync + 'static,
     {
         let mut modules = self.modules.write();
-        let svc = BoxModuleService::new(ModuleService(svc, Default::default()));
+        let svc = self.instrument(name, svc);
 
         match modules.entry(name.to_string()) {
             Entry::Occupied(_) => {
@@ -59,13 +90,13 @@ where
         S::Error: Into<ModuleError> + Send + 'static,
         S::Future: Send + Sync + 'static,
     {
-        let svc = BoxModuleService::new(ModuleService(svc, Default::default()));
+        let svc = self.instrument(name, svc);
 
         let mut modules = self.modules.write();
 
         let entry = modules.entry(name.to_string());
 
-        let mut existing = match entry {
+        let existing = match entry {
             Entry::Occupied(entry) => entry,
             Entry::Vacant(entry) => {
                 entry.insert(Arc::new(Mutex::new(svc)));
@@ -73,19 +104,334 @@ where
             }
         };
 
-        let existing = existing.get_mut();
-        debug_assert!(Arc::strong_count(existing) == 1);
-
-        *Arc::get_mut(existing).unwrap() = Mutex::new(svc)
+        // handles and running invokes share the module, invokes only hold the lock while
+        // they start a call
+        let existing = existing.get();
+        loop {
+            match existing.try_lock() {
+                Ok(mut module) => break *module = svc,
+                Err(_) => std::thread::yield_now(),
+            }
+        }
     }
 
     pub fn get(&self, name: &str) -> Option<Module<Request, Response>> {
         let modules = self.modules.read();
-        modules.get(name).map(|m| Module(Arc::downgrade(m)))
+        modules.get(name).map(|m| Module(Arc::downgrade(m), None))
+    }
+
+    /// Invokes of the modules, including invokes of removed modules.
+    pub(crate) fn metrics(&self) -> &Arc<ModuleMetrics> {
+        &self.metrics
+    }
+
+    fn instrument<S, Req>(&self, name: &str, svc: S) -> BoxModuleService<Request, Response>
+    where
+        S: Service<Req> + Send + 'static,
+        Req: From<ModuleRequest<Request>> + Send + 'static,
+        S::Response: Into<ModuleResponse<Response>> + Send + 'static,
+        S::Error: Into<ModuleError> + Send + 'static,
+        S::Future: Send + Sync + 'static,
+    {
+        BoxModuleService::new(Instrumented {
+            name: name.into(),
+            metrics: self.metrics.clone(),
+            inner: BoxModuleService::new(ModuleService(svc, Default::default())),
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.modules.read().contains_key(name)
     }
 
-    pub fn remove(&self, name: &str) {
+    pub fn remove(&self, name: &str) -> bool {
         let mut modules = self.modules.write();
-        modules.remove(name);
+        modules.remove(name).is_some()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.modules.read().keys().cloned().collect()
+    }
+
+    /// Invokes every module whose name matches `pattern` concurrently and returns the
+    /// results in the order they completed. Calls still running when `completion` is
+    /// reached are dropped. System modules are only invoked if `pattern` starts with
+    /// [`SYSTEM_MODULE_PREFIX`].
+    pub async fn invoke_many(
+        &self,
+        pattern: &Pattern,
+        req: ModuleRequest<Request>,
+        completion: Completion,
+    ) -> Vec<(String, Result<ModuleResponse<Response>, ModuleError>)>
+    where
+        Request: Clone,
+    {
+        self.invoke_many_admitted(pattern, req, completion, |_| Ok(()))
+            .await
+    }
+
+    /// Like [`invoke_many`](Self::invoke_many), modules `admit` returns an error for fail
+    /// with it without being called.
+    pub(crate) async fn invoke_many_admitted(
+        &self,
+        pattern: &Pattern,
+        req: ModuleRequest<Request>,
+        completion: Completion,
+        admit: impl Fn(&str) -> Result<(), ModuleError>,
+    ) -> Vec<(String, Result<ModuleResponse<Response>, ModuleError>)>
+    where
+        Request: Clone,
+    {
+        let modules = self
+            .modules
+            .read()
+            .iter()
+            .filter(|(name, _)| {
+                pattern.matches_strict(name)
+                    && (!is_system_module(name)
+                        || pattern.as_str().starts_with(SYSTEM_MODULE_PREFIX))
+            })
+            .map(|(name, module)| (name.clone(), module.clone()))
+            .collect::<Vec<_>>();
+
+        let mut calls = modules
+            .into_iter()
+            .map(|(name, module)| {
+                let req = ModuleRequest::new(req.action(), req.body().clone());
+                let admitted = admit(&name);
+                async move {
+                    if let Err(e) = admitted {
+                        return (name, Err(e));
+                    }
+                    let fut = module.lock().await.call(req);
+                    drop(module);
+                    (name, fut.await)
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut results = vec![];
+        let mut succeeded = 0;
+        while let Some((name, result)) = calls.next().await {
+            succeeded += result.is_ok() as usize;
+            results.push((name, result));
+
+            let done = match completion {
+                Completion::All => false,
+                Completion::Quorum(n) => succeeded >= n,
+                Completion::First(n) => results.len() >= n,
+            };
+
+            if done {
+                break;
+            }
+        }
+
+        results
+    }
+}
+
+/// Records the invokes of a module in the registry's metrics.
+struct Instrumented<Req, Resp> {
+    name: Arc<str>,
+    metrics: Arc<ModuleMetrics>,
+    inner: BoxModuleService<Req, Resp>,
+}
+
+impl<Req, Resp> Service<ModuleRequest<Req>> for Instrumented<Req, Resp>
+where
+    Resp: 'static,
+{
+    type Response = ModuleResponse<Resp>;
+    type Error = ModuleError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ModuleRequest<Req>) -> Self::Future {
+        let name = self.name.clone();
+        let metrics = self.metrics.clone();
+        let action = req.action.clone();
+        let started = Instant::now();
+        let fut = self.inner.call(req);
+
+        async move {
+            let result = fut.await;
+            let error = result.as_ref().err();
+            metrics.record(&name, &action, error, Some(started.elapsed()));
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use modular_core::module::Module as _;
+    use std::time::Duration;
+
+    /// A module answering with its name after `delay`, or failing if `fail` is set.
+    fn module(
+        name: &'static str,
+        delay: u64,
+        fail: bool,
+    ) -> impl Service<
+        ModuleRequest<Bytes>,
+        Response = ModuleResponse<Bytes>,
+        Error = ModuleError,
+        Future = impl Send + Sync,
+    > + Send
+           + 'static {
+        tower::service_fn(move |_: ModuleRequest<Bytes>| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            if fail {
+                return Err(ModuleError::UnknownMethod);
+            }
+            Ok(ModuleResponse::new(Bytes::from(name)))
+        })
+    }
+
+    fn registry() -> ModulesRegistry<Bytes, Bytes> {
+        let registry = ModulesRegistry::default();
+        registry
+            .register("storage.a", module("a", 0, false))
+            .unwrap();
+        registry
+            .register("storage.b", module("b", 200, false))
+            .unwrap();
+        registry
+            .register("storage.c", module("c", 10, true))
+            .unwrap();
+        registry
+            .register("other", module("other", 0, false))
+            .unwrap();
+        // a prefix of the names above, `storage.>` doesn't match it
+        registry
+            .register("storage", module("storage", 0, false))
+            .unwrap();
+        registry
+    }
+
+    fn names(results: &[(String, Result<ModuleResponse<Bytes>, ModuleError>)]) -> Vec<&str> {
+        let mut names = results
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    async fn invoke(
+        registry: &ModulesRegistry<Bytes, Bytes>,
+        completion: Completion,
+    ) -> Vec<(String, Result<ModuleResponse<Bytes>, ModuleError>)> {
+        let pattern = Pattern::parse("storage.>").unwrap();
+        let req = ModuleRequest::new("health", Bytes::new());
+        registry.invoke_many(&pattern, req, completion).await
+    }
+
+    #[tokio::test]
+    async fn every_matching_module_is_invoked() {
+        let results = invoke(&registry(), Completion::All).await;
+        assert_eq!(names(&results), ["storage.a", "storage.b", "storage.c"]);
+
+        for (name, result) in results {
+            match name.as_str() {
+                "storage.c" => assert!(matches!(result, Err(ModuleError::UnknownMethod))),
+                _ => assert_eq!(result.unwrap().data, name[8..]),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn quorum_waits_for_successful_responses() {
+        // the failing module responds before the slow one, but doesn't count
+        let results = invoke(&registry(), Completion::Quorum(2)).await;
+        assert_eq!(names(&results), ["storage.a", "storage.b", "storage.c"]);
+
+        let results = invoke(&registry(), Completion::Quorum(1)).await;
+        assert_eq!(names(&results), ["storage.a"]);
+    }
+
+    #[tokio::test]
+    async fn first_stops_after_any_responses() {
+        let results = invoke(&registry(), Completion::First(2)).await;
+        assert_eq!(names(&results), ["storage.a", "storage.c"]);
+    }
+
+    #[tokio::test]
+    async fn prefixes_of_the_pattern_are_not_invoked() {
+        let req = || ModuleRequest::new("health", Bytes::new());
+        let registry = registry();
+
+        let pattern = Pattern::parse("storage.{}").unwrap();
+        let results = registry.invoke_many(&pattern, req(), Completion::All).await;
+        assert_eq!(names(&results), ["storage.a", "storage.b", "storage.c"]);
+
+        let pattern = Pattern::parse("storage.a.b").unwrap();
+        let results = registry.invoke_many(&pattern, req(), Completion::All).await;
+        assert!(results.is_empty());
+
+        let pattern = Pattern::parse("storage").unwrap();
+        let results = registry.invoke_many(&pattern, req(), Completion::All).await;
+        assert_eq!(names(&results), ["storage"]);
+    }
+
+    #[tokio::test]
+    async fn modules_can_be_replaced_while_invoked() {
+        let registry = Arc::new(registry());
+        let pending = tokio::spawn({
+            let registry = registry.clone();
+            async move { invoke(&registry, Completion::All).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        registry.register_or_replace("storage.b", module("replaced", 0, false));
+
+        let results = pending.await.unwrap();
+
+        // handles taken before the replacement call the new module
+        let handle = registry.get("storage.b").unwrap();
+        registry.register_or_replace("storage.b", module("again", 0, false));
+        let req = ModuleRequest::new("health", Bytes::new());
+        let response = handle.invoke(req).await.unwrap().await.unwrap();
+        assert_eq!(response.data, "again");
+
+        let (_, result) = results
+            .iter()
+            .find(|(name, _)| name == "storage.b")
+            .unwrap();
+        assert_eq!(result.as_ref().unwrap().data, "b");
+    }
+
+    #[tokio::test]
+    async fn no_matching_modules_give_no_results() {
+        let pattern = Pattern::parse("missing.>").unwrap();
+        let req = ModuleRequest::new("health", Bytes::new());
+        let results = registry().invoke_many(&pattern, req, Completion::All).await;
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_modules_fail_with_the_admission_error() {
+        let pattern = Pattern::parse("storage.>").unwrap();
+        let req = ModuleRequest::new("health", Bytes::new());
+        let results = registry()
+            .invoke_many_admitted(&pattern, req, Completion::All, |name| match name {
+                "storage.a" => Err(ModuleError::Overloaded),
+                _ => Ok(()),
+            })
+            .await;
+
+        let (_, result) = results
+            .iter()
+            .find(|(name, _)| name == "storage.a")
+            .unwrap();
+        assert!(matches!(result, Err(ModuleError::Overloaded)));
+        assert_eq!(results.len(), 3);
     }
 }
@@ -1,15 +1,18 @@
-use anyhow::anyhow;
+use modular_core::error::{Expected, PatternError};
 use nom::branch::alt;
 use nom::bytes::complete::{escaped_transform, is_not, tag};
 use nom::character::complete::{alpha1, alphanumeric1, one_of};
-use nom::combinator::{eof, map, opt, recognize};
+use nom::combinator::{cut, eof, map, not, opt, recognize};
+use nom::error::ErrorKind;
 use nom::multi::{many0, many0_count};
 use nom::sequence::{pair, preceded, tuple};
-use nom::IResult;
 use std::fmt::{Display, Formatter};
 
+type IResult<'a, O> = nom::IResult<&'a str, O, ParseError<'a>>;
+
 #[derive(Debug, Clone)]
 pub struct Pattern {
+    source: String,
     nodes: Vec<Node>,
     is_trailing_any: bool,
 }
@@ -34,18 +37,39 @@ impl Display for Pattern {
 }
 
 impl Pattern {
-    pub fn parse<S: AsRef<str>>(str: S) -> anyhow::Result<Self> {
+    pub fn parse<S: AsRef<str>>(str: S) -> Result<Self, PatternError> {
         let str = str.as_ref();
 
-        let (_, (nodes, is_trailing_any)) =
-            parse(str).map_err(|e| anyhow!("invalid pattern: {:?}", e))?;
+        let (_, (nodes, is_trailing_any)) = parse(str).map_err(|e| match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.into_pattern_error(str),
+            nom::Err::Incomplete(_) => unreachable!("only complete parsers are used"),
+        })?;
 
         Ok(Self {
+            source: str.to_owned(),
             nodes,
             is_trailing_any,
         })
     }
 
+    /// The string this pattern was parsed from.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Segments of the pattern with escapes resolved, without the trailing `>`.
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'_>> {
+        self.nodes.iter().map(|node| match node {
+            Node::Arg(v) => Segment::Arg(v.as_deref()),
+            Node::Const(v) => Segment::Const(v),
+        })
+    }
+
+    /// Whether the pattern ends with `>` and matches any number of trailing segments.
+    pub fn is_trailing_any(&self) -> bool {
+        self.is_trailing_any
+    }
+
     pub fn matches<S: AsRef<str>>(&self, str: S) -> bool {
         let mut nodes_iter = self.nodes.iter();
         let other_iter = str.as_ref().split('.');
@@ -68,6 +92,69 @@ impl Pattern {
 
         true
     }
+
+    /// Like [`matches`](Self::matches), but `str` needs as many segments as the pattern,
+    /// at least one more if it ends with `>`, so prefixes of the pattern don't match.
+    pub fn matches_strict<S: AsRef<str>>(&self, str: S) -> bool {
+        let mut segments = str.as_ref().split('.');
+        for node in &self.nodes {
+            match (node, segments.next()) {
+                (Node::Arg(_), Some(_)) => continue,
+                (Node::Const(v), Some(other)) if v == other => continue,
+                _ => return false,
+            }
+        }
+
+        segments.next().is_some() == self.is_trailing_any
+    }
+
+    /// Escapes `str` so that a pattern parsed from it only matches `str` itself.
+    pub fn escape(str: &str) -> String {
+        let mut out = String::with_capacity(str.len());
+        for c in str.chars() {
+            if matches!(c, '{' | '}' | '\\' | '>') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        out
+    }
+
+    /// Whether every topic `other` matches strictly is also matched strictly by this
+    /// pattern, see [`matches_strict`](Self::matches_strict).
+    pub fn covers(&self, other: &Pattern) -> bool {
+        for (idx, other_node) in other.nodes.iter().enumerate() {
+            match (self.nodes.get(idx), other_node) {
+                (Some(Node::Arg(_)), _) => continue,
+                (Some(Node::Const(v)), Node::Const(other)) if v == other => continue,
+                (Some(Node::Const(_)), _) => return false,
+                (None, _) => return self.is_trailing_any,
+            }
+        }
+
+        self.nodes.len() == other.nodes.len() && self.is_trailing_any == other.is_trailing_any
+    }
+
+    /// The segment of `str` matched by the argument `{name}`, if the pattern has one and
+    /// matches `str`.
+    pub fn capture<'a>(&self, str: &'a str, name: &str) -> Option<&'a str> {
+        if !self.matches(str) {
+            return None;
+        }
+
+        let idx = self
+            .nodes
+            .iter()
+            .position(|node| matches!(node, Node::Arg(Some(v)) if v == name))?;
+        str.split('.').nth(idx)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Segment<'a> {
+    /// `{}` or `{name}`, matches any single segment.
+    Arg(Option<&'a str>),
+    Const(&'a str),
 }
 
 #[derive(Debug, Clone)]
@@ -76,7 +163,84 @@ enum Node {
     Const(String),
 }
 
-fn parse(str: &str) -> IResult<&str, (Vec<Node>, bool)> {
+#[derive(Debug)]
+struct ParseError<'a> {
+    input: &'a str,
+    expected: Option<Expected>,
+}
+
+impl<'a> ParseError<'a> {
+    fn into_pattern_error(self, pattern: &str) -> PatternError {
+        let position = pattern.len() - self.input.len();
+
+        PatternError {
+            position,
+            expected: self.expected.unwrap_or(Expected::Segment),
+            segment: segment_at(pattern, position).to_owned(),
+        }
+    }
+}
+
+impl<'a> nom::error::ParseError<&'a str> for ParseError<'a> {
+    fn from_error_kind(input: &'a str, _: ErrorKind) -> Self {
+        Self {
+            input,
+            expected: None,
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        // report the branch that got the furthest
+        if other.input.len() <= self.input.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Tags errors of `parser` that don't know what was expected yet.
+fn expect<'a, O, F>(expected: Expected, mut parser: F) -> impl FnMut(&'a str) -> IResult<'a, O>
+where
+    F: FnMut(&'a str) -> IResult<'a, O>,
+{
+    move |str| {
+        parser(str).map_err(|e| {
+            e.map(|mut e| {
+                e.expected.get_or_insert(expected);
+                e
+            })
+        })
+    }
+}
+
+/// Returns the `.`-separated segment of `pattern` containing the byte at `position`.
+fn segment_at(pattern: &str, position: usize) -> &str {
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (idx, ch) in pattern.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match ch {
+            '\\' => escaped = true,
+            '.' if idx < position => start = idx + 1,
+            '.' => return &pattern[start..idx],
+            _ => {}
+        }
+    }
+
+    &pattern[start.min(pattern.len())..]
+}
+
+fn parse(str: &str) -> IResult<'_, (Vec<Node>, bool)> {
     let (rest, (mut start_nodes, is_trailing)) = alt((
         map(trailing_any, |is_trailing_any| (vec![], is_trailing_any)),
         map(node, |e| (vec![e], false)),
@@ -86,7 +250,7 @@ fn parse(str: &str) -> IResult<&str, (Vec<Node>, bool)> {
         return Ok((rest, (start_nodes, is_trailing)));
     }
 
-    let (rest, nodes) = many0(preceded(tag("."), node))(rest)?;
+    let (rest, nodes) = many0(preceded(pair(tag("."), not(tag(">"))), cut(node)))(rest)?;
     let (rest, is_trailing_any) = trailing_any(rest)?;
 
     start_nodes.extend(nodes);
@@ -94,33 +258,33 @@ fn parse(str: &str) -> IResult<&str, (Vec<Node>, bool)> {
     Ok((rest, (start_nodes, is_trailing_any)))
 }
 
-fn node(str: &str) -> IResult<&str, Node> {
-    alt((const_node, arg_node))(str).map(|(s, node)| (s, node))
+fn node(str: &str) -> IResult<'_, Node> {
+    expect(Expected::Segment, alt((const_node, arg_node)))(str)
 }
 
-fn const_node(str: &str) -> IResult<&str, Node> {
+fn const_node(str: &str) -> IResult<'_, Node> {
     escaped_node(str).map(|(s, v)| (s, Node::Const(v)))
 }
 
 #[rustfmt::skip]
-fn escaped_node(str: &str) -> IResult<&str, String> {
+fn escaped_node(str: &str) -> IResult<'_, String> {
     if str.is_empty() {
-        return Err(nom::Err::Error(nom::error::Error::new(
+        return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
             str,
-            nom::error::ErrorKind::Eof,
+            ErrorKind::Eof,
         )));
     }
     
     escaped_transform(
         is_not("{}.\\>"), 
         '\\', 
-        one_of("{}.\\>")
+        expect(Expected::EscapedChar, one_of("{}.\\>"))
     )(str)
 }
 
 #[rustfmt::skip]
-fn arg_node(str: &str) -> IResult<&str, Node> {
-    pub fn identifier(input: &str) -> IResult<&str, &str> {
+fn arg_node(str: &str) -> IResult<'_, Node> {
+    pub fn identifier(input: &str) -> IResult<'_, &str> {
         recognize(
             pair(
                 alt((alpha1, tag("_"))),
@@ -132,13 +296,82 @@ fn arg_node(str: &str) -> IResult<&str, Node> {
     tuple((
         tag("{"),
         opt(identifier),
-        tag("}"),
+        cut(expect(Expected::ClosingBrace, tag("}"))),
     ))(str)
     .map(|(s, (_, arg, _))| (s, Node::Arg(arg.map(|i| i.to_string()))))
 }
 
 #[rustfmt::skip]
-fn trailing_any(str: &str) -> IResult<&str, bool> {
-    tuple((opt(tag(".>")), eof))(str)
+fn trailing_any(str: &str) -> IResult<'_, bool> {
+    tuple((opt(tag(".>")), expect(Expected::End, eof)))(str)
         .map(|(v, out)| (v, out.0.is_some()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> Pattern {
+        Pattern::parse(pattern).unwrap()
+    }
+
+    #[test]
+    fn strict_matches_need_every_segment() {
+        assert!(pattern("a.b").matches_strict("a.b"));
+        assert!(pattern("a.{}").matches_strict("a.b"));
+        assert!(!pattern("a.b").matches_strict("a"));
+        assert!(!pattern("a.b").matches_strict("a.b.c"));
+        assert!(!pattern("a.{}").matches_strict("a"));
+
+        assert!(pattern("a.>").matches_strict("a.b"));
+        assert!(pattern("a.>").matches_strict("a.b.c"));
+        assert!(!pattern("a.>").matches_strict("a"));
+        assert!(pattern("{}.>").matches_strict("a.b"));
+        assert!(!pattern("{}.>").matches_strict("a"));
+
+        // the lenient matcher accepts prefixes
+        assert!(pattern("a.b").matches("a"));
+    }
+
+    #[test]
+    fn covered_patterns_match_a_subset() {
+        assert!(pattern("a.>").covers(&pattern("a.b")));
+        assert!(pattern("a.>").covers(&pattern("a.b.>")));
+        assert!(pattern("a.>").covers(&pattern("a.>")));
+        assert!(pattern("a.{}").covers(&pattern("a.b")));
+        assert!(pattern("a.b").covers(&pattern("a.b")));
+
+        assert!(!pattern("a.b").covers(&pattern("a")));
+        assert!(!pattern("a.>").covers(&pattern("a")));
+        assert!(!pattern("a.b.>").covers(&pattern("a.>")));
+        assert!(!pattern("a.{}").covers(&pattern("a.>")));
+        assert!(!pattern("a.b").covers(&pattern("a.b.c")));
+        assert!(!pattern("a.b").covers(&pattern("a.{}")));
+        assert!(!pattern("a.b").covers(&pattern("a.b.>")));
+    }
+
+    #[test]
+    fn errors_point_at_the_failing_segment() {
+        let cases = [
+            ("a.{b", 4, Expected::ClosingBrace, "{b"),
+            ("a.{1b}", 3, Expected::ClosingBrace, "{1b}"),
+            ("a.>.b", 3, Expected::End, ">"),
+            ("a..b", 2, Expected::Segment, ""),
+            ("a.", 2, Expected::Segment, ""),
+            ("a.b\\x", 4, Expected::EscapedChar, "b\\x"),
+        ];
+
+        for (source, position, expected, segment) in cases {
+            assert_eq!(
+                Pattern::parse(source).unwrap_err(),
+                PatternError {
+                    position,
+                    expected,
+                    segment: segment.to_owned(),
+                },
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use bytes::Bytes;
+
+/// Prefix of the inbox topics replies to requests are published on.
+pub const INBOX_PREFIX: &str = "$.inbox.";
+
+/// Request received by a responder, see [`Modular::respond`](super::Modular::respond).
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub body: Bytes,
+    pub(crate) reply_to: String,
+}
+
+impl Request {
+    /// Inbox topic the replies are delivered to.
+    pub fn reply_to(&self) -> &str {
+        &self.reply_to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::acl::{Acl, Permission};
+    use crate::core::Modular;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use modular_core::error::RequestError;
+    use modular_core::event::Event;
+    use modular_core::modular::Modular as _;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Answers requests to `pattern` with `reply` for as long as the instance lives.
+    fn responder(modular: &Arc<Modular>, pattern: &str, reply: &'static str) {
+        let (tx, mut rx) = mpsc::unbounded::<(String, Request)>();
+        modular.respond(pattern, tx).unwrap();
+
+        let modular = Arc::downgrade(modular);
+        tokio::spawn(async move {
+            while let Some((_, request)) = rx.next().await {
+                let Some(modular) = modular.upgrade() else {
+                    return;
+                };
+                modular.reply(&request, Bytes::from(reply));
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn requests_without_responders_fail() {
+        let modular = Modular::default();
+        let result = modular.request("a.b", Bytes::new(), TIMEOUT).await;
+        assert!(matches!(result, Err(RequestError::NoResponders)));
+    }
+
+    #[tokio::test]
+    async fn requests_without_replies_time_out() {
+        let modular = Modular::default();
+        let (tx, _rx) = mpsc::unbounded::<(String, Request)>();
+        modular.respond("a.b", tx).unwrap();
+
+        let result = modular
+            .request("a.b", Bytes::new(), Duration::from_millis(20))
+            .await;
+        assert!(matches!(result, Err(RequestError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn replies_are_collected_up_to_the_maximum() {
+        let modular = Arc::new(Modular::default());
+        responder(&modular, "a.b", "x");
+        responder(&modular, "{}.b", "x");
+        responder(&modular, "a.>", "x");
+
+        let reply = modular.request("a.b", Bytes::new(), TIMEOUT).await.unwrap();
+        assert_eq!(reply, "x");
+
+        let replies = modular
+            .request_many("a.b", Bytes::new(), 2, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(replies.len(), 2);
+
+        // fewer replies than asked for are returned at the timeout
+        let replies = modular
+            .request_many("a.b", Bytes::new(), 5, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(replies.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn inboxes_are_not_exposed() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = mpsc::unbounded::<(String, Request)>();
+        modular.respond("a.b", tx).unwrap();
+        let (events_tx, mut events) = mpsc::unbounded::<Event>();
+        modular
+            .subscribe_filtered("$.inbox.>", |_| true, events_tx)
+            .unwrap();
+
+        let pending = tokio::spawn({
+            let modular = modular.clone();
+            async move { modular.request("a.b", Bytes::new(), TIMEOUT).await }
+        });
+        let (_, request) = rx.next().await.unwrap();
+        assert!(request.reply_to().starts_with(INBOX_PREFIX));
+
+        // the inbox isn't a subscription, so it isn't advertised to federated nodes
+        assert_eq!(modular.subscription_patterns().len(), 1);
+        assert!(modular.subscription_patterns()[0].matches("$.inbox.x"));
+
+        // publishing to the inbox doesn't spoof a reply
+        modular.publish(Event::new(request.reply_to(), Bytes::from("spoofed")));
+        modular.publish_local(Event::new(request.reply_to(), Bytes::from("spoofed")));
+
+        modular.reply(&request, Bytes::from("x"));
+        assert_eq!(pending.await.unwrap().unwrap(), "x");
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn interceptors_apply_to_requests_and_replies() {
+        let modular = Arc::new(Modular::default());
+        responder(&modular, "a.>", "x");
+        modular.add_interceptor(Arc::new(|event: Event| {
+            (event.topic != "a.drop").then_some(event)
+        }));
+
+        let result = modular.request("a.drop", Bytes::new(), TIMEOUT).await;
+        assert!(matches!(result, Err(RequestError::Dropped)));
+
+        let (tx, mut rx) = mpsc::unbounded::<(String, Request)>();
+        modular.respond("b.b", tx).unwrap();
+        let pending = tokio::spawn({
+            let modular = modular.clone();
+            async move {
+                modular
+                    .request("b.b", Bytes::new(), Duration::from_millis(50))
+                    .await
+            }
+        });
+        let (topic, request) = rx.next().await.unwrap();
+        assert_eq!(topic, "b.b");
+
+        // replies can't be rerouted away from the inbox
+        modular.add_interceptor(Arc::new(|event: Event| {
+            Some(Event::new("a.elsewhere", event.data))
+        }));
+        modular.reply(&request, Bytes::from("x"));
+        assert!(matches!(pending.await.unwrap(), Err(RequestError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn interceptors_reroute_requests() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = mpsc::unbounded::<(String, Request)>();
+        modular.respond("a.moved", tx).unwrap();
+        modular.add_interceptor(Arc::new(|event: Event| match event.topic.as_str() {
+            "a.b" => Some(Event::new("a.moved", event.data)),
+            _ => Some(event),
+        }));
+
+        let pending = tokio::spawn({
+            let modular = modular.clone();
+            async move { modular.request("a.b", Bytes::new(), TIMEOUT).await }
+        });
+        let (topic, request) = rx.next().await.unwrap();
+        assert_eq!(topic, "a.moved");
+
+        modular.reply(&request, Bytes::from("x"));
+        assert_eq!(pending.await.unwrap().unwrap(), "x");
+    }
+
+    #[tokio::test]
+    async fn payload_limits_apply_to_requests_and_replies() {
+        let modular = Arc::new(Modular::default());
+        modular.set_max_payload(Some(4));
+        responder(&modular, "a.short", "x");
+        responder(&modular, "a.long", "too long");
+
+        let result = modular
+            .request("a.short", Bytes::from("too long"), TIMEOUT)
+            .await;
+        assert!(matches!(result, Err(RequestError::Dropped)));
+
+        let reply = modular
+            .request("a.short", Bytes::from("x"), TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(reply, "x");
+
+        let result = modular
+            .request("a.long", Bytes::from("x"), Duration::from_millis(20))
+            .await;
+        assert!(matches!(result, Err(RequestError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn scoped_requests_are_limited_by_the_acl() {
+        let modular = Arc::new(Modular::default());
+        responder(&modular, "a.>", "x");
+        let acl = Acl::new()
+            .allow(Permission::Publish, "a.public")
+            .and_then(|acl| acl.allow(Permission::Subscribe, "b.>"))
+            .and_then(|acl| acl.deny(Permission::Subscribe, "b.private"))
+            .unwrap();
+        let scoped = modular.scoped("user", acl);
+
+        let result = scoped.request("a.private", Bytes::new(), TIMEOUT).await;
+        assert!(matches!(result, Err(RequestError::Denied)));
+        let reply = scoped.request("a.public", Bytes::new(), TIMEOUT).await;
+        assert_eq!(reply.unwrap(), "x");
+
+        let (tx, _rx) = mpsc::unbounded::<(String, Request)>();
+        assert!(scoped.respond("c.>", tx).is_err());
+
+        // requests to topics the responder may not subscribe to aren't delivered to it
+        let (tx, mut rx) = mpsc::unbounded::<(String, Request)>();
+        scoped.respond("b.>", tx).unwrap();
+        let result = modular.request("b.private", Bytes::new(), TIMEOUT).await;
+        assert!(matches!(result, Err(RequestError::NoResponders)));
+        assert!(rx.try_recv().is_err());
+    }
+}
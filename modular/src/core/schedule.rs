@@ -0,0 +1,540 @@
+//! Delayed and recurring publishing, see [`Modular::publish_at`](super::Modular::publish_at).
+
+use crate::core::Modular;
+use bytes::Bytes;
+use modular_core::error::CronError;
+use modular_core::modular::Modular as _;
+use modular_core::modules::ModuleRequest;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::AbortHandle;
+
+/// A cron expression with the fields `minute hour day-of-month month day-of-week`,
+/// evaluated in UTC.
+///
+/// Fields are `*`, a value, a range `a-b` or a comma-separated list of those, each
+/// optionally followed by a step `/n`. Day of week is 0-6 starting on Sunday, 7 is
+/// Sunday too. When both day of month and day of week are restricted, a day matching
+/// either is used. Like in other crons, a field starting with `*` such as `*/2` counts
+/// as unrestricted, so a day has to match both fields then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Display for Cron {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Cron {
+    pub fn parse(source: &str) -> Result<Self, CronError> {
+        let fields = source.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError::FieldCount(fields.len()));
+        };
+
+        let mut weekdays_mask = parse_field(weekdays, 0, 7)?;
+        if weekdays_mask & (1 << 7) != 0 {
+            weekdays_mask = (weekdays_mask | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            source: source.to_owned(),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_mask,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// The first time matching the expression strictly after `time`, if there is one in
+    /// the next 5 years.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let minutes = time.duration_since(UNIX_EPOCH).ok()?.as_secs() / 60 + 1;
+        let start_day = (minutes / (24 * 60)) as i64;
+        let start_minute = minutes % (24 * 60);
+
+        for day in start_day..start_day + 5 * 366 {
+            let (_, month, day_of_month) = civil_from_days(day);
+            let weekday = (day + 4).rem_euclid(7) as u32;
+            if !self.matches_day(month, day_of_month, weekday) {
+                continue;
+            }
+
+            let from = if day == start_day { start_minute } else { 0 };
+            for minute_of_day in from..24 * 60 {
+                let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                if self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0 {
+                    let secs = (day as u64 * 24 * 60 + minute_of_day) * 60;
+                    return Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, month: u32, day: u32, weekday: u32) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+/// Parses a cron field into a bit mask of the values it contains.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, CronError> {
+    let invalid = || CronError::InvalidField(field.to_owned());
+    let value = |v: &str| -> Result<u32, CronError> {
+        v.parse::<u32>()
+            .ok()
+            .filter(|v| (min..=max).contains(v))
+            .ok_or_else(invalid)
+    };
+
+    let mut mask = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (item, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if from > to {
+            return Err(invalid());
+        }
+
+        for v in (from..=to).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+
+    Ok(mask)
+}
+
+/// Year, month and day of the `days`-th day since the Unix epoch.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleInfo {
+    pub id: u64,
+    pub topic: String,
+    /// When the event is published next.
+    pub next: SystemTime,
+    /// The expression of recurring schedules.
+    pub cron: Option<Cron>,
+}
+
+struct Entry {
+    info: ScheduleInfo,
+    task: AbortHandle,
+}
+
+#[derive(Default)]
+pub(crate) struct Schedules {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<u64, Entry>>,
+}
+
+impl Schedules {
+    /// Publishes `data` to `topic` at `next` and then at the times of `cron`, if any.
+    pub(crate) fn add(
+        self: &Arc<Self>,
+        modular: Weak<Modular>,
+        topic: &str,
+        data: Bytes,
+        next: SystemTime,
+        cron: Option<Cron>,
+    ) -> ScheduleHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = ScheduleInfo {
+            id,
+            topic: topic.to_owned(),
+            next,
+            cron,
+        };
+
+        // the task removes its entry once done, which waits for the entry to be inserted
+        let mut entries = self.entries.lock();
+        let task = tokio::spawn(run(Arc::downgrade(self), modular, info.clone(), data));
+        entries.insert(
+            id,
+            Entry {
+                info,
+                task: task.abort_handle(),
+            },
+        );
+
+        ScheduleHandle {
+            id,
+            schedules: Arc::downgrade(self),
+        }
+    }
+
+    pub(crate) fn cancel(&self, id: u64) -> bool {
+        match self.entries.lock().remove(&id) {
+            Some(entry) => {
+                entry.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<ScheduleInfo> {
+        let mut list = self
+            .entries
+            .lock()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.next);
+        list
+    }
+}
+
+impl Drop for Schedules {
+    fn drop(&mut self) {
+        for entry in self.entries.get_mut().values() {
+            entry.task.abort();
+        }
+    }
+}
+
+async fn run(schedules: Weak<Schedules>, modular: Weak<Modular>, info: ScheduleInfo, data: Bytes) {
+    let mut next = info.next;
+
+    loop {
+        let delay = next.duration_since(SystemTime::now()).unwrap_or_default();
+        tokio::time::sleep(delay).await;
+
+        let Some(modular) = modular.upgrade() else {
+            break;
+        };
+        modular.publish(ModuleRequest::new(&info.topic, data.clone()));
+
+        match info
+            .cron
+            .as_ref()
+            .and_then(|cron| following(cron, next, SystemTime::now()))
+        {
+            Some(v) => next = v,
+            None => break,
+        }
+
+        let Some(strong) = schedules.upgrade() else {
+            return;
+        };
+        let mut entries = strong.entries.lock();
+        if let Some(entry) = entries.get_mut(&info.id) {
+            entry.info.next = next;
+        }
+    }
+
+    if let Some(schedules) = schedules.upgrade() {
+        schedules.entries.lock().remove(&info.id);
+    }
+}
+
+/// The time after `scheduled` to publish at next, occurrences that passed while the
+/// runtime was stalled are skipped instead of published in a burst.
+fn following(cron: &Cron, scheduled: SystemTime, now: SystemTime) -> Option<SystemTime> {
+    cron.next_after(scheduled.max(now))
+}
+
+/// Cancels a schedule, dropping the handle keeps it running.
+#[derive(Debug, Clone)]
+pub struct ScheduleHandle {
+    id: u64,
+    schedules: Weak<Schedules>,
+}
+
+impl ScheduleHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns whether the schedule was still active.
+    pub fn cancel(&self) -> bool {
+        self.schedules
+            .upgrade()
+            .map(|schedules| schedules.cancel(self.id))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    /// The time of a UTC date, the inverse of `civil_from_days`.
+    fn at(year: i64, month: u32, day: u32, hour: u64, minute: u64) -> SystemTime {
+        let year = year - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let mp = (month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146097 + doe - 719468) as u64;
+        UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60)
+    }
+
+    fn next(cron: &str, time: SystemTime) -> Option<SystemTime> {
+        Cron::parse(cron).unwrap().next_after(time)
+    }
+
+    #[test]
+    fn civil_dates_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        for days in [0, 59, 10956, 20745, 100_000] {
+            let (year, month, day) = civil_from_days(days);
+            let secs = at(year, month, day, 0, 0)
+                .duration_since(UNIX_EPOCH)
+                .unwrap();
+            assert_eq!(secs.as_secs(), days as u64 * 86400);
+        }
+    }
+
+    #[test]
+    fn next_is_strictly_after_the_given_time() {
+        let time = at(2026, 10, 19, 12, 30);
+        assert_eq!(next("30 12 * * *", time), Some(at(2026, 10, 20, 12, 30)));
+        assert_eq!(next("* * * * *", time), Some(at(2026, 10, 19, 12, 31)));
+        assert_eq!(next("*/15 * * * *", time), Some(at(2026, 10, 19, 12, 45)));
+        assert_eq!(
+            next("10-20/5 * * * *", time),
+            Some(at(2026, 10, 19, 13, 10))
+        );
+    }
+
+    #[test]
+    fn months_and_years_roll_over() {
+        assert_eq!(
+            next("59 23 31 12 *", at(2026, 12, 31, 23, 59)),
+            Some(at(2027, 12, 31, 23, 59))
+        );
+        assert_eq!(
+            next("0 0 1 1 *", at(2026, 12, 31, 23, 59)),
+            Some(at(2027, 1, 1, 0, 0))
+        );
+
+        // months without a 31st are skipped
+        assert_eq!(
+            next("0 0 31 * *", at(2026, 10, 31, 0, 0)),
+            Some(at(2026, 12, 31, 0, 0))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", at(2026, 10, 19, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+
+        // the 30th of February never comes
+        assert_eq!(next("0 0 30 2 *", at(2026, 10, 19, 0, 0)), None);
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        assert_eq!(
+            Cron::parse("0 0 * * 7"),
+            Ok(Cron {
+                source: "0 0 * * 7".to_owned(),
+                ..Cron::parse("0 0 * * 0").unwrap()
+            })
+        );
+
+        // 2026-10-19 is a Monday
+        let monday = at(2026, 10, 19, 0, 0);
+        assert_eq!(next("0 0 * * 7", monday), Some(at(2026, 10, 25, 0, 0)));
+        assert_eq!(next("0 0 * * 5-7", monday), Some(at(2026, 10, 23, 0, 0)));
+        assert_eq!(
+            next("0 0 * * 6-7", at(2026, 10, 24, 0, 0)),
+            Some(at(2026, 10, 25, 0, 0))
+        );
+    }
+
+    #[test]
+    fn restricted_days_match_either_field() {
+        // the 1st of the month or a Friday, whichever comes first
+        let monday = at(2026, 10, 19, 0, 0);
+        assert_eq!(next("0 0 1 * 5", monday), Some(at(2026, 10, 23, 0, 0)));
+        assert_eq!(
+            next("0 0 1 * 5", at(2026, 10, 30, 0, 0)),
+            Some(at(2026, 11, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn stepped_wildcard_days_count_as_unrestricted() {
+        // odd days that are Fridays, not odd days or Fridays
+        let monday = at(2026, 10, 19, 0, 0);
+        assert_eq!(next("0 0 */2 * 5", monday), Some(at(2026, 10, 23, 0, 0)));
+        assert_eq!(
+            next("0 0 */2 * 5", at(2026, 10, 23, 0, 0)),
+            Some(at(2026, 11, 13, 0, 0))
+        );
+        assert_eq!(next("0 0 1 * */2", monday), Some(at(2026, 11, 1, 0, 0)));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        assert_eq!(Cron::parse("* * * *"), Err(CronError::FieldCount(4)));
+        for (cron, field) in [
+            ("60 * * * *", "60"),
+            ("* 24 * * *", "24"),
+            ("* * 0 * *", "0"),
+            ("* * * 13 *", "13"),
+            ("* * * * 8", "8"),
+            ("*/0 * * * *", "*/0"),
+            ("5-1 * * * *", "5-1"),
+            ("a * * * *", "a"),
+        ] {
+            assert_eq!(
+                Cron::parse(cron),
+                Err(CronError::InvalidField(field.to_owned())),
+                "{}",
+                cron
+            );
+        }
+    }
+
+    #[test]
+    fn missed_occurrences_are_skipped() {
+        let cron = Cron::parse("* * * * *").unwrap();
+        let scheduled = at(2026, 10, 19, 12, 0);
+        assert_eq!(
+            following(&cron, scheduled, scheduled),
+            Some(at(2026, 10, 19, 12, 1))
+        );
+        assert_eq!(
+            following(&cron, scheduled, at(2026, 10, 19, 12, 30)),
+            Some(at(2026, 10, 19, 12, 31))
+        );
+    }
+
+    #[tokio::test]
+    async fn schedules_are_listed_until_cancelled() {
+        let modular = Arc::new(Modular::default());
+        let now = SystemTime::now();
+        let later = modular.publish_at("a.later", Bytes::new(), now + Duration::from_secs(3600));
+        let sooner = modular.publish_at("a.sooner", Bytes::new(), now + Duration::from_secs(60));
+        let cron = modular
+            .publish_cron("a.cron", Bytes::new(), Cron::parse("0 0 1 1 *").unwrap())
+            .unwrap();
+
+        let topics = modular
+            .schedules()
+            .into_iter()
+            .map(|v| v.topic)
+            .collect::<Vec<_>>();
+        assert_eq!(topics, ["a.sooner", "a.later", "a.cron"]);
+        assert_eq!(
+            modular.schedules()[2].cron.as_ref().map(Cron::as_str),
+            Some("0 0 1 1 *")
+        );
+
+        assert!(later.cancel());
+        assert!(!later.cancel());
+        assert!(modular.cancel_schedule(cron.id()));
+        assert!(!modular.cancel_schedule(cron.id()));
+        let ids = modular
+            .schedules()
+            .into_iter()
+            .map(|v| v.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [sooner.id()]);
+    }
+
+    #[tokio::test]
+    async fn one_off_schedules_publish_once_and_are_removed() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        modular.subscribe("a.>", Some(tx)).unwrap();
+
+        let handle = modular.publish_after("a.b", Bytes::from("x"), Duration::from_millis(10));
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.topic, "a.b");
+        assert_eq!(event.data, "x");
+
+        for _ in 0..500 {
+            if modular.schedules().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        assert!(modular.schedules().is_empty());
+        assert!(!handle.cancel());
+    }
+
+    #[tokio::test]
+    async fn cancelled_schedules_do_not_publish() {
+        let modular = Arc::new(Modular::default());
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        modular.subscribe("a.>", Some(tx)).unwrap();
+
+        let handle = modular.publish_after("a.b", Bytes::new(), Duration::from_millis(20));
+        assert!(handle.cancel());
+        let next = tokio::time::timeout(Duration::from_millis(100), rx.next()).await;
+        assert!(next.is_err());
+    }
+}